@@ -1,12 +1,10 @@
-use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
 use kvs::common::{EngineType, Result};
 use kvs::engine::*;
 use rand::distributions::Alphanumeric;
 use rand::prelude::*;
 use rand_pcg::Pcg64;
 use std::collections::HashMap;
-use std::fs;
-use std::path::PathBuf;
 use tempfile::TempDir;
 #[derive(Clone)]
 struct EngineHolder {
@@ -23,17 +21,17 @@ impl EngineHolder {
         }
     }
 
-    fn remove(&self, key: String) -> Result<()> {
+    fn get(&self, key: String) -> Result<Option<String>> {
         match self.engine_type {
-            EngineType::Kvs => self.lkvs.as_ref().unwrap().remove(key),
-            EngineType::Sled => self.sled.as_ref().unwrap().remove(key),
+            EngineType::Kvs => self.lkvs.as_ref().unwrap().get(key),
+            EngineType::Sled => self.sled.as_ref().unwrap().get(key),
         }
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn sync(&self) -> Result<()> {
         match self.engine_type {
-            EngineType::Kvs => self.lkvs.as_ref().unwrap().get(key),
-            EngineType::Sled => self.sled.as_ref().unwrap().get(key),
+            EngineType::Kvs => self.lkvs.as_ref().unwrap().sync(),
+            EngineType::Sled => self.sled.as_ref().unwrap().sync(),
         }
     }
 }
@@ -47,14 +45,14 @@ fn generate_random_string(seed: u64) -> String {
         .map(char::from)
         .collect();
 
-    return s;
+    s
 }
 
 fn set_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_bench");
     for engine in [EngineType::Sled, EngineType::Kvs].iter() {
         let temp_dir = TempDir::new().unwrap();
-        let mut kv_store = match engine {
+        let kv_store = match engine {
             EngineType::Kvs => EngineHolder {
                 lkvs: Some(OptLogStructKvs::open(temp_dir.path()).unwrap()),
                 sled: None,
@@ -85,9 +83,107 @@ fn set_bench(c: &mut Criterion) {
 
                         (kv_store, keys, values)
                     },
-                    |(mut kv_store, mut keys, mut values)| {
+                    |(kv_store, mut keys, mut values)| {
                         for _ in 0..keys.len() {
-                            kv_store.set(keys.pop().unwrap(), values.pop().unwrap());
+                            kv_store
+                                .set(keys.pop().unwrap(), values.pop().unwrap())
+                                .unwrap();
+                        }
+                    },
+                    BatchSize::LargeInput,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Syncs after every set, sled flushes each write to disk while the log engine otherwise doesn't
+fn set_sync_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_sync_bench");
+    for engine in [EngineType::Sled, EngineType::Kvs].iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let kv_store = match engine {
+            EngineType::Kvs => EngineHolder {
+                lkvs: Some(OptLogStructKvs::open(temp_dir.path()).unwrap()),
+                sled: None,
+                engine_type: EngineType::Kvs,
+            },
+            EngineType::Sled => EngineHolder {
+                lkvs: None,
+                sled: Some(SledStore::open(temp_dir.path()).unwrap()),
+                engine_type: EngineType::Sled,
+            },
+        };
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(engine),
+            &kv_store,
+            |b, kv_store| {
+                b.iter_batched(
+                    || {
+                        let mut rng = Pcg64::seed_from_u64(1);
+                        let pairs: Vec<(String, String)> = (0..200)
+                            .map(|_| {
+                                (
+                                    rng.gen_range(0..100).to_string(),
+                                    rng.gen_range(0..100).to_string(),
+                                )
+                            })
+                            .collect();
+                        (kv_store, pairs)
+                    },
+                    |(kv_store, pairs)| {
+                        for (key, value) in pairs {
+                            kv_store.set(key, value).unwrap();
+                            kv_store.sync().unwrap();
+                        }
+                    },
+                    BatchSize::LargeInput,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Overwrites with compaction on the byte threshold and on every 500 writes
+/// The threshold is met in only some of the iterations, which then run a lot longer than the rest,
+/// with the fixed trigger every iteration compacts 4 times so the samples vary less between runs
+fn compaction_trigger_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction_trigger_bench");
+    let triggers = [
+        ("bytes", EngineConfig::default()),
+        (
+            "every_500_writes",
+            EngineConfig {
+                compact_every_writes: Some(500),
+                ..EngineConfig::default()
+            },
+        ),
+    ];
+    for (name, config) in triggers.iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let kv_store = OptLogStructKvs::open_with_config(temp_dir.path(), config.clone()).unwrap();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(name),
+            &kv_store,
+            |b, kv_store| {
+                b.iter_batched(
+                    || {
+                        let mut rng = Pcg64::seed_from_u64(1);
+                        (0..2000)
+                            .map(|_| {
+                                (
+                                    rng.gen_range(0..100).to_string(),
+                                    generate_random_string(rng.gen()),
+                                )
+                            })
+                            .collect::<Vec<(String, String)>>()
+                    },
+                    |pairs| {
+                        for (key, value) in pairs {
+                            kv_store.set(key, value).unwrap();
                         }
                     },
                     BatchSize::LargeInput,
@@ -102,15 +198,15 @@ fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
     for engine in [EngineType::Sled, EngineType::Kvs].iter() {
         let temp_dir = TempDir::new().unwrap();
-        let mut kv_store = match engine {
+        let kv_store = match engine {
             EngineType::Kvs => EngineHolder {
-                lkvs: Some(OptLogStructKvs::open(&temp_dir.path()).unwrap()),
+                lkvs: Some(OptLogStructKvs::open(temp_dir.path()).unwrap()),
                 sled: None,
                 engine_type: EngineType::Kvs,
             },
             EngineType::Sled => EngineHolder {
                 lkvs: None,
-                sled: Some(SledStore::open(&temp_dir.path()).unwrap()),
+                sled: Some(SledStore::open(temp_dir.path()).unwrap()),
                 engine_type: EngineType::Sled,
             },
         };
@@ -128,12 +224,12 @@ fn get_bench(c: &mut Criterion) {
                             let value = rng.gen_range(0..100).to_string();
                             index.insert(key.clone(), value.clone());
 
-                            kv_store.set(key, value);
+                            kv_store.set(key, value).unwrap();
                         }
 
                         (kv_store, index)
                     },
-                    |(mut kv_store, mut index)| {
+                    |(kv_store, index)| {
                         for (key, value) in index.iter() {
                             assert_eq!(value.clone(), kv_store.get(key.clone()).unwrap().unwrap());
                         }
@@ -145,5 +241,138 @@ fn get_bench(c: &mut Criterion) {
     }
     group.finish();
 }
-criterion_group!(benches, set_bench, get_bench);
+/// Random gets over packed records and over records aligned to 4 KiB pages
+/// Reads go through `pread`, there is no mmap reader for the alignment to help more
+fn aligned_get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("aligned_get_bench");
+    let layouts = [
+        ("packed", EngineConfig::default()),
+        (
+            "aligned_4k",
+            EngineConfig {
+                align_records: Some(4096),
+                ..EngineConfig::default()
+            },
+        ),
+    ];
+    for (name, config) in layouts.iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let kv_store = OptLogStructKvs::open_with_config(temp_dir.path(), config.clone()).unwrap();
+        let mut rng = Pcg64::seed_from_u64(1);
+        for i in 0..2000 {
+            let len = rng.gen_range(100..3000);
+            kv_store.set(format!("key{}", i), "x".repeat(len)).unwrap();
+        }
+        group.bench_with_input(
+            BenchmarkId::from_parameter(name),
+            &kv_store,
+            |b, kv_store| {
+                b.iter_batched(
+                    || {
+                        let mut rng = Pcg64::seed_from_u64(2);
+                        (0..1000)
+                            .map(|_| format!("key{}", rng.gen_range(0..2000)))
+                            .collect::<Vec<String>>()
+                    },
+                    |keys| {
+                        for key in keys {
+                            kv_store.get(key).unwrap().unwrap();
+                        }
+                    },
+                    BatchSize::LargeInput,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Gets keys in the order they were written, so their records are contiguous in the log,
+/// and in random order, with and without the read-ahead buffer
+fn read_ahead_get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_ahead_get_bench");
+    let keys: Vec<String> = (0..10000).map(|i| format!("key{:05}", i)).collect();
+    let mut shuffled = keys.clone();
+    shuffled.shuffle(&mut Pcg64::seed_from_u64(1));
+    for &read_ahead in [false, true].iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = EngineConfig {
+            read_ahead,
+            ..EngineConfig::default()
+        };
+        let kv_store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+        for key in keys.iter() {
+            kv_store
+                .set(key.clone(), generate_random_string(1))
+                .unwrap();
+        }
+        let buffering = if read_ahead { "read_ahead" } else { "direct" };
+        for (order, keys) in [("clustered", &keys), ("random", &shuffled)].iter() {
+            group.bench_function(BenchmarkId::new(*order, buffering), |b| {
+                b.iter(|| {
+                    for key in keys.iter() {
+                        kv_store.get(key.clone()).unwrap().unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// The same mix of sets, gets and range scans on the index in a `SkipMap` and in a locked `BTreeMap`
+fn index_backend_bench(c: &mut Criterion) {
+    fn run<B: IndexBackend>(c: &mut Criterion, name: &str) {
+        let temp_dir = TempDir::new().unwrap();
+        let kv_store =
+            OptLogStructKvs::<B>::open_with_index(temp_dir.path(), EngineConfig::default())
+                .unwrap();
+        for i in 0..10000 {
+            kv_store
+                .set(format!("key{:05}", i), generate_random_string(i))
+                .unwrap();
+        }
+        let mut group = c.benchmark_group("index_backend_bench");
+        group.throughput(Throughput::Elements(1000));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_batched(
+                || {
+                    let mut rng = Pcg64::seed_from_u64(1);
+                    (0..1000)
+                        .map(|_| format!("key{:05}", rng.gen_range(0..10000)))
+                        .collect::<Vec<String>>()
+                },
+                |keys| {
+                    for (i, key) in keys.into_iter().enumerate() {
+                        match i % 10 {
+                            0 => {
+                                kv_store.scan_prefix(&key[..7], None).unwrap();
+                            }
+                            1..=3 => kv_store.set(key, generate_random_string(1)).unwrap(),
+                            _ => {
+                                kv_store.get(key).unwrap().unwrap();
+                            }
+                        }
+                    }
+                },
+                BatchSize::LargeInput,
+            );
+        });
+        group.finish();
+    }
+
+    run::<SkipMapBackend>(c, "skip_map");
+    run::<BTreeBackend>(c, "btree_map");
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    set_sync_bench,
+    compaction_trigger_bench,
+    get_bench,
+    aligned_get_bench,
+    read_ahead_get_bench,
+    index_backend_bench
+);
 criterion_main!(benches);
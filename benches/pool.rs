@@ -1,22 +1,9 @@
-use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
-use kvs::client::KvsClient;
-use kvs::common::Command;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
 use kvs::common::{EngineType, Result};
 use kvs::engine::*;
-use kvs::server::KvsServer;
 use kvs::thread_pool::*;
-use rand::distributions::Alphanumeric;
 use rand::prelude::*;
-use rand_pcg::Pcg64;
-use std::collections::HashMap;
-use std::env;
-use std::fs;
-use std::net::SocketAddr;
 use std::path::Path;
-use std::process;
-use std::rc::Rc;
-use std::sync::{Arc, Barrier};
-use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 struct ThreadPoolHolder {
@@ -86,13 +73,6 @@ impl EngineHolder {
         }
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        match self.engine_type {
-            EngineType::Kvs => self.lkvs.as_ref().unwrap().remove(key),
-            EngineType::Sled => self.sled.as_ref().unwrap().remove(key),
-        }
-    }
-
     fn get(&self, key: String) -> Result<Option<String>> {
         match self.engine_type {
             EngineType::Kvs => self.lkvs.as_ref().unwrap().get(key),
@@ -101,19 +81,6 @@ impl EngineHolder {
     }
 }
 
-fn generate_random_string(seed: u64) -> String {
-    let mut rng = Pcg64::seed_from_u64(seed);
-
-    let len: usize = rng.gen_range(1..10000);
-    let s: String = rng
-        .sample_iter(&Alphanumeric)
-        .take(len)
-        .map(char::from)
-        .collect();
-
-    return s;
-}
-
 fn pool_set(c: &mut Criterion) {
     let mut group = c.benchmark_group("set");
     group
@@ -124,7 +91,7 @@ fn pool_set(c: &mut Criterion) {
         for pool_type in [ThreadPoolType::Rayon, ThreadPoolType::SharedQ] {
             for i in [1, 2, 4, 6, 8] {
                 let temp_dir = TempDir::new().unwrap();
-                let mut kv_store = EngineHolder::new(&engine_type, temp_dir.path()).unwrap();
+                let kv_store = EngineHolder::new(&engine_type, temp_dir.path()).unwrap();
                 group.bench_with_input(
                     BenchmarkId::from_parameter(format!(
                         "Engine: {}, Pool: {:?}, Num cpus: #{}",
@@ -143,8 +110,7 @@ fn pool_set(c: &mut Criterion) {
                                     values.push(rng.gen_range(0..100).to_string());
                                 }
                                 let pool =
-                                    ThreadPoolHolder::new(pool_type.clone(), i.clone() as u32)
-                                        .unwrap();
+                                    ThreadPoolHolder::new(pool_type.clone(), *i as u32).unwrap();
 
                                 (keys, values, pool, pool_type.clone())
                             },
@@ -159,7 +125,7 @@ fn pool_set(c: &mut Criterion) {
                                         });
                                     }
                                 }
-                                ThreadPoolType::Rayon => rayon::scope(|x| {
+                                ThreadPoolType::Rayon => rayon::scope(|_| {
                                     for _ in 0..keys.len() {
                                         let key = keys.pop().unwrap();
                                         let value = values.pop().unwrap();
@@ -188,9 +154,9 @@ fn pool_get(c: &mut Criterion) {
 
     for engine_type in [EngineType::Kvs, EngineType::Sled] {
         let temp_dir = TempDir::new().unwrap();
-        let mut kv_store = EngineHolder::new(&engine_type, temp_dir.path()).unwrap();
+        let kv_store = EngineHolder::new(&engine_type, temp_dir.path()).unwrap();
         for i in 0..10000 {
-            kv_store.set(i.to_string(), i.to_string());
+            kv_store.set(i.to_string(), i.to_string()).unwrap();
         }
         for pool_type in [ThreadPoolType::Rayon, ThreadPoolType::SharedQ] {
             for i in [1, 2, 4, 6, 8] {
@@ -211,12 +177,11 @@ fn pool_get(c: &mut Criterion) {
                                 }
 
                                 let pool =
-                                    ThreadPoolHolder::new(pool_type.clone(), i.clone() as u32)
-                                        .unwrap();
+                                    ThreadPoolHolder::new(pool_type.clone(), *i as u32).unwrap();
 
                                 (data, pool, pool_type.clone())
                             },
-                            |(mut data, pool, pool_type)| {
+                            |(data, pool, _)| {
                                 for key in data {
                                     let kv_store = kv_store.clone();
                                     pool.spawn(move || {
@@ -0,0 +1,147 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::common::Result;
+use kvs::engine::*;
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use std::env;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Keys of the populated storages, `RECOVERY_KEYS` overrides it
+const KEYS: u64 = 10_000;
+/// Times every key is set again after its first set, the garbage open replays,
+/// `RECOVERY_OVERWRITES` overrides it
+const OVERWRITES: u64 = 4;
+/// Compactions run while populating the compacted variant of the optimized log engine
+const COMPACTIONS: u64 = 4;
+/// Keys of the storage of the kvs engine set once each, the size where growing the index shows,
+/// `RECOVERY_MANY_KEYS` overrides it
+const MANY_KEYS: u64 = 200_000;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Never compacts on its own, so the storage holds all the garbage of the overwrites
+fn uncompacted_config() -> EngineConfig {
+    EngineConfig {
+        compact_threshold: u64::MAX,
+        max_log_files: u64::MAX,
+        ..EngineConfig::default()
+    }
+}
+
+/// Sets every key once, then `overwrites` more times, calling `round_done` after each round
+fn populate<E: KvsEngine>(store: &E, keys: u64, overwrites: u64, mut round_done: impl FnMut(&E)) {
+    let mut rng = Pcg64::seed_from_u64(1);
+    for _ in 0..=overwrites {
+        for i in 0..keys {
+            let len = rng.gen_range(10..100);
+            store.set(format!("key{}", i), "x".repeat(len)).unwrap();
+        }
+        round_done(store);
+    }
+    store.sync().unwrap();
+}
+
+/// Opens the storage at `path` once the handle that had it open let it go,
+/// sled releases the folder from a background thread after a drop
+fn open_released<E, O>(path: &Path, open: O) -> E
+where
+    O: Fn(&Path) -> Result<E>,
+{
+    for _ in 0..50 {
+        if let Ok(store) = open(path) {
+            return store;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    open(path).unwrap()
+}
+
+/// Reopens a populated storage of each engine, the index is rebuilt from the files every time
+/// The optimized log engine is measured with all the garbage of the overwrites left in its logs
+/// and after compacting between the rounds of overwrites
+fn recovery_bench(c: &mut Criterion) {
+    let keys = env_or("RECOVERY_KEYS", KEYS);
+    let overwrites = env_or("RECOVERY_OVERWRITES", OVERWRITES);
+    let mut group = c.benchmark_group("recovery_bench");
+    group.sample_size(20);
+
+    let temp_dir = TempDir::new().unwrap();
+    populate(
+        &OptLogStructKvs::open_with_config(temp_dir.path(), uncompacted_config()).unwrap(),
+        keys,
+        overwrites,
+        |_| {},
+    );
+    group.bench_function(BenchmarkId::new("olskv", "no_compaction"), |b| {
+        b.iter(|| OptLogStructKvs::open_with_config(temp_dir.path(), uncompacted_config()).unwrap())
+    });
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut rounds = 0;
+    populate(
+        &OptLogStructKvs::open_with_config(temp_dir.path(), uncompacted_config()).unwrap(),
+        keys,
+        overwrites,
+        |store| {
+            if rounds < COMPACTIONS {
+                store.compact().unwrap();
+                rounds += 1;
+            }
+        },
+    );
+    group.bench_function(
+        BenchmarkId::new("olskv", format!("{}_compactions", COMPACTIONS)),
+        |b| {
+            b.iter(|| {
+                OptLogStructKvs::open_with_config(temp_dir.path(), uncompacted_config()).unwrap()
+            })
+        },
+    );
+
+    let temp_dir = TempDir::new().unwrap();
+    populate(
+        &LogStructKVStore::open(temp_dir.path()).unwrap(),
+        keys,
+        overwrites,
+        |_| {},
+    );
+    group.bench_function(BenchmarkId::new("kvs", "default"), |b| {
+        b.iter(|| LogStructKVStore::open(temp_dir.path()).unwrap())
+    });
+
+    // Every record is a key of the index, which is sized from the logs before they are read
+    let temp_dir = TempDir::new().unwrap();
+    populate(
+        &LogStructKVStore::open(temp_dir.path()).unwrap(),
+        env_or("RECOVERY_MANY_KEYS", MANY_KEYS),
+        0,
+        |_| {},
+    );
+    group.bench_function(BenchmarkId::new("kvs", "many_keys"), |b| {
+        b.iter(|| LogStructKVStore::open(temp_dir.path()).unwrap())
+    });
+
+    let temp_dir = TempDir::new().unwrap();
+    populate(
+        &SledStore::open(temp_dir.path()).unwrap(),
+        keys,
+        overwrites,
+        |_| {},
+    );
+    group.bench_function(BenchmarkId::new("sled", "default"), |b| {
+        b.iter(|| open_released(temp_dir.path(), SledStore::open))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, recovery_bench);
+criterion_main!(benches);
@@ -0,0 +1,262 @@
+use clap::{ArgEnum, Parser, Subcommand};
+use kvs::common::{EngineType, Result};
+use kvs::engine::{upgrade_format, KvsEngine, LogStructKVStore, OptLogStructKvs, SledStore};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::process::exit;
+use std::str::FromStr;
+
+#[derive(Debug, Subcommand)]
+enum AdminCommand {
+    #[clap(
+        name = "verify",
+        about = "Checks that the index matches records in the log files"
+    )]
+    Verify { dir: PathBuf },
+    #[clap(
+        name = "fsck",
+        about = "Cross-checks the index against the log files and reports every inconsistency"
+    )]
+    Fsck { dir: PathBuf },
+    #[clap(
+        name = "scan-raw",
+        about = "Prints every record of a log file with its offset, tombstones included"
+    )]
+    ScanRaw {
+        dir: PathBuf,
+        #[clap(help = "Number of the log")]
+        log: u64,
+    },
+    #[clap(
+        name = "repair",
+        about = "Rebuilds a closed storage from the records that can still be read"
+    )]
+    Repair { dir: PathBuf },
+    #[clap(
+        name = "upgrade",
+        about = "Rewrites a closed storage in the format its engine writes now"
+    )]
+    Upgrade {
+        dir: PathBuf,
+        #[clap(arg_enum, long = "engine", default_value = "kvs")]
+        engine: EngineType,
+    },
+    #[clap(
+        name = "migrate",
+        about = "Copies every key of a closed storage into another one, possibly of another engine"
+    )]
+    Migrate {
+        #[clap(long = "from", help = "Source storage as DIR:ENGINE")]
+        from: Location,
+        #[clap(long = "to", help = "Target storage as DIR:ENGINE")]
+        to: Location,
+    },
+    #[clap(
+        name = "export",
+        about = "Writes every pair of a closed storage to stdout"
+    )]
+    Export {
+        #[clap(help = "Storage as DIR:ENGINE")]
+        store: Location,
+        #[clap(arg_enum, long = "format", default_value = "ndjson")]
+        format: DumpFormat,
+    },
+    #[clap(
+        name = "import",
+        about = "Sets every pair of a dump into a closed storage"
+    )]
+    Import {
+        #[clap(help = "Storage as DIR:ENGINE")]
+        store: Location,
+        #[clap(help = "Dump to read, stdin if not given")]
+        file: Option<PathBuf>,
+        #[clap(arg_enum, long = "format", default_value = "ndjson")]
+        format: DumpFormat,
+    },
+}
+
+/// Format of `export` and `import` dumps
+#[derive(ArgEnum, Clone, Debug)]
+enum DumpFormat {
+    /// One `{"key":...,"value":...}` JSON object per line
+    Ndjson,
+}
+
+/// Storage folder and the engine it is written by, written as `DIR:ENGINE`
+#[derive(Debug)]
+struct Location {
+    dir: PathBuf,
+    engine: EngineType,
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (dir, engine) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected DIR:ENGINE, got {}", s))?;
+        let engine = match engine {
+            "kvs" => EngineType::Kvs,
+            "sled" => EngineType::Sled,
+            _ => return Err(format!("unknown engine {}", engine)),
+        };
+        Ok(Location {
+            dir: PathBuf::from(dir),
+            engine,
+        })
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "kvs-admin",
+    about = "Maintenance tool for Key-Value Storage folders",
+    version
+)]
+struct ApplicationArguments {
+    #[clap(subcommand)]
+    command: AdminCommand,
+}
+
+fn main() -> Result<()> {
+    let args = ApplicationArguments::parse();
+    match args.command {
+        AdminCommand::Verify { dir } => {
+            let report = OptLogStructKvs::open(&dir)?.verify()?;
+            println!("Checked keys: {}", report.checked);
+            for key in report.corrupted.iter() {
+                println!("Unreadable record: {}", key);
+            }
+            for key in report.mismatched.iter() {
+                println!("Mismatched record: {}", key);
+            }
+            if !report.is_ok() {
+                exit(1);
+            }
+        }
+        AdminCommand::Fsck { dir } => {
+            let report = OptLogStructKvs::open(&dir)?.fsck()?;
+            println!("Checked keys: {}", report.checked);
+            for key in report.orphaned.iter() {
+                println!("Orphaned pointer: {}", key);
+            }
+            for key in report.corrupted.iter() {
+                println!("Unreadable record: {}", key);
+            }
+            for key in report.mismatched.iter() {
+                println!("Mismatched record: {}", key);
+            }
+            for key in report.unindexed.iter() {
+                println!("Unindexed key: {}", key);
+            }
+            for (filename, pos) in report.unreadable_tails.iter() {
+                println!("Unreadable tail: {} from {}", filename.display(), pos);
+            }
+            if !report.is_ok() {
+                exit(1);
+            }
+        }
+        AdminCommand::ScanRaw { dir, log } => {
+            for (offset, cmd) in OptLogStructKvs::open(&dir)?.scan_raw(log)? {
+                println!("{}\t{:?}", offset, cmd);
+            }
+        }
+        AdminCommand::Repair { dir } => {
+            let report = OptLogStructKvs::repair(&dir)?;
+            println!("Recovered keys: {}", report.keys);
+            println!("Skipped records: {}", report.skipped);
+            for (filename, pos, size) in report.truncated.iter() {
+                println!(
+                    "Dropped {} bytes of {} starting at {}",
+                    size,
+                    filename.display(),
+                    pos
+                );
+            }
+        }
+        AdminCommand::Upgrade { dir, engine } => {
+            upgrade_format(&dir, engine)?;
+            println!("Upgraded {}", dir.display());
+        }
+        AdminCommand::Migrate { from, to } => {
+            if from.dir == to.dir {
+                eprintln!("Source and target are the same folder");
+                exit(1);
+            }
+            match from.engine {
+                EngineType::Kvs => migrate_from(&LogStructKVStore::open(&from.dir)?, &to)?,
+                EngineType::Sled => migrate_from(&SledStore::open(&from.dir)?, &to)?,
+            }
+        }
+        AdminCommand::Export { store, format } => match store.engine {
+            EngineType::Kvs => export(&LogStructKVStore::open(&store.dir)?, format)?,
+            EngineType::Sled => export(&SledStore::open(&store.dir)?, format)?,
+        },
+        AdminCommand::Import {
+            store,
+            file,
+            format,
+        } => {
+            fs::create_dir_all(&store.dir)?;
+            match store.engine {
+                EngineType::Kvs => import(&LogStructKVStore::open(&store.dir)?, file, format)?,
+                EngineType::Sled => import(&SledStore::open(&store.dir)?, file, format)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn export<S: KvsEngine>(store: &S, format: DumpFormat) -> Result<()> {
+    let stdout = io::stdout();
+    let count = match format {
+        DumpFormat::Ndjson => store.export_ndjson(BufWriter::new(stdout.lock()))?,
+    };
+    eprintln!("Exported keys: {}", count);
+    Ok(())
+}
+
+fn import<S: KvsEngine>(store: &S, file: Option<PathBuf>, format: DumpFormat) -> Result<()> {
+    let count = match (format, file) {
+        (DumpFormat::Ndjson, Some(file)) => {
+            store.import_ndjson(BufReader::new(File::open(file)?))?
+        }
+        (DumpFormat::Ndjson, None) => store.import_ndjson(io::stdin().lock())?,
+    };
+    // On stderr like the count of export, whose stdout is the dump
+    eprintln!("Imported keys: {}", count);
+    Ok(())
+}
+
+fn migrate_from<S: KvsEngine>(source: &S, to: &Location) -> Result<()> {
+    fs::create_dir_all(&to.dir)?;
+    match to.engine {
+        EngineType::Kvs => migrate(source, &LogStructKVStore::open(&to.dir)?),
+        EngineType::Sled => migrate(source, &SledStore::open(&to.dir)?),
+    }
+}
+
+/// Copies all the pairs and checks that the target ends up with as many keys as were copied
+/// Keys the target already had are kept and make the check fail
+fn migrate<S: KvsEngine, T: KvsEngine>(source: &S, target: &T) -> Result<()> {
+    let mut copied = 0u64;
+    source.export(|key, value| {
+        copied += 1;
+        target.set(key, value)
+    })?;
+    let mut found = 0u64;
+    target.export(|_, _| {
+        found += 1;
+        Ok(())
+    })?;
+    println!("Copied keys: {}", copied);
+    if found != copied {
+        eprintln!("Target has {} keys", found);
+        exit(1);
+    }
+    Ok(())
+}
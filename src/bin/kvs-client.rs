@@ -1,7 +1,88 @@
-use clap::Parser;
+use clap::{ArgEnum, Parser, Subcommand};
 use kvs::client::KvsClient;
-use kvs::common::{Command, Result};
+use kvs::common::{Command, Response, Result};
+use kvs::error::KvsError;
+use rand::Rng;
+use serde_json::json;
 use std::net::SocketAddr;
+use std::process::exit;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Subcommand)]
+enum ClientCommand {
+    #[clap(flatten)]
+    Remote(Command),
+    #[clap(
+        name = "bench",
+        about = "Drives a read/write load against the server and reports throughput and latency"
+    )]
+    Bench {
+        #[clap(
+            long = "ops",
+            default_value = "100000",
+            help = "Total number of operations"
+        )]
+        ops: u64,
+        #[clap(
+            long = "clients",
+            default_value = "16",
+            help = "Concurrent connections, each in its own thread"
+        )]
+        clients: u64,
+        #[clap(
+            long = "ratio",
+            default_value = "80:20",
+            help = "Reads to writes ratio"
+        )]
+        ratio: Ratio,
+        #[clap(
+            long = "keys",
+            default_value = "10000",
+            help = "Number of distinct keys"
+        )]
+        keys: u64,
+        #[clap(
+            long = "value-size",
+            default_value = "100",
+            help = "Size of a written value"
+        )]
+        value_size: usize,
+    },
+}
+
+/// Share of reads and writes in the bench load, written as `READS:WRITES`
+#[derive(Debug, Clone, Copy)]
+struct Ratio {
+    reads: u32,
+    writes: u32,
+}
+
+impl FromStr for Ratio {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bad_ratio = || format!("expected READS:WRITES, got {}", s);
+        let (reads, writes) = s.split_once(':').ok_or_else(bad_ratio)?;
+        let ratio = Ratio {
+            reads: reads.parse().map_err(|_| bad_ratio())?,
+            writes: writes.parse().map_err(|_| bad_ratio())?,
+        };
+        if ratio.reads + ratio.writes == 0 {
+            return Err(bad_ratio());
+        }
+        Ok(ratio)
+    }
+}
+
+#[derive(ArgEnum, Debug, Clone, PartialEq)]
+enum OutputFormat {
+    #[clap(alias = "text")]
+    Text,
+    #[clap(alias = "json")]
+    Json,
+}
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -11,22 +92,217 @@ use std::net::SocketAddr;
 )]
 struct ApplicationArguments {
     #[clap(subcommand)]
-    command: Command,
+    command: ClientCommand,
     #[clap(
         global = true,
         short,
         long = "addr",
         name = "addr",
         default_value = "127.0.0.1:4000",
-        about = "Remote server address IP:PORT"
+        help = "Remote server address IP:PORT"
     )]
     address: SocketAddr,
+    #[clap(
+        global = true,
+        long = "compress",
+        help = "Compress the connection if the server allows it"
+    )]
+    compress: bool,
+    #[clap(
+        arg_enum,
+        global = true,
+        long = "output",
+        default_value = "text",
+        help = "Format of the printed response"
+    )]
+    output: OutputFormat,
+    #[clap(
+        global = true,
+        long = "fail-on-missing",
+        help = "Exits with code 1 if the key of a get is not found"
+    )]
+    fail_on_missing: bool,
 }
 
 fn main() -> Result<()> {
     let args = ApplicationArguments::parse();
-    let client = KvsClient::new(&args.address)?;
-    client.send(&args.command)?;
+    let command = match args.command {
+        ClientCommand::Remote(command) => command,
+        ClientCommand::Bench {
+            ops,
+            clients,
+            ratio,
+            keys,
+            value_size,
+        } => {
+            let clients = clients.max(1);
+            let value = "x".repeat(value_size);
+            let started = Instant::now();
+            let handles = (0..clients)
+                .map(|client_index| {
+                    // Spreads the remainder over the first clients
+                    let client_ops = ops / clients + u64::from(client_index < ops % clients);
+                    let (address, compress, value) = (args.address, args.compress, value.clone());
+                    thread::spawn(move || {
+                        bench_client(&address, compress, client_ops, ratio, keys.max(1), value)
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut latencies = Vec::with_capacity(ops as usize);
+            let mut errors = 0;
+            for handle in handles {
+                let (client_latencies, client_errors) = handle.join().unwrap()?;
+                latencies.extend(client_latencies);
+                errors += client_errors;
+            }
+            print_bench_summary(started.elapsed(), latencies, errors);
+            return Ok(());
+        }
+    };
+
+    let client = KvsClient::connect(&args.address, args.compress)?;
+    let response = client.send(&command)?;
     client.shutdown()?;
+    let text = args.output == OutputFormat::Text;
+    if !text {
+        println!("{}", json_response(&response, &command));
+    }
+    match response {
+        Response::Ok(Some(value)) => {
+            if text {
+                println!("{}", value);
+            }
+        }
+        Response::Ok(None) => {
+            if text && matches!(command, Command::Get { .. } | Command::GetDel { .. }) {
+                println!("Key not found");
+            }
+            if args.fail_on_missing && matches!(command, Command::Get { .. }) {
+                exit(1);
+            }
+        }
+        Response::Bool(flag) => {
+            if text {
+                println!("{}", flag);
+            }
+            if !flag {
+                exit(1);
+            }
+        }
+        Response::Err(err) => {
+            if text {
+                eprintln!("{}", err);
+            }
+            return Err(KvsError::UnexpectedError);
+        }
+        Response::Values(values) => {
+            if text {
+                for value in values {
+                    println!("{}", value.as_deref().unwrap_or("Key not found"));
+                }
+            }
+        }
+        Response::Pairs(pairs) => {
+            if text {
+                for (key, value) in pairs {
+                    println!("{}\t{}", key, value);
+                }
+            }
+        }
+        Response::Keys(keys) => {
+            if text {
+                for key in keys {
+                    println!("{}", key);
+                }
+            }
+        }
+        Response::Info(info) => {
+            if text {
+                println!("version\t{}", info.version);
+                println!("engine\t{}", info.engine);
+                println!("uptime_secs\t{}", info.uptime_secs);
+                println!("protocol_version\t{}", info.protocol_version);
+            }
+        }
+    }
     Ok(())
 }
+
+/// Response as a JSON object with a `status` of `ok`, `not_found` or `error`
+fn json_response(response: &Response, command: &Command) -> serde_json::Value {
+    match response {
+        Response::Ok(Some(value)) => json!({ "status": "ok", "value": value }),
+        Response::Ok(None) if matches!(command, Command::Get { .. } | Command::GetDel { .. }) => {
+            json!({ "status": "not_found" })
+        }
+        Response::Ok(None) => json!({ "status": "ok" }),
+        Response::Bool(flag) => json!({ "status": "ok", "value": flag }),
+        Response::Err(message) => json!({ "status": "error", "message": message }),
+        Response::Values(values) => json!({ "status": "ok", "values": values }),
+        Response::Pairs(pairs) => {
+            let pairs: Vec<_> = pairs
+                .iter()
+                .map(|(key, value)| json!({ "key": key, "value": value }))
+                .collect();
+            json!({ "status": "ok", "pairs": pairs })
+        }
+        Response::Info(info) => json!({ "status": "ok", "info": info }),
+        Response::Keys(keys) => json!({ "status": "ok", "keys": keys }),
+    }
+}
+
+/// Runs `ops` random commands over one connection, returns their latencies and the number of errors
+fn bench_client(
+    address: &SocketAddr,
+    compress: bool,
+    ops: u64,
+    ratio: Ratio,
+    keys: u64,
+    value: String,
+) -> Result<(Vec<Duration>, u64)> {
+    let client = KvsClient::connect(address, compress)?;
+    let mut rng = rand::thread_rng();
+    let mut latencies = Vec::with_capacity(ops as usize);
+    let mut errors = 0;
+    for _ in 0..ops {
+        let key = format!("key{}", rng.gen_range(0..keys));
+        let command = if rng.gen_range(0..ratio.reads + ratio.writes) < ratio.reads {
+            Command::Get { key }
+        } else {
+            Command::Set {
+                key,
+                value: value.clone(),
+            }
+        };
+        let started = Instant::now();
+        let response = client.send(&command);
+        latencies.push(started.elapsed());
+        match response {
+            Ok(Response::Err(_)) | Err(_) => errors += 1,
+            Ok(_) => {}
+        }
+    }
+    client.shutdown()?;
+    Ok((latencies, errors))
+}
+
+fn print_bench_summary(elapsed: Duration, mut latencies: Vec<Duration>, errors: u64) {
+    latencies.sort();
+    let percentile = |p: usize| match latencies.len() {
+        0 => Duration::default(),
+        len => latencies[(len * p / 100).min(len - 1)],
+    };
+    println!("Operations: {}", latencies.len());
+    println!("Errors: {}", errors);
+    println!("Elapsed: {:.3}s", elapsed.as_secs_f64());
+    println!(
+        "Throughput: {:.0} ops/sec",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "Latency p50: {:?}, p95: {:?}, p99: {:?}",
+        percentile(50),
+        percentile(95),
+        percentile(99)
+    );
+}
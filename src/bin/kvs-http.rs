@@ -0,0 +1,232 @@
+use clap::Parser;
+use kvs::client::KvsClient;
+use kvs::common::{Command, EngineType, Response, Result};
+use kvs::engine::{KvsEngine, LogStructKVStore, SledStore};
+use kvs::error::KvsError;
+use kvs::server::KEY_NOT_FOUND;
+use serde_json::json;
+use std::io;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Server};
+
+/// Path prefix of the keys
+const KV_PREFIX: &str = "/kv/";
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "kvs-http",
+    about = "HTTP/JSON gateway to Key-Value Storage",
+    version
+)]
+struct ApplicationArguments {
+    #[clap(
+        short,
+        long = "addr",
+        name = "addr",
+        default_value = "127.0.0.1:8080",
+        help = "Address the gateway listens on"
+    )]
+    address: SocketAddr,
+    #[clap(
+        arg_enum,
+        short,
+        long = "engine",
+        name = "engine",
+        default_value = "kvs",
+        help = "Engine of the embedded storage"
+    )]
+    engine: EngineType,
+    #[clap(
+        long = "dir",
+        name = "dir",
+        help = "Folder of the embedded storage, the current one by default"
+    )]
+    dir: Option<PathBuf>,
+    #[clap(
+        long = "server",
+        name = "server",
+        conflicts_with_all = &["engine", "dir"],
+        help = "Proxies to a kvs-server at IP:PORT instead of embedding the storage"
+    )]
+    server: Option<SocketAddr>,
+    #[clap(
+        short = 'n',
+        long = "num_threads",
+        default_value = "4",
+        help = "Threads serving the requests"
+    )]
+    num_threads: u32,
+}
+
+/// Operations the routes translate to
+trait Store: Send + Sync {
+    fn get(&self, key: String) -> Result<Option<String>>;
+    fn set(&self, key: String, value: String) -> Result<()>;
+    /// Returns whether the key existed
+    fn remove(&self, key: String) -> Result<bool>;
+}
+
+/// Storage opened by the gateway itself
+struct Embedded<E>(E);
+
+impl<E: KvsEngine + Sync> Store for Embedded<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<bool> {
+        match self.0.remove(key) {
+            Ok(()) => Ok(true),
+            Err(KvsError::KeyNotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Storage behind a kvs-server, requests of all the threads share one connection
+struct Remote(KvsClient);
+
+impl Remote {
+    fn send(&self, cmd: &Command) -> Result<Response> {
+        match self.0.send(cmd)? {
+            Response::Err(err) if err != KEY_NOT_FOUND => {
+                eprintln!("Server error: {}", err);
+                Err(KvsError::UnexpectedError)
+            }
+            response => Ok(response),
+        }
+    }
+}
+
+impl Store for Remote {
+    /// Whatever `MissingKeyResponse` the server uses, a missing key is `None`
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.0.get(key.clone())? {
+            // Either an empty value or a miss answered with `MissingKeyResponse::Empty`
+            Some(value) if value.is_empty() => match self.send(&Command::Exists { key })? {
+                Response::Bool(true) => Ok(Some(value)),
+                Response::Bool(false) => Ok(None),
+                _ => Err(KvsError::UnexpectedError),
+            },
+            value => Ok(value),
+        }
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        match self.send(&Command::Set { key, value })? {
+            Response::Ok(_) => Ok(()),
+            _ => Err(KvsError::UnexpectedError),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<bool> {
+        match self.send(&Command::Rm { key })? {
+            Response::Ok(_) => Ok(true),
+            Response::Err(_) => Ok(false),
+            _ => Err(KvsError::UnexpectedError),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = ApplicationArguments::parse();
+    let store: Arc<dyn Store> = match args.server {
+        Some(server) => Arc::new(Remote(KvsClient::new(&server)?)),
+        None => {
+            let dir = match args.dir {
+                Some(dir) => dir,
+                None => std::env::current_dir()?,
+            };
+            match args.engine {
+                EngineType::Kvs => Arc::new(Embedded(LogStructKVStore::open(&dir)?)),
+                EngineType::Sled => Arc::new(Embedded(SledStore::open(&dir)?)),
+            }
+        }
+    };
+    let server = Server::http(args.address).map_err(|err| io::Error::other(err.to_string()))?;
+    let server = Arc::new(server);
+    println!("Listening on: {}", args.address);
+
+    let handles = (0..args.num_threads.max(1))
+        .map(|_| {
+            let (server, store) = (Arc::clone(&server), Arc::clone(&store));
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    if let Err(err) = handle_request(&*store, request) {
+                        eprintln!("Cannot answer request: {}", err);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+/// Serves `GET`, `PUT` and `DELETE` of `/kv/:key`, the body of a `PUT` is the value as is
+fn handle_request(store: &dyn Store, mut request: Request) -> io::Result<()> {
+    let path = request.url().split('?').next().unwrap_or("");
+    let key = match path.strip_prefix(KV_PREFIX).and_then(decode_key) {
+        Some(key) if !key.is_empty() => key,
+        _ => return request.respond(json_response(404, json!({ "error": "Not found" }))),
+    };
+    let result = match request.method() {
+        Method::Get => store.get(key).map(|value| match value {
+            Some(value) => json_response(200, json!({ "value": value })),
+            None => json_response(404, json!({ "error": KEY_NOT_FOUND })),
+        }),
+        Method::Put => {
+            let mut value = String::new();
+            if request.as_reader().read_to_string(&mut value).is_err() {
+                let error = json!({ "error": "Value is not valid UTF-8" });
+                return request.respond(json_response(400, error));
+            }
+            store
+                .set(key, value)
+                .map(|()| json_response(200, json!({ "status": "ok" })))
+        }
+        Method::Delete => store.remove(key).map(|existed| match existed {
+            true => json_response(200, json!({ "status": "ok" })),
+            false => json_response(404, json!({ "error": KEY_NOT_FOUND })),
+        }),
+        _ => Ok(json_response(405, json!({ "error": "Method not allowed" }))),
+    };
+    let response =
+        result.unwrap_or_else(|err| json_response(500, json!({ "error": err.to_string() })));
+    request.respond(response)
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> tiny_http::Response<Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    tiny_http::Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+/// Percent-decodes a key of the path, `None` if that doesn't give valid UTF-8
+fn decode_key(raw: &str) -> Option<String> {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
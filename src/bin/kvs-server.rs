@@ -1,12 +1,14 @@
 use clap::Parser;
 use kvs::common::{EngineType, Result};
-use kvs::engine::{LogStructKVStore, SledStore};
-use kvs::server::KvsServer;
-use kvs::thread_pool::*;
+use kvs::config::ServerConfig;
+use kvs::engine::Durability;
+use kvs::server::{KvsServerBuilder, MissingKeyResponse, OverloadPolicy};
+use kvs::thread_pool::ThreadPoolType;
 use slog::*;
 use std::env;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
 
 const ENGINE_FILENAME: &str = ".engine";
@@ -18,90 +20,186 @@ struct ApplicationArguments {
         short,
         long = "addr",
         name = "addr",
-        default_value = "127.0.0.1:4000",
-        about = "Server address with format [IP:PORT]"
+        help = "Server address with format [IP:PORT], 127.0.0.1:4000 by default"
     )]
-    address: SocketAddr,
+    address: Option<SocketAddr>,
     #[clap(
         arg_enum,
         short,
         long = "engine",
         name = "engine",
-        default_value = "kvs",
-        about = "Engine for key value storage"
+        help = "Engine for key value storage, kvs by default"
     )]
-    engine: EngineType,
+    engine: Option<EngineType>,
     #[clap(
         arg_enum,
         short,
         long = "thread_pool",
         name = "thread pool",
-        default_value = "sharedq",
-        about = "Engine for key value storage"
+        help = "Thread pool serving the connections, sharedq by default"
     )]
-    thread_pool: ThreadPoolType,
+    thread_pool: Option<ThreadPoolType>,
     #[clap(
         short = 'n',
         long = "num_threads",
         name = "num of threads",
-        default_value = "8",
-        about = "Num of threads"
+        help = "Num of threads, 8 by default"
     )]
-    num_threads: u32,
+    num_threads: Option<u32>,
+    #[clap(
+        long = "max-threads",
+        help = "Most threads the pool may be resized to at runtime, resizing is refused by default"
+    )]
+    max_threads: Option<u32>,
+    #[clap(
+        long = "compress",
+        min_values = 0,
+        require_equals = true,
+        value_name = "BOOL",
+        default_missing_value = "true",
+        help = "Allow clients to compress their connection, --compress=false refuses it over the config file"
+    )]
+    compress: Option<bool>,
+    #[clap(
+        long = "max-accept-backoff-ms",
+        help = "Longest sleep of the idle accept loop in milliseconds, 5 by default"
+    )]
+    max_accept_backoff_ms: Option<u64>,
+    #[clap(
+        arg_enum,
+        long = "overload",
+        name = "overload",
+        help = "What to do with a new connection while the pool queue is full, retry by default"
+    )]
+    overload: Option<OverloadPolicy>,
+    #[clap(
+        long = "max-overload-wait-ms",
+        help = "Longest wait in milliseconds for room in the pool queue with retry, 50 by default"
+    )]
+    max_overload_wait_ms: Option<u64>,
+    #[clap(
+        long = "response-batch-bytes",
+        help = "Bytes of responses held back before flushing, 0 flushes each one, 4096 by default"
+    )]
+    response_batch_bytes: Option<usize>,
+    #[clap(
+        long = "no-nodelay",
+        help = "Leaves Nagle's algorithm on for the connections"
+    )]
+    no_nodelay: bool,
+    #[clap(
+        long = "keepalive",
+        help = "Sends TCP keepalive probes to drop the connections of vanished clients"
+    )]
+    keepalive: bool,
+    #[clap(
+        long = "max-ops-per-sec",
+        help = "Commands a connection may send per second, the ones beyond are refused, no limit by default"
+    )]
+    max_ops_per_sec: Option<u32>,
+    #[clap(
+        arg_enum,
+        long = "durability",
+        name = "durability",
+        help = "How far a write goes before it is answered, none, os or fsync, os by default"
+    )]
+    durability: Option<Durability>,
+    #[clap(
+        arg_enum,
+        long = "missing-key-response",
+        name = "missing key response",
+        help = "Answer to a get of a missing key, error, empty or null, null by default"
+    )]
+    missing_key_response: Option<MissingKeyResponse>,
+    #[clap(
+        long = "config",
+        name = "config",
+        help = "TOML file with the server settings, flags take precedence over it"
+    )]
+    config: Option<PathBuf>,
+}
+
+impl ApplicationArguments {
+    /// Settings of the config file, or the defaults, overridden by the given flags
+    fn server_config(&self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        if let Some(address) = self.address {
+            config.address = address;
+        }
+        if let Some(engine) = &self.engine {
+            config.engine = engine.clone();
+        }
+        if let Some(thread_pool) = &self.thread_pool {
+            config.thread_pool = thread_pool.clone();
+        }
+        if let Some(num_threads) = self.num_threads {
+            config.num_threads = num_threads;
+        }
+        if let Some(max_threads) = self.max_threads {
+            config.max_threads = Some(max_threads);
+        }
+        if let Some(compress) = self.compress {
+            config.compress = compress;
+        }
+        if let Some(max_accept_backoff_ms) = self.max_accept_backoff_ms {
+            config.max_accept_backoff_ms = max_accept_backoff_ms;
+        }
+        if let Some(overload) = self.overload {
+            config.overload = overload;
+        }
+        if let Some(max_overload_wait_ms) = self.max_overload_wait_ms {
+            config.max_overload_wait_ms = max_overload_wait_ms;
+        }
+        if let Some(response_batch_bytes) = self.response_batch_bytes {
+            config.response_batch_bytes = response_batch_bytes;
+        }
+        config.nodelay &= !self.no_nodelay;
+        config.keepalive |= self.keepalive;
+        if let Some(max_ops_per_sec) = self.max_ops_per_sec {
+            config.max_ops_per_sec = Some(max_ops_per_sec);
+        }
+        if let Some(durability) = self.durability {
+            config.durability = durability;
+        }
+        if let Some(missing_key_response) = self.missing_key_response {
+            config.missing_key_response = missing_key_response;
+        }
+        Ok(config)
+    }
 }
 
 fn main() -> Result<()> {
     let plain = slog_term::PlainSyncDecorator::new(std::io::stderr());
     let logger = Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!());
 
-    let args = ApplicationArguments::parse();
-    if let Some(engine) = get_current_engine(&args.engine)? {
-        if engine != args.engine {
-            eprintln!("Different engine");
+    let config = ApplicationArguments::parse().server_config()?;
+    if let Some(engine) = get_current_engine(&config.engine)? {
+        if engine != config.engine {
+            eprintln!(
+                "Different engine, the storage was created with {} and can't be opened with {}",
+                engine, config.engine
+            );
             exit(1);
         }
     }
 
     info!(logger, "Storage version {}", env!["CARGO_PKG_VERSION"]);
-    info!(logger, "Listening on: {}", args.address);
-    info!(logger, "Backend engine: {}", args.engine);
-    info!(logger, "Thread pool: {:?}", args.thread_pool);
-
-    match args.engine {
-        EngineType::Kvs => {
-            let kv_store = LogStructKVStore::open(env::current_dir()?.as_path())?;
-            match args.thread_pool {
-                ThreadPoolType::Rayon => KvsServer::<LogStructKVStore, RayonThreadPool>::new(
-                    kv_store,
-                    RayonThreadPool::new(args.num_threads as u32)?,
-                )?
-                .run(&args.address)?,
-                ThreadPoolType::SharedQ => {
-                    KvsServer::<LogStructKVStore, SharedQueueThreadPool>::new(
-                        kv_store,
-                        SharedQueueThreadPool::new(args.num_threads as u32)?,
-                    )?
-                    .run(&args.address)?
-                }
-            }
-        }
-        EngineType::Sled => {
-            let kv_store = SledStore::open(env::current_dir()?.as_path())?;
-            match args.thread_pool {
-                ThreadPoolType::Rayon => KvsServer::<SledStore, RayonThreadPool>::new(
-                    kv_store,
-                    RayonThreadPool::new(args.num_threads as u32)?,
-                )?
-                .run(&args.address)?,
-                ThreadPoolType::SharedQ => KvsServer::<SledStore, SharedQueueThreadPool>::new(
-                    kv_store,
-                    SharedQueueThreadPool::new(args.num_threads as u32)?,
-                )?
-                .run(&args.address)?,
-            }
-        }
-    };
+    info!(logger, "Listening on: {}", config.address);
+    info!(logger, "Backend engine: {}", config.engine);
+    info!(logger, "Thread pool: {:?}", config.thread_pool);
+    info!(logger, "Compression: {}", config.compress);
 
+    let result = KvsServerBuilder::from_config(config)
+        .path(env::current_dir()?)
+        .logger(logger.clone())
+        .run();
+    if let Err(err) = result {
+        error!(logger, "Server failed: {}", err);
+        exit(1);
+    }
     Ok(())
 }
 
@@ -1,43 +1,160 @@
 use crate::common::{Command, Response, Result};
 use crate::error::KvsError;
-use std::io::{BufReader, BufWriter, Write};
+use crate::protocol::{
+    min_version, wrap_stream, Hello, StreamReader, StreamWriter, Welcome, BUSY_VERSION,
+    PROTOCOL_VERSION,
+};
+use crate::server::{KEY_NOT_FOUND, STARTING_UP};
+use std::io::{BufWriter, Write};
 use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
+/// Default separator between a namespace and a key
+const NAMESPACE_SEPARATOR: &str = ":";
+
+/// Connection to a server, commands sent through one client are applied in send order
+/// A broken connection isn't reopened, every later command fails, a new client has to connect,
+/// see `KvsClientPool` which does that
 pub struct KvsClient {
     stream: TcpStream,
+    connection: Mutex<(StreamReader, StreamWriter)>,
+    protocol_version: u32,
     shutdown_flag: AtomicBool,
+    /// Set once sending a command or reading its response failed, the stream is then unusable
+    broken: AtomicBool,
 }
 
 impl KvsClient {
     pub fn new(addr: &SocketAddr) -> Result<KvsClient> {
+        KvsClient::connect(addr, false)
+    }
+
+    /// Connects and negotiates the connection options
+    /// Compression is used only when the server allows it as well
+    pub fn connect(addr: &SocketAddr, compress: bool) -> Result<KvsClient> {
+        KvsClient::connect_with_version(addr, compress, PROTOCOL_VERSION)
+    }
+
+    /// Connects speaking at most protocol `version`, an older server can lower it further
+    /// Nagle's algorithm is off, see `set_nodelay`
+    pub fn connect_with_version(
+        addr: &SocketAddr,
+        compress: bool,
+        version: u32,
+    ) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut writer = BufWriter::new(&stream);
+        bincode::serialize_into(&mut writer, &Hello { compress, version })?;
+        writer.flush()?;
+        drop(writer);
+        let welcome: Welcome = bincode::deserialize_from(&stream)?;
+        if welcome.version == BUSY_VERSION {
+            return Err(KvsError::ServerBusy);
+        }
+
         Ok(KvsClient {
-            stream: TcpStream::connect(&addr)?,
+            connection: Mutex::new(wrap_stream(&stream, welcome.compress)?),
+            protocol_version: welcome.version,
+            stream,
             shutdown_flag: AtomicBool::new(false),
+            broken: AtomicBool::new(false),
         })
     }
 
-    pub fn send(&self, cmd: &Command) -> Result<()> {
+    /// Protocol version negotiated with the server
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Sends a command and returns the server response as is
+    pub fn send(&self, cmd: &Command) -> Result<Response> {
         if self.shutdown_flag.load(Ordering::Relaxed) {
-            return Ok(());
+            return Err(KvsError::UnexpectedError);
+        }
+        if min_version(cmd) > self.protocol_version {
+            return Err(KvsError::UnsupportedCommand);
         }
-        let mut reader = BufReader::new(&self.stream);
-        let mut writer = BufWriter::new(&self.stream);
+        let mut connection = self.connection.lock().unwrap();
+        let (reader, writer) = &mut *connection;
 
-        bincode::serialize_into(&mut writer, &cmd)?;
-        writer.flush()?;
-        match bincode::deserialize_from(&mut reader)? {
-            Response::Ok(s) => {
-                if let Some(s) = s {
-                    println!("{}", s)
-                }
-            }
-            Response::Err(s) => {
-                eprintln!("{}", s);
-                return Err(KvsError::UnexpectedError);
-            }
+        let response = bincode::serialize_into(&mut *writer, &cmd)
+            .and_then(|()| Ok(writer.flush()?))
+            .and_then(|()| bincode::deserialize_from(reader));
+        if response.is_err() {
+            self.broken.store(true, Ordering::Relaxed);
         }
-        Ok(())
+        Ok(response?)
+    }
+
+    /// Sends all the commands before reading any response, returns the responses in send order
+    /// The server applies them in that order, so a get observes a set pipelined before it
+    pub fn pipeline(&self, cmds: &[Command]) -> Result<Vec<Response>> {
+        if self.shutdown_flag.load(Ordering::Relaxed) {
+            return Err(KvsError::UnexpectedError);
+        }
+        if cmds
+            .iter()
+            .any(|cmd| min_version(cmd) > self.protocol_version)
+        {
+            return Err(KvsError::UnsupportedCommand);
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let (reader, writer) = &mut *connection;
+
+        let responses = cmds
+            .iter()
+            .try_for_each(|cmd| bincode::serialize_into(&mut *writer, cmd))
+            .and_then(|()| Ok(writer.flush()?))
+            .and_then(|()| {
+                cmds.iter()
+                    .map(|_| bincode::deserialize_from(&mut *reader))
+                    .collect::<bincode::Result<Vec<Response>>>()
+            });
+        if responses.is_err() {
+            self.broken.store(true, Ordering::Relaxed);
+        }
+        Ok(responses?)
+    }
+
+    /// Whether a command failed on the connection, which can't be used anymore then
+    pub fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        match self.send(&Command::Set { key, value })? {
+            Response::Ok(_) => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// A server answering a miss with `MissingKeyResponse::Empty` makes it `Some("")`
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let cmd = Command::Get { key };
+        match self.send(&cmd)? {
+            Response::Ok(value) => Ok(value),
+            Response::Err(message) if message == KEY_NOT_FOUND => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Fails with `KeyNotFound` if the key doesn't exist
+    pub fn remove(&self, key: String) -> Result<()> {
+        match self.send(&Command::Rm { key })? {
+            Response::Ok(_) => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Turns Nagle's algorithm off with `nodelay`, so each command goes out without waiting
+    /// for the answer to the previous one, which suits one command per round trip
+    /// Turning it back on may save packets when many commands are pipelined
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        Ok(self.stream.set_nodelay(nodelay)?)
     }
 
     pub fn shutdown(&self) -> Result<()> {
@@ -45,4 +162,240 @@ impl KvsClient {
         self.shutdown_flag.store(true, Ordering::Relaxed);
         Ok(())
     }
+
+    /// Returns a view of the client where every key lives under `prefix`
+    pub fn namespaced(&self, prefix: &str) -> NamespacedClient<'_> {
+        self.namespaced_with_separator(prefix, NAMESPACE_SEPARATOR)
+    }
+
+    pub fn namespaced_with_separator(&self, prefix: &str, separator: &str) -> NamespacedClient<'_> {
+        NamespacedClient {
+            client: self,
+            prefix: format!("{}{}", prefix, separator),
+        }
+    }
+}
+
+/// Connections to one server shared between threads, each operation checks one out
+/// A connection that broke isn't put back, the operation is retried on a new connection
+/// a bounded number of times, so a server restart doesn't reach the callers
+pub struct KvsClientPool {
+    addr: SocketAddr,
+    compress: bool,
+    idle: Mutex<Vec<KvsClient>>,
+    max_retries: u32,
+    retry_delay: Duration,
+}
+
+impl KvsClientPool {
+    /// Connections are opened when needed, none is opened up front
+    pub fn new(addr: SocketAddr, compress: bool) -> KvsClientPool {
+        KvsClientPool {
+            addr,
+            compress,
+            idle: Mutex::new(Vec::new()),
+            max_retries: 3,
+            retry_delay: Duration::from_millis(100),
+        }
+    }
+
+    /// Retries an operation failing on its connection up to `max_retries` times,
+    /// waiting `retry_delay` before each retry for the server to come back
+    pub fn with_retries(mut self, max_retries: u32, retry_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Runs `op` on an idle connection, or a new one, and puts the connection back afterwards
+    /// `op` runs again on a new connection when the connection failed, so it should be idempotent,
+    /// and again on the same one while the server is starting up
+    /// Other errors the server answered with are returned as they are
+    pub fn with_client<T, F>(&self, mut op: F) -> Result<T>
+    where
+        F: FnMut(&KvsClient) -> Result<T>,
+    {
+        let mut retries = 0;
+        loop {
+            let result = self.checkout().and_then(|client| {
+                let result = op(&client);
+                self.checkin(client);
+                result
+            });
+            let retry = match &result {
+                Err(KvsError::Io(_)) | Err(KvsError::Bincode(_)) => {
+                    // The other idle connections most likely went down with the server as well
+                    self.idle.lock().unwrap().clear();
+                    true
+                }
+                // A restarted server answers so until its engine is open, the connection is fine
+                Err(KvsError::Server(message)) => message == STARTING_UP,
+                _ => false,
+            };
+            if !retry || retries >= self.max_retries {
+                return result;
+            }
+            retries += 1;
+            thread::sleep(self.retry_delay);
+        }
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.with_client(|client| client.set(key.clone(), value.clone()))
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_client(|client| client.get(key.clone()))
+    }
+
+    /// Retried like the other operations, a remove applied just before the connection broke
+    /// then fails with `KeyNotFound`
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.with_client(|client| client.remove(key.clone()))
+    }
+
+    /// Number of connections waiting in the pool, all of them healthy when they were put back
+    pub fn idle_connections(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn checkout(&self) -> Result<KvsClient> {
+        match self.idle.lock().unwrap().pop() {
+            Some(client) => Ok(client),
+            None => KvsClient::connect(&self.addr, self.compress),
+        }
+    }
+
+    fn checkin(&self, client: KvsClient) {
+        if !client.is_broken() {
+            self.idle.lock().unwrap().push(client);
+        }
+    }
+}
+
+/// Error of a response the typed methods don't expect, the message of `Response::Err` is kept
+fn unexpected(response: Response) -> KvsError {
+    match response {
+        Response::Err(message) if message == KEY_NOT_FOUND => KvsError::KeyNotFound,
+        Response::Err(message) => KvsError::Server(message),
+        _ => KvsError::UnexpectedError,
+    }
+}
+
+/// Makes the glob characters of `literal` match themselves, see `KvsEngine::keys_matching`
+fn escape_glob(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Client wrapper that transparently prepends the namespace to every key
+/// The inner client is not exposed, so commands can't leave the namespace
+pub struct NamespacedClient<'a> {
+    client: &'a KvsClient,
+    prefix: String,
+}
+
+impl NamespacedClient<'_> {
+    /// Keys of a scan or of a keys command come back without the namespace
+    /// Commands over all the keys would reach out of the namespace, they fail with `UnsupportedCommand`
+    pub fn send(&self, cmd: &Command) -> Result<Response> {
+        if let Command::DbSize | Command::FlushAll = cmd {
+            return Err(KvsError::UnsupportedCommand);
+        }
+        match self.client.send(&self.namespace_cmd(cmd))? {
+            Response::Pairs(pairs) => Ok(Response::Pairs(
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| (key[self.prefix.len()..].to_owned(), value))
+                    .collect(),
+            )),
+            Response::Keys(keys) => Ok(Response::Keys(
+                keys.into_iter()
+                    .map(|key| key[self.prefix.len()..].to_owned())
+                    .collect(),
+            )),
+            response => Ok(response),
+        }
+    }
+
+    fn namespace_cmd(&self, cmd: &Command) -> Command {
+        match cmd {
+            Command::Set { key, value } => Command::Set {
+                key: self.namespace_key(key),
+                value: value.clone(),
+            },
+            Command::Get { key } => Command::Get {
+                key: self.namespace_key(key),
+            },
+            Command::Rm { key } => Command::Rm {
+                key: self.namespace_key(key),
+            },
+            Command::Exists { key } => Command::Exists {
+                key: self.namespace_key(key),
+            },
+            Command::NextId { name } => Command::NextId {
+                name: self.namespace_key(name),
+            },
+            Command::Expire { key, ttl_secs } => Command::Expire {
+                key: self.namespace_key(key),
+                ttl_secs: *ttl_secs,
+            },
+            Command::Config { key, value } => Command::Config {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            Command::Touch { keys } => Command::Touch {
+                keys: keys.iter().map(|key| self.namespace_key(key)).collect(),
+            },
+            Command::MGet { keys } => Command::MGet {
+                keys: keys.iter().map(|key| self.namespace_key(key)).collect(),
+            },
+            Command::ExpireAt { key, unix_secs } => Command::ExpireAt {
+                key: self.namespace_key(key),
+                unix_secs: *unix_secs,
+            },
+            Command::GetDel { key } => Command::GetDel {
+                key: self.namespace_key(key),
+            },
+            Command::ScanPrefix { prefix, limit } => Command::ScanPrefix {
+                prefix: self.namespace_key(prefix),
+                limit: *limit,
+            },
+            Command::DbSize => Command::DbSize,
+            Command::FlushAll => Command::FlushAll,
+            Command::Info => Command::Info,
+            Command::Replace { key, value } => Command::Replace {
+                key: self.namespace_key(key),
+                value: value.clone(),
+            },
+            Command::SetNx { key, value } => Command::SetNx {
+                key: self.namespace_key(key),
+                value: value.clone(),
+            },
+            Command::RmPrefix { prefix } => Command::RmPrefix {
+                prefix: self.namespace_key(prefix),
+            },
+            Command::Keys { pattern, count } => Command::Keys {
+                pattern: format!("{}{}", escape_glob(&self.prefix), pattern),
+                count: *count,
+            },
+            Command::Reserved6(tag)
+            | Command::Reserved8(tag)
+            | Command::Reserved9(tag)
+            | Command::Reserved10(tag)
+            | Command::Reserved11(tag)
+            | Command::Reserved14(tag)
+            | Command::Reserved20(tag) => match *tag {},
+        }
+    }
+
+    fn namespace_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
 }
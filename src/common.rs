@@ -1,10 +1,13 @@
 use crate::error::KvsError;
-use clap::{ArgEnum, Subcommand};
-use serde::{Deserialize, Serialize};
+use clap::{ArgEnum, ArgMatches, FromArgMatches, Subcommand};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 
 pub type Result<T> = std::result::Result<T, KvsError>;
 
+/// Commands of the protocol
+/// New variants go last, bincode tags a command with the variant index
+/// The log records of the engines took some tags, see `LogRecord`, `Set` and `Rm` are both
 #[derive(Debug, Subcommand, Serialize, Deserialize)]
 pub enum Command {
     #[clap(name = "set", about = "Sets a value for a given key")]
@@ -13,15 +16,257 @@ pub enum Command {
     Get { key: String },
     #[clap(name = "rm", about = "Removes entry with a given key")]
     Rm { key: String },
+    #[clap(name = "exists", about = "Checks whether a given key is present")]
+    Exists { key: String },
+    #[clap(name = "next-id", about = "Returns the next id of a given sequence")]
+    NextId { name: String },
+    #[clap(
+        name = "expire",
+        about = "Sets a time to live in seconds on an existing key"
+    )]
+    Expire { key: String, ttl_secs: u64 },
+    /// Tag of `LogRecord::SetExpiring`
+    #[doc(hidden)]
+    #[clap(skip)]
+    Reserved6(RecordTag),
+    #[clap(
+        name = "config",
+        about = "Reads a server setting, or changes it when a value is given"
+    )]
+    Config { key: String, value: Option<String> },
+    /// Tag of `LogRecord::SetVersioned`
+    #[doc(hidden)]
+    #[clap(skip)]
+    Reserved8(RecordTag),
+    /// Tag of `LogRecord::Shared`
+    #[doc(hidden)]
+    #[clap(skip)]
+    Reserved9(RecordTag),
+    /// Tag of `LogRecord::SetShared`
+    #[doc(hidden)]
+    #[clap(skip)]
+    Reserved10(RecordTag),
+    /// Tag of `LogRecord::SetExpiry`
+    #[doc(hidden)]
+    #[clap(skip)]
+    Reserved11(RecordTag),
+    #[clap(
+        name = "touch",
+        about = "Returns how many of the keys exist, without reading their values"
+    )]
+    Touch {
+        #[clap(required = true)]
+        keys: Vec<String>,
+    },
+    #[clap(name = "mget", about = "Returns the values of several keys")]
+    MGet {
+        #[clap(required = true)]
+        keys: Vec<String>,
+    },
+    /// Tag of `LogRecord::Padding`
+    #[doc(hidden)]
+    #[clap(skip)]
+    Reserved14(RecordTag),
+    #[clap(
+        name = "expire-at",
+        about = "Makes an existing key expire at a Unix timestamp in seconds, a past one removes it"
+    )]
+    ExpireAt { key: String, unix_secs: u64 },
+    #[clap(
+        name = "getdel",
+        about = "Returns the value of a given key and removes the key in the same step"
+    )]
+    GetDel { key: String },
+    #[clap(
+        name = "scan",
+        about = "Returns the keys starting with a given prefix and their values, sorted by key"
+    )]
+    ScanPrefix {
+        #[clap(long = "prefix")]
+        prefix: String,
+        #[clap(long = "limit", help = "Returns at most this many pairs")]
+        limit: Option<usize>,
+    },
+    #[clap(name = "dbsize", about = "Returns the number of keys")]
+    DbSize,
+    #[clap(
+        name = "flushall",
+        about = "Removes every key, sequences keep their ids, returns how many keys were removed"
+    )]
+    FlushAll,
+    /// Tag of `LogRecord::SetBlob`
+    #[doc(hidden)]
+    #[clap(skip)]
+    Reserved20(RecordTag),
+    #[clap(
+        name = "info",
+        about = "Returns the version, engine, uptime and protocol version of the server"
+    )]
+    Info,
+    #[clap(
+        name = "replace",
+        about = "Sets a value only if the key exists, returns whether it was set"
+    )]
+    Replace { key: String, value: String },
+    #[clap(
+        name = "setnx",
+        about = "Sets a value only if the key doesn't exist, returns whether it was set"
+    )]
+    SetNx { key: String, value: String },
+    #[clap(
+        name = "rm-prefix",
+        about = "Removes every key starting with a given prefix, returns how many were removed"
+    )]
+    RmPrefix { prefix: String },
+    #[clap(
+        name = "keys",
+        about = "Returns the keys matching a glob pattern like 'user:*', sorted by key"
+    )]
+    Keys {
+        pattern: String,
+        #[clap(long = "count", help = "Returns only the number of matching keys")]
+        count: bool,
+    },
+}
+
+/// Stand-in for a tag of `Command` taken by a log record
+/// It has no value, so a command with it can neither be built nor decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordTag {}
+
+impl Serialize for RecordTag {
+    fn serialize<S: Serializer>(&self, _serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match *self {}
+    }
+}
+
+// Needed by the derive of `Subcommand`, the variants of the tag are skipped on the command line
+impl FromArgMatches for RecordTag {
+    fn from_arg_matches(_matches: &ArgMatches) -> std::result::Result<Self, clap::Error> {
+        Err(clap::Error::raw(
+            clap::ErrorKind::UnrecognizedSubcommand,
+            "log record, not a command",
+        ))
+    }
+
+    fn update_from_arg_matches(
+        &mut self,
+        _matches: &ArgMatches,
+    ) -> std::result::Result<(), clap::Error> {
+        match *self {}
+    }
+}
+
+impl<'de> Deserialize<'de> for RecordTag {
+    fn deserialize<D: Deserializer<'de>>(_deserializer: D) -> std::result::Result<Self, D::Error> {
+        Err(de::Error::custom("log record, not a command"))
+    }
+}
+
+/// Place of a value in the blob files of a storage, see `EngineConfig::blob_threshold`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BlobRef {
+    pub blob_id: u64,
+    pub offset: u64,
+    /// Length of the value in bytes
+    pub size: u64,
+}
+
+impl Command {
+    /// Name of the command as typed in kvs-client
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set { .. } => "set",
+            Command::Get { .. } => "get",
+            Command::Rm { .. } => "rm",
+            Command::Exists { .. } => "exists",
+            Command::NextId { .. } => "next-id",
+            Command::Expire { .. } => "expire",
+            Command::Config { .. } => "config",
+            Command::Touch { .. } => "touch",
+            Command::MGet { .. } => "mget",
+            Command::ExpireAt { .. } => "expire-at",
+            Command::GetDel { .. } => "getdel",
+            Command::ScanPrefix { .. } => "scan",
+            Command::DbSize => "dbsize",
+            Command::FlushAll => "flushall",
+            Command::Info => "info",
+            Command::Replace { .. } => "replace",
+            Command::SetNx { .. } => "setnx",
+            Command::RmPrefix { .. } => "rm-prefix",
+            Command::Keys { .. } => "keys",
+            Command::Reserved6(tag)
+            | Command::Reserved8(tag)
+            | Command::Reserved9(tag)
+            | Command::Reserved10(tag)
+            | Command::Reserved11(tag)
+            | Command::Reserved14(tag)
+            | Command::Reserved20(tag) => match *tag {},
+        }
+    }
+
+    /// Key the command is about, `None` for commands of several keys or of none
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set { key, .. }
+            | Command::Get { key, .. }
+            | Command::Rm { key }
+            | Command::Exists { key }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::GetDel { key }
+            | Command::Replace { key, .. }
+            | Command::SetNx { key, .. } => Some(key),
+            Command::NextId { .. }
+            | Command::Config { .. }
+            | Command::Touch { .. }
+            | Command::MGet { .. }
+            | Command::ScanPrefix { .. }
+            | Command::RmPrefix { .. }
+            | Command::Keys { .. }
+            | Command::DbSize
+            | Command::FlushAll
+            | Command::Info => None,
+            Command::Reserved6(tag)
+            | Command::Reserved8(tag)
+            | Command::Reserved9(tag)
+            | Command::Reserved10(tag)
+            | Command::Reserved11(tag)
+            | Command::Reserved14(tag)
+            | Command::Reserved20(tag) => match *tag {},
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum Response {
     Ok(Option<String>),
+    Bool(bool),
     Err(String),
+    /// Values of several keys in the order they were asked for, `None` for a missing key
+    Values(Vec<Option<String>>),
+    /// Key value pairs of a scan, in key order
+    Pairs(Vec<(String, String)>),
+    Info(ServerInfo),
+    /// Keys matching a pattern, in key order
+    Keys(Vec<String>),
+}
+
+/// What a client is talking to, the answer to `Command::Info`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Version of the kvs crate the server was built from
+    pub version: String,
+    /// Name of the engine behind the server, see `KvsEngine::name`
+    pub engine: String,
+    /// Seconds since the server was created
+    pub uptime_secs: u64,
+    /// Newest protocol version the server speaks
+    pub protocol_version: u32,
 }
 
 #[derive(ArgEnum, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum EngineType {
     #[clap(alias = "kvs")]
     Kvs,
@@ -0,0 +1,71 @@
+use crate::common::{EngineType, Result};
+use crate::engine::Durability;
+use crate::server::{
+    MissingKeyResponse, OverloadPolicy, DEFAULT_MAX_ACCEPT_BACKOFF, DEFAULT_MAX_OVERLOAD_WAIT,
+    DEFAULT_RESPONSE_BATCH_BYTES,
+};
+use crate::thread_pool::ThreadPoolType;
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Settings of kvs-server, missing fields keep their default value
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: SocketAddr,
+    pub engine: EngineType,
+    pub thread_pool: ThreadPoolType,
+    pub num_threads: u32,
+    /// Most workers `config num_threads` may resize the thread pool to, `None` refuses resizing
+    pub max_threads: Option<u32>,
+    pub compress: bool,
+    /// Cap in milliseconds of the idle accept loop sleep
+    pub max_accept_backoff_ms: u64,
+    /// What happens to a new connection while the thread pool queue is full
+    pub overload: OverloadPolicy,
+    /// Longest time in milliseconds the `retry` policy waits for room in the queue
+    pub max_overload_wait_ms: u64,
+    /// Bytes of responses a connection holds back before flushing, 0 flushes every response
+    pub response_batch_bytes: usize,
+    /// Turns Nagle's algorithm off on the connections
+    pub nodelay: bool,
+    /// Sends TCP keepalive probes on idle connections to drop the ones of vanished clients
+    pub keepalive: bool,
+    /// Commands a connection may send per second, the ones beyond are refused, `None` doesn't limit
+    pub max_ops_per_sec: Option<u32>,
+    /// How far each write goes before it is answered, with either engine
+    pub durability: Durability,
+    /// How a `get` of a missing key is answered
+    pub missing_key_response: MissingKeyResponse,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: SocketAddr::from(([127, 0, 0, 1], 4000)),
+            engine: EngineType::Kvs,
+            thread_pool: ThreadPoolType::SharedQ,
+            num_threads: 8,
+            max_threads: None,
+            compress: false,
+            max_accept_backoff_ms: DEFAULT_MAX_ACCEPT_BACKOFF.as_millis() as u64,
+            overload: OverloadPolicy::Retry,
+            max_overload_wait_ms: DEFAULT_MAX_OVERLOAD_WAIT.as_millis() as u64,
+            response_batch_bytes: DEFAULT_RESPONSE_BATCH_BYTES,
+            nodelay: true,
+            keepalive: false,
+            max_ops_per_sec: None,
+            durability: Durability::default(),
+            missing_key_response: MissingKeyResponse::Null,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the config from a TOML file
+    pub fn load(path: &Path) -> Result<ServerConfig> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+}
@@ -0,0 +1,223 @@
+use crate::engine::{Clock, SystemClock};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Size in bytes after which the write log is rolled over to a new file
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// Size in bytes of redundant commands
+const COMPACT_THRESHOLD: u64 = 2000000;
+/// Number of log files after which compaction is forced
+const MAX_LOG_FILES: u64 = 64;
+
+/// Tuning knobs of the log structured storage
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    /// Size in bytes after which the write log is rolled over to a new file
+    pub max_file_size: u64,
+    /// Size in bytes of redundant commands that triggers compaction
+    pub compact_threshold: u64,
+    /// Share of redundant bytes in the live and redundant ones that triggers compaction instead of
+    /// `compact_threshold`, e.g. 0.4, so the write amplification stays bounded whatever the size
+    /// Less than 64 KiB of redundant commands never triggers it, a small storage isn't compacted
+    /// on every overwrite
+    pub compaction_garbage_ratio: Option<f64>,
+    /// Number of log files that forces compaction regardless of `compact_threshold`
+    pub max_log_files: u64,
+    /// Max size in bytes of records moved by one compaction step
+    /// `None` compacts everything at once
+    pub compaction_step_bytes: Option<u64>,
+    /// Compacts fully after every `n` write operations instead of on the byte and log count thresholds,
+    /// a batch of `extend` counts as one operation
+    /// Meant for benchmarks, compaction then hits the same writes on every run
+    pub compact_every_writes: Option<u64>,
+    /// Order of keys in the index, used by range and prefix scans
+    pub key_order: KeyOrder,
+    /// Number of recent values kept per key, including the current one, read with `get_versions`
+    /// Every kept version stays in the logs through compaction and takes a log pointer
+    /// of memory in the index, so the storage grows up to `versions` times
+    pub versions: usize,
+    /// Max number of keys, setting a new key beyond it fails with `QuotaExceeded`
+    /// Overwriting or removing a key is always allowed, `None` doesn't limit
+    pub max_keys: Option<u64>,
+    /// What opening does with a decodable record of a command that is never written to a log
+    pub recovery: RecoveryPolicy,
+    /// Stores a value once for all the keys set to it, each key record then references the shared one
+    /// Values are matched by hash and their bytes are compared, so a hash collision is stored as usual
+    /// Values written before the storage was opened are shared only by keys set to them afterwards,
+    /// ignored when `versions` is above 1
+    pub dedup: bool,
+    /// Values longer than this many bytes are appended to a blob file instead of the logs,
+    /// the set record of the key only holds a `BlobRef` to it, so compaction doesn't copy the value
+    /// Reading such a value takes a second read, of the blob file, after the one of the record
+    /// Blob files are left alone by compaction, `OptLogStructKvs::gc_blobs` reclaims the space
+    /// of the values no key references anymore. Takes precedence over `dedup`, streamed values
+    /// stay in the logs, ignored when `versions` is above 1, `None` keeps every value in the logs
+    pub blob_threshold: Option<usize>,
+    /// Time source of the expiry deadlines and of the checks of them,
+    /// a `MockClock` lets tests expire keys without waiting
+    pub clock: Arc<dyn Clock>,
+    /// Number of subfolders the logs are spread over, a log goes to the one named `log % log_shards`
+    /// Bounds the entries per folder for storages with many logs, 0 and 1 keep the logs in the storage folder
+    /// Logs are moved on open when the number changed since they were written
+    /// Only the files are spread, there is still one write log and compaction covers all the shards
+    pub log_shards: u64,
+    /// Number of the most recently written keys whose records are read on a background thread after open,
+    /// pulling them into the OS page cache so the first gets don't all go to disk
+    /// Open doesn't wait for it, `Stats` shows the progress, 0 skips the warm-up
+    pub warm_cache_keys: usize,
+    /// Pads every record written to the logs so it ends on a multiple of this many bytes,
+    /// so a record that fits in a page doesn't straddle two
+    /// Costs up to the alignment plus 12 bytes per record, `None` packs the records
+    pub align_records: Option<usize>,
+    /// Max number of writes waiting for or holding the write lock, the ones beyond fail with `Busy`
+    /// instead of queueing, so a server can shed write load, `None` lets them all queue
+    pub max_inflight_writes: Option<usize>,
+    /// Syncs the write log to disk this often on a background thread, so a power loss takes
+    /// at most the writes of the last interval, the last handle dropped syncs once more
+    /// Records held back by `Durability::None` reach the OS with each sync, `None` leaves syncing
+    /// to `sync` and `close`
+    pub sync_interval: Option<Duration>,
+    /// How far each write of the write log goes before it returns, the records of compaction
+    /// reach the OS as they are written and are synced once their log is complete
+    pub durability: Durability,
+    /// What a `get` does while a compaction step is moving records
+    pub read_during_compaction: ReadDuringCompaction,
+    /// Keeps an index from each value to the keys set to it, read with `OptLogStructKvs::keys_by_value`
+    /// Holds every value and every key in memory once more, which about doubles the memory of the storage
+    /// unless the values are much shorter than the keys, each overwrite and remove also reads
+    /// the old value, and open reads every value to build the index
+    pub value_index: bool,
+    /// Reads 64 KiB of the log at once and serves the records that follow the requested one
+    /// from a buffer of the reading thread, which pays off when keys are read in the order
+    /// they were written. Random gets read the whole 64 KiB for each record, so it is off by default
+    pub read_ahead: bool,
+    /// Called with each expired key the storage reclaims, by a compaction step once the write lock
+    /// is released and by open when it leaves the key out of the index, never by `remove`
+    /// A new key set beyond `max_keys` reclaims them as well, then the write lock is held,
+    /// so the callback must not write to the storage
+    /// A key closed before compaction dropped its records is reported again by the next open
+    pub on_evict: Option<OnEvict>,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            max_file_size: MAX_FILE_SIZE,
+            compact_threshold: COMPACT_THRESHOLD,
+            compaction_garbage_ratio: None,
+            max_log_files: MAX_LOG_FILES,
+            compaction_step_bytes: None,
+            compact_every_writes: None,
+            key_order: KeyOrder::Lexicographic,
+            versions: 1,
+            max_keys: None,
+            recovery: RecoveryPolicy::Strict,
+            dedup: false,
+            blob_threshold: None,
+            clock: Arc::new(SystemClock),
+            log_shards: 1,
+            warm_cache_keys: 0,
+            align_records: None,
+            max_inflight_writes: None,
+            sync_interval: None,
+            durability: Durability::default(),
+            read_during_compaction: ReadDuringCompaction::ServeConsistent,
+            value_index: false,
+            read_ahead: false,
+            on_evict: None,
+        }
+    }
+}
+
+/// Callback of `EngineConfig::on_evict`
+#[derive(Clone)]
+pub struct OnEvict(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl OnEvict {
+    pub fn new(callback: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        OnEvict(Arc::new(callback))
+    }
+
+    pub(crate) fn call(&self, key: &str) {
+        (self.0)(key)
+    }
+}
+
+impl fmt::Debug for OnEvict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnEvict")
+    }
+}
+
+/// How far a write goes before it returns, every engine takes the same levels
+/// Sled keeps its writes in its own cache below `Fsync` and syncs it every 500ms
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Records stay in the buffer of the write log until it is full, the log rolls over or is compacted,
+    /// or a read needs them, a crash of the process loses up to a buffer of the last writes
+    /// Reads of the write log then flush it first
+    /// The kvs engine reads its records back from the files, it hands each write to the OS like `Os`
+    #[clap(alias = "none")]
+    None,
+    /// Each write is handed to the OS, a crash of the process loses nothing,
+    /// a power loss loses what the OS hadn't written to the disk yet
+    #[clap(alias = "os")]
+    #[default]
+    Os,
+    /// Each write reaches the disk before it returns, nothing returned is lost
+    /// The kvs engines sync their one write log once per write, whatever `log_shards` is,
+    /// concurrent writes aren't grouped into a shared sync
+    #[clap(alias = "fsync")]
+    Fsync,
+}
+
+/// Handling of corrupted records met while rebuilding the index on open
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecoveryPolicy {
+    /// Fails the open with `UnexpectedCommandType`
+    Strict,
+    /// Drops the record and carries on, compaction removes it from the logs
+    SkipCorrupt,
+}
+
+/// How `get` of the kvs engine races a compaction, see `EngineConfig::read_during_compaction`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadDuringCompaction {
+    /// Waits for the compaction step in progress to finish, so no read overlaps the moving of records
+    /// An incremental compaction lets the reads through between its steps
+    Block,
+    /// Reads right away, a record moved and removed under the read is read again at its new place
+    ServeConsistent,
+}
+
+/// Order of keys for the ordered scans
+#[derive(Clone, Copy, Debug)]
+pub enum KeyOrder {
+    /// Byte-wise string order, "10" goes before "9"
+    Lexicographic,
+    /// Integer keys go first ordered by value, the rest follow lexicographically
+    Numeric,
+    /// Custom comparator, must be a total order that treats only equal keys as `Equal`
+    Custom(fn(&str, &str) -> Ordering),
+}
+
+impl KeyOrder {
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            KeyOrder::Lexicographic => a.cmp(b),
+            KeyOrder::Numeric => match (a.parse::<i64>(), b.parse::<i64>()) {
+                // "01" and "1" are different keys with the same value
+                (Ok(x), Ok(y)) => x.cmp(&y).then_with(|| a.cmp(b)),
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                (Err(_), Err(_)) => a.cmp(b),
+            },
+            KeyOrder::Custom(compare) => compare(a, b),
+        }
+    }
+}
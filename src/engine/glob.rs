@@ -0,0 +1,112 @@
+/// Whether `key` matches the glob `pattern`
+/// `*` matches any run of characters, `?` any one character,
+/// `[abc]` and `[a-z]` a character of the set, `[^a-z]` or `[!a-z]` one outside it,
+/// `\` makes the next character literal
+pub(crate) fn matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // Pattern position after the last star and the key position the star matches up to,
+    // a mismatch later on retries with the star taking one more character
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        if p < pattern.len() {
+            let (len, matched) = match_char(&pattern[p..], key[k]);
+            if matched {
+                p += len;
+                k += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((star_p, star_k)) => {
+                p = star_p;
+                k = star_k + 1;
+                star = Some((star_p, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Literal characters the pattern starts with, every key it matches starts with them
+pub(crate) fn literal_prefix(pattern: &str) -> String {
+    let mut prefix = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '?' | '[' => break,
+            '\\' => prefix.push(chars.next().unwrap_or('\\')),
+            c => prefix.push(c),
+        }
+    }
+    prefix
+}
+
+/// Pattern matching `literal` and nothing else, its special characters escaped
+pub(crate) fn escape(literal: &str) -> String {
+    let mut pattern = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+/// Matches `c` against the element the pattern starts with, not a star
+/// Returns the length of the element in the pattern and whether `c` matched it
+fn match_char(pattern: &[char], c: char) -> (usize, bool) {
+    match pattern[0] {
+        '?' => (1, true),
+        '\\' => match pattern.get(1) {
+            Some(&escaped) => (2, escaped == c),
+            None => (1, c == '\\'),
+        },
+        '[' => match match_set(pattern, c) {
+            Some(set) => set,
+            // No closing bracket, the bracket is literal
+            None => (1, c == '['),
+        },
+        literal => (1, literal == c),
+    }
+}
+
+/// Matches `c` against the set the pattern starts with, `None` if the set isn't closed
+/// A `]` right after the opening bracket is part of the set
+fn match_set(pattern: &[char], c: char) -> Option<(usize, bool)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('^') | Some('!'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut low = *pattern.get(i)?;
+        if low == ']' && !first {
+            return Some((i + 1, matched != negated));
+        }
+        first = false;
+        if low == '\\' {
+            i += 1;
+            low = *pattern.get(i)?;
+        }
+        i += 1;
+        let high = match (pattern.get(i), pattern.get(i + 1)) {
+            (Some('-'), Some(&high)) if high != ']' => {
+                i += 2;
+                high
+            }
+            _ => low,
+        };
+        matched |= low <= c && c <= high;
+    }
+}
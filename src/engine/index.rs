@@ -0,0 +1,244 @@
+use crossbeam_skiplist::map::Entry;
+use crossbeam_skiplist::SkipMap;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+/// Ordered map the kvs engine keeps its keys in, shared by the threads of the storage
+/// Scans walk it in key order and compaction resumes from the last key it moved
+pub trait Index<K, V>: Default + Send + Sync + 'static {
+    type Entry<'a>: IndexEntry<K, V>
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Option<Self::Entry<'_>>;
+
+    /// Replaces the entry of the key if there is one
+    fn insert(&self, key: K, value: V);
+
+    /// Returns the removed entry
+    fn remove(&self, key: &K) -> Option<Self::Entry<'_>>;
+
+    /// Entries in key order
+    fn iter(&self) -> Box<dyn Iterator<Item = Self::Entry<'_>> + '_>;
+
+    /// Entries within the bounds in key order
+    fn range(&self, range: (Bound<K>, Bound<K>)) -> Box<dyn Iterator<Item = Self::Entry<'_>> + '_>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+/// Entry of an `Index`, its value stays readable after the entry is removed or replaced
+pub trait IndexEntry<K, V> {
+    fn key(&self) -> &K;
+
+    fn value(&self) -> &V;
+
+    /// Whether the entry was removed or replaced since it was read
+    fn is_removed(&self) -> bool;
+}
+
+/// Map behind the index of `OptLogStructKvs`, picked by type as the keys and values are the engine's
+pub trait IndexBackend: Clone + Send + Sync + 'static {
+    type Index<K: Ord + Clone + Send + Sync + 'static, V: Send + Sync + 'static>: Index<K, V>;
+}
+
+/// Lock-free `SkipMap`, the default backend
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SkipMapBackend;
+
+impl IndexBackend for SkipMapBackend {
+    type Index<K: Ord + Clone + Send + Sync + 'static, V: Send + Sync + 'static> = SkipMap<K, V>;
+}
+
+/// `BTreeMap` behind a read-write lock, see `BTreeIndex`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BTreeBackend;
+
+impl IndexBackend for BTreeBackend {
+    type Index<K: Ord + Clone + Send + Sync + 'static, V: Send + Sync + 'static> = BTreeIndex<K, V>;
+}
+
+impl<K, V> IndexEntry<K, V> for Entry<'_, K, V> {
+    fn key(&self) -> &K {
+        Entry::key(self)
+    }
+
+    fn value(&self) -> &V {
+        Entry::value(self)
+    }
+
+    fn is_removed(&self) -> bool {
+        Entry::is_removed(self)
+    }
+}
+
+impl<K, V> Index<K, V> for SkipMap<K, V>
+where
+    K: Ord + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    type Entry<'a> = Entry<'a, K, V>;
+
+    fn get(&self, key: &K) -> Option<Entry<'_, K, V>> {
+        SkipMap::get(self, key)
+    }
+
+    fn insert(&self, key: K, value: V) {
+        SkipMap::insert(self, key, value);
+    }
+
+    fn remove(&self, key: &K) -> Option<Entry<'_, K, V>> {
+        SkipMap::remove(self, key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Entry<'_, K, V>> + '_> {
+        Box::new(SkipMap::iter(self))
+    }
+
+    fn range(&self, range: (Bound<K>, Bound<K>)) -> Box<dyn Iterator<Item = Entry<'_, K, V>> + '_> {
+        Box::new(SkipMap::range(self, range))
+    }
+
+    fn len(&self) -> usize {
+        SkipMap::len(self)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        SkipMap::contains_key(self, key)
+    }
+}
+
+/// `BTreeMap` behind a read-write lock, the values are in an `Arc` so entries outlive the lock
+/// Iterators take the read lock for each entry they return, so writes go on during a scan
+pub struct BTreeIndex<K, V> {
+    map: RwLock<BTreeMap<K, Arc<V>>>,
+}
+
+impl<K, V> Default for BTreeIndex<K, V> {
+    fn default() -> Self {
+        BTreeIndex {
+            map: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+/// Entry of a `BTreeIndex`, holds a copy of the key
+pub struct BTreeEntry<'a, K, V> {
+    index: &'a BTreeIndex<K, V>,
+    key: K,
+    value: Arc<V>,
+}
+
+impl<K: Ord, V> IndexEntry<K, V> for BTreeEntry<'_, K, V> {
+    fn key(&self) -> &K {
+        &self.key
+    }
+
+    fn value(&self) -> &V {
+        &self.value
+    }
+
+    fn is_removed(&self) -> bool {
+        let map = self.index.map.read().unwrap();
+        !map.get(&self.key)
+            .is_some_and(|value| Arc::ptr_eq(value, &self.value))
+    }
+}
+
+impl<K, V> Index<K, V> for BTreeIndex<K, V>
+where
+    K: Ord + Clone + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    type Entry<'a> = BTreeEntry<'a, K, V>;
+
+    fn get(&self, key: &K) -> Option<BTreeEntry<'_, K, V>> {
+        let map = self.map.read().unwrap();
+        map.get_key_value(key).map(|(key, value)| BTreeEntry {
+            index: self,
+            key: key.clone(),
+            value: Arc::clone(value),
+        })
+    }
+
+    fn insert(&self, key: K, value: V) {
+        self.map.write().unwrap().insert(key, Arc::new(value));
+    }
+
+    fn remove(&self, key: &K) -> Option<BTreeEntry<'_, K, V>> {
+        let mut map = self.map.write().unwrap();
+        map.remove_entry(key).map(|(key, value)| BTreeEntry {
+            index: self,
+            key,
+            value,
+        })
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = BTreeEntry<'_, K, V>> + '_> {
+        self.range((Bound::Unbounded, Bound::Unbounded))
+    }
+
+    fn range(
+        &self,
+        range: (Bound<K>, Bound<K>),
+    ) -> Box<dyn Iterator<Item = BTreeEntry<'_, K, V>> + '_> {
+        Box::new(BTreeRange {
+            index: self,
+            from: range.0,
+            to: range.1,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.map.read().unwrap().len()
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.map.read().unwrap().contains_key(key)
+    }
+}
+
+/// Iterator of a `BTreeIndex`, looks up the entry after the last one returned on each step
+struct BTreeRange<'a, K, V> {
+    index: &'a BTreeIndex<K, V>,
+    from: Bound<K>,
+    to: Bound<K>,
+}
+
+impl<'a, K: Ord + Clone, V> Iterator for BTreeRange<'a, K, V> {
+    type Item = BTreeEntry<'a, K, V>;
+
+    fn next(&mut self) -> Option<BTreeEntry<'a, K, V>> {
+        // `BTreeMap::range` panics on bounds that cross
+        if let (
+            Bound::Included(from) | Bound::Excluded(from),
+            Bound::Included(to) | Bound::Excluded(to),
+        ) = (&self.from, &self.to)
+        {
+            let both_included = matches!(
+                (&self.from, &self.to),
+                (Bound::Included(_), Bound::Included(_))
+            );
+            if from > to || (from == to && !both_included) {
+                return None;
+            }
+        }
+        let map = self.index.map.read().unwrap();
+        let (key, value) = map.range((self.from.as_ref(), self.to.as_ref())).next()?;
+        self.from = Bound::Excluded(key.clone());
+        Some(BTreeEntry {
+            index: self.index,
+            key: key.clone(),
+            value: Arc::clone(value),
+        })
+    }
+}
@@ -1,14 +1,23 @@
-use crate::common::{Command, Result};
-use crate::engine::KvsEngine;
+use crate::common::Result;
+use crate::engine::upgrade::Rewrite;
+use crate::engine::{
+    create_checkpoint_folder, deadline_after, expire_matching_keys, expiry_deadline, glob,
+    is_sequence_key, lock_folder, parse_sequence, reserve_ids, sequence_key, unix_millis,
+    unix_secs_deadline, Clock, Durability, KvsEngine, LogRecord, RecoveryPolicy, SystemClock,
+    REMOVE_PREFIX_CHUNK,
+};
 use crate::error::KvsError;
 use std::cmp::max;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
-use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 /// Max log file size
 const MAX_FILE_SIZE: u64 = 20000;
@@ -26,6 +35,14 @@ const LOG_FULL: u8 = 2;
 const LOG_COMP: u8 = 3;
 /// Extension of a log file
 const LOG_EXT: &str = "log";
+/// Size in bytes assumed for a set record when sizing the index on open, a short key and value
+const ESTIMATED_RECORD_SIZE: u64 = 100;
+/// File of the storage folder with the version of its on-disk format
+const FORMAT_FILENAME: &str = "FORMAT";
+/// Version of the on-disk format written by this build, see `upgrade_format`
+/// 1: bincode `LogRecord`s one after the other, the storage has no `FORMAT` file
+/// 2: each record is preceded by the CRC32 of its bytes, little endian
+pub(crate) const FORMAT_VERSION: u32 = 2;
 
 #[derive(Clone)]
 struct LogPointer {
@@ -41,40 +58,47 @@ struct LogPointer {
 pub struct LogStructKVStore {
     log_writer: Arc<Mutex<BufWriter<File>>>,
     key_dir: Arc<RwLock<HashMap<String, LogPointer>>>,
+    /// Expiry deadlines in milliseconds since the Unix epoch, also written in the set records
+    expiry: Arc<RwLock<HashMap<String, u64>>>,
+    /// Keys whose expiry was changed by `touch` after their set record, with a `SetExpiry` record
+    /// Compaction writes the set record again with the new expiry, the `SetExpiry` one is dropped
+    touched: Arc<RwLock<HashSet<String>>>,
     path: Arc<PathBuf>,
     log: Arc<AtomicU64>,
     log_counter: Arc<AtomicU64>,
     uncompacted_size: Arc<AtomicU64>,
+    durability: Durability,
+    /// Time source of the expiry deadlines and of the checks of them
+    clock: Arc<dyn Clock>,
+    /// On-disk format of the storage, `FORMAT_VERSION` but while an old storage is upgraded
+    format: u32,
+    /// Lock of the folder, released once every clone is dropped
+    _lock: Arc<File>,
 }
 
 impl KvsEngine for LogStructKVStore {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, value))
+    )]
     fn set(&self, key: String, value: String) -> Result<()> {
         let mut log_writer = self.log_writer.lock().unwrap();
-        let pos_before = log_writer.stream_position()?;
-        let set_cmd = Command::Set { key, value };
-        bincode::serialize_into(&mut *log_writer, &set_cmd)?;
-        log_writer.flush()?;
-        let pos_after = log_writer.stream_position()?;
-
-        if let Command::Set { key, value: _ } = set_cmd {
-            let insert_result = self.key_dir.write().unwrap().insert(
-                key,
-                LogPointer {
-                    pos: Arc::new(AtomicU64::new(pos_before)),
-                    size: pos_after - pos_before,
-                    log: Arc::new(AtomicU64::new(self.log.load(Ordering::Relaxed))),
-                    log_state: Arc::new(AtomicU8::new(LOG_WRITE)),
-                },
-            );
-            self.update_uncompacted_size(insert_result, log_writer)?;
-        }
+        self.write_set(&mut log_writer, key, value, None)
+    }
 
-        Ok(())
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let mut log_writer = self.log_writer.lock().unwrap();
+        if self.contains_key(key.clone())? {
+            return Ok(false);
+        }
+        self.write_set(&mut log_writer, key, value, None)?;
+        Ok(true)
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     fn get(&self, key: String) -> Result<Option<String>> {
         let key_dir = self.key_dir.read().unwrap();
-        if !key_dir.contains_key(&key) {
+        if !key_dir.contains_key(&key) || self.is_expired(&key) {
             return Ok(None);
         }
 
@@ -84,36 +108,368 @@ impl KvsEngine for LogStructKVStore {
             &log_pointer.log_state.load(Ordering::Relaxed),
         )?)?;
         reader.seek(SeekFrom::Start(log_pointer.pos.load(Ordering::Relaxed)))?;
-        match bincode::deserialize_from(&mut reader)? {
-            Command::Set { key: _, value } => Ok(Some(value)),
+        let mut record = vec![0u8; log_pointer.size as usize];
+        reader.read_exact(&mut record)?;
+        match decode_record(&record, self.format)? {
+            LogRecord::Set { value, .. } | LogRecord::SetExpiring { value, .. } => Ok(Some(value)),
             _ => Err(KvsError::UnexpectedCommandType),
         }
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     fn remove(&self, key: String) -> Result<()> {
-        if !self.key_dir.read().unwrap().contains_key(&key) {
+        if !self.contains_key(key.clone())? {
             return Err(KvsError::KeyNotFound);
         }
-        let cmd = Command::Rm { key };
         let mut log_writer = self.log_writer.lock().unwrap();
-        bincode::serialize_into(&mut *log_writer, &cmd)?;
+        self.write_rm(&mut log_writer, key)
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        let mut log_writer = self.log_writer.lock().unwrap();
+        let old = self.get(key.clone())?;
+        let existed = old.is_some();
+        let new = f(old);
+        match &new {
+            Some(value) => self.write_set(&mut log_writer, key, value.clone(), None)?,
+            None if existed => self.write_rm(&mut log_writer, key)?,
+            None => {}
+        }
+        Ok(new)
+    }
+
+    fn expire(&self, key: String, ttl_secs: u64) -> Result<bool> {
+        self.expire_until(key, expiry_deadline(self.now_millis(), ttl_secs))
+    }
+
+    fn expire_at(&self, key: String, unix_secs: u64) -> Result<bool> {
+        self.expire_until(key, unix_secs_deadline(unix_secs))
+    }
+
+    /// Appends a `SetExpiry` record, the value stays where it is until the next compaction
+    fn touch(&self, key: String, new_ttl: Duration) -> Result<bool> {
+        let mut log_writer = self.log_writer.lock().unwrap();
+        if !self.contains_key(key.clone())? {
+            return Ok(false);
+        }
+        let expires_at = deadline_after(self.now_millis(), new_ttl);
+        let size = write_record(
+            &mut log_writer,
+            &LogRecord::SetExpiry {
+                key: key.clone(),
+                expires_at,
+            },
+            self.format,
+        )?;
+        self.end_write(&mut log_writer)?;
+
+        self.expiry.write().unwrap().insert(key.clone(), expires_at);
+        self.touched.write().unwrap().insert(key);
+        // Never read again, compaction folds the deadline into the set record
+        self.add_uncompacted_size(size, &mut log_writer)?;
+        Ok(true)
+    }
+
+    fn expires_at(&self, key: String) -> Result<Option<u64>> {
+        if !self.key_dir.read().unwrap().contains_key(&key) || self.is_expired(&key) {
+            return Ok(None);
+        }
+        Ok(self.expiry.read().unwrap().get(&key).copied())
+    }
+
+    /// The keys come from the index, without reading a value
+    fn expire_matching(&self, pattern: &str, ttl_secs: u64) -> Result<usize> {
+        let prefix = glob::literal_prefix(pattern);
+        let keys: Vec<String> = self
+            .key_dir
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        expire_matching_keys(self, pattern, keys, ttl_secs)
+    }
+
+    /// Matching keys are picked and sorted from the index, only the returned values are read
+    fn scan_prefix(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let mut keys: Vec<String> = self
+            .key_dir
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        let mut pairs = Vec::new();
+        for key in keys {
+            if pairs.len() >= limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            // Key could be removed or expired meanwhile
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.key_dir.read().unwrap().contains_key(&key) && !self.is_expired(&key))
+    }
+
+    /// Matching keys are picked from the index and sorted, the index isn't ordered so all are checked
+    fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .key_dir
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|key| self.is_live_match(key, pattern))
+            .cloned()
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn count_matching(&self, pattern: &str) -> Result<u64> {
+        let key_dir = self.key_dir.read().unwrap();
+        Ok(key_dir
+            .keys()
+            .filter(|key| self.is_live_match(key, pattern))
+            .count() as u64)
+    }
+
+    /// Counts the keys of the index, without reading a value
+    fn key_count(&self) -> Result<u64> {
+        let key_dir = self.key_dir.read().unwrap();
+        Ok(key_dir
+            .keys()
+            .filter(|key| !is_sequence_key(key) && !self.is_expired(key))
+            .count() as u64)
+    }
+
+    /// Writes the remove records in chunks of `REMOVE_PREFIX_CHUNK` keys under the write lock,
+    /// so other writes get the lock in between, the keys come from the index
+    fn remove_prefix(&self, prefix: String) -> Result<u64> {
+        let keys: Vec<String> = self
+            .key_dir
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(&prefix) && !is_sequence_key(key))
+            .cloned()
+            .collect();
+        let mut removed = 0;
+        for chunk in keys.chunks(REMOVE_PREFIX_CHUNK) {
+            let mut log_writer = self.log_writer.lock().unwrap();
+            for key in chunk {
+                // Removed or expired meanwhile
+                if !self.contains_key(key.clone())? {
+                    continue;
+                }
+                self.write_rm(&mut log_writer, key.clone())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Appends all the pairs under one acquisition of the write lock and flushes them once,
+    /// they are indexed and counted for compaction after the flush
+    fn extend<I>(&self, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut log_writer = self.log_writer.lock().unwrap();
+        let mut pos = log_writer.stream_position()?;
+        let mut written = Vec::new();
+        let append = || -> Result<()> {
+            for (key, value) in pairs {
+                let size = write_record(
+                    &mut log_writer,
+                    &LogRecord::Set {
+                        key: key.clone(),
+                        value,
+                    },
+                    self.format,
+                )?;
+                written.push((key, pos, size));
+                pos += size;
+            }
+            Ok(())
+        };
+        // Pairs appended before an error stay, like with one set after the other
+        let result = append();
+        self.end_write(&mut log_writer)?;
+        let mut redundant = 0;
+        for (key, pos, size) in written {
+            if let Some(old_log_pointer) = self.index_set(key, pos, size, None) {
+                redundant += old_log_pointer.size;
+            }
+        }
+        self.add_uncompacted_size(redundant, &mut log_writer)?;
+        result
+    }
+
+    fn export<F>(&self, mut visit: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        let keys: Vec<String> = self.key_dir.read().unwrap().keys().cloned().collect();
+        for key in keys {
+            // Key could be removed meanwhile
+            if let Some(value) = self.get(key.clone())? {
+                visit(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_id_batch(&self, name: &str, count: u64) -> Result<Range<u64>> {
+        let key = sequence_key(name);
+        let mut log_writer = self.log_writer.lock().unwrap();
+        let last = match self.get(key.clone())? {
+            Some(value) => parse_sequence(&value)?,
+            None => 0,
+        };
+        let ids = reserve_ids(last, count)?;
+        self.write_set(&mut log_writer, key, (ids.end - 1).to_string(), None)?;
+        Ok(ids)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut log_writer = self.log_writer.lock().unwrap();
         log_writer.flush()?;
+        log_writer.get_ref().sync_all()?;
+        Ok(())
+    }
 
-        if let Command::Rm { key } = cmd {
-            let remove_result = self.key_dir.write().unwrap().remove(&key);
-            self.update_uncompacted_size(remove_result, log_writer)?;
+    /// Compaction runs under the write lock too, so the logs stay put while the pairs are copied
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        create_checkpoint_folder(dest)?;
+        let copy = LogStructKVStore::open(dest)?;
+        {
+            let _log_writer = self.log_writer.lock().unwrap();
+            self.export_expiring(|key, value, expires_at| {
+                copy.set_expiring(key, value, expires_at)
+            })?;
         }
+        copy.close()
+    }
 
+    /// Sequentially reads every log file, files removed by a concurrent compaction are skipped
+    fn warmup(&self) -> Result<()> {
+        for filename in get_sorted_log_files(&self.path)? {
+            match File::open(&filename) {
+                Ok(mut file) => {
+                    io::copy(&mut file, &mut io::sink())?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
         Ok(())
     }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+}
+
+impl Rewrite for LogStructKVStore {
+    fn export_expiring<F>(&self, mut visit: F) -> Result<()>
+    where
+        F: FnMut(String, String, Option<u64>) -> Result<()>,
+    {
+        self.export(|key, value| {
+            let expires_at = self.expiry.read().unwrap().get(&key).copied();
+            visit(key, value, expires_at)
+        })
+    }
+
+    fn set_expiring(&self, key: String, value: String, expires_at: Option<u64>) -> Result<()> {
+        let mut log_writer = self.log_writer.lock().unwrap();
+        self.write_set(&mut log_writer, key, value, expires_at)
+    }
 }
 
 impl LogStructKVStore {
     pub fn open(path: &Path) -> Result<LogStructKVStore> {
-        let filenames = get_sorted_log_files(path);
+        LogStructKVStore::open_with_recovery(path, RecoveryPolicy::Strict)
+    }
+
+    /// Opens the storage handling corrupted records of the logs according to `recovery`
+    /// Fails with `AlreadyOpen` while the folder is open by another handle that isn't a clone
+    /// Fails with `IncompatibleFormat` for a storage of another on-disk format, see `upgrade_format`
+    pub fn open_with_recovery(path: &Path, recovery: RecoveryPolicy) -> Result<LogStructKVStore> {
+        LogStructKVStore::open_with(
+            path,
+            recovery,
+            Durability::default(),
+            default_clock(),
+            false,
+        )
+    }
+
+    /// Opens the storage writing with `durability`, `None` hands each write to the OS like `Os`
+    pub fn open_with_durability(path: &Path, durability: Durability) -> Result<LogStructKVStore> {
+        LogStructKVStore::open_with(
+            path,
+            RecoveryPolicy::Strict,
+            durability,
+            default_clock(),
+            false,
+        )
+    }
+
+    /// Opens the storage reading the time of the expiries from `clock`,
+    /// a `MockClock` lets tests expire keys without waiting
+    pub fn open_with_clock(path: &Path, clock: Arc<dyn Clock>) -> Result<LogStructKVStore> {
+        LogStructKVStore::open_with(
+            path,
+            RecoveryPolicy::Strict,
+            Durability::default(),
+            clock,
+            false,
+        )
+    }
+
+    /// Opens the storage in whatever format it has, to be read by `upgrade_format`
+    pub(crate) fn open_any_format(path: &Path) -> Result<LogStructKVStore> {
+        LogStructKVStore::open_with(
+            path,
+            RecoveryPolicy::Strict,
+            Durability::default(),
+            default_clock(),
+            true,
+        )
+    }
+
+    fn open_with(
+        path: &Path,
+        recovery: RecoveryPolicy,
+        durability: Durability,
+        clock: Arc<dyn Clock>,
+        any_format: bool,
+    ) -> Result<LogStructKVStore> {
+        let lock = lock_folder(path)?;
+        let filenames = get_sorted_log_files(path)?;
         let current_folder = PathBuf::from(path);
+        let format = storage_format(path, !filenames.is_empty())?;
+        if format > FORMAT_VERSION || (format < FORMAT_VERSION && !any_format) {
+            return Err(KvsError::IncompatibleFormat {
+                found: format,
+                current: FORMAT_VERSION,
+            });
+        }
 
-        let (key_dir, uncompacted_size, mut log_counter) = build_key_dir(&filenames)?;
+        let (key_dir, expiry, touched, uncompacted_size, mut log_counter) =
+            build_key_dir(&filenames, recovery, format, unix_millis(clock.now()))?;
         let key_dir = Arc::new(RwLock::new(key_dir));
         let uncompacted_size = Arc::new(AtomicU64::new(uncompacted_size));
         let log_filename = if filenames.is_empty() {
@@ -131,42 +487,165 @@ impl LogStructKVStore {
         Ok(LogStructKVStore {
             log_writer,
             key_dir,
+            expiry: Arc::new(RwLock::new(expiry)),
+            touched: Arc::new(RwLock::new(touched)),
             path: Arc::new(current_folder),
             log: Arc::new(AtomicU64::new(log)),
             log_counter,
             uncompacted_size,
+            durability,
+            clock,
+            format,
+            _lock: Arc::new(lock),
         })
     }
 
+    /// Rewrites the set command of the key with the deadline, `expires_at` milliseconds since the Unix epoch
+    /// Writes a remove command instead if the deadline already passed
+    fn expire_until(&self, key: String, expires_at: u64) -> Result<bool> {
+        let mut log_writer = self.log_writer.lock().unwrap();
+        match self.get(key.clone())? {
+            Some(_) if expires_at <= self.now_millis() => {
+                self.write_rm(&mut log_writer, key)?;
+                Ok(true)
+            }
+            Some(value) => {
+                self.write_set(&mut log_writer, key, value, Some(expires_at))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Appends a set command and points the key to it
+    /// The key expires at `expires_at` if given, otherwise an expiry it had is cleared
+    /// The caller holds the write lock, compaction may run under it
+    fn write_set(
+        &self,
+        log_writer: &mut BufWriter<File>,
+        key: String,
+        value: String,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let pos = log_writer.stream_position()?;
+        let set_cmd = match expires_at {
+            Some(expires_at) => LogRecord::SetExpiring {
+                key: key.clone(),
+                value,
+                expires_at,
+            },
+            None => LogRecord::Set {
+                key: key.clone(),
+                value,
+            },
+        };
+        let size = write_record(log_writer, &set_cmd, self.format)?;
+        self.end_write(log_writer)?;
+
+        let insert_result = self.index_set(key, pos, size, expires_at);
+        self.update_uncompacted_size(insert_result, log_writer)
+    }
+
+    /// Points the key to the flushed set record of `size` bytes at `pos` of the current log,
+    /// returns the pointer it replaced
+    fn index_set(
+        &self,
+        key: String,
+        pos: u64,
+        size: u64,
+        expires_at: Option<u64>,
+    ) -> Option<LogPointer> {
+        {
+            let mut expiry = self.expiry.write().unwrap();
+            match expires_at {
+                Some(expires_at) => expiry.insert(key.clone(), expires_at),
+                None => expiry.remove(&key),
+            };
+        }
+        self.touched.write().unwrap().remove(&key);
+        self.key_dir.write().unwrap().insert(
+            key,
+            LogPointer {
+                pos: Arc::new(AtomicU64::new(pos)),
+                size,
+                log: Arc::new(AtomicU64::new(self.log.load(Ordering::Relaxed))),
+                log_state: Arc::new(AtomicU8::new(LOG_WRITE)),
+            },
+        )
+    }
+
+    fn write_rm(&self, log_writer: &mut BufWriter<File>, key: String) -> Result<()> {
+        let cmd = LogRecord::Rm { key };
+        write_record(log_writer, &cmd, self.format)?;
+        self.end_write(log_writer)?;
+
+        if let LogRecord::Rm { key } = cmd {
+            self.expiry.write().unwrap().remove(&key);
+            self.touched.write().unwrap().remove(&key);
+            let remove_result = self.key_dir.write().unwrap().remove(&key);
+            self.update_uncompacted_size(remove_result, log_writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Hands the written records to the OS, and to the disk with `Durability::Fsync`
+    fn end_write(&self, writer: &mut BufWriter<File>) -> Result<()> {
+        writer.flush()?;
+        if self.durability == Durability::Fsync {
+            writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
     fn update_uncompacted_size(
         &self,
         old_log_pointer: Option<LogPointer>,
-        log_writer: MutexGuard<BufWriter<File>>,
+        log_writer: &mut BufWriter<File>,
     ) -> Result<()> {
-        if let Some(old_log_pointer) = old_log_pointer {
-            let mut comp_thresh = self
-                .uncompacted_size
-                .fetch_add(old_log_pointer.size, Ordering::Relaxed);
-            comp_thresh += old_log_pointer.size;
-
-            if comp_thresh >= COMPACT_THRESHOLD {
-                self.compact_logs(log_writer)?;
-            }
+        match old_log_pointer {
+            Some(old_log_pointer) => self.add_uncompacted_size(old_log_pointer.size, log_writer),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts `size` more redundant bytes, compacting the logs once they reach the threshold
+    fn add_uncompacted_size(&self, size: u64, log_writer: &mut BufWriter<File>) -> Result<()> {
+        let comp_thresh = self.uncompacted_size.fetch_add(size, Ordering::Relaxed) + size;
+        if comp_thresh >= COMPACT_THRESHOLD {
+            self.compact_logs(log_writer)?;
         }
         Ok(())
     }
 
+    /// Whether a key of the index is live and matches the glob `pattern`, sequence keys never do
+    fn is_live_match(&self, key: &str, pattern: &str) -> bool {
+        !is_sequence_key(key) && !self.is_expired(key) && glob::matches(pattern, key)
+    }
+
+    /// Current time of the clock, in milliseconds since the Unix epoch
+    fn now_millis(&self) -> u64 {
+        unix_millis(self.clock.now())
+    }
+
+    fn is_expired(&self, key: &str) -> bool {
+        match self.expiry.read().unwrap().get(key) {
+            Some(expires_at) => *expires_at <= self.now_millis(),
+            None => false,
+        }
+    }
+
     fn get_new_log(&self) -> u64 {
         self.log_counter.fetch_add(1, Ordering::Relaxed)
     }
 
     /// Compact logs
     /// Iterates over key_dir and save latest commands in the newly generatd log files
-    /// Redundant are removed
-
-    fn compact_logs(&self, mut log_writer: MutexGuard<BufWriter<File>>) -> Result<()> {
+    /// Redundant are removed, and so are expired keys
+    fn compact_logs(&self, log_writer: &mut BufWriter<File>) -> Result<()> {
+        self.drop_expired();
         let current_folder = &self.path;
-        let old_files = get_sorted_log_files(current_folder);
+        let old_files = get_sorted_log_files(current_folder)?;
 
         let current_log = self.get_new_log();
         self.log.store(current_log, Ordering::Relaxed);
@@ -177,8 +656,10 @@ impl LogStructKVStore {
             let mut comp_writer =
                 create_file_writer(&self.generate_full_log_path(&comp_log, &LOG_COMP)?)?;
 
-            let key_dir = self.key_dir.read().unwrap();
-            for (_, log_pointer) in key_dir.iter() {
+            let mut key_dir = self.key_dir.write().unwrap();
+            let mut touched = self.touched.write().unwrap();
+            let expiry = self.expiry.read().unwrap();
+            for (key, log_pointer) in key_dir.iter_mut() {
                 let mut buf = vec![0u8; log_pointer.size as usize];
 
                 let mut current_reader = create_file_reader(&self.generate_full_log_path(
@@ -189,27 +670,61 @@ impl LogStructKVStore {
                 current_reader.seek(SeekFrom::Start(log_pointer.pos.load(Ordering::Relaxed)))?;
                 current_reader.read_exact(&mut buf)?;
 
-                log_pointer
-                    .pos
-                    .store(comp_writer.stream_position()?, Ordering::Relaxed);
+                let pos = comp_writer.stream_position()?;
+                log_pointer.pos.store(pos, Ordering::Relaxed);
                 log_pointer.log.store(comp_log, Ordering::Relaxed);
                 log_pointer.log_state.store(LOG_COMP, Ordering::Relaxed);
 
-                comp_writer.write_all(&buf)?;
+                match (touched.contains(key), expiry.get(key)) {
+                    // The deadline of the `SetExpiry` record goes into the moved one
+                    (true, Some(&expires_at)) => {
+                        let value = match decode_record(&buf, self.format)? {
+                            LogRecord::Set { value, .. } | LogRecord::SetExpiring { value, .. } => {
+                                value
+                            }
+                            _ => return Err(KvsError::UnexpectedCommandType),
+                        };
+                        let record = LogRecord::SetExpiring {
+                            key: key.clone(),
+                            value,
+                            expires_at,
+                        };
+                        log_pointer.size = write_record(&mut comp_writer, &record, self.format)?;
+                    }
+                    _ => comp_writer.write_all(&buf)?,
+                }
                 if comp_writer.stream_position()? > MAX_FILE_SIZE {
+                    self.end_write(&mut comp_writer)?;
                     comp_log = self.get_new_log();
                     comp_writer =
                         create_file_writer(&self.generate_full_log_path(&comp_log, &LOG_COMP)?)?;
                 }
             }
+            touched.clear();
+            // Before the old logs are removed, so their records are synced in the compacted ones with Fsync
+            self.end_write(&mut comp_writer)?;
         }
         self.uncompacted_size.store(0, Ordering::Relaxed);
         for filename in old_files.iter() {
-            fs::remove_file(&filename)?;
+            fs::remove_file(filename)?;
         }
         Ok(())
     }
 
+    /// Drops the expired keys from the index, compaction leaves their records behind
+    fn drop_expired(&self) {
+        let mut key_dir = self.key_dir.write().unwrap();
+        let mut expiry = self.expiry.write().unwrap();
+        let now = self.now_millis();
+        expiry.retain(|key, expires_at| {
+            if *expires_at > now {
+                return true;
+            }
+            key_dir.remove(key);
+            false
+        });
+    }
+
     fn generate_full_log_path(&self, log: &u64, log_state: &u8) -> Result<PathBuf> {
         Ok(self
             .path
@@ -226,9 +741,27 @@ fn get_state_flag(state: &u8) -> &str {
     }
 }
 
-/// Builds key_dir from all the log files
-fn build_key_dir(filenames: &[PathBuf]) -> Result<(HashMap<String, LogPointer>, u64, u64)> {
-    let mut key_dir = HashMap::<String, LogPointer>::new();
+/// Key dir, expiry deadlines, touched keys, uncompacted size and last log number rebuilt from the log files
+type RecoveredLogs = (
+    HashMap<String, LogPointer>,
+    HashMap<String, u64>,
+    HashSet<String>,
+    u64,
+    u64,
+);
+
+/// Builds key_dir and the expiry deadlines from all the log files, written in `format`
+/// Keys that expired at `now` milliseconds since the Unix epoch are left out
+fn build_key_dir(
+    filenames: &[PathBuf],
+    recovery: RecoveryPolicy,
+    format: u32,
+    now: u64,
+) -> Result<RecoveredLogs> {
+    // Sized up front, so inserting the keys of a big storage doesn't rehash them again and again
+    let mut key_dir = HashMap::<String, LogPointer>::with_capacity(estimate_key_count(filenames));
+    let mut expiry = HashMap::<String, u64>::new();
+    let mut touched = HashSet::<String>::new();
     let mut uncompacted_size = 0u64;
     let mut log_counter = 0u64;
 
@@ -237,9 +770,27 @@ fn build_key_dir(filenames: &[PathBuf]) -> Result<(HashMap<String, LogPointer>,
         let mut log_position = reader.stream_position()?;
         let (log, log_state) = parse_filename(filename)?;
         log_counter = max(log_counter, log);
-        while let Ok(cmd) = bincode::deserialize_from(&mut reader) {
+        while let Ok(cmd) = read_record(&mut reader, format) {
+            let cmd = match cmd {
+                Some(cmd) => cmd,
+                None if recovery == RecoveryPolicy::SkipCorrupt => {
+                    uncompacted_size += reader.stream_position()? - log_position;
+                    log_position = reader.stream_position()?;
+                    continue;
+                }
+                None => return Err(KvsError::BadLogFile),
+            };
+            let expires_at = match &cmd {
+                LogRecord::SetExpiring { expires_at, .. } => Some(*expires_at),
+                _ => None,
+            };
             match cmd {
-                Command::Set { key, value: _ } => {
+                LogRecord::Set { key, .. } | LogRecord::SetExpiring { key, .. } => {
+                    match expires_at {
+                        Some(expires_at) => expiry.insert(key.clone(), expires_at),
+                        None => expiry.remove(&key),
+                    };
+                    touched.remove(&key);
                     if let Some(old_log_pointer) = key_dir.insert(
                         key,
                         LogPointer {
@@ -252,54 +803,180 @@ fn build_key_dir(filenames: &[PathBuf]) -> Result<(HashMap<String, LogPointer>,
                         uncompacted_size += old_log_pointer.size;
                     }
                 }
-                Command::Rm { key } => {
+                LogRecord::Rm { key } => {
                     if let Some(old_log_pointer) = key_dir.remove(&key) {
                         uncompacted_size += old_log_pointer.size;
                     }
+                    expiry.remove(&key);
+                    touched.remove(&key);
+                }
+                LogRecord::SetExpiry { key, expires_at } => {
+                    if key_dir.contains_key(&key) {
+                        expiry.insert(key.clone(), expires_at);
+                        touched.insert(key);
+                    }
+                    uncompacted_size += reader.stream_position()? - log_position;
+                }
+                _ if recovery == RecoveryPolicy::SkipCorrupt => {
+                    uncompacted_size += reader.stream_position()? - log_position
                 }
                 _ => return Err(KvsError::UnexpectedCommandType),
             };
             log_position = reader.stream_position()?;
         }
     }
-    Ok((key_dir, uncompacted_size, log_counter))
+    // Expired keys are left out, compaction drops their records
+    expiry.retain(|key, expires_at| {
+        if *expires_at > now {
+            return true;
+        }
+        if let Some(old_log_pointer) = key_dir.remove(key) {
+            uncompacted_size += old_log_pointer.size;
+        }
+        touched.remove(key);
+        false
+    });
+    Ok((key_dir, expiry, touched, uncompacted_size, log_counter))
+}
+
+/// Version of the on-disk format of the storage at `path`
+/// A storage without a `FORMAT` file is a new one, which takes the current format, or an old one with logs
+fn storage_format(path: &Path, has_logs: bool) -> Result<u32> {
+    let format_path = path.join(FORMAT_FILENAME);
+    match fs::read_to_string(&format_path) {
+        Ok(format) => format.trim().parse().map_err(|_| KvsError::BadLogFile),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && has_logs => Ok(1),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut file = File::create(&format_path)?;
+            writeln!(file, "{}", FORMAT_VERSION)?;
+            file.sync_all()?;
+            Ok(FORMAT_VERSION)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Appends the record in `format`, see `FORMAT_VERSION`, returns its size in bytes
+fn write_record(writer: &mut BufWriter<File>, record: &LogRecord, format: u32) -> Result<u64> {
+    let bytes = bincode::serialize(record)?;
+    let mut size = bytes.len() as u64;
+    if format >= 2 {
+        writer.write_all(&crc32fast::hash(&bytes).to_le_bytes())?;
+        size += 4;
+    }
+    writer.write_all(&bytes)?;
+    Ok(size)
 }
 
+/// Decodes the bytes of a record written in `format`
+/// Fails with `BadLogFile` if they don't match their checksum
+fn decode_record(bytes: &[u8], format: u32) -> Result<LogRecord> {
+    if format < 2 {
+        return Ok(bincode::deserialize(bytes)?);
+    }
+    if bytes.len() < 4 {
+        return Err(KvsError::BadLogFile);
+    }
+    let (crc, record) = bytes.split_at(4);
+    if crc32fast::hash(record).to_le_bytes() != crc {
+        return Err(KvsError::BadLogFile);
+    }
+    Ok(bincode::deserialize(record)?)
+}
+
+/// Reads the next record of a log written in `format`, `None` if it doesn't match its checksum
+/// Fails at the end of the log, or where the bytes can't be decoded
+fn read_record<R: Read>(reader: &mut R, format: u32) -> Result<Option<LogRecord>> {
+    if format < 2 {
+        return Ok(Some(bincode::deserialize_from(reader)?));
+    }
+    let crc: u32 = bincode::deserialize_from(&mut *reader)?;
+    let mut reader = CrcReader {
+        inner: reader,
+        hasher: crc32fast::Hasher::new(),
+    };
+    let record = bincode::deserialize_from(&mut reader)?;
+    if reader.hasher.finalize() == crc {
+        Ok(Some(record))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reader hashing the bytes read through it
+struct CrcReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Number of keys the logs would hold if every record set another key, from the size of the files
+/// Redundant records make it too high, at most by `COMPACT_THRESHOLD` worth of them,
+/// records bigger than `ESTIMATED_RECORD_SIZE` too low
+fn estimate_key_count(filenames: &[PathBuf]) -> usize {
+    let bytes: u64 = filenames
+        .iter()
+        .filter_map(|filename| fs::metadata(filename).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    (bytes / ESTIMATED_RECORD_SIZE) as usize
+}
+
+/// Number and state of a log file name, `BadLogFile` for a name that isn't one of a log
 fn parse_filename(path: &Path) -> Result<(u64, u8)> {
-    let fullname = path.file_name().unwrap().to_str().unwrap();
-    let log_state = match &fullname[0..1] {
-        WRITE_FLAG => LOG_WRITE,
-        FULL_FLAG => LOG_FULL,
-        COMP_FLAG => LOG_COMP,
+    let fullname = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or(KvsError::BadLogFile)?;
+    let log_state = match fullname.get(0..1) {
+        Some(WRITE_FLAG) => LOG_WRITE,
+        Some(FULL_FLAG) => LOG_FULL,
+        Some(COMP_FLAG) => LOG_COMP,
         _ => LOG_WRITE,
     };
-    let log_id = fullname[1..fullname.len() - LOG_EXT.len() - 1]
-        .parse::<u64>()
-        .unwrap();
+    let log_id = fullname
+        .get(1..)
+        .and_then(|x| x.strip_suffix(LOG_EXT))
+        .and_then(|x| x.strip_suffix('.'))
+        .and_then(|x| x.parse::<u64>().ok())
+        .ok_or(KvsError::BadLogFile)?;
     Ok((log_id, log_state))
 }
 
+/// Clock of the storages opened without one
+fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
 /// Created a buffered writer for a given file
 fn create_file_writer(path: &Path) -> Result<BufWriter<File>> {
-    let file = OpenOptions::new().append(true).create(true).open(&path)?;
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
     let mut log_writer = BufWriter::new(file);
     log_writer.seek(SeekFrom::End(0))?;
     Ok(log_writer)
 }
 /// Created a buffered reader for a given file
 fn create_file_reader(path: &Path) -> Result<BufReader<File>> {
-    Ok(BufReader::new(File::open(&path)?))
+    Ok(BufReader::new(File::open(path)?))
 }
 
 /// Returns all the log file paths in the current directory
-fn get_sorted_log_files(path: &Path) -> Vec<PathBuf> {
-    let mut files = fs::read_dir(path)
-        .unwrap()
-        .into_iter()
-        .map(|x| x.unwrap().path())
-        .filter(|x| x.file_name().unwrap().to_str().unwrap().ends_with(&LOG_EXT))
-        .collect::<Vec<PathBuf>>();
+fn get_sorted_log_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?.path();
+        if entry.extension().is_some_and(|ext| ext == LOG_EXT) {
+            files.push(entry);
+        }
+    }
 
     files.sort();
-    files
+    Ok(files)
 }
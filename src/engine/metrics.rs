@@ -0,0 +1,103 @@
+use std::cmp::min;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Number of histogram buckets, bucket `i` counts latencies below 2^i microseconds
+const BUCKETS: usize = 32;
+
+/// Latency percentiles of one operation, in microseconds
+/// Values are bucket bounds, so they are precise up to a power of two
+#[derive(Clone, Debug, Default)]
+pub struct Percentiles {
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+/// Latency percentiles of the storage operations
+#[derive(Clone, Debug, Default)]
+pub struct LatencyReport {
+    pub get: Percentiles,
+    pub set: Percentiles,
+    pub remove: Percentiles,
+}
+
+/// Lock-free histogram with power of two buckets
+#[derive(Default)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Histogram {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros()) as usize;
+        self.buckets[min(bucket, BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the time until the returned timer is dropped
+    pub(crate) fn timer(&self) -> Timer<'_> {
+        Timer {
+            histogram: self,
+            start: Instant::now(),
+        }
+    }
+
+    fn percentiles(&self) -> Percentiles {
+        let counts = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect::<Vec<u64>>();
+        let count = counts.iter().sum();
+        Percentiles {
+            count,
+            p50: percentile(&counts, count, 0.5),
+            p90: percentile(&counts, count, 0.9),
+            p99: percentile(&counts, count, 0.99),
+        }
+    }
+}
+
+/// Upper bound of the bucket holding the `quantile`
+fn percentile(counts: &[u64], count: u64, quantile: f64) -> u64 {
+    let rank = ((quantile * count as f64).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (bucket, bucket_count) in counts.iter().enumerate() {
+        seen += bucket_count;
+        if seen >= rank {
+            return 1 << bucket;
+        }
+    }
+    0
+}
+
+pub(crate) struct Timer<'a> {
+    histogram: &'a Histogram,
+    start: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histogram.record(self.start.elapsed());
+    }
+}
+
+/// Latency histograms of the storage operations
+#[derive(Default)]
+pub(crate) struct Latencies {
+    pub(crate) get: Histogram,
+    pub(crate) set: Histogram,
+    pub(crate) remove: Histogram,
+}
+
+impl Latencies {
+    pub(crate) fn report(&self) -> LatencyReport {
+        LatencyReport {
+            get: self.get.percentiles(),
+            set: self.set.percentiles(),
+            remove: self.remove.percentiles(),
+        }
+    }
+}
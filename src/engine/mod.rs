@@ -1,4 +1,29 @@
 use crate::common::Result;
+use crate::error::KvsError;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Prefix of the reserved keys that store the id sequences
+const SEQUENCE_PREFIX: &str = "__seq:";
+/// File of a log structured storage folder that its opener locks
+const LOCK_FILENAME: &str = "LOCK";
+/// Keys removed by `remove_prefix` under one acquisition of the write lock
+pub(crate) const REMOVE_PREFIX_CHUNK: usize = 256;
+
+/// Line of the NDJSON dump
+#[derive(Serialize, Deserialize)]
+struct JsonPair {
+    key: String,
+    value: String,
+}
 
 pub trait KvsEngine: Clone + Send + 'static {
     /// Sets a `value` for a given `key`
@@ -9,13 +34,415 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// Returs None if key not found
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Retrieves the values of `keys` in the same order, `None` for a missing key
+    /// Each key is read on its own, a write between two reads may be observed partly
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key.clone())).collect()
+    }
+
+    /// Returns the key value pairs with keys starting with `prefix`, sorted by key
+    /// byte-wise unless the engine is configured with another order
+    /// Stops after `limit` pairs, `None` doesn't limit
+    /// Pairs written while scanning may or may not be returned
+    fn scan_prefix(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        self.export(|key, value| {
+            if key.starts_with(prefix) {
+                pairs.push((key, value));
+            }
+            Ok(())
+        })?;
+        pairs.sort();
+        pairs.truncate(limit.unwrap_or(usize::MAX));
+        Ok(pairs)
+    }
+
     /// Removes a entry for a given `key`
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Removes `key` and returns the value it had, `None` if it was missing
+    /// Of several callers racing on a key, only one gets its value
+    fn get_and_remove(&self, key: String) -> Result<Option<String>> {
+        let mut old = None;
+        self.update(key, |value| {
+            old = value;
+            None
+        })?;
+        Ok(old)
+    }
+
+    /// Atomically replaces the value of `key` with `f` applied to the current one
+    /// `f` gets `None` for a missing key, returning `None` removes the key
+    /// No other write can happen between the read and the write, returns the new value
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>;
+
+    /// Sets `value` only if `key` exists, like Redis `SET XX`, returns whether it was set
+    /// Atomic like `update`, a missing key is left missing and nothing is written
+    fn replace(&self, key: String, value: String) -> Result<bool> {
+        let mut existed = false;
+        self.update(key, |old| {
+            existed = old.is_some();
+            old.map(|_| value)
+        })?;
+        Ok(existed)
+    }
+
+    /// Sets `value` only if `key` doesn't exist, like Redis `SETNX`, returns whether it was set
+    /// Checked and written under the write lock, of several callers racing on a missing key
+    /// only one sets it. Nothing is written for an existing key, its expiry stays
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool>;
+
+    /// Sets every pair of `pairs` in order, handy to fill a storage in tests and fixtures
+    /// Stops at the first error, pairs set before it stay
+    fn extend<I>(&self, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Makes `key` expire in `ttl_secs` seconds, replacing an expiry it already has
+    /// Returns whether the key existed, an already expired key doesn't
+    /// An expired key reads as missing, a later set or remove clears its expiry
+    fn expire(&self, key: String, ttl_secs: u64) -> Result<bool>;
+
+    /// Makes `key` expire at `unix_secs` seconds since the Unix epoch, like `expire` otherwise
+    /// A time already passed removes the key right away, its space is then reclaimed like after `remove`
+    fn expire_at(&self, key: String, unix_secs: u64) -> Result<bool>;
+
+    /// Returns the deadline of `key` in milliseconds since the Unix epoch,
+    /// `None` for a key without expiry and for a missing or expired one
+    fn expires_at(&self, key: String) -> Result<Option<u64>>;
+
+    /// Makes every key matching the glob `pattern` expire in `ttl_secs` seconds, returns how many did
+    /// `*` matches any run of characters, `?` one, `[a-z]` and `[^a-z]` one of a set or outside it,
+    /// `\` makes the next character literal. Reserved sequence keys never match
+    /// Costs a scan of all the keys unless the engine can seek to a literal prefix of the pattern,
+    /// the keys are then expired one by one, so it isn't atomic across them
+    fn expire_matching(&self, pattern: &str, ttl_secs: u64) -> Result<usize>;
+
+    /// Returns the keys matching the glob `pattern` of `expire_matching`, sorted by key
+    /// byte-wise unless the engine is configured with another order. Reserved sequence keys never match
+    /// Only the keys are read, but a pattern starting with a wildcard costs a scan of all the keys,
+    /// a literal prefix lets the engines that keep their keys sorted seek to it
+    fn keys_matching(&self, pattern: &str) -> Result<Vec<String>>;
+
+    /// Counts the keys `keys_matching` would return, without collecting them
+    fn count_matching(&self, pattern: &str) -> Result<u64> {
+        Ok(self.keys_matching(pattern)?.len() as u64)
+    }
+
+    /// Makes `key` expire in `new_ttl` without writing its value again, returns whether the key existed
+    /// Engines that can't change the expiry alone rewrite the value with `expire`,
+    /// `new_ttl` rounded up to whole seconds
+    fn touch(&self, key: String, new_ttl: Duration) -> Result<bool> {
+        let ttl_secs = new_ttl.as_secs() + u64::from(new_ttl.subsec_nanos() > 0);
+        self.expire(key, ttl_secs)
+    }
+
+    /// Returns how many of `keys` exist without reading their values, expired keys don't count
+    /// No engine has an eviction policy or tracks access recency, so nothing else changes
+    /// Named apart from `touch`, which refreshes the expiry of a single key
+    fn touch_keys(&self, keys: &[String]) -> Result<usize> {
+        let mut existing = 0;
+        for key in keys {
+            if self.contains_key(key.clone())? {
+                existing += 1;
+            }
+        }
+        Ok(existing)
+    }
+
+    /// Checks whether a `key` is present without reading its value
+    fn contains_key(&self, key: String) -> Result<bool>;
+
+    /// Returns the number of live keys, reserved sequence keys aside
+    /// Costs a scan of all the pairs unless the engine counts the keys of its index
+    fn key_count(&self) -> Result<u64> {
+        let mut count = 0;
+        self.export(|key, _| {
+            if !is_sequence_key(&key) {
+                count += 1;
+            }
+            Ok(())
+        })?;
+        Ok(count)
+    }
+
+    /// Removes every key but the reserved sequence keys, so no id is issued twice,
+    /// returns how many were removed
+    /// Keys are removed one by one, a key set meanwhile may stay
+    fn clear(&self) -> Result<u64> {
+        let mut keys = Vec::new();
+        self.export(|key, _| {
+            if !is_sequence_key(&key) {
+                keys.push(key);
+            }
+            Ok(())
+        })?;
+        let mut removed = 0;
+        for key in keys {
+            match self.remove(key) {
+                Ok(()) => removed += 1,
+                // Removed or expired meanwhile
+                Err(KvsError::KeyNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes every key starting with `prefix` but the reserved sequence keys, returns how many were removed
+    /// Only the keys are read, through `keys_matching`, and removed one by one,
+    /// a key set meanwhile may stay
+    fn remove_prefix(&self, prefix: String) -> Result<u64> {
+        let mut removed = 0;
+        for key in self.keys_matching(&format!("{}*", glob::escape(&prefix)))? {
+            match self.remove(key) {
+                Ok(()) => removed += 1,
+                // Removed or expired meanwhile
+                Err(KvsError::KeyNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the next id of the `name` sequence, ids start from 1 and only grow
+    fn next_id(&self, name: &str) -> Result<u64> {
+        Ok(self.next_id_batch(name, 1)?.start)
+    }
+
+    /// Atomically reserves `count` consecutive ids of the `name` sequence
+    /// The last reserved id is written under a reserved key before returning,
+    /// so ids are as durable as any other write and are recovered from the log on open
+    /// Ids of a reserved range left unused before a restart are skipped, never issued again
+    fn next_id_batch(&self, name: &str, count: u64) -> Result<Range<u64>>;
+
+    /// Calls `visit` with every key value pair, stops at the first error
+    /// Pairs written while exporting may or may not be visited
+    fn export<F>(&self, visit: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>;
+
+    /// Writes every pair as a `{"key":...,"value":...}` JSON object on its own line (NDJSON)
+    /// Returns the number of pairs written, `out` is better buffered
+    fn export_ndjson<W: Write>(&self, mut out: W) -> Result<usize> {
+        let mut count = 0;
+        self.export(|key, value| {
+            serde_json::to_writer(&mut out, &JsonPair { key, value })?;
+            out.write_all(b"\n")?;
+            count += 1;
+            Ok(())
+        })?;
+        out.flush()?;
+        Ok(count)
+    }
+
+    /// Sets every pair of a dump written by `export_ndjson`, blank lines are skipped
+    /// Stops at the first line that isn't a pair, pairs set before it stay
+    fn import_ndjson<R: BufRead>(&self, input: R) -> Result<usize> {
+        let mut count = 0;
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let pair: JsonPair = serde_json::from_str(&line)?;
+            self.set(pair.key, pair.value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Hands every write done so far to the OS, they then survive a crash of the process
+    /// The engines do that on each write already, so it only matters to a buffering engine
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Makes every write done so far durable on disk, they then survive a power loss
+    /// The log engines don't fsync on their own, sled does on each write
+    fn sync(&self) -> Result<()>;
+
+    /// Syncs and drops this handle, clones keep using the storage
+    fn close(self) -> Result<()> {
+        self.sync()
+    }
+
+    /// Writes the live pairs, with their expiry, to a new storage of the same engine at `dest`
+    /// The copy is compacted and opens on its own, writes wait until it is done so it is consistent
+    /// `dest` is created if missing and must be empty otherwise
+    fn checkpoint(&self, dest: &Path) -> Result<()>;
+
+    /// Reads all the stored data once to pull it into the OS page cache
+    /// Useful right after `open` so the first gets are not served cold
+    fn warmup(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stops a compaction in progress at the next key and keeps new ones from starting,
+    /// so shutting down doesn't wait for it, the engines that compact in one go finish it
+    fn cancel_compaction(&self) {}
+
+    /// Short name of the engine, as reported by `Command::Info`
+    fn name(&self) -> &'static str;
+}
+
+/// Milliseconds from the Unix epoch to `time`, the unit of expiry deadlines
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Deadline of a time to live in seconds starting at `now` milliseconds
+pub(crate) fn expiry_deadline(now: u64, ttl_secs: u64) -> u64 {
+    deadline_after(now, Duration::from_secs(ttl_secs))
+}
+
+/// Deadline in milliseconds of a Unix timestamp in seconds
+pub(crate) fn unix_secs_deadline(unix_secs: u64) -> u64 {
+    unix_secs.saturating_mul(1000)
+}
+
+/// Deadline of a time to live starting at `now` milliseconds
+pub(crate) fn deadline_after(now: u64, ttl: Duration) -> u64 {
+    now.saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
+/// Takes the exclusive advisory lock of a storage folder, held until the returned file is closed
+/// Fails with `AlreadyOpen` while another opener holds it, in this process or another one
+pub(crate) fn lock_folder(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join(LOCK_FILENAME))?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(err) if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+            Err(KvsError::AlreadyOpen)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Creates the folder of a checkpoint, fails if it exists with something in it
+pub(crate) fn create_checkpoint_folder(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Checkpoint folder is not empty",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Syncs the entries of the folder at `path`, so the files created, renamed or removed in it survive a crash
+pub(crate) fn sync_folder(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// Syncs every file under the folder at `path`, the folders themselves and the parent of `path`,
+/// so a copy written there survives a crash as a whole
+pub(crate) fn sync_tree(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            sync_tree(&entry.path())?;
+        } else {
+            File::open(entry.path())?.sync_all()?;
+        }
+    }
+    sync_folder(path)?;
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => sync_folder(parent),
+        _ => Ok(()),
+    }
+}
+
+/// Expires the keys among `candidates` that match the glob `pattern`, see `KvsEngine::expire_matching`
+pub(crate) fn expire_matching_keys<E, I>(
+    engine: &E,
+    pattern: &str,
+    candidates: I,
+    ttl_secs: u64,
+) -> Result<usize>
+where
+    E: KvsEngine,
+    I: IntoIterator<Item = String>,
+{
+    let mut expired = 0;
+    for key in candidates {
+        if !is_sequence_key(&key) && glob::matches(pattern, &key) && engine.expire(key, ttl_secs)? {
+            expired += 1;
+        }
+    }
+    Ok(expired)
+}
+
+/// Reserved key of the `name` sequence
+pub(crate) fn sequence_key(name: &str) -> String {
+    format!("{}{}", SEQUENCE_PREFIX, name)
+}
+
+pub(crate) fn is_sequence_key(key: &str) -> bool {
+    key.starts_with(SEQUENCE_PREFIX)
+}
+
+/// Ids `count` after the `last` one issued, the last id of the range is the new value of the sequence
+/// Fails once the ids would go beyond `u64::MAX - 1`, the end of the range has to fit too
+pub(crate) fn reserve_ids(last: u64, count: u64) -> Result<Range<u64>> {
+    match last.checked_add(count).and_then(|x| x.checked_add(1)) {
+        Some(end) => Ok(last + 1..end),
+        None => Err(KvsError::SequenceExhausted),
+    }
+}
+
+pub(crate) fn parse_sequence(value: &str) -> Result<u64> {
+    value.parse::<u64>().map_err(|_| KvsError::NotAnInteger)
 }
 
+mod clock;
+mod config;
+mod glob;
+mod index;
 mod lskv;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod olskv;
+mod record;
+mod retirement;
 mod sled;
+mod stats;
+mod upgrade;
+mod verify;
+mod write_through;
 pub use self::sled::SledStore;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use config::{
+    Durability, EngineConfig, KeyOrder, OnEvict, ReadDuringCompaction, RecoveryPolicy,
+};
+pub use index::{
+    BTreeBackend, BTreeEntry, BTreeIndex, Index, IndexBackend, IndexEntry, SkipMapBackend,
+};
 pub use lskv::LogStructKVStore;
-pub use olskv::OptLogStructKvs;
+#[cfg(feature = "metrics")]
+pub use metrics::{LatencyReport, Percentiles};
+#[cfg(feature = "read-trace")]
+pub use olskv::ReadSource;
+pub use olskv::{LogReplay, OptLogStructKvs};
+pub use record::LogRecord;
+pub use stats::Stats;
+pub use upgrade::upgrade_format;
+pub use verify::{FsckReport, RepairReport, VerifyReport};
+pub use write_through::WriteThrough;
@@ -1,231 +1,2611 @@
-use crate::common::{Command, Result};
-use crate::engine::KvsEngine;
+use crate::common::{BlobRef, Result};
+#[cfg(feature = "metrics")]
+use crate::engine::metrics::{Latencies, LatencyReport};
+use crate::engine::retirement::Retirement;
+use crate::engine::{
+    create_checkpoint_folder, deadline_after, expire_matching_keys, expiry_deadline, glob,
+    is_sequence_key, lock_folder, parse_sequence, reserve_ids, sequence_key, sync_folder,
+    sync_tree, unix_millis, unix_secs_deadline, Durability, EngineConfig, FsckReport, Index,
+    IndexBackend, IndexEntry, KeyOrder, KvsEngine, LogRecord, ReadDuringCompaction, RecoveryPolicy,
+    RepairReport, SkipMapBackend, Stats, VerifyReport, REMOVE_PREFIX_CHUNK,
+};
 use crate::error::KvsError;
 use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::{SkipMap, SkipSet};
-use std::cmp::max;
+use std::cell::RefCell;
+use std::cmp::{max, Ordering as CmpOrdering, Reverse};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Range};
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-/// Size in bytes of redundant commands
-const COMPACT_THRESHOLD: u64 = 2000000;
 /// A flag in the log filename that is compacted and full
 const COMP_FLAG: char = '#';
 /// A flag in the log filename that is being written into
 const WRITE_FLAG: char = '?';
 /// Extension of a log file
 const LOG_EXT: &str = "log";
+/// Extension of a blob file, see `EngineConfig::blob_threshold`
+const BLOB_EXT: &str = "blob";
+/// File holding the highest version given so far, written by compaction before it drops records
+/// The logs alone hold it until then
+const VERSION_MARK_FILENAME: &str = "VERSION_MARK";
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
 struct LogPointer {
     pos: u64,
     size: u64,
     log: u64,
     log_state: char,
+    /// Number of writes of the key up to this record since the key was created
+    version: u64,
+}
+
+/// Key of the index, ordered with the configured `KeyOrder`
+#[derive(Clone)]
+struct IndexKey {
+    key: String,
+    order: KeyOrder,
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.order.compare(&self.key, &other.key)
+    }
+}
+
+/// Index of the keys, a `SkipMap` unless the storage was opened with `open_with_index`
+type KeyDir<B> = <B as IndexBackend>::Index<IndexKey, AtomicCell<LogPointer>>;
+type KeyDirEntry<'a, B> = <KeyDir<B> as Index<IndexKey, AtomicCell<LogPointer>>>::Entry<'a>;
+/// Older versions of the keys, newest first, kept only when `EngineConfig::versions` is above 1
+/// A key is locked while its current pointer is replaced or its versions are moved
+type History = SkipMap<IndexKey, Mutex<VecDeque<LogPointer>>>;
+/// Expiry deadlines of the keys that have one, in milliseconds since the Unix epoch
+/// Also written in the set records of the keys, so compaction keeps them
+type Expiry = SkipMap<IndexKey, u64>;
+/// Keys whose expiry was changed by `touch` after their set record, with the `SetExpiry` record
+/// Compaction writes the set record again with the new expiry, the `SetExpiry` one is dropped
+type Touched = SkipMap<IndexKey, LogPointer>;
+
+/// Value stored once for the keys set to it, see `EngineConfig::dedup`
+struct SharedValue {
+    pointer: AtomicCell<LogPointer>,
+    /// Keys whose current record references the value, it becomes redundant at 0
+    refs: AtomicU64,
+}
+
+/// Index of the shared values
+struct Dedup {
+    /// Shared values by the hash of their bytes
+    values: SkipMap<u64, SharedValue>,
+    /// Hash of the value of the keys whose current record is a `SetShared`
+    keys: SkipMap<IndexKey, u64>,
+}
+
+/// Keys of each value, see `EngineConfig::value_index`
+/// Changed under the write lock only, so emptied sets can be dropped without racing an insert
+#[derive(Default)]
+struct ValueIndex {
+    keys: SkipMap<String, SkipSet<String>>,
+}
+
+impl ValueIndex {
+    fn insert(&self, value: String, key: String) {
+        self.keys
+            .get_or_insert_with(value, SkipSet::new)
+            .value()
+            .insert(key);
+    }
+
+    fn remove(&self, value: &str, key: &str) {
+        if let Some(entry) = self.keys.get(value) {
+            entry.value().remove(key);
+            if entry.value().is_empty() {
+                entry.remove();
+            }
+        }
+    }
 }
 
 struct LogWriter {
-    writer: BufWriter<File>,
+    /// Shared with the reader while it holds records back, see `LogReader::follow_writer`
+    writer: Arc<Mutex<BufWriter<File>>>,
     log: u64,
     pos: u64,
+    /// Records are padded to end on a multiple of it, see `EngineConfig::align_records`
+    align: u64,
+    /// Hands each record to the OS, all but write logs with `Durability::None` do
+    flush_records: bool,
+    /// Syncs each record, write logs with `Durability::Fsync` do
+    sync_records: bool,
 }
 
 impl LogWriter {
-    fn new(folder: &Path, log: u64, log_state: char) -> Result<LogWriter> {
-        let mut writer =
-            create_file_writer(generate_full_log_path(folder, &log, &log_state)?.as_path())?;
+    /// Writer of the log in its shard, aligning the records like `config` says
+    fn new(folder: &Path, config: &EngineConfig, log: u64, log_state: char) -> Result<LogWriter> {
+        let path = generate_full_log_path(folder, config.log_shards, &log, &log_state)?;
+        if config.log_shards > 1 {
+            fs::create_dir_all(path.parent().unwrap())?;
+        }
+        let mut writer = create_file_writer(&path)?;
+        let write_log = log_state == WRITE_FLAG;
         Ok(LogWriter {
             pos: writer.stream_position()?,
-            writer,
+            writer: Arc::new(Mutex::new(writer)),
             log,
+            align: config.align_records.unwrap_or(1).max(1) as u64,
+            flush_records: config.durability != Durability::None || !write_log,
+            sync_records: config.durability == Durability::Fsync && write_log,
         })
     }
 
-    fn write_cmd(&mut self, cmd: &Command) -> Result<u64> {
-        let pos_before = self.pos;
-        bincode::serialize_into(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        self.pos = self.writer.stream_position()?;
-        Ok(self.pos - pos_before)
+    fn write_record(&mut self, record: &LogRecord) -> Result<u64> {
+        bincode::serialize_into(&mut *self.writer.lock().unwrap(), record)?;
+        self.end_record(bincode::serialized_size(record)?)
     }
 
     fn write_buf(&mut self, buf: &[u8]) -> Result<u64> {
+        self.writer.lock().unwrap().write_all(buf)?;
+        self.end_record(buf.len() as u64)
+    }
+
+    /// Pads the record of `size` bytes written at `pos` up to the alignment and flushes it,
+    /// unless records are held back
+    /// Returns the size of the record with its padding
+    fn end_record(&mut self, size: u64) -> Result<u64> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut gap = (self.align - (self.pos + size) % self.align) % self.align;
+        if gap > 0 {
+            while gap < PADDING_HEADER_SIZE {
+                gap += self.align;
+            }
+            let filler = vec![0; (gap - PADDING_HEADER_SIZE) as usize];
+            bincode::serialize_into(&mut *writer, &LogRecord::Padding { filler })?;
+        }
         let pos_before = self.pos;
-        self.writer.write_all(buf)?;
-        self.writer.flush()?;
-        self.pos = self.writer.stream_position()?;
+        if !self.flush_records {
+            // Seeking would flush the buffer, the padding takes exactly the gap
+            self.pos += size + gap;
+            return Ok(self.pos - pos_before);
+        }
+        writer.flush()?;
+        if self.sync_records {
+            writer.get_ref().sync_data()?;
+        }
+        self.pos = writer.stream_position()?;
         Ok(self.pos - pos_before)
     }
+
+    /// Writes a record from the halves of `LogRecord::encode_around_value` and its value
+    fn write_value(&mut self, header: &[u8], value: &[u8], trailer: &[u8]) -> Result<u64> {
+        {
+            let mut writer = self.writer.lock().unwrap();
+            writer.write_all(header)?;
+            writer.write_all(value)?;
+            writer.write_all(trailer)?;
+        }
+        self.end_record((header.len() + value.len() + trailer.len()) as u64)
+    }
+
+    /// Writes `header`, `len` bytes of `src` and `trailer`, checking on the way the bytes are valid UTF-8
+    /// On error a partial record may be left, the writer must not be written to anymore
+    fn write_stream<R: Read>(
+        &mut self,
+        header: &[u8],
+        src: &mut R,
+        len: u64,
+        trailer: &[u8],
+    ) -> Result<u64> {
+        self.writer.lock().unwrap().write_all(header)?;
+        let mut utf8 = Utf8Check::default();
+        self.copy_chunks(src, len, |chunk| utf8.feed(chunk))?;
+        utf8.finish()?;
+        self.writer.lock().unwrap().write_all(trailer)?;
+        self.end_record(header.len() as u64 + len + trailer.len() as u64)
+    }
+
+    /// Like `write_stream` for bytes already checked, copied from another log
+    fn copy_stream<R: Read>(
+        &mut self,
+        header: &[u8],
+        src: &mut R,
+        len: u64,
+        trailer: &[u8],
+    ) -> Result<u64> {
+        self.writer.lock().unwrap().write_all(header)?;
+        self.copy_chunks(src, len, |_| Ok(()))?;
+        self.writer.lock().unwrap().write_all(trailer)?;
+        self.end_record(header.len() as u64 + len + trailer.len() as u64)
+    }
+
+    /// Copies `len` bytes of `src` in chunks, passing each one to `check` before it is written
+    fn copy_chunks<R, F>(&mut self, src: &mut R, len: u64, mut check: F) -> Result<()>
+    where
+        R: Read,
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut left = len;
+        while left > 0 {
+            let chunk = (left as usize).min(buf.len());
+            let read = src.read(&mut buf[..chunk])?;
+            if read == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            check(&buf[..read])?;
+            self.writer.lock().unwrap().write_all(&buf[..read])?;
+            left -= read as u64;
+        }
+        Ok(())
+    }
+
+    /// Hands the records held back to the OS
+    fn flush(&mut self) -> Result<()> {
+        self.writer.lock().unwrap().flush()?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// Size of an empty `Padding` record, its bincode variant index and filler length
+const PADDING_HEADER_SIZE: u64 = 12;
+/// Size of the chunks a streamed value is copied in
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// Values shorter than that are cheaper to store again than to reference
+const DEDUP_MIN_VALUE_SIZE: usize = 64;
+
+/// Incremental UTF-8 validation of a value read in chunks
+/// A character split between two chunks is kept until the next one completes it
+#[derive(Default)]
+struct Utf8Check {
+    pending: Vec<u8>,
+}
+
+impl Utf8Check {
+    fn feed(&mut self, mut chunk: &[u8]) -> io::Result<()> {
+        // Completes the character split off the previous chunk first
+        while !self.pending.is_empty() {
+            let (&byte, rest) = match chunk.split_first() {
+                Some(split) => split,
+                None => return Ok(()),
+            };
+            self.pending.push(byte);
+            chunk = rest;
+            match std::str::from_utf8(&self.pending) {
+                Ok(_) => self.pending.clear(),
+                Err(err) if err.error_len().is_none() => {}
+                Err(_) => return Err(invalid_utf8()),
+            }
+        }
+        match std::str::from_utf8(chunk) {
+            Ok(_) => Ok(()),
+            Err(err) if err.error_len().is_none() => {
+                self.pending = chunk[err.valid_up_to()..].to_vec();
+                Ok(())
+            }
+            Err(_) => Err(invalid_utf8()),
+        }
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_utf8())
+        }
+    }
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "value is not valid UTF-8")
+}
+
+/// Reads a file from a position with pread, leaving the shared file offset alone
+struct PosReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl Read for PosReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+/// Bytes read at once from a log, records that follow the requested one are served from them
+const READ_AHEAD_SIZE: usize = 64 * 1024;
+
+/// Tells the read-ahead buffers of different storages apart
+static NEXT_READER_ID: AtomicU64 = AtomicU64::new(0);
+/// Number no log has
+const NO_LOG: u64 = u64::MAX;
+
+thread_local! {
+    /// Every thread buffers its own reads, so concurrent readers never contend on a buffer
+    static READ_AHEAD: RefCell<PBufReader> = RefCell::new(PBufReader::default());
+}
+
+/// Positional buffered reader, the last `READ_AHEAD_SIZE` bytes read from one log
+/// Logs are append only and a pread never returns bytes past the end of the file,
+/// so buffered bytes never go stale and a record past them is simply read again
+#[derive(Default)]
+struct PBufReader {
+    /// Reader id, log number and log state of the buffered bytes
+    log: Option<(u64, u64, char)>,
+    pos: u64,
+    buf: Vec<u8>,
+}
+
+impl PBufReader {
+    fn get(&self, log: (u64, u64, char), pos: u64, size: usize) -> Option<&[u8]> {
+        if self.log != Some(log) || pos < self.pos {
+            return None;
+        }
+        let start = (pos - self.pos) as usize;
+        self.buf.get(start..start + size)
+    }
+
+    fn fill(&mut self, file: &File, log: (u64, u64, char), pos: u64) -> Result<()> {
+        self.log = None;
+        self.buf.resize(READ_AHEAD_SIZE, 0);
+        let mut filled = 0;
+        while filled < READ_AHEAD_SIZE {
+            match file.read_at(&mut self.buf[filled..], pos + filled as u64)? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        self.buf.truncate(filled);
+        self.log = Some(log);
+        self.pos = pos;
+        Ok(())
+    }
 }
 
 struct LogReader {
+    id: u64,
     readers: SkipMap<(u64, char), File>,
-    to_clean: SkipSet<(u64, char)>,
+    /// Logs no pointer refers to anymore, waiting for the readers that could still follow an old one
+    retired: SkipSet<(u64, char)>,
     folder: PathBuf,
+    shards: u64,
+    /// Whether small records are read through the read-ahead buffer, see `EngineConfig::read_ahead`
+    read_ahead: bool,
+    /// Write log holding records back, `NO_LOG` if none does, see `Durability::None`
+    buffered_log: AtomicU64,
+    /// Buffer of `buffered_log`, flushed before reading that log
+    buffer: Mutex<Option<Arc<Mutex<BufWriter<File>>>>>,
 }
 
 impl LogReader {
-    fn new(folder: PathBuf) -> Result<LogReader> {
+    fn new(folder: PathBuf, shards: u64, read_ahead: bool) -> Result<LogReader> {
         Ok(LogReader {
+            id: NEXT_READER_ID.fetch_add(1, Ordering::Relaxed),
             folder,
-            to_clean: SkipSet::new(),
+            shards,
+            read_ahead,
+            retired: SkipSet::new(),
             readers: SkipMap::new(),
+            buffered_log: AtomicU64::new(NO_LOG),
+            buffer: Mutex::new(None),
         })
     }
+
+    /// Makes reads of the log of `writer` flush it first if it holds records back
+    /// Called with every new write log, the writer of the previous one must be flushed already
+    fn follow_writer(&self, writer: &LogWriter) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if writer.flush_records {
+            self.buffered_log.store(NO_LOG, Ordering::Release);
+            *buffer = None;
+        } else {
+            *buffer = Some(Arc::clone(&writer.writer));
+            self.buffered_log.store(writer.log, Ordering::Release);
+        }
+    }
+    /// Reads the record through the read-ahead buffer of the current thread if it is enabled
+    /// Records as big as the buffer are read directly
     fn read_log(&self, log_pointer: &LogPointer) -> Result<Vec<u8>> {
-        let entry = self.readers.get_or_insert(
-            (log_pointer.log, log_pointer.log_state),
-            File::open(generate_full_log_path(
-                &self.folder,
-                &log_pointer.log,
-                &log_pointer.log_state,
-            )?)?,
-        );
+        let size = log_pointer.size as usize;
+        if !self.read_ahead || size >= READ_AHEAD_SIZE {
+            return self.read_log_direct(log_pointer);
+        }
+        let file = self.file(log_pointer)?;
+        let log = (self.id, log_pointer.log, log_pointer.log_state);
+        READ_AHEAD.with(|read_ahead| {
+            let mut read_ahead = read_ahead.borrow_mut();
+            if read_ahead.get(log, log_pointer.pos, size).is_none() {
+                read_ahead.fill(file.value(), log, log_pointer.pos)?;
+            }
+            match read_ahead.get(log, log_pointer.pos, size) {
+                Some(buf) => Ok(buf.to_vec()),
+                None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            }
+        })
+    }
+
+    /// Reads the record with its own pread, bypassing the read-ahead buffer
+    fn read_log_direct(&self, log_pointer: &LogPointer) -> Result<Vec<u8>> {
+        let file = self.file(log_pointer)?;
+        let mut buf = vec![0u8; log_pointer.size as usize];
+        file.value().read_exact_at(&mut buf, log_pointer.pos)?;
+        Ok(buf)
+    }
+
+    fn file(&self, log_pointer: &LogPointer) -> Result<Entry<'_, (u64, char), File>> {
+        if log_pointer.log_state == WRITE_FLAG
+            && self.buffered_log.load(Ordering::Acquire) == log_pointer.log
+        {
+            // Cloned out, flushing doesn't hold up the switch to a new write log
+            let buffer = self.buffer.lock().unwrap().clone();
+            if let Some(buffer) = buffer {
+                buffer.lock().unwrap().flush()?;
+            }
+        }
+        let log = (log_pointer.log, log_pointer.log_state);
+        // An open reader keeps working after compaction removes its log
+        let entry = match self.readers.get(&log) {
+            Some(entry) => entry,
+            None => self.readers.get_or_insert(
+                log,
+                File::open(generate_full_log_path(
+                    &self.folder,
+                    self.shards,
+                    &log_pointer.log,
+                    &log_pointer.log_state,
+                )?)?,
+            ),
+        };
+        Ok(entry)
+    }
+
+    /// Whether the record is in the read-ahead buffer of the current thread
+    #[cfg(feature = "read-trace")]
+    fn is_read_ahead(&self, log_pointer: &LogPointer) -> bool {
+        let log = (self.id, log_pointer.log, log_pointer.log_state);
+        let size = log_pointer.size as usize;
+        self.read_ahead
+            && size < READ_AHEAD_SIZE
+            && READ_AHEAD.with(|read_ahead| {
+                read_ahead
+                    .borrow()
+                    .get(log, log_pointer.pos, size)
+                    .is_some()
+            })
+    }
+
+    fn deserialize(&self, log_pointer: &LogPointer) -> Result<LogRecord> {
+        Ok(bincode::deserialize(&self.read_log(log_pointer)?)?)
+    }
+
+    /// Decodes the record as it is on disk right now, for the consistency checks
+    fn deserialize_direct(&self, log_pointer: &LogPointer) -> Result<LogRecord> {
+        Ok(bincode::deserialize(&self.read_log_direct(log_pointer)?)?)
+    }
+
+    fn is_retired(&self, path: &Path) -> bool {
+        parse_filename(path).is_ok_and(|log| self.retired.contains(&log))
+    }
+}
+
+/// Where `OptLogStructKvs::get_traced` found the value
+#[cfg(feature = "read-trace")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadSource {
+    /// Record was in the read-ahead buffer of the reading thread
+    ReadAhead,
+    /// Record was read from its log
+    Disk,
+    /// Key is missing or expired, the index alone answered
+    Index,
+}
+
+/// Blob file the values are appended to
+struct BlobWriter {
+    file: File,
+    blob_id: u64,
+    pos: u64,
+}
+
+/// Values stored out of the logs, in the blob files `<blob_id>.blob` of the storage folder
+/// Blob files are append only, a value is never moved but by `OptLogStructKvs::gc_blobs`
+struct Blobs {
+    folder: PathBuf,
+    /// Created with the first value after open or a GC pass, only written under the write lock
+    writer: Mutex<Option<BlobWriter>>,
+    next_id: AtomicU64,
+    readers: SkipMap<u64, File>,
+    /// Blob of the value of the keys whose current record is a `SetBlob`
+    keys: SkipMap<IndexKey, BlobRef>,
+    /// Bytes of the blob files no key references, reclaimed by `gc_blobs`
+    garbage: AtomicU64,
+    /// Syncs each value before its record is written, with `Durability::Fsync`
+    sync_values: bool,
+}
+
+impl Blobs {
+    /// Appends the value to the current blob file, the caller holds the write lock
+    /// Values reach the OS before their record is written, whatever the `Durability`
+    fn write(&self, value: &str) -> Result<BlobRef> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_none() {
+            let blob_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let file = OpenOptions::new()
+                .append(true)
+                .create_new(true)
+                .open(blob_path(&self.folder, blob_id))?;
+            *writer = Some(BlobWriter {
+                file,
+                blob_id,
+                pos: 0,
+            });
+        }
+        let current = writer.as_mut().unwrap();
+        let written = current.file.write_all(value.as_bytes()).and_then(|_| {
+            if self.sync_values {
+                current.file.sync_data()?;
+            }
+            Ok(())
+        });
+        if let Err(err) = written {
+            // The position of the next value is unknown after a partial write, it goes to a new file
+            *writer = None;
+            return Err(err.into());
+        }
+        let blob = BlobRef {
+            blob_id: current.blob_id,
+            offset: current.pos,
+            size: value.len() as u64,
+        };
+        current.pos += blob.size;
+        Ok(blob)
+    }
+
+    fn read(&self, blob: &BlobRef) -> Result<String> {
+        let file = self.file(blob.blob_id)?;
+        let mut buf = vec![0u8; blob.size as usize];
+        file.value().read_exact_at(&mut buf, blob.offset)?;
+        Ok(String::from_utf8(buf).map_err(|_| invalid_utf8())?)
+    }
+
+    /// Copies the value to `dest` without holding it in memory
+    fn copy(&self, blob: &BlobRef, dest: &Path) -> Result<()> {
+        let file = self.file(blob.blob_id)?;
+        let mut value = PosReader {
+            file: file.value(),
+            pos: blob.offset,
+        }
+        .take(blob.size);
+        let mut writer = BufWriter::new(File::create(dest)?);
+        if io::copy(&mut value, &mut writer)? < blob.size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn file(&self, blob_id: u64) -> Result<Entry<'_, u64, File>> {
+        // An open reader keeps working after a GC pass removes its blob file
+        let entry = match self.readers.get(&blob_id) {
+            Some(entry) => entry,
+            None => self
+                .readers
+                .get_or_insert(blob_id, File::open(blob_path(&self.folder, blob_id))?),
+        };
+        Ok(entry)
+    }
+
+    /// Drops the blob the key references, if any, its value becomes garbage
+    fn release(&self, key: &IndexKey) {
+        if let Some(entry) = self.keys.remove(key) {
+            self.garbage
+                .fetch_add(entry.value().size, Ordering::Relaxed);
+        }
+    }
+
+    /// Ends the current blob file, the next value goes to a new one
+    fn close_writer(&self) -> Result<()> {
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            writer.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        if let Some(writer) = self.writer.lock().unwrap().as_ref() {
+            writer.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Progress of the warm-up started by open, see `EngineConfig::warm_cache_keys`
+#[derive(Default)]
+struct WarmUp {
+    keys: AtomicU64,
+    done: AtomicBool,
+}
+
+/// Thread syncing the write log to disk every `EngineConfig::sync_interval`
+/// Dropping it wakes the thread, which syncs a last time before it exits
+struct PeriodicSync {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicSync {
+    fn spawn(
+        log_writer: Arc<Mutex<LogWriter>>,
+        blobs: Arc<Blobs>,
+        interval: Duration,
+    ) -> PeriodicSync {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || loop {
+            let last = stopped.recv_timeout(interval) != Err(RecvTimeoutError::Timeout);
+            // Best effort, a failing disk fails the writes and explicit syncs too
+            let _ = sync_with_blobs(&mut log_writer.lock().unwrap(), &blobs);
+            if last {
+                break;
+            }
+        });
+        PeriodicSync {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for PeriodicSync {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Write counted against `EngineConfig::max_inflight_writes`, counted out on drop
+struct InflightWrite<'a>(&'a AtomicUsize);
+
+impl Drop for InflightWrite<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Temporary folder inside the storage folder where `repair` builds the clean log
+const REPAIR_FOLDER: &str = ".repair";
+/// Times a record is re-read when compaction moves it while it is being read
+const MOVED_RETRIES: usize = 3;
+/// Redundant bytes below which `EngineConfig::compaction_garbage_ratio` doesn't compact
+const MIN_RATIO_GARBAGE: u64 = 64 * 1024;
+
+/// Progress of an incremental compaction
+struct Compaction {
+    /// Logs created before the compaction started, removed once it is finished
+    old_files: Vec<PathBuf>,
+    /// Records stored in logs older than this one are moved to the compacted log
+    new_log: u64,
+    writer: LogWriter,
+    /// Last key moved to the compacted log
+    cursor: Option<IndexKey>,
+    /// Pointers replaced so far, put back if the compaction is cancelled
+    moved: Vec<Moved>,
+    /// Bytes of the live records moved out of the old logs so far, the rest of them is redundant
+    copied: u64,
+}
+
+/// Pointer of a key replaced by a compaction
+struct Moved {
+    key: IndexKey,
+    from: LogPointer,
+    to: LogPointer,
+    /// `SetExpiry` record merged into the moved one, see `Touched`
+    touched: Option<LogPointer>,
+    /// An older version of the key was moved, not its current record
+    version: bool,
+}
+
+/// What `check_key` found wrong with the record of a key
+enum Problem {
+    /// The pointer goes past the end of its log or to a log that doesn't exist
+    Orphaned,
+    /// The record or the value it references can't be read or decoded
+    Corrupted,
+    /// The record is of another key or isn't a set command
+    Mismatched,
+}
+
+/// Optimized version of Log Structured Key Value Storage
+/// 1) Change HashMap to SkipMap +
+/// 2) Utilize pread +
+/// 3) Optimize Compaction, create only one db file +
+/// 4) Optimize log_pointer update with bit mask and atomics - failed T_T
+/// 5) Implement PBufReader +
+/// 6) Separate thread for compaction
+#[derive(Clone)]
+pub struct OptLogStructKvs<B: IndexBackend = SkipMapBackend> {
+    log_writer: Arc<Mutex<LogWriter>>,
+    key_dir: Arc<KeyDir<B>>,
+    history: Arc<History>,
+    expiry: Arc<Expiry>,
+    touched: Arc<Touched>,
+    dedup: Arc<Dedup>,
+    blobs: Arc<Blobs>,
+    /// `None` unless `EngineConfig::value_index` is set
+    value_index: Option<Arc<ValueIndex>>,
+    folder: Arc<PathBuf>,
+    reader: Arc<LogReader>,
+    /// Logs and blob files replaced by compaction or blob GC, removed once the reads using them are done
+    retirement: Arc<Retirement>,
+    log_counter: Arc<AtomicU64>,
+    /// Highest version any key got so far, removed ones included, a new key starts above it
+    version_mark: Arc<AtomicU64>,
+    log_files: Arc<AtomicU64>,
+    uncompacted_size: Arc<AtomicU64>,
+    live_size: Arc<AtomicU64>,
+    /// Write operations since open, counted for `EngineConfig::compact_every_writes`
+    writes: Arc<AtomicU64>,
+    warm_up: Arc<WarmUp>,
+    /// Writes admitted and not done yet, see `EngineConfig::max_inflight_writes`
+    inflight_writes: Arc<AtomicUsize>,
+    /// Compactions finished since open
+    compactions: Arc<AtomicU64>,
+    comp_lock: Arc<Mutex<()>>,
+    compaction: Arc<Mutex<Option<Compaction>>>,
+    /// Set by `cancel_compaction`, no compaction runs afterwards
+    compaction_cancelled: Arc<AtomicBool>,
+    /// Write logs rolled over with their records only handed to the OS, synced by the next `sync`
+    /// unless compaction retires them first
+    unsynced_logs: Arc<Mutex<Vec<(u64, File)>>>,
+    config: Arc<EngineConfig>,
+    #[cfg(feature = "metrics")]
+    latencies: Arc<Latencies>,
+    /// Thread syncing the write log every `EngineConfig::sync_interval`, stopped once every clone is dropped
+    /// Goes before the folder lock so the last sync is done while the folder is still held
+    _periodic_sync: Option<Arc<PeriodicSync>>,
+    /// Lock of the folder, released once every clone is dropped
+    _lock: Arc<File>,
+}
+
+impl<B: IndexBackend> KvsEngine for OptLogStructKvs<B> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, value))
+    )]
+    fn set(&self, key: String, value: String) -> Result<()> {
+        #[cfg(feature = "metrics")]
+        let _timer = self.latencies.set.timer();
+        {
+            let _inflight = self.admit_write()?;
+            let mut log_writer = self.log_writer.lock().unwrap();
+            self.write_set(&mut log_writer, key, value, None)?;
+        }
+        self.maybe_compact()
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        {
+            let _inflight = self.admit_write()?;
+            let mut log_writer = self.log_writer.lock().unwrap();
+            if self.contains_key(key.clone())? {
+                return Ok(false);
+            }
+            self.write_set(&mut log_writer, key, value, None)?;
+        }
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    fn get(&self, key: String) -> Result<Option<String>> {
+        #[cfg(feature = "metrics")]
+        let _timer = self.latencies.get.timer();
+        let _compaction = match self.config.read_during_compaction {
+            ReadDuringCompaction::Block => Some(self.compaction.lock().unwrap()),
+            ReadDuringCompaction::ServeConsistent => None,
+        };
+        self.read_key(key)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    fn remove(&self, key: String) -> Result<()> {
+        #[cfg(feature = "metrics")]
+        let _timer = self.latencies.remove.timer();
+        {
+            let _inflight = self.admit_write()?;
+            let mut log_writer = self.log_writer.lock().unwrap();
+            if !self.write_rm(&mut log_writer, key)? {
+                return Err(KvsError::KeyNotFound);
+            }
+        }
+        self.maybe_compact()
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        let new = {
+            let _inflight = self.admit_write()?;
+            let mut log_writer = self.log_writer.lock().unwrap();
+            let new = f(self.read_key(key.clone())?);
+            match &new {
+                Some(value) => {
+                    self.write_set(&mut log_writer, key, value.clone(), None)?;
+                }
+                None => {
+                    self.write_rm(&mut log_writer, key)?;
+                }
+            }
+            new
+        };
+        self.maybe_compact()?;
+        Ok(new)
+    }
+
+    /// Rewrites the set record of the key with the new deadline
+    fn expire(&self, key: String, ttl_secs: u64) -> Result<bool> {
+        self.expire_until(key, expiry_deadline(self.now_millis(), ttl_secs))
+    }
+
+    fn expire_at(&self, key: String, unix_secs: u64) -> Result<bool> {
+        self.expire_until(key, unix_secs_deadline(unix_secs))
+    }
+
+    fn expires_at(&self, key: String) -> Result<Option<u64>> {
+        let key = self.index_key(key);
+        if !self.key_dir.contains_key(&key) || self.is_expired(&key) {
+            return Ok(None);
+        }
+        Ok(self.expiry.get(&key).map(|entry| *entry.value()))
+    }
+
+    /// Seeks to the literal prefix of the pattern with the lexicographic order, other orders scan all the keys
+    fn expire_matching(&self, pattern: &str, ttl_secs: u64) -> Result<usize> {
+        let prefix = glob::literal_prefix(pattern);
+        // Collected first, expiring writes and may compact
+        let keys: Vec<String> = self
+            .prefix_entries(&prefix)
+            .map(|entry| entry.key().key.clone())
+            .collect();
+        expire_matching_keys(self, pattern, keys, ttl_secs)
+    }
+
+    /// Keys come in the configured `KeyOrder`, seeking like `expire_matching`
+    fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let prefix = glob::literal_prefix(pattern);
+        Ok(self
+            .prefix_entries(&prefix)
+            .filter(|entry| self.is_live_match(entry.key(), pattern))
+            .map(|entry| entry.key().key.clone())
+            .collect())
+    }
+
+    /// Walks the same range of the index as `keys_matching`, without cloning a key
+    fn count_matching(&self, pattern: &str) -> Result<u64> {
+        let prefix = glob::literal_prefix(pattern);
+        Ok(self
+            .prefix_entries(&prefix)
+            .filter(|entry| self.is_live_match(entry.key(), pattern))
+            .count() as u64)
+    }
+
+    /// Pairs come in the configured `KeyOrder`
+    /// Only the lexicographic order keeps such keys together, other orders scan all the keys
+    fn scan_prefix(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for entry in self.prefix_entries(prefix) {
+            if pairs.len() >= limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            if self.is_expired(entry.key()) {
+                continue;
+            }
+            pairs.push((entry.key().key.clone(), self.read_current(entry.value())?));
+        }
+        Ok(pairs)
+    }
+
+    /// Writes the tombstones in chunks of `REMOVE_PREFIX_CHUNK` keys, so other writes get the lock
+    /// in between, seeks to the prefix like `scan_prefix`
+    fn remove_prefix(&self, prefix: String) -> Result<u64> {
+        // Collected first, removing writes and may compact
+        let keys: Vec<String> = self
+            .prefix_entries(&prefix)
+            .map(|entry| entry.key().key.clone())
+            .filter(|key| !is_sequence_key(key))
+            .collect();
+        let mut removed = 0;
+        for chunk in keys.chunks(REMOVE_PREFIX_CHUNK) {
+            {
+                let _inflight = self.admit_write()?;
+                let mut log_writer = self.log_writer.lock().unwrap();
+                for key in chunk {
+                    if self.write_rm(&mut log_writer, key.clone())? {
+                        removed += 1;
+                    }
+                }
+            }
+            self.maybe_compact()?;
+        }
+        Ok(removed)
+    }
+
+    /// Appends a `SetExpiry` record, the value stays where it is until the next compaction
+    fn touch(&self, key: String, new_ttl: Duration) -> Result<bool> {
+        let _inflight = self.admit_write()?;
+        let mut log_writer = self.log_writer.lock().unwrap();
+        if !self.contains_key(key.clone())? {
+            return Ok(false);
+        }
+        let expires_at = deadline_after(self.now_millis(), new_ttl);
+        let cmd = LogRecord::SetExpiry { key, expires_at };
+        let log_pointer = LogPointer {
+            pos: log_writer.pos,
+            size: log_writer.write_record(&cmd)?,
+            log: log_writer.log,
+            log_state: WRITE_FLAG,
+            version: 0,
+        };
+        self.roll_over(&mut log_writer)?;
+
+        let key = self.index_key(extract_key_from_cmd(cmd)?);
+        self.expiry.insert(key.clone(), expires_at);
+        self.live_size
+            .fetch_add(log_pointer.size, Ordering::Relaxed);
+        self.untouch(&key);
+        self.touched.insert(key, log_pointer);
+        Ok(true)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        let key = self.index_key(key);
+        Ok(self.key_dir.contains_key(&key) && !self.is_expired(&key))
+    }
+
+    /// Counts the keys of the index, without reading a value
+    fn key_count(&self) -> Result<u64> {
+        Ok(self
+            .key_dir
+            .iter()
+            .filter(|entry| !is_sequence_key(&entry.key().key) && !self.is_expired(entry.key()))
+            .count() as u64)
+    }
+
+    /// Writes all the pairs under one acquisition of the write lock, compaction runs once after them
+    fn extend<I>(&self, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        {
+            let _inflight = self.admit_write()?;
+            let mut log_writer = self.log_writer.lock().unwrap();
+            for (key, value) in pairs {
+                self.write_set(&mut log_writer, key, value, None)?;
+            }
+        }
+        self.maybe_compact()
+    }
+
+    fn export<F>(&self, mut visit: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        for entry in self.key_dir.iter() {
+            if self.is_expired(entry.key()) {
+                continue;
+            }
+            visit(entry.key().key.clone(), self.read_current(entry.value())?)?;
+        }
+        Ok(())
+    }
+
+    fn next_id_batch(&self, name: &str, count: u64) -> Result<Range<u64>> {
+        let key = sequence_key(name);
+        let ids = {
+            let _inflight = self.admit_write()?;
+            let mut log_writer = self.log_writer.lock().unwrap();
+            let last = match self.read_key(key.clone())? {
+                Some(value) => parse_sequence(&value)?,
+                None => 0,
+            };
+            let ids = reserve_ids(last, count)?;
+            self.write_set(&mut log_writer, key, (ids.end - 1).to_string(), None)?;
+            ids
+        };
+        self.maybe_compact()?;
+        Ok(ids)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut log_writer = self.log_writer.lock().unwrap();
+        let mut unsynced_logs = self.unsynced_logs.lock().unwrap();
+        for (_, file) in unsynced_logs.iter() {
+            file.sync_all()?;
+        }
+        sync_with_blobs(&mut log_writer, &self.blobs)?;
+        if !unsynced_logs.is_empty() {
+            // The logs rolled over to since the last sync are new entries of their folders
+            self.sync_log_folders()?;
+            unsynced_logs.clear();
+        }
+        Ok(())
+    }
+
+    /// Copies the current value of every key, older versions and dedup are left to the copy's own writes
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        create_checkpoint_folder(dest)?;
+        let copy = Self::open_with_index(dest, (*self.config).clone())?;
+        {
+            // Compaction only moves records, values change under the write lock alone
+            let _log_writer = self.log_writer.lock().unwrap();
+            let mut copy_writer = copy.log_writer.lock().unwrap();
+            for entry in self.key_dir.iter() {
+                if self.is_expired(entry.key()) {
+                    continue;
+                }
+                let value = self.read_current(entry.value())?;
+                let expires_at = self.expiry.get(entry.key()).map(|x| *x.value());
+                copy.write_set(&mut copy_writer, entry.key().key.clone(), value, expires_at)?;
+            }
+        }
+        copy.close()?;
+        // Close syncs the logs the copy wrote, not its other files nor the folders holding them
+        sync_tree(dest)
+    }
+
+    /// Sequentially reads every log file, files removed by a concurrent compaction are skipped
+    fn warmup(&self) -> Result<()> {
+        for filename in get_sorted_log_files(&self.folder)? {
+            match File::open(&filename) {
+                Ok(mut file) => {
+                    io::copy(&mut file, &mut io::sink())?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Only requests the cancellation, the step in progress stops at its next key,
+    /// a paused incremental compaction is rolled back by the next call to compact, see `abandon_compaction`
+    fn cancel_compaction(&self) {
+        self.compaction_cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn name(&self) -> &'static str {
+        "olskv"
+    }
+}
+
+impl OptLogStructKvs {
+    pub fn open(path: &Path) -> Result<OptLogStructKvs> {
+        OptLogStructKvs::open_with_config(path, EngineConfig::default())
+    }
+
+    /// Fails with `AlreadyOpen` while the folder is open by another handle that isn't a clone
+    pub fn open_with_config(path: &Path, config: EngineConfig) -> Result<OptLogStructKvs> {
+        OptLogStructKvs::open_with_index(path, config)
+    }
+}
+
+impl<B: IndexBackend> OptLogStructKvs<B> {
+    /// Opens with the index in the map of `B`, the storage on disk is the same whatever the map
+    pub fn open_with_index(path: &Path, config: EngineConfig) -> Result<Self> {
+        let lock = lock_folder(path)?;
+        let filenames = place_logs(path, config.log_shards)?;
+        let current_folder = PathBuf::from(path);
+
+        let (
+            key_dir,
+            history,
+            expiry,
+            touched,
+            dedup,
+            blob_keys,
+            uncompacted_size,
+            log_counter,
+            version_mark,
+        ) = build_key_dir::<B>(&filenames, &config)?;
+        let version_mark = max(version_mark, read_version_mark(path)?);
+        let key_dir = Arc::new(key_dir);
+        let uncompacted_size = Arc::new(AtomicU64::new(uncompacted_size));
+        let live_size = Arc::new(AtomicU64::new(
+            key_dir
+                .iter()
+                .map(|entry| entry.value().load().size)
+                .chain(
+                    dedup
+                        .values
+                        .iter()
+                        .map(|entry| entry.value().pointer.load().size),
+                )
+                .chain(touched.iter().map(|entry| entry.value().size))
+                .sum(),
+        ));
+        let log = if filenames.is_empty() {
+            log_counter
+        } else {
+            parse_filename(&filenames.last().unwrap().to_path_buf())?.0
+        };
+        let log_writer = Arc::new(Mutex::new(LogWriter::new(
+            &current_folder,
+            &config,
+            log,
+            WRITE_FLAG,
+        )?));
+        let log_counter = Arc::new(AtomicU64::new(log_counter));
+        log_counter.fetch_add(1, Ordering::Relaxed);
+        let log_files = Arc::new(AtomicU64::new(
+            get_sorted_log_files(&current_folder)?.len() as u64
+        ));
+
+        let blob_files = get_blob_files(&current_folder)?;
+        let mut blob_bytes = 0;
+        for (_, filename) in blob_files.iter() {
+            blob_bytes += fs::metadata(filename)?.len();
+        }
+        let live_blob_bytes: u64 = blob_keys.iter().map(|entry| entry.value().size).sum();
+        let blobs = Arc::new(Blobs {
+            folder: current_folder.clone(),
+            writer: Mutex::new(None),
+            next_id: AtomicU64::new(blob_files.last().map_or(0, |(blob_id, _)| blob_id + 1)),
+            readers: SkipMap::new(),
+            keys: blob_keys,
+            // Also counts the values left by a write that failed or a crash
+            garbage: AtomicU64::new(blob_bytes.saturating_sub(live_blob_bytes)),
+            sync_values: config.durability == Durability::Fsync,
+        });
+
+        let periodic_sync = config.sync_interval.map(|interval| {
+            Arc::new(PeriodicSync::spawn(
+                Arc::clone(&log_writer),
+                Arc::clone(&blobs),
+                interval,
+            ))
+        });
+
+        let mut store = OptLogStructKvs::<B> {
+            reader: Arc::new(LogReader::new(
+                current_folder.clone(),
+                config.log_shards,
+                config.read_ahead,
+            )?),
+            log_writer,
+            key_dir,
+            history: Arc::new(history),
+            expiry: Arc::new(expiry),
+            touched: Arc::new(touched),
+            dedup: Arc::new(dedup),
+            blobs,
+            value_index: None,
+            folder: Arc::new(current_folder),
+            retirement: Arc::new(Retirement::default()),
+            log_counter,
+            version_mark: Arc::new(AtomicU64::new(version_mark)),
+            log_files,
+            uncompacted_size,
+            live_size,
+            writes: Arc::new(AtomicU64::new(0)),
+            warm_up: Arc::new(WarmUp::default()),
+            inflight_writes: Arc::new(AtomicUsize::new(0)),
+            compactions: Arc::new(AtomicU64::new(0)),
+            comp_lock: Arc::new(Mutex::new(())),
+            compaction: Arc::new(Mutex::new(None)),
+            compaction_cancelled: Arc::new(AtomicBool::new(false)),
+            unsynced_logs: Arc::new(Mutex::new(Vec::new())),
+            config: Arc::new(config),
+            #[cfg(feature = "metrics")]
+            latencies: Arc::new(Latencies::default()),
+            _periodic_sync: periodic_sync,
+            _lock: Arc::new(lock),
+        };
+        store
+            .reader
+            .follow_writer(&store.log_writer.lock().unwrap());
+        if store.config.value_index {
+            let value_index = ValueIndex::default();
+            for entry in store.key_dir.iter() {
+                value_index.insert(store.read_current(entry.value())?, entry.key().key.clone());
+            }
+            store.value_index = Some(Arc::new(value_index));
+        }
+        if store.config.warm_cache_keys > 0 {
+            store.spawn_warm_up();
+        } else {
+            store.warm_up.done.store(true, Ordering::Release);
+        }
+        Ok(store)
+    }
+
+    /// Reads the records of the `warm_cache_keys` most recently written keys on a thread of its own,
+    /// so the first gets of them find their bytes in the OS page cache
+    /// Records of the write logs are the recent ones, compacted logs only hold older ones
+    fn spawn_warm_up(&self) {
+        let key_dir = Arc::clone(&self.key_dir);
+        let reader = Arc::clone(&self.reader);
+        let retirement = Arc::clone(&self.retirement);
+        let warm_up = Arc::clone(&self.warm_up);
+        let count = self.config.warm_cache_keys;
+        thread::spawn(move || {
+            let mut pointers = key_dir
+                .iter()
+                .map(|entry| entry.value().load())
+                .collect::<Vec<LogPointer>>();
+            drop(key_dir);
+            pointers.sort_unstable_by_key(|x| Reverse((x.log_state == WRITE_FLAG, x.log, x.pos)));
+            pointers.truncate(count);
+            for log_pointer in pointers {
+                // Keeps the log from being removed while it is read, see `retire_logs`
+                let _guard = retirement.enter();
+                // Best effort, a record moved by a compaction since is just not warmed
+                let _ = reader.read_log_direct(&log_pointer);
+                warm_up.keys.fetch_add(1, Ordering::Relaxed);
+            }
+            warm_up.done.store(true, Ordering::Release);
+        });
+    }
+
+    /// Returns key value pairs within the bounds, ordered with the configured `KeyOrder`
+    pub fn range(&self, from: Bound<String>, to: Bound<String>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for entry in self
+            .key_dir
+            .range((self.index_bound(from), self.index_bound(to)))
+        {
+            if self.is_expired(entry.key()) {
+                continue;
+            }
+            pairs.push((entry.key().key.clone(), self.read_current(entry.value())?));
+        }
+        Ok(pairs)
+    }
+
+    /// Entries of the index with keys starting with `prefix`, expired ones included
+    /// Only the lexicographic order keeps such keys together, other orders scan all the keys
+    fn prefix_entries<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = KeyDirEntry<'a, B>> + 'a {
+        let lexicographic = matches!(self.config.key_order, KeyOrder::Lexicographic);
+        let from = if lexicographic {
+            Bound::Included(self.index_key(prefix.to_owned()))
+        } else {
+            Bound::Unbounded
+        };
+        self.key_dir
+            .range((from, Bound::Unbounded))
+            .take_while(move |entry| !lexicographic || entry.key().key.starts_with(prefix))
+            .filter(move |entry| entry.key().key.starts_with(prefix))
+    }
+
+    /// Returns the keys set to `value`, sorted byte-wise whatever the `KeyOrder`, sequence keys left out
+    /// Fails with `InvalidSetting` unless the storage is opened with `EngineConfig::value_index`
+    pub fn keys_by_value(&self, value: String) -> Result<Vec<String>> {
+        let value_index = self.value_index.as_ref().ok_or(KvsError::InvalidSetting)?;
+        Ok(match value_index.keys.get(&value) {
+            Some(entry) => entry
+                .value()
+                .iter()
+                .map(|key| key.value().clone())
+                .filter(|key| {
+                    !is_sequence_key(key) && !self.is_expired(&self.index_key(key.clone()))
+                })
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+
+    /// Returns up to `n` most recent values of `key`, newest first
+    /// Only the current value is returned unless `EngineConfig::versions` is above 1
+    pub fn get_versions(&self, key: &str, n: usize) -> Result<Vec<String>> {
+        let key = self.index_key(key.to_owned());
+        let _guard = self.retirement.enter();
+        let history = self.history.get(&key);
+        let history = history.as_ref().map(|entry| entry.value().lock().unwrap());
+        let mut values = Vec::new();
+        if let Some(entry) = self.key_dir.get(&key) {
+            values.push(self.read_current(entry.value())?);
+        }
+        if let Some(history) = &history {
+            for log_pointer in history.iter() {
+                values.push(self.read_value(log_pointer)?);
+            }
+        }
+        values.truncate(n);
+        Ok(values)
+    }
+
+    /// Returns the value of `key` with its version, the token to pass to `set_if_version`
+    /// The version grows by one with every write of the key, a key created starts above the versions
+    /// every key had so far, so a token of a removed key never matches the key set again
+    pub fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        let key = self.index_key(key);
+        match self.key_dir.get(&key) {
+            Some(entry) if !self.is_expired(&key) => {
+                // The version is read along with the record it belongs to
+                self.read_current_with(entry.value(), |log_pointer| {
+                    Ok(Some((self.read_value(log_pointer)?, log_pointer.version)))
+                })
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Like `get`, also telling where the value was found, to check that reads hit the read-ahead buffer
+    /// The source is the one of the set record, a shared or blob value it references is read after it
+    /// Never waits for a compaction, whatever `EngineConfig::read_during_compaction` says
+    #[cfg(feature = "read-trace")]
+    pub fn get_traced(&self, key: &str) -> Result<(Option<String>, ReadSource)> {
+        let key = self.index_key(key.to_owned());
+        let entry = match self.key_dir.get(&key) {
+            Some(entry) if !self.is_expired(&key) => entry,
+            _ => return Ok((None, ReadSource::Index)),
+        };
+        self.read_current_with(entry.value(), |log_pointer| {
+            let source = if self.reader.is_read_ahead(log_pointer) {
+                ReadSource::ReadAhead
+            } else {
+                ReadSource::Disk
+            };
+            Ok((Some(self.read_value(log_pointer)?), source))
+        })
+    }
+
+    /// Decodes every record of the log numbered `log` with its offset in the file, bypassing the index,
+    /// so a delete can be checked for its `Rm` record
+    /// Reads the write log of that number, or its COMPACTED log once compaction removed the write log
+    /// Padding is skipped, a record not fully written yet ends the scan
+    pub fn scan_raw(&self, log: u64) -> Result<Vec<(u64, LogRecord)>> {
+        // Records held back in the buffer of the write log
+        self.log_writer.lock().unwrap().flush()?;
+        // The COMPACTED log of a number is sorted before its write log
+        let filename = get_sorted_log_files(&self.folder)?
+            .into_iter()
+            .rfind(|x| parse_filename(x).is_ok_and(|(number, _)| number == log))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No log with this number"))?;
+        let mut reader = create_file_reader(&filename)?;
+        let mut records = Vec::new();
+        loop {
+            let pos = reader.stream_position()?;
+            match bincode::deserialize_from(&mut reader) {
+                Ok(LogRecord::Padding { .. }) => continue,
+                Ok(cmd) => records.push((pos, cmd)),
+                Err(_) => break,
+            }
+        }
+        Ok(records)
+    }
+
+    /// Returns the number of the log holding the current record of `key`, for debugging compaction
+    /// Lower numbers are older, compaction moves the records it keeps to a log with a higher number
+    pub fn key_age(&self, key: String) -> Result<Option<u64>> {
+        let key = self.index_key(key);
+        match self.key_dir.get(&key) {
+            Some(entry) if !self.is_expired(&key) => Ok(Some(entry.value().load().log)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Sets the value only if the key is at `expected_version`, `None` meaning the key must not exist
+    /// Returns the new version, or fails with `VersionConflict` without writing anything
+    pub fn set_if_version(
+        &self,
+        key: String,
+        value: String,
+        expected_version: Option<u64>,
+    ) -> Result<u64> {
+        let version = {
+            let _inflight = self.admit_write()?;
+            let mut log_writer = self.log_writer.lock().unwrap();
+            let index_key = self.index_key(key.clone());
+            let current_version = match self.key_dir.get(&index_key) {
+                Some(entry) if !self.is_expired(&index_key) => Some(entry.value().load().version),
+                _ => None,
+            };
+            if current_version != expected_version {
+                return Err(KvsError::VersionConflict);
+            }
+            self.write_set(&mut log_writer, key, value, None)?
+        };
+        self.maybe_compact()?;
+        Ok(version)
+    }
+
+    /// Rewrites the set record of the key with the deadline, `expires_at` milliseconds since the Unix epoch
+    /// Writes a remove record instead if the deadline already passed
+    fn expire_until(&self, key: String, expires_at: u64) -> Result<bool> {
+        {
+            let _inflight = self.admit_write()?;
+            let mut log_writer = self.log_writer.lock().unwrap();
+            let value = match self.read_key(key.clone())? {
+                Some(value) => value,
+                None => return Ok(false),
+            };
+            if expires_at <= self.now_millis() {
+                self.write_rm(&mut log_writer, key)?;
+            } else {
+                self.write_set(&mut log_writer, key, value, Some(expires_at))?;
+            }
+        }
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    /// Streams the value of `key` into the file at `dest`, without holding the value in memory
+    /// Returns whether the key was found, `dest` is created or truncated only if it was
+    pub fn get_to_path(&self, key: String, dest: &Path) -> Result<bool> {
+        let key = self.index_key(key);
+        match self.key_dir.get(&key) {
+            Some(entry) if !self.is_expired(&key) => {
+                self.read_current_with(entry.value(), |log_pointer| {
+                    self.copy_value(log_pointer, dest)
+                })?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Streams the file at `src` in as the value of `key`, without holding the value in memory
+    /// The file must be valid UTF-8 like any other value, it is checked before anything is written
+    /// If the file can't be read to the end after all, the partial record is left behind
+    /// at the end of a write log that is closed, the next writes go to a new one
+    pub fn set_from_path(&self, key: String, src: &Path) -> Result<()> {
+        let mut utf8 = Utf8Check::default();
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut file = File::open(src)?;
+        loop {
+            match file.read(&mut buf)? {
+                0 => break,
+                read => utf8.feed(&buf[..read])?,
+            }
+        }
+        utf8.finish()?;
+
+        let mut file = File::open(src)?;
+        let len = file.metadata()?.len();
+        {
+            let _inflight = self.admit_write()?;
+            let mut log_writer = self.log_writer.lock().unwrap();
+            let key = self.index_key(key);
+            self.check_quota(&key)?;
+            let (version, implied) = self.next_version(&key);
+            let old_value = self.indexed_value(&key)?;
+            let record = if implied {
+                LogRecord::Set {
+                    key: key.key.clone(),
+                    value: String::new(),
+                }
+            } else {
+                LogRecord::SetVersioned {
+                    key: key.key.clone(),
+                    value: String::new(),
+                    version,
+                    expires_at: None,
+                }
+            };
+            let (header, trailer) = record.encode_around_value(len)?;
+            let (pos, log) = (log_writer.pos, log_writer.log);
+            let size = match log_writer.write_stream(&header, &mut file, len, &trailer) {
+                Ok(size) => size,
+                Err(err) => {
+                    // A record appended after the partial one couldn't be read on open
+                    self.switch_write_log(&mut log_writer)?;
+                    return Err(err);
+                }
+            };
+            let log_pointer = LogPointer {
+                pos,
+                size,
+                log,
+                log_state: WRITE_FLAG,
+                version,
+            };
+            self.roll_over(&mut log_writer)?;
+            self.index_set(key.clone(), log_pointer, None, None, None);
+            // The value was streamed, it is read back for the index
+            let new_value = self.indexed_value(&key)?;
+            self.update_value_index(&key, old_value, new_value);
+        }
+        self.maybe_compact()
+    }
+
+    /// Checks that every key of the index points to a readable set command of the same key
+    /// Doesn't modify anything, a pointer moved by a concurrent compaction is re-read
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for entry in self.key_dir.iter() {
+            let key = &entry.key().key;
+            let problem = self.check_key(key, entry.value())?;
+            if entry.is_removed() {
+                continue;
+            }
+
+            report.checked += 1;
+            match problem {
+                None => {}
+                Some(Problem::Orphaned) | Some(Problem::Corrupted) => {
+                    report.corrupted.push(key.clone())
+                }
+                Some(Problem::Mismatched) => report.mismatched.push(key.clone()),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Cross-checks the index against the log files, the tool to run after a suspected corruption
+    /// Unlike `verify` it also tells pointers past the end of their log apart from unreadable records
+    /// and replays the logs to find keys that should be indexed but aren't
+    /// Meant for a storage without concurrent writers, a write during the replay can be reported as unindexed
+    pub fn fsck(&self) -> Result<FsckReport> {
+        // The replay reads the files, records held back must be in them
+        self.log_writer.lock().unwrap().flush()?;
+        let mut report = FsckReport::default();
+        for entry in self.key_dir.iter() {
+            let key = &entry.key().key;
+            let problem = self.check_key(key, entry.value())?;
+            if entry.is_removed() {
+                continue;
+            }
+
+            report.checked += 1;
+            match problem {
+                None => {}
+                Some(Problem::Orphaned) => report.orphaned.push(key.clone()),
+                Some(Problem::Corrupted) => report.corrupted.push(key.clone()),
+                Some(Problem::Mismatched) => report.mismatched.push(key.clone()),
+            }
+        }
+
+        // Replays the logs the same way `open` does, keeping only whether each key is set
+        let mut live = HashMap::<String, bool>::new();
+        for filename in get_sorted_log_files(&self.folder)? {
+            if self.reader.is_retired(&filename) {
+                continue;
+            }
+            let read_size = walk_log(&filename, |cmd, _, _| {
+                match cmd {
+                    LogRecord::Set { key, .. }
+                    | LogRecord::SetExpiring { key, .. }
+                    | LogRecord::SetVersioned { key, .. }
+                    | LogRecord::SetShared { key, .. }
+                    | LogRecord::SetBlob { key, .. } => live.insert(key, true),
+                    LogRecord::Rm { key } => live.insert(key, false),
+                    LogRecord::Shared { .. } | LogRecord::SetExpiry { .. } => None,
+                    _ => return Err(KvsError::UnexpectedCommandType),
+                };
+                Ok(())
+            })?;
+            if read_size < fs::metadata(&filename)?.len() {
+                report.unreadable_tails.push((filename, read_size));
+            }
+        }
+        for (key, is_set) in live {
+            if is_set && !self.key_dir.contains_key(&self.index_key(key.clone())) {
+                report.unindexed.push(key);
+            }
+        }
+        report.unindexed.sort();
+        Ok(report)
+    }
+
+    /// Reads the current record of the key, and the value it references, through `pointer`
+    /// A pointer moved by a concurrent compaction is re-read, the caller checks that the entry is still there
+    fn check_key(&self, key: &str, pointer: &AtomicCell<LogPointer>) -> Result<Option<Problem>> {
+        let _guard = self.retirement.enter();
+        let mut log_pointer = pointer.load();
+        let mut retries = 0;
+        let record = loop {
+            let record = self.reader.deserialize_direct(&log_pointer);
+            let current = pointer.load();
+            if record.is_ok() || current == log_pointer || retries == MOVED_RETRIES {
+                break record;
+            }
+            log_pointer = current;
+            retries += 1;
+        };
+        Ok(match record {
+            Ok(LogRecord::Set {
+                key: record_key, ..
+            })
+            | Ok(LogRecord::SetExpiring {
+                key: record_key, ..
+            })
+            | Ok(LogRecord::SetVersioned {
+                key: record_key, ..
+            }) if record_key == key => None,
+            Ok(LogRecord::SetShared {
+                key: record_key,
+                hash,
+                ..
+            }) if record_key == key => self.read_shared(hash).err().map(|_| Problem::Corrupted),
+            Ok(LogRecord::SetBlob {
+                key: record_key,
+                blob,
+                ..
+            }) if record_key == key => self.blobs.read(&blob).err().map(|_| Problem::Corrupted),
+            Ok(_) => Some(Problem::Mismatched),
+            Err(_) if !self.pointer_in_log(&log_pointer)? => Some(Problem::Orphaned),
+            Err(_) => Some(Problem::Corrupted),
+        })
+    }
+
+    /// Whether the log of the pointer exists and is long enough to hold the pointed record
+    fn pointer_in_log(&self, log_pointer: &LogPointer) -> Result<bool> {
+        let path = generate_full_log_path(
+            &self.folder,
+            self.config.log_shards,
+            &log_pointer.log,
+            &log_pointer.log_state,
+        )?;
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(log_pointer.pos + log_pointer.size <= metadata.len()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl OptLogStructKvs {
+    /// Rebuilds the storage folder from the records that can still be read, the storage must be closed
+    /// Logs are replayed in order, unexpected commands and the unreadable tail of a log are dropped
+    /// The clean log is built in a temporary folder and moved in with a number above all the old logs,
+    /// old logs are removed only after that, so a failed repair leaves the folder as it was
+    pub fn repair(path: &Path) -> Result<RepairReport> {
+        let _lock = lock_folder(path)?;
+        let filenames = get_sorted_log_files(path)?;
+        let mut report = RepairReport::default();
+        let mut records = HashMap::<String, (usize, u64, u64)>::new();
+        let mut shared_values = HashMap::<u64, (usize, u64, u64)>::new();
+        let mut shared_keys = HashMap::<String, u64>::new();
+        let mut touches = HashMap::<String, (usize, u64, u64)>::new();
+        // Versions of the keys, a plain record only implies it, see `next_version`
+        let mut versions = HashMap::<String, u64>::new();
+        let mut version_mark = read_version_mark(path)?;
+        let mut new_log = 0u64;
+
+        for (file_index, filename) in filenames.iter().enumerate() {
+            new_log = max(new_log, parse_filename(filename)?.0 + 1);
+            let read_size = walk_log(filename, |cmd, pos, size| {
+                let mut set_version = |key: &str, version: Option<u64>| {
+                    let version = version.unwrap_or_else(|| versions.get(key).map_or(1, |x| x + 1));
+                    version_mark = max(version_mark, version);
+                    versions.insert(key.to_owned(), version);
+                };
+                match cmd {
+                    LogRecord::Set { key, .. } | LogRecord::SetExpiring { key, .. } => {
+                        set_version(&key, None);
+                        shared_keys.remove(&key);
+                        touches.remove(&key);
+                        records.insert(key, (file_index, pos, size));
+                    }
+                    LogRecord::SetVersioned { key, version, .. }
+                    | LogRecord::SetBlob { key, version, .. } => {
+                        set_version(&key, Some(version));
+                        shared_keys.remove(&key);
+                        touches.remove(&key);
+                        records.insert(key, (file_index, pos, size));
+                    }
+                    LogRecord::SetShared {
+                        key, hash, version, ..
+                    } => {
+                        set_version(&key, Some(version));
+                        shared_keys.insert(key.clone(), hash);
+                        touches.remove(&key);
+                        records.insert(key, (file_index, pos, size));
+                    }
+                    LogRecord::SetExpiry { key, .. } if records.contains_key(&key) => {
+                        touches.insert(key, (file_index, pos, size));
+                    }
+                    LogRecord::Shared { hash, .. } => {
+                        shared_values.insert(hash, (file_index, pos, size));
+                    }
+                    LogRecord::Rm { key } => {
+                        shared_keys.remove(&key);
+                        touches.remove(&key);
+                        versions.remove(&key);
+                        records.remove(&key);
+                    }
+                    _ => report.skipped += 1,
+                };
+                Ok(())
+            })?;
+            let file_size = fs::metadata(filename)?.len();
+            if read_size < file_size {
+                report
+                    .truncated
+                    .push((filename.clone(), read_size, file_size - read_size));
+            }
+        }
+
+        let repair_folder = path.join(REPAIR_FOLDER);
+        if repair_folder.exists() {
+            fs::remove_dir_all(&repair_folder)?;
+        }
+        fs::create_dir(&repair_folder)?;
+        let files = filenames
+            .iter()
+            .map(File::open)
+            .collect::<io::Result<Vec<File>>>()?;
+        // A key whose shared value was lost can't be read anymore
+        for (key, hash) in shared_keys.iter() {
+            if !shared_values.contains_key(hash) {
+                records.remove(key);
+                touches.remove(key);
+                report.skipped += 1;
+            }
+        }
+        let referenced = shared_keys.values().collect::<HashSet<&u64>>();
+        // Logs are put back in their shards by the next open
+        let mut writer =
+            LogWriter::new(&repair_folder, &EngineConfig::default(), new_log, COMP_FLAG)?;
+        for (key, (file_index, pos, size)) in shared_values
+            .iter()
+            .filter(|(hash, _)| referenced.contains(hash))
+            .map(|(_, record)| (None, record))
+            .chain(records.iter().map(|(key, record)| (Some(key), record)))
+            .chain(touches.values().map(|record| (None, record)))
+        {
+            let mut buf = vec![0u8; *size as usize];
+            files[*file_index].read_exact_at(&mut buf, *pos)?;
+            // Alone in the new log, a plain record would imply the first version
+            let version = key.and_then(|key| versions.get(key)).copied();
+            match version.filter(|&version| version != 1) {
+                Some(version) => match bincode::deserialize(&buf)? {
+                    LogRecord::Set { key, value } => {
+                        writer.write_record(&LogRecord::SetVersioned {
+                            key,
+                            value,
+                            version,
+                            expires_at: None,
+                        })?
+                    }
+                    LogRecord::SetExpiring {
+                        key,
+                        value,
+                        expires_at,
+                    } => writer.write_record(&LogRecord::SetVersioned {
+                        key,
+                        value,
+                        version,
+                        expires_at: Some(expires_at),
+                    })?,
+                    _ => writer.write_buf(&buf)?,
+                },
+                None => writer.write_buf(&buf)?,
+            };
+        }
+        writer.sync()?;
+        write_version_mark(path, version_mark)?;
+        report.keys = records.len() as u64;
+
+        fs::rename(
+            generate_full_log_path(&repair_folder, 1, &new_log, &COMP_FLAG)?,
+            generate_full_log_path(path, 1, &new_log, &COMP_FLAG)?,
+        )?;
+        for filename in filenames.iter() {
+            fs::remove_file(filename)?;
+        }
+        fs::remove_dir(&repair_folder)?;
+        Ok(report)
+    }
+}
+
+impl<B: IndexBackend> OptLogStructKvs<B> {
+    /// Latency percentiles of get, set and remove since the storage was opened
+    #[cfg(feature = "metrics")]
+    pub fn latency_percentiles(&self) -> LatencyReport {
+        self.latencies.report()
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        let mut physical_bytes = 0;
+        for filename in get_sorted_log_files(&self.folder)? {
+            match fs::metadata(&filename) {
+                Ok(metadata) => physical_bytes += metadata.len(),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Stats {
+            keys: self.key_dir.len() as u64,
+            max_keys: self.config.max_keys,
+            log_files: self.log_files.load(Ordering::Relaxed),
+            uncompacted_size: self.uncompacted_size.load(Ordering::Relaxed),
+            logical_bytes: self.live_size.load(Ordering::Relaxed),
+            physical_bytes,
+            retired_files: self.retirement.pending(),
+            failed_removals: self.retirement.failed_removals(),
+            warmed_keys: self.warm_up.keys.load(Ordering::Relaxed),
+            warm_up_done: self.warm_up.done.load(Ordering::Acquire),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            inflight_writes: self.inflight_writes.load(Ordering::Relaxed) as u64,
+        })
+    }
+
+    /// Appends a set command and points the key to it, the caller holds the write lock
+    /// The key expires at `expires_at` if given, otherwise an expiry it had is cleared
+    /// Returns the version of the written value
+    fn write_set(
+        &self,
+        log_writer: &mut LogWriter,
+        key: String,
+        value: String,
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        let key = self.index_key(key);
+        self.check_quota(&key)?;
+        let (version, implied) = self.next_version(&key);
+        let old_value = self.indexed_value(&key)?;
+        let new_value = self.value_index.as_ref().map(|_| value.clone());
+        let blob = if self.separates(&value) {
+            Some(self.blobs.write(&value)?)
+        } else {
+            None
+        };
+        let shared = if blob.is_none() && self.dedups(&value) {
+            self.share_value(log_writer, &value)?
+        } else {
+            None
+        };
+        // A version the replay of the logs implies goes with a plain record
+        let cmd = match (blob, shared, implied, expires_at) {
+            (Some(blob), _, _, expires_at) => LogRecord::SetBlob {
+                key: key.key,
+                blob,
+                version,
+                expires_at,
+            },
+            (None, Some(hash), _, expires_at) => LogRecord::SetShared {
+                key: key.key,
+                hash,
+                version,
+                expires_at,
+            },
+            (None, None, true, None) => LogRecord::Set {
+                key: key.key,
+                value,
+            },
+            (None, None, true, Some(expires_at)) => LogRecord::SetExpiring {
+                key: key.key,
+                value,
+                expires_at,
+            },
+            (None, None, false, expires_at) => LogRecord::SetVersioned {
+                key: key.key,
+                value,
+                version,
+                expires_at,
+            },
+        };
+        let pos = log_writer.pos;
+        let size = match log_writer.write_record(&cmd) {
+            Ok(size) => size,
+            Err(err) => {
+                // The key didn't take the reference `share_value` counted for it
+                if let Some(hash) = shared {
+                    self.unshare_value(hash);
+                }
+                return Err(err);
+            }
+        };
+        let log_pointer = LogPointer {
+            pos,
+            size,
+            log: log_writer.log,
+            log_state: WRITE_FLAG,
+            version,
+        };
+        self.roll_over(log_writer)?;
+        let key = self.index_key(extract_key_from_cmd(cmd)?);
+        self.update_value_index(&key, old_value, new_value);
+        self.index_set(key, log_pointer, expires_at, shared, blob);
+        Ok(version)
+    }
+
+    fn dedups(&self, value: &str) -> bool {
+        self.config.dedup && self.config.versions == 1 && value.len() >= DEDUP_MIN_VALUE_SIZE
+    }
+
+    /// Whether the value goes to a blob file, see `EngineConfig::blob_threshold`
+    fn separates(&self, value: &str) -> bool {
+        match self.config.blob_threshold {
+            Some(threshold) => self.config.versions == 1 && value.len() > threshold,
+            None => false,
+        }
+    }
+
+    /// Takes a reference to the shared value equal to `value`, writing it first if there is none
+    /// Returns its hash, or `None` if another value with the same hash is shared already,
+    /// then `value` is stored in the set record as usual. The caller holds the write lock
+    fn share_value(&self, log_writer: &mut LogWriter, value: &str) -> Result<Option<u64>> {
+        let hash = value_hash(value);
+        if let Some(shared) = self.dedup.values.get(&hash) {
+            if self.read_current(&shared.value().pointer)? != value {
+                return Ok(None);
+            }
+            shared.value().refs.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(hash));
+        }
+        // Written around the value, without copying it into a record
+        let record = LogRecord::Shared {
+            hash,
+            value: String::new(),
+        };
+        let (header, trailer) = record.encode_around_value(value.len() as u64)?;
+        let log_pointer = LogPointer {
+            pos: log_writer.pos,
+            size: log_writer.write_value(&header, value.as_bytes(), &trailer)?,
+            log: log_writer.log,
+            log_state: WRITE_FLAG,
+            version: 0,
+        };
+        self.roll_over(log_writer)?;
+        self.live_size
+            .fetch_add(log_pointer.size, Ordering::Relaxed);
+        self.dedup.values.insert(
+            hash,
+            SharedValue {
+                pointer: AtomicCell::new(log_pointer),
+                refs: AtomicU64::new(1),
+            },
+        );
+        Ok(Some(hash))
+    }
+
+    /// Drops a reference to the shared value, the last one makes the value redundant
+    fn unshare_value(&self, hash: u64) {
+        if let Some(shared) = self.dedup.values.get(&hash) {
+            if shared.value().refs.fetch_sub(1, Ordering::Relaxed) == 1 {
+                let size = shared.value().pointer.load().size;
+                shared.remove();
+                self.live_size.fetch_sub(size, Ordering::Relaxed);
+                self.update_uncompacted_size(size);
+            }
+        }
+    }
+
+    fn read_shared(&self, hash: u64) -> Result<String> {
+        match self.dedup.values.get(&hash) {
+            Some(shared) => self.read_current(&shared.value().pointer),
+            None => Err(KvsError::BadLogFile),
+        }
+    }
+
+    /// Version the next write of the key gets, and whether the replay of the logs implies it
+    /// A new key starts above the versions every key had, so a version is never given twice to a key
+    /// even once it was removed. The caller holds the write lock
+    fn next_version(&self, key: &IndexKey) -> (u64, bool) {
+        let (version, implied) = match self.key_dir.get(key) {
+            Some(entry) => (entry.value().load().version + 1, true),
+            None => {
+                let version = self.version_mark.load(Ordering::Relaxed) + 1;
+                (version, version == 1)
+            }
+        };
+        self.version_mark.fetch_max(version, Ordering::Relaxed);
+        (version, implied)
+    }
+
+    /// Fails with `QuotaExceeded` if setting a new `key` would go beyond `max_keys`,
+    /// the caller holds the write lock
+    /// Expired keys don't count, they are reclaimed once the index is full before checking again
+    fn check_quota(&self, key: &IndexKey) -> Result<()> {
+        if let Some(max_keys) = self.config.max_keys {
+            // Keys are only added under the write lock, so the count can't overshoot
+            if self.key_dir.len() as u64 >= max_keys && !self.key_dir.contains_key(key) {
+                self.reclaim_expired()?;
+                if self.key_dir.len() as u64 >= max_keys {
+                    return Err(KvsError::QuotaExceeded);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the expired keys from the index without writing a remove record, the caller holds the write lock
+    /// Their records hold the deadline, so open leaves them out as well and compaction drops them
+    fn reclaim_expired(&self) -> Result<()> {
+        let now = self.now_millis();
+        let expired: Vec<IndexKey> = self
+            .expiry
+            .iter()
+            .filter(|entry| *entry.value() <= now)
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in expired {
+            let old_value = self.indexed_value(&key)?;
+            self.unindex(&key, old_value);
+            if let Some(on_evict) = &self.config.on_evict {
+                on_evict.call(&key.key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Current value of the key, read only when there is a value index to update
+    fn indexed_value(&self, key: &IndexKey) -> Result<Option<String>> {
+        match (&self.value_index, self.key_dir.get(key)) {
+            (Some(_), Some(entry)) => Ok(Some(self.read_current(entry.value())?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Moves the key from the keys of its old value to those of the new one, the caller holds the write lock
+    fn update_value_index(&self, key: &IndexKey, old: Option<String>, new: Option<String>) {
+        if let Some(value_index) = &self.value_index {
+            if let Some(old) = old {
+                value_index.remove(&old, &key.key);
+            }
+            if let Some(new) = new {
+                value_index.insert(new, key.key.clone());
+            }
+        }
+    }
+
+    /// Points the key to its just written set record, the caller holds the write lock
+    /// `shared` is the hash of the shared value the record references, if any,
+    /// `blob` the value in a blob file it references, if any
+    fn index_set(
+        &self,
+        key: IndexKey,
+        log_pointer: LogPointer,
+        expires_at: Option<u64>,
+        shared: Option<u64>,
+        blob: Option<BlobRef>,
+    ) {
+        let old_shared = self.dedup.keys.get(&key).map(|entry| *entry.value());
+        match shared {
+            Some(hash) => {
+                self.dedup.keys.insert(key.clone(), hash);
+            }
+            None => {
+                self.dedup.keys.remove(&key);
+            }
+        }
+        if let Some(hash) = old_shared {
+            self.unshare_value(hash);
+        }
+        self.blobs.release(&key);
+        if let Some(blob) = blob {
+            self.blobs.keys.insert(key.clone(), blob);
+        }
+        self.untouch(&key);
+        match expires_at {
+            Some(expires_at) => {
+                self.expiry.insert(key.clone(), expires_at);
+            }
+            None => {
+                self.expiry.remove(&key);
+            }
+        }
+        self.live_size
+            .fetch_add(log_pointer.size, Ordering::Relaxed);
+        let history = self.history_entry(&key);
+        let mut history = history.as_ref().map(|entry| entry.value().lock().unwrap());
+        if let Some(old_entry) = self.key_dir.get(&key) {
+            let old_log_pointer = old_entry.value().swap(log_pointer);
+            self.live_size
+                .fetch_sub(old_log_pointer.size, Ordering::Relaxed);
+            match &mut history {
+                Some(history) => self.push_version(history, old_log_pointer),
+                None => self.update_uncompacted_size(old_log_pointer.size),
+            }
+        } else {
+            self.key_dir.insert(key, AtomicCell::new(log_pointer));
+        }
+    }
+
+    /// Appends a remove command if the key exists, the caller holds the write lock
+    /// Returns whether the key existed, an expired key is removed but doesn't count as existing
+    fn write_rm(&self, log_writer: &mut LogWriter, key: String) -> Result<bool> {
+        let key = self.index_key(key);
+        if !self.key_dir.contains_key(&key) {
+            return Ok(false);
+        }
+        let expired = self.is_expired(&key);
+        let old_value = self.indexed_value(&key)?;
+        let cmd = LogRecord::Rm { key: key.key };
+        let size = log_writer.write_record(&cmd)?;
+        self.roll_over(log_writer)?;
+
+        let key = self.index_key(extract_key_from_cmd(cmd)?);
+        // Remove command not needed
+        self.update_uncompacted_size(size);
+        self.unindex(&key, old_value);
+        Ok(!expired)
+    }
+
+    /// Drops the key and its versions from the index, their records become redundant
+    /// `old_value` is the current value when there is a value index, the caller holds the write lock
+    fn unindex(&self, key: &IndexKey, old_value: Option<String>) {
+        self.update_value_index(key, old_value, None);
+        if let Some(old_entry) = self.key_dir.remove(key) {
+            let old_size = old_entry.value().load().size;
+            self.live_size.fetch_sub(old_size, Ordering::Relaxed);
+            self.update_uncompacted_size(old_size);
+        }
+        if let Some(history) = self.history.remove(key) {
+            let history = history.value().lock().unwrap();
+            self.update_uncompacted_size(history.iter().map(|x| x.size).sum());
+        }
+        self.expiry.remove(key);
+        self.untouch(key);
+        if let Some(entry) = self.dedup.keys.remove(key) {
+            self.unshare_value(*entry.value());
+        }
+        self.blobs.release(key);
+    }
+
+    /// Drops an expired key whose records are all in logs older than `new_log` instead of moving them,
+    /// no remove record is needed once those logs are gone
+    /// Takes the write lock, returns false if the key was written again meanwhile
+    fn drop_expired(&self, key: &IndexKey, new_log: u64) -> Result<bool> {
+        let _log_writer = self.log_writer.lock().unwrap();
+        let log_pointer = match self.key_dir.get(key) {
+            Some(entry) => entry.value().load(),
+            None => return Ok(false),
+        };
+        let touched_later = self
+            .touched
+            .get(key)
+            .is_some_and(|entry| entry.value().log >= new_log);
+        if log_pointer.log >= new_log || touched_later || !self.is_expired(key) {
+            return Ok(false);
+        }
+        let old_value = self.indexed_value(key)?;
+        self.unindex(key, old_value);
+        Ok(true)
+    }
+
+    /// Makes the `SetExpiry` record of the key redundant, if it has one
+    fn untouch(&self, key: &IndexKey) {
+        if let Some(entry) = self.touched.remove(key) {
+            let size = entry.value().size;
+            self.live_size.fetch_sub(size, Ordering::Relaxed);
+            self.update_uncompacted_size(size);
+        }
+    }
+
+    /// Whether a key of the index is live and matches the glob `pattern`, sequence keys never do
+    fn is_live_match(&self, key: &IndexKey, pattern: &str) -> bool {
+        !is_sequence_key(&key.key) && !self.is_expired(key) && glob::matches(pattern, &key.key)
+    }
+
+    fn is_expired(&self, key: &IndexKey) -> bool {
+        match self.expiry.get(key) {
+            Some(entry) => *entry.value() <= self.now_millis(),
+            None => false,
+        }
+    }
+
+    /// Current time of the configured clock, in milliseconds since the Unix epoch
+    fn now_millis(&self) -> u64 {
+        unix_millis(self.config.clock.now())
+    }
+
+    /// Versions of the key, `None` unless older versions are kept
+    fn history_entry(
+        &self,
+        key: &IndexKey,
+    ) -> Option<Entry<'_, IndexKey, Mutex<VecDeque<LogPointer>>>> {
+        if self.config.versions > 1 {
+            Some(
+                self.history
+                    .get_or_insert(key.clone(), Mutex::new(VecDeque::new())),
+            )
+        } else {
+            None
+        }
+    }
+
+    /// Keeps the replaced version, the oldest one beyond the configured number becomes redundant
+    fn push_version(
+        &self,
+        history: &mut MutexGuard<VecDeque<LogPointer>>,
+        log_pointer: LogPointer,
+    ) {
+        history.push_front(log_pointer);
+        while history.len() >= self.config.versions {
+            let dropped = history.pop_back().unwrap();
+            self.update_uncompacted_size(dropped.size);
+        }
+    }
+
+    fn index_key(&self, key: String) -> IndexKey {
+        IndexKey {
+            key,
+            order: self.config.key_order,
+        }
+    }
+
+    fn index_bound(&self, bound: Bound<String>) -> Bound<IndexKey> {
+        match bound {
+            Bound::Included(key) => Bound::Included(self.index_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.index_key(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        }
+    }
+
+    /// Current value of the key, like `get` without waiting for a compaction
+    /// Writes read through it, compaction takes the write lock while it holds its own
+    fn read_key(&self, key: String) -> Result<Option<String>> {
+        let key = self.index_key(key);
+        match self.key_dir.get(&key) {
+            Some(entry) if !self.is_expired(&key) => Ok(Some(self.read_current(entry.value())?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads the value a pointer of the index points to
+    /// Compaction can move the record and remove its log between loading the pointer and reading,
+    /// then the read fails and is retried with the new pointer, a failure of an unmoved record is returned
+    fn read_current(&self, pointer: &AtomicCell<LogPointer>) -> Result<String> {
+        self.read_current_with(pointer, |log_pointer| self.read_value(log_pointer))
+    }
+
+    /// Like `read_current`, with `read` doing the reading of the record
+    fn read_current_with<T, F>(&self, pointer: &AtomicCell<LogPointer>, mut read: F) -> Result<T>
+    where
+        F: FnMut(&LogPointer) -> Result<T>,
+    {
+        // Keeps the log of the loaded pointer from being removed, see `retire_logs`
+        let _guard = self.retirement.enter();
+        let mut log_pointer = pointer.load();
+        let mut retries = 0;
+        loop {
+            match read(&log_pointer) {
+                Ok(value) => return Ok(value),
+                Err(err) => {
+                    let current = pointer.load();
+                    if current == log_pointer || retries == MOVED_RETRIES {
+                        return Err(err);
+                    }
+                    log_pointer = current;
+                    retries += 1;
+                }
+            }
+        }
+    }
+
+    /// Copies the value of a set record to `dest` without decoding the record as a whole
+    fn copy_value(&self, log_pointer: &LogPointer, dest: &Path) -> Result<()> {
+        let file = self.reader.file(log_pointer)?;
+        let mut record = PosReader {
+            file: file.value(),
+            pos: log_pointer.pos,
+        }
+        .take(log_pointer.size);
+        let mut writer = None;
+        let (cmd, _) = LogRecord::read_without_value(&mut record, |record, len| {
+            let dest = writer.insert(BufWriter::new(File::create(dest)?));
+            if io::copy(&mut record.take(len), dest)? < len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            dest.flush()
+        })?;
+        match cmd {
+            LogRecord::Set { .. }
+            | LogRecord::SetExpiring { .. }
+            | LogRecord::SetVersioned { .. }
+            | LogRecord::Shared { .. } => Ok(()),
+            LogRecord::SetShared { hash, .. } => match self.dedup.values.get(&hash) {
+                Some(shared) => self.read_current_with(&shared.value().pointer, |log_pointer| {
+                    self.copy_value(log_pointer, dest)
+                }),
+                None => Err(KvsError::BadLogFile),
+            },
+            LogRecord::SetBlob { blob, .. } => self.blobs.copy(&blob, dest),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    fn read_value(&self, log_pointer: &LogPointer) -> Result<String> {
+        match self.reader.deserialize(log_pointer)? {
+            LogRecord::Set { value, .. }
+            | LogRecord::SetExpiring { value, .. }
+            | LogRecord::SetVersioned { value, .. }
+            | LogRecord::Shared { value, .. } => Ok(value),
+            LogRecord::SetShared { hash, .. } => self.read_shared(hash),
+            LogRecord::SetBlob { blob, .. } => self.blobs.read(&blob),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Counts a write in until the returned slot is dropped, the caller then takes the write lock
+    /// Fails with `Busy` when `EngineConfig::max_inflight_writes` writes are already in
+    fn admit_write(&self) -> Result<InflightWrite<'_>> {
+        let inflight = self.inflight_writes.fetch_add(1, Ordering::AcqRel);
+        let slot = InflightWrite(&self.inflight_writes);
+        match self.config.max_inflight_writes {
+            Some(max) if inflight >= max => Err(KvsError::Busy),
+            _ => Ok(slot),
+        }
+    }
 
-        let reader = entry.value();
-        let mut buf = vec![0u8; log_pointer.size as usize];
-        reader.read_exact_at(&mut buf, log_pointer.pos)?;
-        Ok(buf)
+    /// Monitoring the number of bytes of redundant command logs
+    fn update_uncompacted_size(&self, redundant_size: u64) {
+        self.uncompacted_size
+            .fetch_add(redundant_size, Ordering::Release);
     }
 
-    fn deserialize(&self, log_pointer: &LogPointer) -> Result<Command> {
-        Ok(bincode::deserialize(&self.read_log(log_pointer)?)?)
+    /// Counts a live record of `from` bytes as `to` bytes once it is moved
+    fn resize_live(&self, from: u64, to: u64) {
+        if to > from {
+            self.live_size.fetch_add(to - from, Ordering::Relaxed);
+        } else {
+            self.live_size.fetch_sub(from - to, Ordering::Relaxed);
+        }
     }
 
-    fn read_log_clean_after(&self, log_pointer: &LogPointer) -> Result<Vec<u8>> {
-        let buf = self.read_log(log_pointer)?;
-        self.to_clean
-            .insert((log_pointer.log, log_pointer.log_state));
-        Ok(buf)
+    /// Counts out the redundant records of `removed` bytes of logs compaction removed,
+    /// `copied` of those bytes were live records moved to a COMPACTED log
+    fn reclaim_uncompacted(&self, removed: u64, copied: u64) {
+        let reclaimed = removed.saturating_sub(copied);
+        let _ = self
+            .uncompacted_size
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |size| {
+                Some(size.saturating_sub(reclaimed))
+            });
     }
 
-    fn clean_up(&self) -> Result<()> {
-        for log in self.to_clean.iter() {
-            self.readers.remove(log.value());
+    /// If redundant bytes or number of log files hit threshold, merging launches
+    /// Called once after each write operation
+    fn maybe_compact(&self) -> Result<()> {
+        if let Some(every) = self.config.compact_every_writes {
+            let every = every.max(1);
+            if self.writes.fetch_add(1, Ordering::Relaxed) % every == every - 1 {
+                return self.compact();
+            }
+            return Ok(());
+        }
+        if !self.compaction_needed() {
+            return Ok(());
+        }
+        // The guard is held until compact_logs returns, other writers crossing the threshold skip it
+        let _comp_guard = match self.comp_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(()),
+        };
+        // A compaction may have finished between the check and the lock
+        if self.compaction_needed() {
+            self.compact_logs(self.config.compaction_step_bytes.unwrap_or(u64::MAX))?;
         }
-        self.to_clean.clear();
         Ok(())
     }
-}
-
-/// Optimized version of Log Structured Key Value Storage
-/// 1) Change HashMap to SkipMap +
-/// 2) Utilize pread +
-/// 3) Optimize Compaction, create only one db file +
-/// 4) Optimize log_pointer update with bit mask and atomics - failed T_T
-/// 5) Implement PBufReader @TODO
-/// 6) Separate thread for compaction
-#[derive(Clone)]
-pub struct OptLogStructKvs {
-    log_writer: Arc<Mutex<LogWriter>>,
-    key_dir: Arc<SkipMap<String, AtomicCell<LogPointer>>>,
-    folder: Arc<PathBuf>,
-    reader: Arc<LogReader>,
-    log_counter: Arc<AtomicU64>,
-    uncompacted_size: Arc<AtomicU64>,
-    comp_lock: Arc<Mutex<()>>,
-}
 
-impl KvsEngine for OptLogStructKvs {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set { key, value };
-        let log_pointer = {
-            let mut log_writer = self.log_writer.lock().unwrap();
-            LogPointer {
-                pos: log_writer.pos,
-                size: log_writer.write_cmd(&cmd)?,
-                log: log_writer.log,
-                log_state: WRITE_FLAG,
+    fn compaction_needed(&self) -> bool {
+        let uncompacted_size = self.uncompacted_size.load(Ordering::Acquire);
+        let too_much_garbage = match self.config.compaction_garbage_ratio {
+            Some(ratio) => {
+                let total_size = uncompacted_size + self.live_size.load(Ordering::Relaxed);
+                uncompacted_size >= MIN_RATIO_GARBAGE
+                    && uncompacted_size as f64 >= ratio * total_size as f64
             }
+            None => uncompacted_size >= self.config.compact_threshold,
         };
+        too_much_garbage || self.log_files.load(Ordering::Relaxed) > self.config.max_log_files
+    }
 
-        let key = extract_key_from_cmd(cmd);
-        let old_entry = self.key_dir.get(&key);
-        if let Some(old_entry) = old_entry {
-            old_entry.value().store(log_pointer);
-            self.update_uncompacted_size(old_entry.value().load().size)?;
-        } else {
-            self.key_dir.insert(key, AtomicCell::new(log_pointer));
-        }
-        Ok(())
+    /// Runs compaction to the end, finishing the one in progress if any
+    pub fn compact(&self) -> Result<()> {
+        // The lock guards no data, a compaction that panicked leaves nothing to recover
+        let _comp_guard = self
+            .comp_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.compact_logs(u64::MAX)
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(entry) = self.key_dir.get(&key) {
-            match self.reader.deserialize(&entry.value().load())? {
-                Command::Set { key: _, value } => Ok(Some(value)),
-                _ => Err(KvsError::UnexpectedCommandType),
+    /// Merges all the COMPACTED logs into a new one, records of the write logs are not moved
+    /// Only live records are copied, does nothing if there are less than two COMPACTED logs
+    /// or an incremental compaction is in progress
+    pub fn merge_compacted(&self) -> Result<()> {
+        if self.config.versions > 1 {
+            // Older versions moved to a new log would be replayed after newer records of write logs
+            return self.compact();
+        }
+        // Only compaction creates COMPACTED logs, holding its lock keeps the list below valid
+        let compaction = self.compaction.lock().unwrap();
+        if compaction.is_some() {
+            return Ok(());
+        }
+        let merged_files: Vec<PathBuf> = get_sorted_log_files(&self.folder)?
+            .into_iter()
+            .filter(|x| {
+                parse_filename(x).is_ok_and(|(_, flag)| flag == COMP_FLAG)
+                    && !self.reader.is_retired(x)
+            })
+            .collect();
+        if merged_files.len() < 2 {
+            return Ok(());
+        }
+        let merged_logs = merged_files
+            .iter()
+            .map(|x| Ok(parse_filename(x)?.0))
+            .collect::<Result<Vec<u64>>>()?;
+
+        let mut writer = self.new_compacted_log()?;
+        let mut copied = 0;
+        for entry in self.key_dir.iter() {
+            let log_pointer = entry.value().load();
+            if log_pointer.log_state == COMP_FLAG && merged_logs.contains(&log_pointer.log) {
+                let moved_pointer = self.copy_record(&mut writer, &log_pointer)?;
+                // Key could be overwritten meanwhile, then the copied record is garbage
+                match entry.value().compare_exchange(log_pointer, moved_pointer) {
+                    Ok(_) => copied += log_pointer.size,
+                    Err(_) => self.update_uncompacted_size(moved_pointer.size),
+                }
+            }
+        }
+        for entry in self.dedup.values.iter() {
+            let log_pointer = entry.value().pointer.load();
+            if log_pointer.log_state == COMP_FLAG && merged_logs.contains(&log_pointer.log) {
+                let moved_pointer = self.copy_record(&mut writer, &log_pointer)?;
+                match entry
+                    .value()
+                    .pointer
+                    .compare_exchange(log_pointer, moved_pointer)
+                {
+                    Ok(_) => copied += log_pointer.size,
+                    Err(_) => self.update_uncompacted_size(moved_pointer.size),
+                }
             }
-        } else {
-            Ok(None)
         }
+        self.sync_compacted_log(&mut writer)?;
+        self.save_version_mark()?;
+        let merged_size = logs_size(&merged_files)?;
+        self.retire_logs(merged_files)?;
+        self.reclaim_uncompacted(merged_size, copied);
+        Ok(())
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        if !self.key_dir.contains_key(&key) {
-            return Err(KvsError::KeyNotFound);
+    /// Moves the values of blob files still referenced to a new blob file and removes the old ones,
+    /// the GC pass of the values compaction leaves alone, see `EngineConfig::blob_threshold`
+    /// Each moved value gets a new set record in the write log, the old one is left to compaction
+    /// Writes wait until it is done, returns the bytes of garbage reclaimed
+    pub fn gc_blobs(&self) -> Result<u64> {
+        let mut log_writer = self.log_writer.lock().unwrap();
+        self.blobs.close_writer()?;
+        let old_files = get_blob_files(&self.folder)?;
+        let mut reclaimed = 0;
+        for (_, filename) in old_files.iter() {
+            reclaimed += fs::metadata(filename)?.len();
         }
-        let cmd = Command::Rm { key };
-        let size = {
-            let mut log_writer = self.log_writer.lock().unwrap();
-            log_writer.write_cmd(&cmd)?
-        }; // Remove command not needed
-
-        let key = extract_key_from_cmd(cmd);
-        if let Some(old_entry) = self.key_dir.remove(&key) {
-            self.update_uncompacted_size(old_entry.value().load().size + size)?;
+        let keys = self
+            .blobs
+            .keys
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect::<Vec<(IndexKey, BlobRef)>>();
+        for (key, blob) in keys {
+            let version = match self.key_dir.get(&key) {
+                Some(entry) => entry.value().load().version,
+                None => continue,
+            };
+            let moved = self.blobs.write(&self.blobs.read(&blob)?)?;
+            reclaimed -= moved.size;
+            let expires_at = self.expiry.get(&key).map(|x| *x.value());
+            let cmd = LogRecord::SetBlob {
+                key: key.key.clone(),
+                blob: moved,
+                version,
+                expires_at,
+            };
+            let log_pointer = LogPointer {
+                pos: log_writer.pos,
+                size: log_writer.write_record(&cmd)?,
+                log: log_writer.log,
+                log_state: WRITE_FLAG,
+                version,
+            };
+            self.roll_over(&mut log_writer)?;
+            self.index_set(key, log_pointer, expires_at, None, Some(moved));
         }
+        // The old values are removed only once the records of the moved ones are on disk
+        sync_with_blobs(&mut log_writer, &self.blobs)?;
+        self.blobs.garbage.store(0, Ordering::Relaxed);
+        self.retire_blobs(old_files);
+        drop(log_writer);
+        self.maybe_compact()?;
+        Ok(reclaimed)
+    }
 
+    /// Starts a new write log once the current one exceeds the max file size
+    fn roll_over(&self, log_writer: &mut LogWriter) -> Result<()> {
+        if log_writer.pos >= self.config.max_file_size {
+            self.switch_write_log(log_writer)?;
+        }
         Ok(())
     }
-}
 
-impl OptLogStructKvs {
-    pub fn open(path: &Path) -> Result<OptLogStructKvs> {
-        let filenames = get_sorted_log_files(path);
-        let current_folder = PathBuf::from(path);
+    fn switch_write_log(&self, log_writer: &mut LogWriter) -> Result<()> {
+        self.sync_retired_writer(log_writer)?;
+        *log_writer = LogWriter::new(&self.folder, &self.config, self.get_new_log(), WRITE_FLAG)?;
+        self.reader.follow_writer(log_writer);
+        self.log_files.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
 
-        let (key_dir, uncompacted_size, log_counter) = build_key_dir(&filenames)?;
-        let key_dir = Arc::new(key_dir);
-        let uncompacted_size = Arc::new(AtomicU64::new(uncompacted_size));
-        let log = if filenames.is_empty() {
-            log_counter
+    /// Syncs a log that won't be written anymore when syncing is periodic or every write is synced,
+    /// the thread only syncs the current write log and the logs it replaces may be removed
+    /// Otherwise hands the records it held back to the OS, reads of the log don't flush it anymore,
+    /// and leaves the log to the next `sync`
+    fn sync_retired_writer(&self, log_writer: &mut LogWriter) -> Result<()> {
+        if self.config.sync_interval.is_some() || self.config.durability == Durability::Fsync {
+            log_writer.sync()
         } else {
-            parse_filename(&filenames.last().unwrap().to_path_buf())?.0
-        };
-        let log_writer = Arc::new(Mutex::new(LogWriter::new(
-            &current_folder,
-            log,
-            WRITE_FLAG,
-        )?));
-        let log_counter = Arc::new(AtomicU64::new(log_counter));
-        log_counter.fetch_add(1, Ordering::Relaxed);
+            log_writer.flush()?;
+            let file = log_writer.writer.lock().unwrap().get_ref().try_clone()?;
+            self.unsynced_logs
+                .lock()
+                .unwrap()
+                .push((log_writer.log, file));
+            Ok(())
+        }
+    }
 
-        Ok(OptLogStructKvs {
-            reader: Arc::new(LogReader::new(current_folder.clone())?),
-            log_writer,
-            key_dir,
-            folder: Arc::new(current_folder),
-            log_counter,
-            uncompacted_size,
-            comp_lock: Arc::new(Mutex::new(())),
-        })
+    /// Syncs a complete COMPACTED log and its folder whatever the durability,
+    /// the logs whose records it holds are removed right after
+    fn sync_compacted_log(&self, writer: &mut LogWriter) -> Result<()> {
+        writer.sync()?;
+        let path = generate_full_log_path(
+            &self.folder,
+            self.config.log_shards,
+            &writer.log,
+            &COMP_FLAG,
+        )?;
+        sync_folder(path.parent().unwrap_or(&self.folder))
     }
-    /// Monitoring the number of bytes of redundant command logs
-    /// If it hits threshold, merging launches
-    fn update_uncompacted_size(&self, redundant_size: u64) -> Result<()> {
-        let mut comp_thresh = self
-            .uncompacted_size
-            .fetch_add(redundant_size, Ordering::Release);
-        comp_thresh += redundant_size;
 
-        if comp_thresh >= COMPACT_THRESHOLD && self.comp_lock.try_lock().is_ok() {
-            self.compact_logs()?;
+    /// Syncs the folder and the shard folders the logs are in
+    fn sync_log_folders(&self) -> Result<()> {
+        sync_folder(&self.folder)?;
+        if self.config.log_shards > 1 {
+            for shard in 0..self.config.log_shards {
+                let shard_folder = self.folder.join(shard.to_string());
+                if shard_folder.is_dir() {
+                    sync_folder(&shard_folder)?;
+                }
+            }
         }
         Ok(())
     }
@@ -235,124 +2615,921 @@ impl OptLogStructKvs {
     }
 
     /// Log compaction
-    /// Creates a new log for writing
+    /// Moves at most `max_bytes` of records per call, resuming from the key it stopped at
     /// Merges all the commands for a given key to one, saves to COMPACTED log
-    /// Redundant commands and logs are removed
+    /// Redundant commands and logs are removed once all the keys are moved
+    fn compact_logs(&self, max_bytes: u64) -> Result<()> {
+        let mut compaction = self.compaction.lock().unwrap();
+        if self.compaction_cancelled.load(Ordering::Relaxed) {
+            if let Some(cancelled) = compaction.take() {
+                self.abandon_compaction(cancelled)?;
+            }
+            return Ok(());
+        }
+        if compaction.is_none() {
+            *compaction = Some(self.start_compaction()?);
+        }
+        if self.compaction_step(compaction.as_mut().unwrap(), max_bytes)? {
+            self.finish_compaction(compaction.take().unwrap())?;
+        } else if self.compaction_cancelled.load(Ordering::Relaxed) {
+            self.abandon_compaction(compaction.take().unwrap())?;
+        }
+        Ok(())
+    }
 
-    fn compact_logs(&self) -> Result<()> {
-        let old_files = get_sorted_log_files(&self.folder);
+    /// Creates a new log for writing and returns an empty COMPACTED log with the same number
+    /// Compacted log goes before the write log on open, so records written meanwhile win
+    fn new_compacted_log(&self) -> Result<LogWriter> {
         let new_log = self.get_new_log();
-
         {
             let mut log_writer = self.log_writer.lock().unwrap();
-            *log_writer = LogWriter::new(&self.folder, new_log, WRITE_FLAG)?;
+            self.sync_retired_writer(&mut log_writer)?;
+            *log_writer = LogWriter::new(&self.folder, &self.config, new_log, WRITE_FLAG)?;
+            self.reader.follow_writer(&log_writer);
         }
+        let writer = LogWriter::new(&self.folder, &self.config, new_log, COMP_FLAG)?;
+        self.log_files.fetch_add(2, Ordering::Relaxed);
+        Ok(writer)
+    }
 
-        let mut comp_log_writer = LogWriter::new(&self.folder, new_log, COMP_FLAG)?;
+    fn start_compaction(&self) -> Result<Compaction> {
+        let writer = self.new_compacted_log()?;
+        let new_log = writer.log;
 
-        for entry in self.key_dir.iter() {
-            let log_pointer = entry.value();
-            let buf = self.reader.read_log_clean_after(&log_pointer.load())?;
-            comp_log_writer.write_buf(&buf)?;
-
-            log_pointer.store(LogPointer {
-                pos: comp_log_writer.pos,
-                size: buf.len() as u64,
-                log: comp_log_writer.log,
-                log_state: COMP_FLAG,
-            });
+        let old_files = get_sorted_log_files(&self.folder)?
+            .into_iter()
+            .filter(|x| {
+                parse_filename(x).is_ok_and(|(log, _)| log < new_log) && !self.reader.is_retired(x)
+            })
+            .collect();
+        Ok(Compaction {
+            old_files,
+            new_log,
+            writer,
+            cursor: None,
+            moved: Vec::new(),
+            copied: 0,
+        })
+    }
+
+    /// Moves records from the old logs to the COMPACTED log until `max_bytes` are written
+    /// Returns true once there are no more keys to move, false when it stops early
+    /// or the compaction is cancelled
+    fn compaction_step(&self, compaction: &mut Compaction, max_bytes: u64) -> Result<bool> {
+        let cursor = compaction.cursor.take();
+        let start = match &cursor {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        let mut moved = 0u64;
+        for entry in self.key_dir.range((start.cloned(), Bound::Unbounded)) {
+            if moved >= max_bytes || self.compaction_cancelled.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+            if self.is_expired(entry.key()) && self.drop_expired(entry.key(), compaction.new_log)? {
+                if let Some(on_evict) = &self.config.on_evict {
+                    on_evict.call(&entry.key().key);
+                }
+                compaction.cursor = Some(entry.key().clone());
+                continue;
+            }
+            let history = self.history_entry(entry.key());
+            let mut history = history.as_ref().map(|entry| entry.value().lock().unwrap());
+            if let Some(history) = &mut history {
+                // Oldest first, so the versions are replayed in order on open
+                for version in history.iter_mut().rev() {
+                    if version.log < compaction.new_log {
+                        let moved_version = self.copy_record(&mut compaction.writer, version)?;
+                        compaction.copied += version.size;
+                        compaction.moved.push(Moved {
+                            key: entry.key().clone(),
+                            from: *version,
+                            to: moved_version,
+                            touched: None,
+                            version: true,
+                        });
+                        *version = moved_version;
+                        moved += version.size;
+                    }
+                }
+            }
+            let log_pointer = entry.value().load();
+            if log_pointer.log < compaction.new_log {
+                let touched = self.touched.get(entry.key());
+                let moved_pointer = match &touched {
+                    Some(_) => {
+                        let expires_at = self.expiry.get(entry.key()).map(|x| *x.value());
+                        self.rewrite_record(&mut compaction.writer, &log_pointer, expires_at)?
+                    }
+                    None => self.copy_record(&mut compaction.writer, &log_pointer)?,
+                };
+                moved += moved_pointer.size;
+                // Key could be overwritten meanwhile, then the copied record is garbage
+                let replaced = entry
+                    .value()
+                    .compare_exchange(log_pointer, moved_pointer)
+                    .is_ok();
+                // A key touched again meanwhile has a new entry, its record is kept
+                let mut merged = None;
+                if let Some(touched) = touched {
+                    if touched.remove() {
+                        let size = touched.value().size;
+                        self.live_size.fetch_sub(size, Ordering::Relaxed);
+                        self.update_uncompacted_size(size);
+                        merged = Some(*touched.value());
+                    }
+                }
+                if replaced {
+                    // A record rewritten with its version or expiry changes size
+                    self.resize_live(log_pointer.size, moved_pointer.size);
+                    compaction.copied += log_pointer.size;
+                    compaction.moved.push(Moved {
+                        key: entry.key().clone(),
+                        from: log_pointer,
+                        to: moved_pointer,
+                        touched: merged,
+                        version: false,
+                    });
+                } else {
+                    self.update_uncompacted_size(moved_pointer.size);
+                }
+            }
+            compaction.cursor = Some(entry.key().clone());
+        }
+        // Shared values are moved all at once after the keys, set records reference them by hash
+        for entry in self.dedup.values.iter() {
+            let log_pointer = entry.value().pointer.load();
+            if log_pointer.log < compaction.new_log {
+                let moved_pointer = self.copy_record(&mut compaction.writer, &log_pointer)?;
+                // Value could be dropped meanwhile, then the copied record is garbage
+                match entry
+                    .value()
+                    .pointer
+                    .compare_exchange(log_pointer, moved_pointer)
+                {
+                    Ok(_) => compaction.copied += log_pointer.size,
+                    Err(_) => self.update_uncompacted_size(moved_pointer.size),
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Appends a copy of the record to a COMPACTED log, returns the pointer to the copy
+    /// Records too big for the read-ahead buffer are streamed, a big value is never held in memory
+    /// A record implying its version is rewritten with it, the records before it aren't copied along
+    fn copy_record(&self, writer: &mut LogWriter, log_pointer: &LogPointer) -> Result<LogPointer> {
+        let pos = writer.pos;
+        let size = if (log_pointer.size as usize) < READ_AHEAD_SIZE {
+            let buf = self.reader.read_log(log_pointer)?;
+            if log_pointer.version != 1 && LogRecord::implies_version(&buf) {
+                return self.rewrite_record_with(writer, log_pointer, |expires_at| expires_at);
+            }
+            writer.write_buf(&buf)?
+        } else {
+            let file = self.reader.file(log_pointer)?;
+            let mut tag = [0u8; 4];
+            file.value().read_exact_at(&mut tag, log_pointer.pos)?;
+            if log_pointer.version != 1 && LogRecord::implies_version(&tag) {
+                return self.rewrite_record_with(writer, log_pointer, |expires_at| expires_at);
+            }
+            let mut src = PosReader {
+                file: file.value(),
+                pos: log_pointer.pos,
+            };
+            writer.copy_stream(&[], &mut src, log_pointer.size, &[])?
+        };
+        Ok(LogPointer {
+            pos,
+            size,
+            log: writer.log,
+            log_state: COMP_FLAG,
+            version: log_pointer.version,
+        })
+    }
+
+    /// Appends the set record at the pointer to a COMPACTED log with its expiry replaced
+    fn rewrite_record(
+        &self,
+        writer: &mut LogWriter,
+        log_pointer: &LogPointer,
+        expires_at: Option<u64>,
+    ) -> Result<LogPointer> {
+        self.rewrite_record_with(writer, log_pointer, |_| expires_at)
+    }
+
+    /// Appends the set record at the pointer to a COMPACTED log with its version written down,
+    /// `expiry` gets the deadline of the record and returns the one to write
+    fn rewrite_record_with<F>(
+        &self,
+        writer: &mut LogWriter,
+        log_pointer: &LogPointer,
+        expiry: F,
+    ) -> Result<LogPointer>
+    where
+        F: FnOnce(Option<u64>) -> Option<u64>,
+    {
+        let version = log_pointer.version;
+        let file = self.reader.file(log_pointer)?;
+        let mut src = PosReader {
+            file: file.value(),
+            pos: log_pointer.pos,
+        };
+        // The value is streamed from the old record into the new one
+        let mut value_pos = 0;
+        let (old_record, value_len) = LogRecord::read_without_value(&mut src, |src, len| {
+            value_pos = src.pos;
+            src.pos += len;
+            Ok(())
+        })?;
+        let expires_at = expiry(match old_record {
+            LogRecord::SetExpiring { expires_at, .. } => Some(expires_at),
+            LogRecord::SetVersioned { expires_at, .. }
+            | LogRecord::SetShared { expires_at, .. }
+            | LogRecord::SetBlob { expires_at, .. } => expires_at,
+            _ => None,
+        });
+        let cmd = match old_record {
+            LogRecord::SetShared { key, hash, .. } => LogRecord::SetShared {
+                key,
+                hash,
+                version,
+                expires_at,
+            },
+            LogRecord::SetBlob { key, blob, .. } => LogRecord::SetBlob {
+                key,
+                blob,
+                version,
+                expires_at,
+            },
+            LogRecord::Set { key, value }
+            | LogRecord::SetExpiring { key, value, .. }
+            | LogRecord::SetVersioned { key, value, .. } => match (version, expires_at) {
+                (1, None) => LogRecord::Set { key, value },
+                (1, Some(expires_at)) => LogRecord::SetExpiring {
+                    key,
+                    value,
+                    expires_at,
+                },
+                (version, expires_at) => LogRecord::SetVersioned {
+                    key,
+                    value,
+                    version,
+                    expires_at,
+                },
+            },
+            _ => return Err(KvsError::UnexpectedCommandType),
+        };
+        let pos = writer.pos;
+        let size = match value_len {
+            Some(len) => {
+                let (header, trailer) = cmd.encode_around_value(len)?;
+                let mut value = PosReader {
+                    file: file.value(),
+                    pos: value_pos,
+                };
+                writer.copy_stream(&header, &mut value, len, &trailer)?
+            }
+            None => writer.write_record(&cmd)?,
+        };
+        Ok(LogPointer {
+            pos,
+            size,
+            log: writer.log,
+            log_state: COMP_FLAG,
+            version,
+        })
+    }
+
+    /// Writes down the version mark before records holding the highest versions may be dropped
+    fn save_version_mark(&self) -> Result<()> {
+        write_version_mark(&self.folder, self.version_mark.load(Ordering::Relaxed))
+    }
+
+    /// Rolls back a compaction cancelled by `cancel_compaction`: points the moved keys back to
+    /// their records in the old logs and removes the COMPACTED log, the old logs are left as they are
+    fn abandon_compaction(&self, compaction: Compaction) -> Result<()> {
+        for moved in compaction.moved.iter().rev() {
+            let history = self.history_entry(&moved.key);
+            let mut history = history.as_ref().map(|entry| entry.value().lock().unwrap());
+            if moved.version {
+                if let Some(history) = &mut history {
+                    for version in history.iter_mut().filter(|version| **version == moved.to) {
+                        *version = moved.from;
+                    }
+                }
+                continue;
+            }
+            let entry = match self.key_dir.get(&moved.key) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // A key overwritten meanwhile doesn't need its old record anymore
+            if entry.value().compare_exchange(moved.to, moved.from).is_ok() {
+                self.resize_live(moved.to.size, moved.from.size);
+                if let Some(touched) = moved.touched {
+                    self.touched.insert(moved.key.clone(), touched);
+                    self.live_size.fetch_add(touched.size, Ordering::Relaxed);
+                    // Live again, it was counted as redundant once merged
+                    self.reclaim_uncompacted(touched.size, 0);
+                }
+            }
+        }
+        let filename = generate_full_log_path(
+            &self.folder,
+            self.config.log_shards,
+            &compaction.new_log,
+            &COMP_FLAG,
+        )?;
+        drop(compaction.writer);
+        // Readers that loaded a moved pointer retry with the one put back
+        self.retire_logs(vec![filename])
+    }
+
+    fn finish_compaction(&self, mut compaction: Compaction) -> Result<()> {
+        self.sync_compacted_log(&mut compaction.writer)?;
+        self.save_version_mark()?;
+        let old_size = logs_size(&compaction.old_files)?;
+        self.retire_logs(compaction.old_files)?;
+        // Records made redundant meanwhile in the logs written since it started are still there
+        self.reclaim_uncompacted(old_size, compaction.copied);
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Removes logs that no pointer of the index refers to anymore
+    /// A reader could still follow a pointer it loaded before the record was moved, readers enter the
+    /// retirement while they do, so removal waits until the reads in progress at this point are done
+    /// Retired logs are left out of compaction until they are removed
+    fn retire_logs(&self, files: Vec<PathBuf>) -> Result<()> {
+        let logs = files
+            .iter()
+            .map(|x| parse_filename(x))
+            .collect::<Result<Vec<(u64, char)>>>()?;
+        for log in logs.iter() {
+            self.reader.retired.insert(*log);
         }
-        self.reader.clean_up()?;
-        for filename in old_files.iter() {
-            fs::remove_file(&filename)?;
+        // Their records are in a synced COMPACTED log now
+        self.unsynced_logs
+            .lock()
+            .unwrap()
+            .retain(|(log, _)| !logs.contains(&(*log, WRITE_FLAG)));
+        self.log_files
+            .fetch_sub(files.len() as u64, Ordering::Relaxed);
+
+        for (log, filename) in logs.into_iter().zip(files) {
+            let reader = Arc::clone(&self.reader);
+            self.retirement.retire(filename, move || {
+                reader.readers.remove(&log);
+                reader.retired.remove(&log);
+            });
         }
-        self.uncompacted_size.store(0, Ordering::Relaxed);
         Ok(())
     }
+
+    /// Removes blob files no key references anymore, once the reads in progress are done like `retire_logs`
+    fn retire_blobs(&self, files: Vec<(u64, PathBuf)>) {
+        for (blob_id, filename) in files {
+            let blobs = Arc::clone(&self.blobs);
+            self.retirement.retire(filename, move || {
+                blobs.readers.remove(&blob_id);
+            });
+        }
+    }
+}
+
+/// Reads the records of the logs of an `OptLogStructKvs` folder in log order, for change data capture
+/// Each command comes with its offset, the bytes of the logs before its record
+/// Reading doesn't lock the folder nor create a log, an engine may write in it meanwhile
+/// A record not fully written yet ends the log it is in
+/// Compaction rewrites the logs, so an offset doesn't point to the same record after one,
+/// consumers should checkpoint by the state of the keys rather than by offset
+/// A `SetShared` record refers to the value of the `Shared` record with the same hash before it,
+/// a `SetBlob` one to a value in a blob file of the folder, see `EngineConfig::blob_threshold`
+pub struct LogReplay {
+    files: VecDeque<PathBuf>,
+    reader: Option<BufReader<File>>,
+    /// Offset of the start of the current log
+    base: u64,
+}
+
+impl LogReplay {
+    /// Lists the logs of the folder at `path`, logs created afterwards aren't read
+    pub fn open(path: &Path) -> Result<LogReplay> {
+        if !path.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No storage folder").into());
+        }
+        Ok(LogReplay {
+            files: get_sorted_log_files(path)?.into(),
+            reader: None,
+            base: 0,
+        })
+    }
+}
+
+impl Iterator for LogReplay {
+    type Item = Result<(u64, LogRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let reader = match self.reader.as_mut() {
+                Some(reader) => reader,
+                None => {
+                    let filename = self.files.pop_front()?;
+                    match create_file_reader(&filename) {
+                        Ok(reader) => self.reader.insert(reader),
+                        Err(err) => {
+                            self.files.clear();
+                            return Some(Err(err));
+                        }
+                    }
+                }
+            };
+            let pos = match reader.stream_position() {
+                Ok(pos) => pos,
+                Err(err) => {
+                    self.files.clear();
+                    self.reader = None;
+                    return Some(Err(err.into()));
+                }
+            };
+            match bincode::deserialize_from(reader) {
+                Ok(LogRecord::Padding { .. }) => continue,
+                Ok(cmd) => return Some(Ok((self.base + pos, cmd))),
+                Err(_) => {
+                    self.base += pos;
+                    self.reader = None;
+                }
+            }
+        }
+    }
+}
+
+/// Bytes of the logs, a log already removed counts for nothing
+fn logs_size(files: &[PathBuf]) -> Result<u64> {
+    let mut size = 0;
+    for filename in files {
+        match fs::metadata(filename) {
+            Ok(metadata) => size += metadata.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(size)
+}
+
+/// Path of a log, in the subfolder of its shard when the logs are spread over `shards` of them
+fn generate_full_log_path(
+    folder: &Path,
+    shards: u64,
+    log: &u64,
+    log_state: &char,
+) -> Result<PathBuf> {
+    let filename = format!("{}{}.{}", log_state, log, LOG_EXT);
+    if shards > 1 {
+        Ok(folder.join((log % shards).to_string()).join(filename))
+    } else {
+        Ok(folder.join(filename))
+    }
 }
 
-fn generate_full_log_path(folder: &Path, log: &u64, log_state: &char) -> Result<PathBuf> {
-    Ok(folder.join(format!("{}{}.{}", log_state, log, LOG_EXT)))
+/// Moves the logs that are not where `shards` puts them, so the count can change between opens
+/// Returns the sorted logs at their new paths
+fn place_logs(folder: &Path, shards: u64) -> Result<Vec<PathBuf>> {
+    let mut placed = Vec::new();
+    for filename in get_sorted_log_files(folder)? {
+        let (log, log_state) = parse_filename(&filename)?;
+        let path = generate_full_log_path(folder, shards, &log, &log_state)?;
+        if path != filename {
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::rename(&filename, &path)?;
+        }
+        placed.push(path);
+    }
+    Ok(placed)
 }
 
-/// Recreates key dir from all the log files
-fn build_key_dir(
+/// Index and side tables rebuilt from the log files, with the uncompacted size, the last log number
+/// and the highest version found
+type RecoveredLogs<B> = (
+    KeyDir<B>,
+    History,
+    Expiry,
+    Touched,
+    Dedup,
+    SkipMap<IndexKey, BlobRef>,
+    u64,
+    u64,
+    u64,
+);
+
+/// Recreates key dir, the kept versions, the expiry deadlines, the shared values
+/// and the blobs of the keys from all the log files
+fn build_key_dir<B: IndexBackend>(
     filenames: &[PathBuf],
-) -> Result<(SkipMap<String, AtomicCell<LogPointer>>, u64, u64)> {
-    let key_dir = SkipMap::<String, AtomicCell<LogPointer>>::new();
+    config: &EngineConfig,
+) -> Result<RecoveredLogs<B>> {
+    let key_order = config.key_order;
+    let key_dir = KeyDir::<B>::default();
+    let history = History::new();
+    let expiry = Expiry::new();
+    let touched = Touched::new();
+    let dedup = Dedup {
+        values: SkipMap::new(),
+        keys: SkipMap::new(),
+    };
+    let blob_keys = SkipMap::new();
+    // A shared value can be replayed after the keys referencing it, it is counted at the end
+    let mut refs = HashMap::<u64, u64>::new();
     let mut uncompacted_size = 0u64;
     let mut log_counter = 0u64;
+    let mut version_mark = 0u64;
 
     for filename in filenames {
-        let mut reader = create_file_reader(filename)?;
-        let mut log_position = reader.stream_position()?;
         let (log, log_state) = parse_filename(filename)?;
         log_counter = max(log_counter, log);
-        while let Ok(cmd) = bincode::deserialize_from(&mut reader) {
+        walk_log(filename, |cmd, pos, size| {
+            let (expires_at, version, shared) = match &cmd {
+                LogRecord::SetExpiring { expires_at, .. } => (Some(*expires_at), None, None),
+                LogRecord::SetVersioned {
+                    expires_at,
+                    version,
+                    ..
+                } => (*expires_at, Some(*version), None),
+                LogRecord::SetShared {
+                    expires_at,
+                    version,
+                    hash,
+                    ..
+                } => (*expires_at, Some(*version), Some(*hash)),
+                LogRecord::SetBlob {
+                    expires_at,
+                    version,
+                    ..
+                } => (*expires_at, Some(*version), None),
+                _ => (None, None, None),
+            };
+            let blob = match &cmd {
+                LogRecord::SetBlob { blob, .. } => Some(*blob),
+                _ => None,
+            };
             match cmd {
-                Command::Set { key, value: _ } => {
+                LogRecord::Set { key, .. }
+                | LogRecord::SetExpiring { key, .. }
+                | LogRecord::SetVersioned { key, .. }
+                | LogRecord::SetShared { key, .. }
+                | LogRecord::SetBlob { key, .. } => {
+                    let key = IndexKey {
+                        key,
+                        order: key_order,
+                    };
+                    match blob {
+                        Some(blob) => {
+                            blob_keys.insert(key.clone(), blob);
+                        }
+                        None => {
+                            blob_keys.remove(&key);
+                        }
+                    }
+                    let old_shared = match shared {
+                        Some(hash) => {
+                            *refs.entry(hash).or_default() += 1;
+                            let old_shared = dedup.keys.get(&key).map(|entry| *entry.value());
+                            dedup.keys.insert(key.clone(), hash);
+                            old_shared
+                        }
+                        None => dedup.keys.remove(&key).map(|entry| *entry.value()),
+                    };
+                    if let Some(hash) = old_shared {
+                        *refs.entry(hash).or_default() -= 1;
+                    }
+                    if let Some(entry) = touched.remove(&key) {
+                        uncompacted_size += entry.value().size;
+                    }
+                    // Logs written before versions were recorded only imply them
+                    let version = version.unwrap_or_else(|| match key_dir.get(&key) {
+                        Some(entry) => entry.value().load().version + 1,
+                        None => 1,
+                    });
+                    version_mark = max(version_mark, version);
+                    match expires_at {
+                        Some(expires_at) => {
+                            expiry.insert(key.clone(), expires_at);
+                        }
+                        None => {
+                            expiry.remove(&key);
+                        }
+                    }
                     if let Some(old_entry) = key_dir.get(&key) {
-                        uncompacted_size += old_entry.value().load().size;
+                        let old_log_pointer = old_entry.value().load();
+                        if config.versions > 1 {
+                            let entry =
+                                history.get_or_insert(key.clone(), Mutex::new(VecDeque::new()));
+                            let mut versions = entry.value().lock().unwrap();
+                            versions.push_front(old_log_pointer);
+                            while versions.len() >= config.versions {
+                                uncompacted_size += versions.pop_back().unwrap().size;
+                            }
+                        } else {
+                            uncompacted_size += old_log_pointer.size;
+                        }
                     }
                     key_dir.insert(
                         key,
                         AtomicCell::new(LogPointer {
-                            pos: log_position,
-                            size: reader.stream_position()? - log_position,
+                            pos,
+                            size,
                             log,
                             log_state,
+                            version,
                         }),
                     );
                 }
-                Command::Rm { key } => {
+                LogRecord::Rm { key } => {
+                    let key = IndexKey {
+                        key,
+                        order: key_order,
+                    };
                     if let Some(old_entry) = key_dir.remove(&key) {
                         uncompacted_size += old_entry.value().load().size;
-                        uncompacted_size += reader.stream_position()? - log_position;
+                        uncompacted_size += size;
+                    }
+                    if let Some(entry) = history.remove(&key) {
+                        let versions = entry.value().lock().unwrap();
+                        uncompacted_size += versions.iter().map(|x| x.size).sum::<u64>();
+                    }
+                    expiry.remove(&key);
+                    if let Some(entry) = touched.remove(&key) {
+                        uncompacted_size += entry.value().size;
+                    }
+                    if let Some(entry) = dedup.keys.remove(&key) {
+                        *refs.entry(*entry.value()).or_default() -= 1;
                     }
+                    blob_keys.remove(&key);
                 }
+                LogRecord::SetExpiry { key, expires_at } => {
+                    let key = IndexKey {
+                        key,
+                        order: key_order,
+                    };
+                    if key_dir.contains_key(&key) {
+                        expiry.insert(key.clone(), expires_at);
+                        if let Some(entry) = touched.remove(&key) {
+                            uncompacted_size += entry.value().size;
+                        }
+                        let log_pointer = LogPointer {
+                            pos,
+                            size,
+                            log,
+                            log_state,
+                            version: 0,
+                        };
+                        touched.insert(key, log_pointer);
+                    } else {
+                        uncompacted_size += size;
+                    }
+                }
+                LogRecord::Shared { hash, .. } => {
+                    if let Some(old_entry) = dedup.values.get(&hash) {
+                        uncompacted_size += old_entry.value().pointer.load().size;
+                    }
+                    dedup.values.insert(
+                        hash,
+                        SharedValue {
+                            pointer: AtomicCell::new(LogPointer {
+                                pos,
+                                size,
+                                log,
+                                log_state,
+                                version: 0,
+                            }),
+                            refs: AtomicU64::new(0),
+                        },
+                    );
+                }
+                _ if config.recovery == RecoveryPolicy::SkipCorrupt => uncompacted_size += size,
                 _ => return Err(KvsError::UnexpectedCommandType),
             };
-            log_position = reader.stream_position()?;
+            Ok(())
+        })?;
+    }
+    // Expired keys are left out, compaction drops their records
+    let now = unix_millis(config.clock.now());
+    for entry in expiry.iter().filter(|entry| *entry.value() <= now) {
+        let key = entry.key();
+        if let Some(old_entry) = key_dir.remove(key) {
+            uncompacted_size += old_entry.value().load().size;
+        }
+        if let Some(versions) = history.remove(key) {
+            let versions = versions.value().lock().unwrap();
+            uncompacted_size += versions.iter().map(|x| x.size).sum::<u64>();
+        }
+        if let Some(touched) = touched.remove(key) {
+            uncompacted_size += touched.value().size;
+        }
+        if let Some(shared) = dedup.keys.remove(key) {
+            *refs.entry(*shared.value()).or_default() -= 1;
+        }
+        blob_keys.remove(key);
+        entry.remove();
+        if let Some(on_evict) = &config.on_evict {
+            on_evict.call(&key.key);
+        }
+    }
+    for entry in dedup.values.iter() {
+        match refs.get(entry.key()) {
+            Some(&count) if count > 0 => entry.value().refs.store(count, Ordering::Relaxed),
+            _ => {
+                uncompacted_size += entry.value().pointer.load().size;
+                entry.remove();
+            }
         }
     }
-    Ok((key_dir, uncompacted_size, log_counter))
+    Ok((
+        key_dir,
+        history,
+        expiry,
+        touched,
+        dedup,
+        blob_keys,
+        uncompacted_size,
+        log_counter,
+        version_mark,
+    ))
 }
+
+/// Version mark written by the last compaction, 0 if there was none
+fn read_version_mark(folder: &Path) -> Result<u64> {
+    match fs::read_to_string(folder.join(VERSION_MARK_FILENAME)) {
+        Ok(mark) => mark.trim().parse().map_err(|_| KvsError::BadLogFile),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the version mark of the folder, a crash leaves the old one or the new one
+fn write_version_mark(folder: &Path, mark: u64) -> Result<()> {
+    let path = folder.join(VERSION_MARK_FILENAME);
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{}", mark)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    sync_folder(folder)
+}
+
+/// Reads the records of a log one by one, passing each with its position and size
+/// Values are skipped, not read, the records are passed with empty values
+/// `Padding` records aren't passed, their size counts in the one of the record before them
+/// like when it was written
+/// Returns the position where reading stopped, the log size if all of it was read
+fn walk_log<F>(filename: &Path, mut visit: F) -> Result<u64>
+where
+    F: FnMut(LogRecord, u64, u64) -> Result<()>,
+{
+    let mut reader = create_file_reader(filename)?;
+    let log_size = reader.get_ref().metadata()?.len();
+    let mut log_position = reader.stream_position()?;
+    let mut last: Option<(LogRecord, u64)> = None;
+    let skip = |reader: &mut BufReader<File>, len: u64| match i64::try_from(len) {
+        Ok(len) => reader.seek_relative(len),
+        Err(_) => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    };
+    while let Ok((cmd, _)) = LogRecord::read_without_value(&mut reader, skip) {
+        // Seeking doesn't fail past the end, a value cut short ends the readable part like a bad record
+        if reader.stream_position()? > log_size {
+            break;
+        }
+        if !matches!(cmd, LogRecord::Padding { .. }) {
+            if let Some((last_cmd, last_position)) = last.take() {
+                visit(last_cmd, last_position, log_position - last_position)?;
+            }
+            last = Some((cmd, log_position));
+        }
+        log_position = reader.stream_position()?;
+    }
+    if let Some((last_cmd, last_position)) = last {
+        visit(last_cmd, last_position, log_position - last_position)?;
+    }
+    Ok(log_position)
+}
+
 /// Parses to log and log state (WRITE, COMPACTED)
+/// Fails with `BadLogFile` for a name not made like `generate_full_log_path` does
 fn parse_filename(path: &Path) -> Result<(u64, char)> {
-    let fullname = path.file_name().unwrap().to_str().unwrap();
-    let log_id = fullname[1..fullname.len() - LOG_EXT.len() - 1]
-        .parse::<u64>()
-        .unwrap();
-    Ok((log_id, fullname.chars().next().unwrap()))
+    let mut name = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or(KvsError::BadLogFile)?
+        .chars();
+    let flag = name.next().ok_or(KvsError::BadLogFile)?;
+    let log_id = name
+        .as_str()
+        .strip_suffix(LOG_EXT)
+        .and_then(|x| x.strip_suffix('.'))
+        .and_then(|x| x.parse::<u64>().ok())
+        .ok_or(KvsError::BadLogFile)?;
+    Ok((log_id, flag))
 }
 
 fn create_file_writer(path: &Path) -> Result<BufWriter<File>> {
-    let file = OpenOptions::new().append(true).create(true).open(&path)?;
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
     let mut log_writer = BufWriter::new(file);
     log_writer.seek(SeekFrom::End(0))?;
     Ok(log_writer)
 }
 fn create_file_reader(path: &Path) -> Result<BufReader<File>> {
-    Ok(BufReader::new(File::open(&path)?))
+    Ok(BufReader::new(File::open(path)?))
 }
 
-/// Returns all the log file paths in the current directory
-fn get_sorted_log_files(path: &Path) -> Vec<PathBuf> {
-    let mut files = fs::read_dir(path)
-        .unwrap()
-        .into_iter()
-        .map(|x| x.unwrap().path())
-        .filter(|x| x.file_name().unwrap().to_str().unwrap().ends_with(&LOG_EXT))
-        .collect::<Vec<PathBuf>>();
+/// Returns all the log file paths in the current directory and in its shard subfolders
+/// Sorted by log number, compacted log goes before the write log with the same number
+/// A file with the log extension and a name that isn't one of a log fails with `BadLogFile`
+fn get_sorted_log_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let is_shard = |x: &Path| {
+        x.is_dir()
+            && x.file_name()
+                .and_then(|x| x.to_str())
+                .is_some_and(|x| x.parse::<u64>().is_ok())
+    };
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?.path();
+        if is_shard(&entry) {
+            for shard_entry in fs::read_dir(&entry)? {
+                entries.push(shard_entry?.path());
+            }
+        } else {
+            entries.push(entry);
+        }
+    }
+    let mut files = Vec::new();
+    for entry in entries {
+        if entry.extension().is_some_and(|ext| ext == LOG_EXT) {
+            files.push((parse_filename(&entry)?, entry));
+        }
+    }
+
+    files.sort_by_key(|(log, _)| *log);
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
 
+/// Returns the blob files of the storage folder with their ids, sorted by id
+fn get_blob_files(path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == BLOB_EXT) {
+            if let Some(blob_id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                files.push((blob_id, path));
+            }
+        }
+    }
     files.sort();
-    files
+    Ok(files)
 }
 
-fn extract_key_from_cmd(cmd: Command) -> String {
+fn blob_path(folder: &Path, blob_id: u64) -> PathBuf {
+    folder.join(format!("{}.{}", blob_id, BLOB_EXT))
+}
+
+/// Syncs the blob file before the write log, so a synced record never references a value that isn't
+/// The caller holds the write lock, no value is written in between
+fn sync_with_blobs(log_writer: &mut LogWriter, blobs: &Blobs) -> Result<()> {
+    blobs.sync()?;
+    log_writer.sync()
+}
+
+/// Key of a record written for a key, fails for the records of no key
+fn extract_key_from_cmd(cmd: LogRecord) -> Result<String> {
     match cmd {
-        Command::Rm { key } => key,
-        Command::Get { key } => key,
-        Command::Set { key, value: _ } => key,
+        LogRecord::Rm { key } | LogRecord::SetExpiry { key, .. } => Ok(key),
+        LogRecord::Set { key, .. }
+        | LogRecord::SetExpiring { key, .. }
+        | LogRecord::SetVersioned { key, .. }
+        | LogRecord::SetShared { key, .. }
+        | LogRecord::SetBlob { key, .. } => Ok(key),
+        LogRecord::Shared { .. } | LogRecord::Padding { .. } => {
+            Err(KvsError::UnexpectedCommandType)
+        }
     }
 }
+
+/// Hash function of `value_hash`, in the top byte of the hashes it gives
+/// Hashes of older storages come from the std hasher, which may change between Rust releases
+const VALUE_HASH_TAG: u64 = 1;
+
+/// Hash identifying a shared value, values with the same hash are compared before being shared
+/// FNV-1a of the bytes, so the hashes written down stay the same from one build to the next,
+/// its top byte replaced by `VALUE_HASH_TAG`. A hash of another function can still match,
+/// it would only share a value equal to the one it was computed for
+fn value_hash(value: &str) -> u64 {
+    let hash = value.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    (VALUE_HASH_TAG << 56) | (hash & 0x00ff_ffff_ffff_ffff)
+}
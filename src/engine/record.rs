@@ -0,0 +1,361 @@
+use crate::common::BlobRef;
+use serde::de::{self, EnumAccess, VariantAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io::{self, Cursor, Read};
+
+/// Records of the log files written by the engines, never sent over the wire
+///
+/// Encoded with bincode like an enum, a `u32` tag and then the fields in order
+/// `Set` and `Rm` encode like the commands of the same name, which were the only records before,
+/// the tags of the other records are reserved in `Command` so no command decodes as one
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogRecord {
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Value that expires at `expires_at` milliseconds since the Unix epoch
+    SetExpiring {
+        key: String,
+        value: String,
+        expires_at: u64,
+    },
+    /// Value that is the `version`th write of its key, with the deadline of a `SetExpiring`
+    SetVersioned {
+        key: String,
+        value: String,
+        version: u64,
+        expires_at: Option<u64>,
+    },
+    /// Value shared by keys, identified by the hash of its bytes
+    Shared {
+        hash: u64,
+        value: String,
+    },
+    /// Key set to the shared value with the `hash`, otherwise like `SetVersioned`
+    SetShared {
+        key: String,
+        hash: u64,
+        version: u64,
+        expires_at: Option<u64>,
+    },
+    /// New expiry of a key, the value stays in its set record, written by `KvsEngine::touch`
+    SetExpiry {
+        key: String,
+        expires_at: u64,
+    },
+    /// Fills the space up to the next aligned record, see `EngineConfig::align_records`
+    Padding {
+        filler: Vec<u8>,
+    },
+    /// Key whose value is stored in a blob file, otherwise like `SetVersioned`
+    SetBlob {
+        key: String,
+        blob: BlobRef,
+        version: u64,
+        expires_at: Option<u64>,
+    },
+}
+
+const SET: u32 = 0;
+const RM: u32 = 2;
+const SET_EXPIRING: u32 = 6;
+const SET_VERSIONED: u32 = 8;
+const SHARED: u32 = 9;
+const SET_SHARED: u32 = 10;
+const SET_EXPIRY: u32 = 11;
+const PADDING: u32 = 14;
+const SET_BLOB: u32 = 20;
+
+const NAME: &str = "LogRecord";
+const VARIANTS: &[&str] = &[
+    "Set",
+    "Rm",
+    "SetExpiring",
+    "SetVersioned",
+    "Shared",
+    "SetShared",
+    "SetExpiry",
+    "Padding",
+    "SetBlob",
+];
+
+impl LogRecord {
+    /// Key the record is about, `None` for the records of no key
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            LogRecord::Set { key, .. }
+            | LogRecord::Rm { key }
+            | LogRecord::SetExpiring { key, .. }
+            | LogRecord::SetVersioned { key, .. }
+            | LogRecord::SetShared { key, .. }
+            | LogRecord::SetExpiry { key, .. }
+            | LogRecord::SetBlob { key, .. } => Some(key),
+            LogRecord::Shared { .. } | LogRecord::Padding { .. } => None,
+        }
+    }
+
+    /// Whether the encoded record is a set record leaving its version to be implied by the ones before it
+    pub(crate) fn implies_version(bytes: &[u8]) -> bool {
+        match bytes.get(..4) {
+            Some(tag) => matches!(
+                u32::from_le_bytes([tag[0], tag[1], tag[2], tag[3]]),
+                SET | SET_EXPIRING
+            ),
+            None => false,
+        }
+    }
+
+    /// Splits the encoding of the record around its value, the value of `self` is left out
+    /// A value of `len` bytes written between the two halves makes the encoding of the record
+    /// with that value, so a big value can be streamed into a log
+    /// Fails for the records without a value
+    pub(crate) fn encode_around_value(&self, len: u64) -> bincode::Result<(Vec<u8>, Vec<u8>)> {
+        Ok(match self {
+            LogRecord::Set { key, .. } => (bincode::serialize(&(SET, key, len))?, Vec::new()),
+            LogRecord::SetExpiring {
+                key, expires_at, ..
+            } => (
+                bincode::serialize(&(SET_EXPIRING, key, len))?,
+                bincode::serialize(expires_at)?,
+            ),
+            LogRecord::SetVersioned {
+                key,
+                version,
+                expires_at,
+                ..
+            } => (
+                bincode::serialize(&(SET_VERSIONED, key, len))?,
+                bincode::serialize(&(version, expires_at))?,
+            ),
+            LogRecord::Shared { hash, .. } => {
+                (bincode::serialize(&(SHARED, hash, len))?, Vec::new())
+            }
+            _ => {
+                return Err(Box::new(bincode::ErrorKind::Custom(
+                    "record without a value".to_owned(),
+                )))
+            }
+        })
+    }
+
+    /// Decodes a record from `reader` without reading its value into memory
+    /// `skip` is called with the reader right before the value and the length of the value,
+    /// it must move the reader past it, reading the bytes or seeking
+    /// Returns the record with an empty value and the length of the value, `None` for records without one
+    pub(crate) fn read_without_value<R, F>(
+        reader: &mut R,
+        mut skip: F,
+    ) -> bincode::Result<(LogRecord, Option<u64>)>
+    where
+        R: Read,
+        F: FnMut(&mut R, u64) -> io::Result<()>,
+    {
+        let tag: u32 = bincode::deserialize_from(&mut *reader)?;
+        let mut value_len = |reader: &mut R| -> bincode::Result<u64> {
+            let len = bincode::deserialize_from(&mut *reader)?;
+            skip(reader, len)?;
+            Ok(len)
+        };
+        Ok(match tag {
+            SET => {
+                let key = bincode::deserialize_from(&mut *reader)?;
+                let len = value_len(reader)?;
+                let record = LogRecord::Set {
+                    key,
+                    value: String::new(),
+                };
+                (record, Some(len))
+            }
+            SET_EXPIRING => {
+                let key = bincode::deserialize_from(&mut *reader)?;
+                let len = value_len(reader)?;
+                let record = LogRecord::SetExpiring {
+                    key,
+                    value: String::new(),
+                    expires_at: bincode::deserialize_from(&mut *reader)?,
+                };
+                (record, Some(len))
+            }
+            SET_VERSIONED => {
+                let key = bincode::deserialize_from(&mut *reader)?;
+                let len = value_len(reader)?;
+                let (version, expires_at) = bincode::deserialize_from(&mut *reader)?;
+                let record = LogRecord::SetVersioned {
+                    key,
+                    value: String::new(),
+                    version,
+                    expires_at,
+                };
+                (record, Some(len))
+            }
+            SHARED => {
+                let hash = bincode::deserialize_from(&mut *reader)?;
+                let len = value_len(reader)?;
+                let record = LogRecord::Shared {
+                    hash,
+                    value: String::new(),
+                };
+                (record, Some(len))
+            }
+            // The other records are small, the tag is put back in front of them to decode them whole
+            _ => (
+                bincode::deserialize_from(Cursor::new(tag.to_le_bytes()).chain(reader))?,
+                None,
+            ),
+        })
+    }
+}
+
+// The fields of a variant are written as a tuple, bincode encodes it exactly like the fields
+// of a struct variant, and the tag is explicit instead of the position of the variant
+impl Serialize for LogRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            LogRecord::Set { key, value } => {
+                serializer.serialize_newtype_variant(NAME, SET, "Set", &(key, value))
+            }
+            LogRecord::Rm { key } => serializer.serialize_newtype_variant(NAME, RM, "Rm", key),
+            LogRecord::SetExpiring {
+                key,
+                value,
+                expires_at,
+            } => serializer.serialize_newtype_variant(
+                NAME,
+                SET_EXPIRING,
+                "SetExpiring",
+                &(key, value, expires_at),
+            ),
+            LogRecord::SetVersioned {
+                key,
+                value,
+                version,
+                expires_at,
+            } => serializer.serialize_newtype_variant(
+                NAME,
+                SET_VERSIONED,
+                "SetVersioned",
+                &(key, value, version, expires_at),
+            ),
+            LogRecord::Shared { hash, value } => {
+                serializer.serialize_newtype_variant(NAME, SHARED, "Shared", &(hash, value))
+            }
+            LogRecord::SetShared {
+                key,
+                hash,
+                version,
+                expires_at,
+            } => serializer.serialize_newtype_variant(
+                NAME,
+                SET_SHARED,
+                "SetShared",
+                &(key, hash, version, expires_at),
+            ),
+            LogRecord::SetExpiry { key, expires_at } => serializer.serialize_newtype_variant(
+                NAME,
+                SET_EXPIRY,
+                "SetExpiry",
+                &(key, expires_at),
+            ),
+            LogRecord::Padding { filler } => {
+                serializer.serialize_newtype_variant(NAME, PADDING, "Padding", filler)
+            }
+            LogRecord::SetBlob {
+                key,
+                blob,
+                version,
+                expires_at,
+            } => serializer.serialize_newtype_variant(
+                NAME,
+                SET_BLOB,
+                "SetBlob",
+                &(key, blob, version, expires_at),
+            ),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for LogRecord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_enum(NAME, VARIANTS, RecordVisitor)
+    }
+}
+
+struct RecordVisitor;
+
+impl<'de> Visitor<'de> for RecordVisitor {
+    type Value = LogRecord;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a log record")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<LogRecord, A::Error> {
+        let (tag, fields): (u32, _) = data.variant()?;
+        Ok(match tag {
+            SET => {
+                let (key, value) = fields.newtype_variant()?;
+                LogRecord::Set { key, value }
+            }
+            RM => LogRecord::Rm {
+                key: fields.newtype_variant()?,
+            },
+            SET_EXPIRING => {
+                let (key, value, expires_at) = fields.newtype_variant()?;
+                LogRecord::SetExpiring {
+                    key,
+                    value,
+                    expires_at,
+                }
+            }
+            SET_VERSIONED => {
+                let (key, value, version, expires_at) = fields.newtype_variant()?;
+                LogRecord::SetVersioned {
+                    key,
+                    value,
+                    version,
+                    expires_at,
+                }
+            }
+            SHARED => {
+                let (hash, value) = fields.newtype_variant()?;
+                LogRecord::Shared { hash, value }
+            }
+            SET_SHARED => {
+                let (key, hash, version, expires_at) = fields.newtype_variant()?;
+                LogRecord::SetShared {
+                    key,
+                    hash,
+                    version,
+                    expires_at,
+                }
+            }
+            SET_EXPIRY => {
+                let (key, expires_at) = fields.newtype_variant()?;
+                LogRecord::SetExpiry { key, expires_at }
+            }
+            PADDING => LogRecord::Padding {
+                filler: fields.newtype_variant()?,
+            },
+            SET_BLOB => {
+                let (key, blob, version, expires_at) = fields.newtype_variant()?;
+                LogRecord::SetBlob {
+                    key,
+                    blob,
+                    version,
+                    expires_at,
+                }
+            }
+            _ => {
+                return Err(de::Error::invalid_value(
+                    de::Unexpected::Unsigned(tag.into()),
+                    &self,
+                ))
+            }
+        })
+    }
+}
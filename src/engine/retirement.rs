@@ -0,0 +1,130 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Files no pointer of the index refers to anymore, removed once the reads that could still
+/// have loaded an old pointer to them are done
+///
+/// A read enters the current generation and leaves it when its guard drops. Files retired in a
+/// generation are removed once no read of that generation is left. The generation only moves on
+/// when the reads of the one before it are gone, so reads of two generations at most are in progress
+/// and a counter for each parity is enough
+#[derive(Default)]
+pub(crate) struct Retirement {
+    generation: AtomicU64,
+    reads: [AtomicUsize; 2],
+    /// Files waiting for their removal, in the order they were retired
+    files: Mutex<Vec<RetiredFile>>,
+    /// Length of `files`, so a read leaving doesn't take the lock when nothing waits
+    pending: AtomicUsize,
+    /// Removals that failed, the files are kept and their removal is tried again later
+    failed_removals: AtomicU64,
+}
+
+struct RetiredFile {
+    path: PathBuf,
+    generation: u64,
+    /// Drops what still refers to the file, like its open handle, once it is removed
+    release: Box<dyn FnOnce() + Send>,
+}
+
+/// Read in progress, the files retired meanwhile stay until it is dropped
+pub(crate) struct ReadGuard<'a> {
+    retirement: &'a Retirement,
+    slot: usize,
+}
+
+impl Retirement {
+    /// Enters the current generation, to be done before loading a pointer of the index
+    pub(crate) fn enter(&self) -> ReadGuard<'_> {
+        loop {
+            let generation = self.generation.load(Ordering::SeqCst);
+            let slot = (generation % 2) as usize;
+            self.reads[slot].fetch_add(1, Ordering::SeqCst);
+            // The generation moved on meanwhile, the slot may belong to the reads it waits for
+            if self.generation.load(Ordering::SeqCst) == generation {
+                return ReadGuard {
+                    retirement: self,
+                    slot,
+                };
+            }
+            self.reads[slot].fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Removes the file at `path` once the reads in progress are done, then calls `release`
+    /// Removes it right away when there are none
+    pub(crate) fn retire<F>(&self, path: PathBuf, release: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut files = self.files.lock().unwrap();
+        files.push(RetiredFile {
+            path,
+            generation: self.generation.load(Ordering::SeqCst),
+            release: Box::new(release),
+        });
+        self.pending.store(files.len(), Ordering::SeqCst);
+        self.collect(&mut files);
+    }
+
+    /// Files retired and still on disk
+    pub(crate) fn pending(&self) -> u64 {
+        self.pending.load(Ordering::Relaxed) as u64
+    }
+
+    /// Removals that failed since open, the files are left for a later try
+    pub(crate) fn failed_removals(&self) -> u64 {
+        self.failed_removals.load(Ordering::Relaxed)
+    }
+
+    /// Removes the files no read can use anymore and moves the generation on while it can
+    fn collect(&self, files: &mut Vec<RetiredFile>) {
+        loop {
+            let generation = self.generation.load(Ordering::SeqCst);
+            // Reads of the generation before the current one, it moved on only once the ones before were gone
+            let previous_done =
+                self.reads[((generation + 1) % 2) as usize].load(Ordering::SeqCst) == 0;
+            if !previous_done {
+                break;
+            }
+            let mut kept = Vec::new();
+            for file in files.drain(..) {
+                if file.generation >= generation {
+                    kept.push(file);
+                    continue;
+                }
+                match fs::remove_file(&file.path) {
+                    Ok(()) => (file.release)(),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (file.release)(),
+                    Err(_) => {
+                        // Tried again with the next collection, a file left behind holds only stale records
+                        self.failed_removals.fetch_add(1, Ordering::Relaxed);
+                        kept.push(file);
+                    }
+                }
+            }
+            *files = kept;
+            // Files of the current generation wait for its reads, which the next one doesn't share
+            if files.iter().any(|file| file.generation == generation) {
+                self.generation.store(generation + 1, Ordering::SeqCst);
+            } else {
+                break;
+            }
+        }
+        self.pending.store(files.len(), Ordering::SeqCst);
+    }
+}
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        let retirement = self.retirement;
+        if retirement.reads[self.slot].fetch_sub(1, Ordering::SeqCst) == 1
+            && retirement.pending.load(Ordering::SeqCst) > 0
+        {
+            retirement.collect(&mut retirement.files.lock().unwrap());
+        }
+    }
+}
@@ -1,30 +1,152 @@
 use crate::common::Result;
-use crate::engine::KvsEngine;
+use crate::engine::upgrade::Rewrite;
+use crate::engine::{
+    create_checkpoint_folder, deadline_after, expire_matching_keys, expiry_deadline, glob,
+    is_sequence_key, parse_sequence, reserve_ids, sequence_key, unix_millis, unix_secs_deadline,
+    Clock, Durability, KvsEngine, SystemClock, REMOVE_PREFIX_CHUNK,
+};
 use crate::error::KvsError;
 
+use std::ops::Range;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Name of the sled tree with the expiry deadlines
+const EXPIRY_TREE: &str = "expiry";
 
 #[derive(Clone)]
 pub struct SledStore {
     db: sled::Db,
+    /// Expiry deadlines in milliseconds since the Unix epoch, big endian, kept apart from the values
+    expiry: sled::Tree,
+    /// Held by plain writes and `update`, sled may call its own update closures more than once
+    write_lock: Arc<Mutex<()>>,
+    durability: Durability,
+    /// Time source of the expiry deadlines and of the checks of them
+    clock: Arc<dyn Clock>,
 }
 
 impl SledStore {
+    /// Values that expired while the storage was closed are removed
     pub fn open(path: &Path) -> Result<SledStore> {
-        Ok(SledStore {
-            db: sled::open(path)?,
-        })
+        SledStore::open_with_durability(path, Durability::default())
+    }
+
+    /// Opens the storage writing with `durability`, `Fsync` flushes sled on each write
+    pub fn open_with_durability(path: &Path, durability: Durability) -> Result<SledStore> {
+        SledStore::open_with(path, durability, Arc::new(SystemClock))
+    }
+
+    /// Opens the storage reading the time of the expiries from `clock`,
+    /// a `MockClock` lets tests expire keys without waiting
+    pub fn open_with_clock(path: &Path, clock: Arc<dyn Clock>) -> Result<SledStore> {
+        SledStore::open_with(path, Durability::default(), clock)
+    }
+
+    fn open_with(path: &Path, durability: Durability, clock: Arc<dyn Clock>) -> Result<SledStore> {
+        let db = sled::open(path)?;
+        let store = SledStore {
+            expiry: db.open_tree(EXPIRY_TREE)?,
+            db,
+            write_lock: Arc::new(Mutex::new(())),
+            durability,
+            clock,
+        };
+        store.purge_expired()?;
+        Ok(store)
+    }
+
+    /// Removes the expired keys with their values, which reads otherwise only skip
+    /// Returns the number of keys removed
+    pub fn purge_expired(&self) -> Result<usize> {
+        let _guard = self.write_lock.lock().unwrap();
+        let now = self.now_millis();
+        let mut expired = sled::Batch::default();
+        let mut expiry_batch = sled::Batch::default();
+        let mut count = 0;
+        for entry in self.expiry.iter() {
+            let (key, expires_at) = entry?;
+            if parse_deadline(&expires_at) <= now {
+                expired.remove(key.clone());
+                expiry_batch.remove(key);
+                count += 1;
+            }
+        }
+        if count > 0 {
+            self.db.apply_batch(expired)?;
+            self.expiry.apply_batch(expiry_batch)?;
+            self.end_write()?;
+        }
+        Ok(count)
+    }
+
+    /// Flushes sled with `Durability::Fsync`, otherwise sled flushes on its own
+    fn end_write(&self) -> Result<()> {
+        if self.durability == Durability::Fsync {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Stores the deadline of the key, `expires_at` milliseconds since the Unix epoch
+    /// Removes the key instead if the deadline already passed
+    fn expire_until(&self, key: String, expires_at: u64) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap();
+        if !self.contains_key(key.clone())? {
+            return Ok(false);
+        }
+        if expires_at <= self.now_millis() {
+            self.expiry.remove(&key)?;
+            self.db.remove(key)?;
+        } else {
+            self.expiry.insert(key, &expires_at.to_be_bytes())?;
+        }
+        self.end_write()?;
+        Ok(true)
+    }
+
+    /// Returns the bytes of the value as stored, `get` fails with `KvsError::Utf8` on a value
+    /// that isn't valid UTF-8, which only a tree written by another tool than kvs can hold
+    pub fn get_raw(&self, key: String) -> Result<Option<Vec<u8>>> {
+        if self.is_expired(&key)? {
+            return Ok(None);
+        }
+        Ok(self.db.get(&key)?.map(|v| v.to_vec()))
+    }
+
+    /// Current time of the clock, in milliseconds since the Unix epoch
+    fn now_millis(&self) -> u64 {
+        unix_millis(self.clock.now())
+    }
+
+    fn is_expired(&self, key: &str) -> Result<bool> {
+        match self.expiry.get(key)? {
+            Some(expires_at) => Ok(parse_deadline(&expires_at) <= self.now_millis()),
+            None => Ok(false),
+        }
     }
 }
 
 impl KvsEngine for SledStore {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, value))
+    )]
     fn set(&self, key: String, value: String) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.expiry.remove(&key)?;
         self.db.insert(key, value.as_bytes().to_vec())?;
-        self.db.flush()?;
+        self.end_write()?;
         Ok(())
     }
 
+    /// Fails with `KvsError::Utf8` if the value isn't valid UTF-8, see `get_raw`
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     fn get(&self, key: String) -> Result<Option<String>> {
+        if self.is_expired(&key)? {
+            return Ok(None);
+        }
         let value = self.db.get(&key)?;
         match value {
             Some(v) => Ok(Some(String::from_utf8(v.to_vec())?)),
@@ -32,9 +154,304 @@ impl KvsEngine for SledStore {
         }
     }
 
+    /// Swaps the value in only where sled has none, an expired key is dropped first
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap();
+        if self.is_expired(&key)? {
+            self.expiry.remove(&key)?;
+            self.db.remove(&key)?;
+        }
+        let swapped = self
+            .db
+            .compare_and_swap(&key, None as Option<&[u8]>, Some(value.as_bytes()))?
+            .is_ok();
+        self.end_write()?;
+        Ok(swapped)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     fn remove(&self, key: String) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let expired = self.is_expired(&key)?;
+        self.expiry.remove(&key)?;
         self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.end_write()?;
+        if expired {
+            return Err(KvsError::KeyNotFound);
+        }
+        Ok(())
+    }
+
+    /// The value comes back from the removal itself, an expired one is dropped as missing
+    fn get_and_remove(&self, key: String) -> Result<Option<String>> {
+        let _guard = self.write_lock.lock().unwrap();
+        let expired = self.is_expired(&key)?;
+        self.expiry.remove(&key)?;
+        let old = self.db.remove(key)?;
+        self.end_write()?;
+        match old {
+            Some(value) if !expired => Ok(Some(String::from_utf8(value.to_vec())?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        let _guard = self.write_lock.lock().unwrap();
+        let new = f(self.get(key.clone())?);
+        self.expiry.remove(&key)?;
+        match &new {
+            Some(value) => {
+                self.db.insert(key, value.as_bytes().to_vec())?;
+            }
+            None => {
+                self.db.remove(key)?;
+            }
+        }
+        self.end_write()?;
+        Ok(new)
+    }
+
+    /// The deadline goes to its own tree, the value is left untouched
+    fn expire(&self, key: String, ttl_secs: u64) -> Result<bool> {
+        self.expire_until(key, expiry_deadline(self.now_millis(), ttl_secs))
+    }
+
+    fn expire_at(&self, key: String, unix_secs: u64) -> Result<bool> {
+        self.expire_until(key, unix_secs_deadline(unix_secs))
+    }
+
+    fn expires_at(&self, key: String) -> Result<Option<u64>> {
+        if self.is_expired(&key)? || !self.db.contains_key(&key)? {
+            return Ok(None);
+        }
+        Ok(self.expiry.get(&key)?.map(|x| parse_deadline(&x)))
+    }
+
+    /// Only keys with the literal prefix of the pattern are scanned, sled keeps them together
+    fn expire_matching(&self, pattern: &str, ttl_secs: u64) -> Result<usize> {
+        let mut keys = Vec::new();
+        for key in self.db.scan_prefix(glob::literal_prefix(pattern)).keys() {
+            keys.push(String::from_utf8(key?.to_vec())?);
+        }
+        expire_matching_keys(self, pattern, keys, ttl_secs)
+    }
+
+    /// Sled keeps its keys sorted, only the keys starting with the literal prefix are read
+    fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for key in self.db.scan_prefix(glob::literal_prefix(pattern)).keys() {
+            let key = String::from_utf8(key?.to_vec())?;
+            if !is_sequence_key(&key) && glob::matches(pattern, &key) && !self.is_expired(&key)? {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Sled keeps its keys sorted, the scan stops at the limit
+    fn scan_prefix(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for entry in self.db.scan_prefix(prefix) {
+            if pairs.len() >= limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            let (key, value) = entry?;
+            let key = String::from_utf8(key.to_vec())?;
+            if self.is_expired(&key)? {
+                continue;
+            }
+            pairs.push((key, String::from_utf8(value.to_vec())?));
+        }
+        Ok(pairs)
+    }
+
+    /// The expiry lives in its own tree, so it is the only thing written
+    fn touch(&self, key: String, new_ttl: Duration) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap();
+        if !self.contains_key(key.clone())? {
+            return Ok(false);
+        }
+        self.expiry.insert(
+            key,
+            &deadline_after(self.now_millis(), new_ttl).to_be_bytes(),
+        )?;
+        self.end_write()?;
+        Ok(true)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(!self.is_expired(&key)? && self.db.contains_key(key)?)
+    }
+
+    /// Walks the keys, sled doesn't keep a count
+    fn key_count(&self) -> Result<u64> {
+        let mut count = 0;
+        for key in self.db.iter().keys() {
+            let key = String::from_utf8(key?.to_vec())?;
+            if !is_sequence_key(&key) && !self.is_expired(&key)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Removes the keys in sled batches of `REMOVE_PREFIX_CHUNK` keys under the write lock,
+    /// so other writes get the lock in between, only the keys are read
+    fn remove_prefix(&self, prefix: String) -> Result<u64> {
+        let mut keys = Vec::new();
+        for key in self.db.scan_prefix(&prefix).keys() {
+            let key = String::from_utf8(key?.to_vec())?;
+            if !is_sequence_key(&key) {
+                keys.push(key);
+            }
+        }
+        let mut removed = 0;
+        for chunk in keys.chunks(REMOVE_PREFIX_CHUNK) {
+            let _guard = self.write_lock.lock().unwrap();
+            let mut batch = sled::Batch::default();
+            let mut expiry_batch = sled::Batch::default();
+            for key in chunk {
+                // Removed meanwhile, an expired key is dropped without being counted
+                if self.contains_key(key.clone())? {
+                    removed += 1;
+                }
+                batch.remove(key.as_bytes());
+                expiry_batch.remove(key.as_bytes());
+            }
+            self.db.apply_batch(batch)?;
+            self.expiry.apply_batch(expiry_batch)?;
+            self.end_write()?;
+        }
+        Ok(removed)
+    }
+
+    /// Applies all the pairs as one atomic sled batch with a single flush
+    fn extend<I>(&self, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut batch = sled::Batch::default();
+        let mut expiry_batch = sled::Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.as_bytes());
+            expiry_batch.remove(key.as_bytes());
+        }
+        let _guard = self.write_lock.lock().unwrap();
+        self.expiry.apply_batch(expiry_batch)?;
+        self.db.apply_batch(batch)?;
+        self.end_write()?;
+        Ok(())
+    }
+
+    fn export<F>(&self, mut visit: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            let key = String::from_utf8(key.to_vec())?;
+            if self.is_expired(&key)? {
+                continue;
+            }
+            visit(key, String::from_utf8(value.to_vec())?)?;
+        }
+        Ok(())
+    }
+
+    fn next_id_batch(&self, name: &str, count: u64) -> Result<Range<u64>> {
+        // Malformed value, or a sequence without ids left, is left untouched and reported after the update
+        let old = self.db.fetch_and_update(sequence_key(name), |old| {
+            let last = match old {
+                None => 0,
+                Some(old) => match parse_sequence_bytes(old) {
+                    Some(last) => last,
+                    None => return Some(old.to_vec()),
+                },
+            };
+            match reserve_ids(last, count) {
+                Ok(ids) => Some((ids.end - 1).to_string().into_bytes()),
+                Err(_) => old.map(|old| old.to_vec()),
+            }
+        })?;
+        let last = match old {
+            Some(old) => parse_sequence(&String::from_utf8(old.to_vec())?)?,
+            None => 0,
+        };
+        let ids = reserve_ids(last, count)?;
+        self.end_write()?;
+        Ok(ids)
+    }
+
+    fn sync(&self) -> Result<()> {
         self.db.flush()?;
         Ok(())
     }
+
+    /// Flushes before dropping the db, instead of leaving it to the drop of the last clone
+    fn close(self) -> Result<()> {
+        self.db.flush()?;
+        drop(self.db);
+        Ok(())
+    }
+
+    /// Sequences are bumped without the write lock, a checkpoint may hold an id batch taken during it
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        create_checkpoint_folder(dest)?;
+        let copy = SledStore::open(dest)?;
+        {
+            let _guard = self.write_lock.lock().unwrap();
+            self.export_expiring(|key, value, expires_at| {
+                copy.set_expiring(key, value, expires_at)
+            })?;
+        }
+        copy.close()
+    }
+
+    /// Full scan over the tree, sled pages everything it touches into its own cache
+    fn warmup(&self) -> Result<()> {
+        for entry in self.db.iter() {
+            entry?;
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+}
+
+impl Rewrite for SledStore {
+    fn export_expiring<F>(&self, mut visit: F) -> Result<()>
+    where
+        F: FnMut(String, String, Option<u64>) -> Result<()>,
+    {
+        self.export(|key, value| {
+            let expires_at = self.expiry.get(&key)?.map(|x| parse_deadline(&x));
+            visit(key, value, expires_at)
+        })
+    }
+
+    fn set_expiring(&self, key: String, value: String, expires_at: Option<u64>) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        match expires_at {
+            Some(expires_at) => self.expiry.insert(&key, &expires_at.to_be_bytes())?,
+            None => self.expiry.remove(&key)?,
+        };
+        self.db.insert(key, value.as_bytes())?;
+        self.end_write()?;
+        Ok(())
+    }
+}
+
+fn parse_deadline(value: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&value[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn parse_sequence_bytes(value: &[u8]) -> Option<u64> {
+    std::str::from_utf8(value).ok()?.parse::<u64>().ok()
 }
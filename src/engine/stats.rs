@@ -0,0 +1,30 @@
+/// Snapshot of the storage state
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Number of live keys
+    pub keys: u64,
+    /// Max number of keys, `None` if not limited
+    pub max_keys: Option<u64>,
+    /// Number of log files in the storage folder
+    pub log_files: u64,
+    /// Size in bytes of redundant commands waiting for compaction
+    pub uncompacted_size: u64,
+    /// Size in bytes of the live records as they are serialized
+    pub logical_bytes: u64,
+    /// Size in bytes of the log files on disk, includes redundant commands
+    /// `physical_bytes / logical_bytes` shows the space overhead, records aren't compressed
+    pub physical_bytes: u64,
+    /// Files replaced by compaction or blob GC still on disk, waiting for the reads that may use them
+    /// or for another try after their removal failed
+    pub retired_files: u64,
+    /// Removals of retired files that failed since the storage was opened
+    pub failed_removals: u64,
+    /// Keys whose records the warm-up on open has read so far, see `EngineConfig::warm_cache_keys`
+    pub warmed_keys: u64,
+    /// Whether the warm-up is over, also set when there was none to do
+    pub warm_up_done: bool,
+    /// Number of compactions finished since the storage was opened
+    pub compactions: u64,
+    /// Writes waiting for or holding the write lock, see `EngineConfig::max_inflight_writes`
+    pub inflight_writes: u64,
+}
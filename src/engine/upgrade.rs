@@ -0,0 +1,155 @@
+use crate::common::{EngineType, Result};
+use crate::engine::{KvsEngine, LogStructKVStore, SledStore};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// File an upgrade creates in its staging folder, the staging folder of another one is never touched
+const MARKER_FILENAME: &str = "UPGRADE";
+/// Content of the marker once the staging folder holds the whole storage
+const COMPLETE: &str = "complete";
+
+/// What an upgrade carries over beyond the pairs of `KvsEngine::export`
+pub(crate) trait Rewrite: KvsEngine {
+    /// Calls `visit` with every live pair and its deadline in milliseconds since the Unix epoch
+    fn export_expiring<F>(&self, visit: F) -> Result<()>
+    where
+        F: FnMut(String, String, Option<u64>) -> Result<()>;
+
+    /// Sets `key` to `value`, expiring at `expires_at` if given
+    fn set_expiring(&self, key: String, value: String, expires_at: Option<u64>) -> Result<()>;
+}
+
+/// Rewrites the closed storage at `path`, written by `from_engine`, in the format the engine writes now
+/// A `LogStructKVStore` of an older on-disk format is read with the old format and written with the
+/// new one, a sled storage is copied as sled writes it now
+/// The storage is copied into the sibling folder `<dir>.upgrade`, which then takes its place
+/// Dot files of the folder, like the `.engine` marker of kvs-server, are moved along
+/// Running it again after a crash finishes the upgrade: a crash between the two renames
+/// leaves the storage in `<dir>.old` and its complete copy in `<dir>.upgrade`
+pub fn upgrade_format(path: &Path, from_engine: EngineType) -> Result<()> {
+    let path = canonicalize_missing(path)?;
+    let staging = sibling(&path, "upgrade");
+    let retired = sibling(&path, "old");
+    if retired.exists() {
+        return resume(&path, &staging, &retired);
+    }
+    if staging.exists() {
+        if !staging.join(MARKER_FILENAME).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} wasn't created by an upgrade", staging.display()),
+            )
+            .into());
+        }
+        // Left by an upgrade that didn't finish copying, the storage itself was untouched
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir(&staging)?;
+    write_marker(&staging, "")?;
+    match from_engine {
+        EngineType::Kvs => rewrite(
+            LogStructKVStore::open_any_format(&path)?,
+            LogStructKVStore::open(&staging)?,
+        )?,
+        EngineType::Sled => rewrite(SledStore::open(&path)?, SledStore::open(&staging)?)?,
+    }
+    write_marker(&staging, COMPLETE)?;
+    for entry in fs::read_dir(&path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') && !staging.join(&name).exists() {
+            fs::rename(entry.path(), staging.join(&name))?;
+        }
+    }
+    fs::rename(&path, &retired)?;
+    fs::rename(&staging, &path)?;
+    sync_parent(&path)?;
+    finish(&path, &retired)
+}
+
+/// Finishes an upgrade that crashed after the storage was moved to `retired`
+fn resume(path: &Path, staging: &Path, retired: &Path) -> Result<()> {
+    if path.exists() {
+        // Crashed after the copy took the place of the storage
+        if !is_complete(path)? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is left by another upgrade", retired.display()),
+            )
+            .into());
+        }
+    } else if is_complete(staging)? {
+        fs::rename(staging, path)?;
+        sync_parent(path)?;
+    } else {
+        // The copy can't be used, the storage goes back in its place
+        fs::rename(retired, path)?;
+        return sync_parent(path);
+    }
+    finish(path, retired)
+}
+
+/// Removes the old storage, then the marker, the storage at `path` is the upgraded one
+fn finish(path: &Path, retired: &Path) -> Result<()> {
+    fs::remove_dir_all(retired)?;
+    fs::remove_file(path.join(MARKER_FILENAME))?;
+    Ok(())
+}
+
+/// Whether `folder` is the staging folder of an upgrade that copied the whole storage
+fn is_complete(folder: &Path) -> Result<bool> {
+    match fs::read_to_string(folder.join(MARKER_FILENAME)) {
+        Ok(marker) => Ok(marker == COMPLETE),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes the marker of the staging folder and syncs it, so a crash never leaves a stale one
+fn write_marker(staging: &Path, content: &str) -> Result<()> {
+    let mut file = fs::File::create(staging.join(MARKER_FILENAME))?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::File::open(staging)?.sync_all()?;
+    Ok(())
+}
+
+/// Syncs the folder holding `path`, so its renames survive a crash
+fn sync_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Copies the live pairs with their expiry, expired ones are left behind
+fn rewrite<S: Rewrite, T: Rewrite>(source: S, target: T) -> Result<()> {
+    source.export_expiring(|key, value, expires_at| target.set_expiring(key, value, expires_at))?;
+    target.close()?;
+    source.close()
+}
+
+/// Absolute `path`, which may be missing after a crash between the renames, its parent can't
+fn canonicalize_missing(path: &Path) -> Result<PathBuf> {
+    match path.canonicalize() {
+        Ok(path) => Ok(path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.canonicalize()?,
+                _ => std::env::current_dir()?,
+            };
+            Ok(parent.join(path.file_name().unwrap_or_default()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `path` with `.suffix` appended to its last component
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
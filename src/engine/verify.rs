@@ -0,0 +1,56 @@
+use std::path::PathBuf;
+
+/// Result of checking the index against the log files
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// Number of checked keys
+    pub checked: u64,
+    /// Keys pointing to a record that can't be read or decoded
+    pub corrupted: Vec<String>,
+    /// Keys pointing to a record of another key or to a non set command
+    pub mismatched: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupted.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Result of the full self-check of the index against the log files
+#[derive(Clone, Debug, Default)]
+pub struct FsckReport {
+    /// Number of checked keys of the index
+    pub checked: u64,
+    /// Keys pointing past the end of their log or to a log that doesn't exist
+    pub orphaned: Vec<String>,
+    /// Keys pointing to a record that can't be read or decoded
+    pub corrupted: Vec<String>,
+    /// Keys pointing to a record of another key or to a non set command
+    pub mismatched: Vec<String>,
+    /// Keys set by the last record of the logs for them but missing from the index
+    pub unindexed: Vec<String>,
+    /// Logs with an unreadable tail and the position where reading stopped
+    pub unreadable_tails: Vec<(PathBuf, u64)>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.orphaned.is_empty()
+            && self.corrupted.is_empty()
+            && self.mismatched.is_empty()
+            && self.unindexed.is_empty()
+            && self.unreadable_tails.is_empty()
+    }
+}
+
+/// Result of rebuilding a storage folder from its log files
+#[derive(Clone, Debug, Default)]
+pub struct RepairReport {
+    /// Number of keys written to the rebuilt log
+    pub keys: u64,
+    /// Number of dropped records with an unexpected command type
+    pub skipped: u64,
+    /// Logs with an unreadable tail: position where reading stopped and number of dropped bytes
+    pub truncated: Vec<(PathBuf, u64, u64)>,
+}
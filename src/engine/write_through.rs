@@ -0,0 +1,207 @@
+use crate::common::Result;
+use crate::engine::{sequence_key, KvsEngine};
+use crate::error::KvsError;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Locks of `WriteThrough`, a key maps to one of them by its hash
+const LOCK_STRIPES: usize = 64;
+
+/// Engine reading through a fast `cache` engine in front of a durable `backing` one
+/// Writes go to the backing engine first, then to the cache, a get missing the cache
+/// reads the backing engine and puts the value in the cache
+/// A cache hit is served without looking at the backing engine, so every write and expiry
+/// must go through the `WriteThrough`: it updates or drops the cached value, a change made
+/// to the backing engine directly stays hidden while the key is cached
+/// Keys with an expiry aren't cached, they are read from the backing engine
+/// The backing engine holds the truth, scans, counts, sequences and checkpoints go to it alone
+#[derive(Clone)]
+pub struct WriteThrough<C, B> {
+    cache: C,
+    backing: B,
+    /// Held by the writes of a key and by the fills of the cache with it,
+    /// so a fill never puts back a stale value
+    locks: Arc<Vec<Mutex<()>>>,
+}
+
+impl<C: KvsEngine, B: KvsEngine> WriteThrough<C, B> {
+    pub fn new(cache: C, backing: B) -> WriteThrough<C, B> {
+        WriteThrough {
+            cache,
+            backing,
+            locks: Arc::new((0..LOCK_STRIPES).map(|_| Mutex::new(())).collect()),
+        }
+    }
+
+    /// Lock of the stripe of `key`
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.locks[hasher.finish() as usize % LOCK_STRIPES]
+            .lock()
+            .unwrap()
+    }
+
+    /// Locks of every stripe, for the writes of many keys, taken in order
+    fn lock_all(&self) -> Vec<MutexGuard<'_, ()>> {
+        self.locks.iter().map(|lock| lock.lock().unwrap()).collect()
+    }
+
+    /// Drops `key` from the cache, the next get reads it from the backing engine
+    fn evict(&self, key: String) -> Result<()> {
+        match self.cache.remove(key) {
+            Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<C: KvsEngine, B: KvsEngine> KvsEngine for WriteThrough<C, B> {
+    /// A set clears the expiry of the key, so the value is cached
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let _guard = self.lock(&key);
+        self.backing.set(key.clone(), value.clone())?;
+        self.cache.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.cache.get(key.clone())? {
+            return Ok(Some(value));
+        }
+        let _guard = self.lock(&key);
+        let value = self.backing.get(key.clone())?;
+        if let Some(value) = &value {
+            if self.backing.expires_at(key.clone())?.is_none() {
+                self.cache.set(key, value.clone())?;
+            }
+        }
+        Ok(value)
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let _guard = self.lock(&key);
+        if !self.backing.set_if_absent(key.clone(), value.clone())? {
+            return Ok(false);
+        }
+        self.cache.set(key, value)?;
+        Ok(true)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let _guard = self.lock(&key);
+        self.backing.remove(key.clone())?;
+        self.evict(key)
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        let _guard = self.lock(&key);
+        let new = self.backing.update(key.clone(), f)?;
+        // The key keeps an expiry it had, the next get caches it if it has none
+        self.evict(key)?;
+        Ok(new)
+    }
+
+    fn remove_prefix(&self, prefix: String) -> Result<u64> {
+        let _guards = self.lock_all();
+        let removed = self.backing.remove_prefix(prefix.clone())?;
+        self.cache.remove_prefix(prefix)?;
+        Ok(removed)
+    }
+
+    /// The expiry is kept by the backing engine, the key is dropped from the cache
+    fn expire(&self, key: String, ttl_secs: u64) -> Result<bool> {
+        let _guard = self.lock(&key);
+        let existed = self.backing.expire(key.clone(), ttl_secs)?;
+        self.evict(key)?;
+        Ok(existed)
+    }
+
+    fn expire_at(&self, key: String, unix_secs: u64) -> Result<bool> {
+        let _guard = self.lock(&key);
+        let existed = self.backing.expire_at(key.clone(), unix_secs)?;
+        self.evict(key)?;
+        Ok(existed)
+    }
+
+    fn expires_at(&self, key: String) -> Result<Option<u64>> {
+        self.backing.expires_at(key)
+    }
+
+    fn expire_matching(&self, pattern: &str, ttl_secs: u64) -> Result<usize> {
+        let _guards = self.lock_all();
+        let expired = self.backing.expire_matching(pattern, ttl_secs)?;
+        for key in self.cache.keys_matching(pattern)? {
+            self.evict(key)?;
+        }
+        Ok(expired)
+    }
+
+    fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        self.backing.keys_matching(pattern)
+    }
+
+    fn count_matching(&self, pattern: &str) -> Result<u64> {
+        self.backing.count_matching(pattern)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        self.backing.contains_key(key)
+    }
+
+    fn key_count(&self) -> Result<u64> {
+        self.backing.key_count()
+    }
+
+    /// The sequence key is dropped from the cache, its value there would be stale
+    fn next_id_batch(&self, name: &str, count: u64) -> Result<Range<u64>> {
+        let key = sequence_key(name);
+        let _guard = self.lock(&key);
+        let ids = self.backing.next_id_batch(name, count)?;
+        self.evict(key)?;
+        Ok(ids)
+    }
+
+    fn export<F>(&self, visit: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.backing.export(visit)
+    }
+
+    fn scan_prefix(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        self.backing.scan_prefix(prefix, limit)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.backing.flush()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.backing.sync()
+    }
+
+    /// Copies the backing engine only, the copy is one of the backing engine
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        self.backing.checkpoint(dest)
+    }
+
+    fn warmup(&self) -> Result<()> {
+        self.backing.warmup()
+    }
+
+    fn cancel_compaction(&self) {
+        self.cache.cancel_compaction();
+        self.backing.cancel_compaction();
+    }
+
+    fn name(&self) -> &'static str {
+        "write-through"
+    }
+}
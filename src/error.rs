@@ -1,3 +1,6 @@
+// The impls of the `Fail` derive live in an anonymous const
+#![allow(non_local_definitions)]
+
 use bincode::Error;
 use failure::Fail;
 use std::io;
@@ -13,6 +16,37 @@ pub enum KvsError {
     UnexpectedCommandType,
     #[fail(display = "Bad log file")]
     BadLogFile,
+    #[fail(display = "Value is not an integer")]
+    NotAnInteger,
+    #[fail(display = "Quota of keys exceeded")]
+    QuotaExceeded,
+    #[fail(display = "Command is not supported by the protocol version of the connection")]
+    UnsupportedCommand,
+    #[fail(display = "Unknown setting or invalid value")]
+    InvalidSetting,
+    #[fail(display = "Setting can't be changed on this server")]
+    ReadOnlySetting,
+    #[fail(display = "Version of the key doesn't match the expected one")]
+    VersionConflict,
+    #[fail(display = "Server is too busy to take the connection")]
+    ServerBusy,
+    #[fail(display = "Storage folder is already open")]
+    AlreadyOpen,
+    #[fail(display = "Too many writes in flight")]
+    Busy,
+    #[fail(display = "Rate limit of the connection exceeded")]
+    RateLimited,
+    #[fail(display = "Keys starting with __seq: are reserved for the id sequences")]
+    ReservedKey,
+    #[fail(display = "Sequence has no ids left")]
+    SequenceExhausted,
+    #[fail(
+        display = "Storage is in on-disk format {}, this build writes {}, see kvs-admin upgrade",
+        found, current
+    )]
+    IncompatibleFormat { found: u32, current: u32 },
+    #[fail(display = "Server error {}", _0)]
+    Server(String),
     #[fail(display = "Error with de/serialization  {}", _0)]
     Bincode(#[cause] bincode::Error),
     #[fail(display = "Error with sled storage  {}", _0)]
@@ -21,6 +55,10 @@ pub enum KvsError {
     Io(#[cause] io::Error),
     #[fail(display = "Problem with Utf8 {}", _0)]
     Utf8(#[cause] FromUtf8Error),
+    #[fail(display = "Invalid config {}", _0)]
+    Config(#[cause] toml::de::Error),
+    #[fail(display = "Error with JSON {}", _0)]
+    Json(#[cause] serde_json::Error),
 }
 
 impl From<bincode::Error> for KvsError {
@@ -46,3 +84,15 @@ impl From<FromUtf8Error> for KvsError {
         KvsError::Utf8(err)
     }
 }
+
+impl From<toml::de::Error> for KvsError {
+    fn from(err: toml::de::Error) -> Self {
+        KvsError::Config(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> Self {
+        KvsError::Json(err)
+    }
+}
@@ -1,6 +1,8 @@
 pub mod client;
 pub mod common;
+pub mod config;
 pub mod engine;
 pub mod error;
+pub mod protocol;
 pub mod server;
 pub mod thread_pool;
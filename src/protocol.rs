@@ -0,0 +1,188 @@
+use crate::common::{Command, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+use zstd::zstd_safe::DCtx;
+
+/// zstd level of the connection compression, favours speed
+const COMPRESSION_LEVEL: i32 = 1;
+
+/// Newest protocol version spoken by this build
+/// 1: set, get and rm
+/// 2: exists and next-id
+/// 3: expire
+/// 4: config
+/// 5: touch
+/// 6: mget, multi-key responses
+/// 7: expire-at
+/// 8: getdel
+/// 9: scan
+/// 10: dbsize and flushall
+/// 11: info
+/// 12: replace
+/// 13: setnx
+/// 14: rm-prefix
+/// 15: keys
+pub const PROTOCOL_VERSION: u32 = 15;
+
+/// Version of a `Welcome` turning the connection down because the server is overloaded
+pub const BUSY_VERSION: u32 = 0;
+
+/// First message of a connection, sent by the client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    /// Client wants the rest of the connection to be compressed
+    pub compress: bool,
+    /// Newest protocol version the client speaks
+    pub version: u32,
+}
+
+/// Server answer to `Hello`, settles the options of the connection
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Welcome {
+    /// Both sides compress the rest of the connection
+    pub compress: bool,
+    /// Protocol version both sides speak for the rest of the connection
+    pub version: u32,
+}
+
+/// Version used by a connection, the newest one both sides speak
+pub fn negotiate_version(client_version: u32, server_version: u32) -> u32 {
+    client_version.min(server_version)
+}
+
+/// Oldest protocol version that has the command
+pub fn min_version(cmd: &Command) -> u32 {
+    match cmd {
+        Command::Set { .. } | Command::Get { .. } | Command::Rm { .. } => 1,
+        Command::Exists { .. } | Command::NextId { .. } => 2,
+        Command::Expire { .. } => 3,
+        Command::Config { .. } => 4,
+        Command::Touch { .. } => 5,
+        Command::MGet { .. } => 6,
+        Command::ExpireAt { .. } => 7,
+        Command::GetDel { .. } => 8,
+        Command::ScanPrefix { .. } => 9,
+        Command::DbSize | Command::FlushAll => 10,
+        Command::Info => 11,
+        Command::Replace { .. } => 12,
+        Command::SetNx { .. } => 13,
+        Command::RmPrefix { .. } => 14,
+        Command::Keys { .. } => 15,
+        Command::Reserved6(tag)
+        | Command::Reserved8(tag)
+        | Command::Reserved9(tag)
+        | Command::Reserved10(tag)
+        | Command::Reserved11(tag)
+        | Command::Reserved14(tag)
+        | Command::Reserved20(tag) => match *tag {},
+    }
+}
+
+/// Read half of a connection that can tell whether more input already arrived
+pub trait BufferedRead: Read + Send {
+    /// Whether input is ready to be read without waiting on the socket
+    fn has_buffered(&mut self) -> io::Result<bool>;
+}
+
+impl BufferedRead for BufReader<TcpStream> {
+    fn has_buffered(&mut self) -> io::Result<bool> {
+        Ok(!self.buffer().is_empty())
+    }
+}
+
+/// Decompresses the zstd stream of a connection
+/// Unlike `zstd::stream::read::Decoder` it keeps the decoded bytes itself, so it can tell
+/// whether any of them are ready: received bytes of an incomplete block don't count
+pub struct DecompressReader {
+    reader: BufReader<TcpStream>,
+    decoder: zstd::stream::raw::Decoder<'static>,
+    decoded: Vec<u8>,
+    pos: usize,
+    end: usize,
+}
+
+impl DecompressReader {
+    pub fn new(reader: BufReader<TcpStream>) -> Result<DecompressReader> {
+        Ok(DecompressReader {
+            reader,
+            decoder: zstd::stream::raw::Decoder::new()?,
+            decoded: vec![0; DCtx::out_size()],
+            pos: 0,
+            end: 0,
+        })
+    }
+
+    /// Decodes the bytes already received, without reading the socket
+    fn decode_received(&mut self) -> io::Result<()> {
+        self.pos = 0;
+        self.end = 0;
+        loop {
+            let mut input = InBuffer::around(self.reader.buffer());
+            let mut output = OutBuffer::around(&mut self.decoded[..]);
+            self.decoder.run(&mut input, &mut output)?;
+            let (read, written) = (input.pos(), output.pos());
+            self.reader.consume(read);
+            self.end = written;
+            if written > 0 || read == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Read for DecompressReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.end {
+            self.decode_received()?;
+            if self.pos == self.end && self.reader.fill_buf()?.is_empty() {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.end - self.pos);
+        buf[..len].copy_from_slice(&self.decoded[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl BufferedRead for DecompressReader {
+    fn has_buffered(&mut self) -> io::Result<bool> {
+        if self.pos == self.end {
+            self.decode_received()?;
+        }
+        Ok(self.pos < self.end)
+    }
+}
+
+impl<T: BufferedRead + ?Sized> BufferedRead for Box<T> {
+    fn has_buffered(&mut self) -> io::Result<bool> {
+        (**self).has_buffered()
+    }
+}
+
+pub type StreamReader = Box<dyn BufferedRead>;
+pub type StreamWriter = Box<dyn Write + Send>;
+
+/// Creates buffered halves of a connection
+/// With `compress` the whole byte stream is wrapped in zstd, not single messages,
+/// each flush ends a zstd block so the other side can decode everything written so far
+pub fn wrap_stream(stream: &TcpStream, compress: bool) -> Result<(StreamReader, StreamWriter)> {
+    let reader = BufReader::new(stream.try_clone()?);
+    if compress {
+        // The encoder buffers on its own and its flush does not reach an inner `BufWriter`
+        Ok((
+            Box::new(DecompressReader::new(reader)?),
+            Box::new(zstd::stream::write::Encoder::new(
+                stream.try_clone()?,
+                COMPRESSION_LEVEL,
+            )?),
+        ))
+    } else {
+        Ok((
+            Box::new(reader),
+            Box::new(BufWriter::new(stream.try_clone()?)),
+        ))
+    }
+}
@@ -1,50 +1,318 @@
-use crate::common::{Command, Response, Result};
-use crate::engine::KvsEngine;
+use crate::common::{Command, EngineType, Response, Result, ServerInfo};
+use crate::config::ServerConfig;
+use crate::engine::{is_sequence_key, Durability, KvsEngine, LogStructKVStore, SledStore};
 use crate::error::KvsError;
-use crate::thread_pool::ThreadPool;
+use crate::protocol::{
+    min_version, negotiate_version, wrap_stream, BufferedRead, Hello, Welcome, BUSY_VERSION,
+    PROTOCOL_VERSION,
+};
+use crate::thread_pool::{Job, RayonThreadPool, SharedQueueThreadPool, ThreadPool, ThreadPoolType};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+use slog::{o, warn, Discard, Logger};
+use socket2::SockRef;
 use std::io;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::io::{BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Error returned for commands received before the engine is opened
+pub const STARTING_UP: &str = "starting up";
+/// Error returned for removing a missing key, and for getting one with `MissingKeyResponse::Error`
+pub const KEY_NOT_FOUND: &str = "Key not found";
+
+/// First sleep of the accept loop once no connection is pending
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_micros(1);
+/// Default cap of the accept loop sleep, a new connection waits at most that long
+pub const DEFAULT_MAX_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+
+/// First sleep of the accept loop waiting for room in a full thread pool queue
+const MIN_OVERLOAD_BACKOFF: Duration = Duration::from_millis(1);
+/// Default time the accept loop waits for room in a full thread pool queue
+pub const DEFAULT_MAX_OVERLOAD_WAIT: Duration = Duration::from_millis(50);
+/// Default size in bytes of the responses a connection holds back before flushing
+pub const DEFAULT_RESPONSE_BATCH_BYTES: usize = 4 * 1024;
+/// Longest sleep of a connection after a command beyond its rate limit
+const MAX_RATE_LIMITED_SLEEP: Duration = Duration::from_millis(100);
+
+/// Setting of `Command::Config` holding the number of workers of the thread pool
+pub const NUM_THREADS_SETTING: &str = "num_threads";
+
+/// Engine shared by the connections, `None` while it is still being opened
+type SharedEngine<T> = Arc<Mutex<Option<T>>>;
+
+/// What the accept loop does with a new connection while the thread pool queue is full
+/// A connection turned down gets a busy answer to its handshake, the client fails with `ServerBusy`
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverloadPolicy {
+    /// Turns the connection down right away
+    #[clap(alias = "reject")]
+    Reject,
+    /// Waits for room with a doubling sleep up to the max overload wait, then turns it down
+    #[clap(alias = "retry")]
+    Retry,
+}
+
+/// How the server answers a `get` of a missing key
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingKeyResponse {
+    /// `Response::Err` with `KEY_NOT_FOUND`
+    #[clap(alias = "error")]
+    Error,
+    /// `Response::Ok` with an empty value, for clients that can't tell a miss apart anyway
+    #[clap(alias = "empty")]
+    Empty,
+    /// `Response::Ok` without a value, distinct from a key set to an empty value
+    #[clap(alias = "null")]
+    Null,
+}
+
+impl MissingKeyResponse {
+    fn response(self) -> Response {
+        match self {
+            MissingKeyResponse::Error => Response::Err(KEY_NOT_FOUND.to_string()),
+            MissingKeyResponse::Empty => Response::Ok(Some(String::new())),
+            MissingKeyResponse::Null => Response::Ok(None),
+        }
+    }
+}
 
 pub struct KvsServer<T, F> {
-    engine: T,
-    pool: F,
+    engine: SharedEngine<T>,
+    /// Failure of the background open of `KvsServer::opening`, returned by `run`
+    open_error: Arc<Mutex<Option<KvsError>>>,
+    pool: Arc<F>,
     shutdown_flag: Arc<AtomicBool>,
+    compress: bool,
+    protocol_version: u32,
+    max_accept_backoff: Duration,
+    idle_sleeps: Arc<AtomicU64>,
+    overload: OverloadPolicy,
+    max_overload_wait: Duration,
+    response_batch_bytes: usize,
+    nodelay: bool,
+    keepalive: bool,
+    max_ops_per_sec: Option<u32>,
+    missing_key_response: MissingKeyResponse,
+    max_threads: Option<u32>,
+    logger: Logger,
+    /// Creation of the server, the start of the uptime reported by `Command::Info`
+    started: Instant,
 }
 
 impl<T, F> KvsServer<T, F>
 where
     T: KvsEngine,
-    F: ThreadPool,
+    F: ThreadPool + Send + Sync + 'static,
 {
     pub fn new(engine: T, pool: F) -> Result<KvsServer<T, F>> {
         Ok(KvsServer {
-            engine,
-            pool,
+            engine: Arc::new(Mutex::new(Some(engine))),
+            open_error: Arc::new(Mutex::new(None)),
+            pool: Arc::new(pool),
             shutdown_flag: Arc::new(AtomicBool::new(false)),
+            compress: false,
+            protocol_version: PROTOCOL_VERSION,
+            max_accept_backoff: DEFAULT_MAX_ACCEPT_BACKOFF,
+            idle_sleeps: Arc::new(AtomicU64::new(0)),
+            overload: OverloadPolicy::Retry,
+            max_overload_wait: DEFAULT_MAX_OVERLOAD_WAIT,
+            response_batch_bytes: DEFAULT_RESPONSE_BATCH_BYTES,
+            nodelay: true,
+            keepalive: false,
+            max_ops_per_sec: None,
+            missing_key_response: MissingKeyResponse::Null,
+            max_threads: None,
+            logger: Logger::root(Discard, o!()),
+            started: Instant::now(),
+        })
+    }
+
+    /// Creates a server whose engine is opened by `open` in a background thread
+    /// Connections are accepted right away, commands get `STARTING_UP` errors until `open` returns
+    /// The server shuts down if `open` fails, `run` then returns the error
+    pub fn opening<O>(open: O, pool: F) -> Result<KvsServer<T, F>>
+    where
+        O: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let engine: SharedEngine<T> = Arc::new(Mutex::new(None));
+        let open_error = Arc::new(Mutex::new(None));
+        let shutdown_flag = Arc::new(AtomicBool::new(false));
+        {
+            let engine = Arc::clone(&engine);
+            let open_error = Arc::clone(&open_error);
+            let shutdown_flag = Arc::clone(&shutdown_flag);
+            thread::spawn(move || match open() {
+                Ok(opened) => *engine.lock().unwrap() = Some(opened),
+                Err(err) => {
+                    *open_error.lock().unwrap() = Some(err);
+                    shutdown_flag.store(true, Ordering::Relaxed);
+                }
+            });
+        }
+        Ok(KvsServer {
+            engine,
+            open_error,
+            pool: Arc::new(pool),
+            shutdown_flag,
+            compress: false,
+            protocol_version: PROTOCOL_VERSION,
+            max_accept_backoff: DEFAULT_MAX_ACCEPT_BACKOFF,
+            idle_sleeps: Arc::new(AtomicU64::new(0)),
+            overload: OverloadPolicy::Retry,
+            max_overload_wait: DEFAULT_MAX_OVERLOAD_WAIT,
+            response_batch_bytes: DEFAULT_RESPONSE_BATCH_BYTES,
+            nodelay: true,
+            keepalive: false,
+            max_ops_per_sec: None,
+            missing_key_response: MissingKeyResponse::Null,
+            max_threads: None,
+            logger: Logger::root(Discard, o!()),
+            started: Instant::now(),
         })
     }
 
+    /// Allows clients to ask for a compressed connection
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Speaks at most protocol `version`, for running as an older server
+    pub fn with_protocol_version(mut self, version: u32) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    /// Caps the sleep of the idle accept loop, bounding the delay of a new connection
+    pub fn with_max_accept_backoff(mut self, max_accept_backoff: Duration) -> Self {
+        self.max_accept_backoff = max_accept_backoff;
+        self
+    }
+
+    /// Sets what happens to a new connection while the thread pool queue is full,
+    /// `max_wait` only matters to `OverloadPolicy::Retry`
+    pub fn with_overload_policy(mut self, policy: OverloadPolicy, max_wait: Duration) -> Self {
+        self.overload = policy;
+        self.max_overload_wait = max_wait;
+        self
+    }
+
+    /// Holds responses back until `batch_bytes` of them are pending or the client sent
+    /// nothing more to answer, so a pipelined burst of small commands is flushed once
+    /// A response of `batch_bytes` or more is flushed right away, 0 flushes every response
+    pub fn with_response_batching(mut self, batch_bytes: usize) -> Self {
+        self.response_batch_bytes = batch_bytes;
+        self
+    }
+
+    /// Turns Nagle's algorithm off on the connections with `nodelay`, the default,
+    /// so a response goes out without waiting for the client to acknowledge the previous one
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Sends TCP keepalive probes on idle connections, with the timing of the system settings,
+    /// so the connection of a client that vanished fails and frees its thread
+    pub fn with_keepalive(mut self, keepalive: bool) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Lets each connection send at most `max_ops_per_sec` commands a second, with bursts of as many
+    /// The ones beyond get a `RateLimited` error and the connection sleeps a little,
+    /// `None` and 0 don't limit
+    pub fn with_rate_limit(mut self, max_ops_per_sec: Option<u32>) -> Self {
+        self.max_ops_per_sec = max_ops_per_sec;
+        self
+    }
+
+    /// Sets how a `get` of a missing key is answered, `MissingKeyResponse::Null` by default
+    pub fn with_missing_key_response(mut self, missing_key_response: MissingKeyResponse) -> Self {
+        self.missing_key_response = missing_key_response;
+        self
+    }
+
+    /// Lets clients resize the thread pool up to `max_threads` workers with `Command::Config`,
+    /// `None`, the default, refuses resizing
+    pub fn with_max_threads(mut self, max_threads: Option<u32>) -> Self {
+        self.max_threads = max_threads;
+        self
+    }
+
+    /// Logs the failures of the connections, which are dropped, nothing is logged by default
+    pub fn with_logger(mut self, logger: Logger) -> Self {
+        self.logger = logger;
+        self
+    }
+
+    /// Number of times the accept loop slept for lack of pending connections
+    /// Shared with the running loop, so it can be read while the server runs
+    pub fn idle_sleeps(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.idle_sleeps)
+    }
+
+    /// Accepts connections until shutdown
+    /// With nothing to accept the loop sleeps, doubling the sleep up to `max_accept_backoff`,
+    /// so an idle server stays near 0% CPU while a busy one accepts without delay
+    /// A full thread pool queue never blocks the loop, see `OverloadPolicy`
     pub fn run(&self, addr: &SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
         listener
             .set_nonblocking(true)
             .expect("Cannot set non-blocking");
+        let mut backoff = MIN_ACCEPT_BACKOFF;
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    let kv_store = self.engine.clone();
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    let engine = Arc::clone(&self.engine);
+                    let pool = Arc::clone(&self.pool);
                     let shutdown_flag = Arc::clone(&self.shutdown_flag);
-                    self.pool.spawn(move || {
-                        handle_stream(kv_store, stream, shutdown_flag).unwrap();
+                    let options = ConnectionOptions {
+                        compress: self.compress,
+                        version: self.protocol_version,
+                        response_batch_bytes: self.response_batch_bytes,
+                        nodelay: self.nodelay,
+                        keepalive: self.keepalive,
+                        max_ops_per_sec: self.max_ops_per_sec,
+                        missing_key_response: self.missing_key_response,
+                        max_threads: self.max_threads,
+                        started: self.started,
+                    };
+                    let busy_stream = match stream.try_clone() {
+                        Ok(busy_stream) => busy_stream,
+                        Err(_) => continue,
+                    };
+                    let logger = self.logger.clone();
+                    let job: Job = Box::new(move || {
+                        let peer = stream.peer_addr().ok();
+                        // Only this connection is affected, the stream is closed when dropped
+                        if let Err(err) =
+                            handle_stream(engine, pool, stream, shutdown_flag, options)
+                        {
+                            warn!(logger, "Connection dropped"; "peer" => ?peer, "error" => %err);
+                        }
                     });
+                    if self.dispatch(job).is_err() {
+                        // The client may be gone already, nothing to do about it
+                        reject_busy(busy_stream).unwrap_or(());
+                    }
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     if self.shutdown_flag.load(Ordering::Relaxed) {
                         break;
                     }
+                    thread::sleep(backoff);
+                    self.idle_sleeps.fetch_add(1, Ordering::Relaxed);
+                    backoff = (backoff * 2).min(self.max_accept_backoff);
                     continue;
                 }
                 // @TODO logging
@@ -52,24 +320,445 @@ where
             };
         }
         println!("Shutting down");
-        Ok(())
+        match self.open_error.lock().unwrap().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 
+    /// Also cancels a compaction of the engine in progress, which would hold the exit back
     pub fn shutdown(&self) {
         self.shutdown_flag.store(true, Ordering::Relaxed);
+        if let Some(engine) = self.engine.lock().unwrap().as_ref() {
+            engine.cancel_compaction();
+        }
+    }
+
+    /// Queues the job of a connection following the overload policy,
+    /// hands it back if the queue stays full or the server shuts down meanwhile
+    fn dispatch(&self, mut job: Job) -> std::result::Result<(), Job> {
+        let mut waited = Duration::default();
+        let mut backoff = MIN_OVERLOAD_BACKOFF;
+        loop {
+            job = match self.pool.try_spawn(job) {
+                Ok(()) => return Ok(()),
+                Err(job) => job,
+            };
+            if self.overload == OverloadPolicy::Reject
+                || waited >= self.max_overload_wait
+                || self.shutdown_flag.load(Ordering::Relaxed)
+            {
+                return Err(job);
+            }
+            let sleep = backoff.min(self.max_overload_wait - waited);
+            thread::sleep(sleep);
+            waited += sleep;
+            backoff *= 2;
+        }
+    }
+}
+
+impl KvsServer<LogStructKVStore, SharedQueueThreadPool> {
+    /// Builder picking the engine and the thread pool at runtime
+    /// The type parameters of this impl only anchor the call, they don't constrain the built server
+    pub fn builder() -> KvsServerBuilder {
+        KvsServerBuilder::default()
+    }
+}
+
+/// Server with its engine and thread pool erased, as built by `KvsServerBuilder`
+pub trait Server: Send + Sync {
+    fn run(&self, addr: &SocketAddr) -> Result<()>;
+    fn shutdown(&self);
+}
+
+impl<T, F> Server for KvsServer<T, F>
+where
+    T: KvsEngine,
+    F: ThreadPool + Send + Sync + 'static,
+{
+    fn run(&self, addr: &SocketAddr) -> Result<()> {
+        KvsServer::run(self, addr)
+    }
+
+    fn shutdown(&self) {
+        KvsServer::shutdown(self)
+    }
+}
+
+/// Configures a server whose engine and thread pool are chosen at runtime
+/// The engine is opened in the background like with `KvsServer::opening`
+pub struct KvsServerBuilder {
+    config: ServerConfig,
+    path: PathBuf,
+    protocol_version: u32,
+    logger: Logger,
+}
+
+impl Default for KvsServerBuilder {
+    fn default() -> Self {
+        KvsServerBuilder::from_config(ServerConfig::default())
+    }
+}
+
+impl KvsServerBuilder {
+    /// Starts from the settings of a kvs-server config, the storage is the current folder
+    pub fn from_config(config: ServerConfig) -> Self {
+        KvsServerBuilder {
+            config,
+            path: PathBuf::from("."),
+            protocol_version: PROTOCOL_VERSION,
+            logger: Logger::root(Discard, o!()),
+        }
+    }
+
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.config.address = address;
+        self
+    }
+
+    pub fn engine(mut self, engine: EngineType) -> Self {
+        self.config.engine = engine;
+        self
+    }
+
+    pub fn thread_pool(mut self, thread_pool: ThreadPoolType) -> Self {
+        self.config.thread_pool = thread_pool;
+        self
+    }
+
+    pub fn num_threads(mut self, num_threads: u32) -> Self {
+        self.config.num_threads = num_threads;
+        self
+    }
+
+    pub fn max_threads(mut self, max_threads: Option<u32>) -> Self {
+        self.config.max_threads = max_threads;
+        self
+    }
+
+    /// Folder of the storage
+    pub fn path(mut self, path: PathBuf) -> Self {
+        self.path = path;
+        self
+    }
+
+    pub fn compression(mut self, compress: bool) -> Self {
+        self.config.compress = compress;
+        self
+    }
+
+    pub fn protocol_version(mut self, version: u32) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    pub fn logger(mut self, logger: Logger) -> Self {
+        self.logger = logger;
+        self
+    }
+
+    pub fn max_accept_backoff(mut self, max_accept_backoff: Duration) -> Self {
+        self.config.max_accept_backoff_ms = max_accept_backoff.as_millis() as u64;
+        self
+    }
+
+    pub fn overload_policy(mut self, policy: OverloadPolicy, max_wait: Duration) -> Self {
+        self.config.overload = policy;
+        self.config.max_overload_wait_ms = max_wait.as_millis() as u64;
+        self
     }
+
+    pub fn response_batching(mut self, batch_bytes: usize) -> Self {
+        self.config.response_batch_bytes = batch_bytes;
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: bool) -> Self {
+        self.config.keepalive = keepalive;
+        self
+    }
+
+    pub fn rate_limit(mut self, max_ops_per_sec: Option<u32>) -> Self {
+        self.config.max_ops_per_sec = max_ops_per_sec;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.config.durability = durability;
+        self
+    }
+
+    pub fn missing_key_response(mut self, missing_key_response: MissingKeyResponse) -> Self {
+        self.config.missing_key_response = missing_key_response;
+        self
+    }
+
+    /// Creates the thread pool and starts opening the engine
+    pub fn build(self) -> Result<Box<dyn Server>> {
+        let path = self.path.clone();
+        let durability = self.config.durability;
+        match self.config.engine {
+            EngineType::Kvs => {
+                self.build_with(move || LogStructKVStore::open_with_durability(&path, durability))
+            }
+            EngineType::Sled => {
+                self.build_with(move || SledStore::open_with_durability(&path, durability))
+            }
+        }
+    }
+
+    /// Builds the server and serves on the configured address until shutdown
+    pub fn run(self) -> Result<()> {
+        let address = self.config.address;
+        self.build()?.run(&address)
+    }
+
+    fn build_with<T, O>(self, open: O) -> Result<Box<dyn Server>>
+    where
+        T: KvsEngine,
+        O: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let num_threads = self.config.num_threads;
+        Ok(match self.config.thread_pool {
+            ThreadPoolType::Rayon => self.configure(KvsServer::opening(
+                open,
+                RayonThreadPool::new(num_threads)?,
+            )?),
+            ThreadPoolType::SharedQ => self.configure(KvsServer::opening(
+                open,
+                SharedQueueThreadPool::new(num_threads)?,
+            )?),
+        })
+    }
+
+    fn configure<T, F>(&self, server: KvsServer<T, F>) -> Box<dyn Server>
+    where
+        T: KvsEngine,
+        F: ThreadPool + Send + Sync + 'static,
+    {
+        Box::new(
+            server
+                .with_compression(self.config.compress)
+                .with_protocol_version(self.protocol_version)
+                .with_max_accept_backoff(Duration::from_millis(self.config.max_accept_backoff_ms))
+                .with_overload_policy(
+                    self.config.overload,
+                    Duration::from_millis(self.config.max_overload_wait_ms),
+                )
+                .with_response_batching(self.config.response_batch_bytes)
+                .with_nodelay(self.config.nodelay)
+                .with_keepalive(self.config.keepalive)
+                .with_rate_limit(self.config.max_ops_per_sec)
+                .with_missing_key_response(self.config.missing_key_response)
+                .with_max_threads(self.config.max_threads)
+                .with_logger(self.logger.clone()),
+        )
+    }
+}
+
+/// Answers the handshake of a connection with `BUSY_VERSION` and closes it
+/// Runs on the accept loop, so it doesn't wait for the `Hello` of the client
+fn reject_busy(mut stream: TcpStream) -> Result<()> {
+    // Closing with unread data resets the connection, which may discard the answer
+    stream.set_nonblocking(true)?;
+    let mut hello = [0; 64];
+    while let Ok(read) = stream.read(&mut hello) {
+        if read == 0 {
+            break;
+        }
+    }
+    stream.set_nonblocking(false)?;
+    let welcome = Welcome {
+        compress: false,
+        version: BUSY_VERSION,
+    };
+    bincode::serialize_into(&mut stream, &welcome)?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+/// Settings of the server a connection is served with
+#[derive(Clone, Copy)]
+struct ConnectionOptions {
+    compress: bool,
+    version: u32,
+    response_batch_bytes: usize,
+    nodelay: bool,
+    keepalive: bool,
+    max_ops_per_sec: Option<u32>,
+    missing_key_response: MissingKeyResponse,
+    max_threads: Option<u32>,
+    started: Instant,
+}
+
+/// Token bucket of the commands of a connection, refilled at the rate and holding a second worth
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    fn new(ops_per_sec: u32) -> RateLimiter {
+        let rate = f64::from(ops_per_sec);
+        RateLimiter {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token for a command, returns false if there is none left
+    fn admit(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time until the next token, at most `MAX_RATE_LIMITED_SLEEP`
+    fn next_token(&self) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens) / self.rate).min(MAX_RATE_LIMITED_SLEEP)
+    }
+}
+
+/// Writer counting the bytes written since the last flush
+struct PendingWriter<W> {
+    inner: W,
+    pending: usize,
 }
 
-fn handle_stream<E: KvsEngine>(
-    kv_store: E,
+impl<W: Write> Write for PendingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.pending += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pending = 0;
+        self.inner.flush()
+    }
+}
+
+/// Turns on the `SO_KEEPALIVE` option of the socket, std has no setter for it
+fn set_keepalive(stream: &TcpStream) -> io::Result<()> {
+    SockRef::from(stream).set_keepalive(true)
+}
+
+/// Serves one connection, starting with the handshake
+fn handle_stream<E: KvsEngine, F: ThreadPool>(
+    engine: SharedEngine<E>,
+    pool: Arc<F>,
     stream: TcpStream,
     shutdown_flag: Arc<AtomicBool>,
+    options: ConnectionOptions,
 ) -> Result<()> {
-    let mut reader = BufReader::new(&stream);
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("connection", peer = ?stream.peer_addr().ok()).entered();
+    stream.set_nodelay(options.nodelay)?;
+    if options.keepalive {
+        set_keepalive(&stream)?;
+    }
+    let hello: Hello = bincode::deserialize_from(&stream)?;
+    let welcome = Welcome {
+        compress: hello.compress && options.compress,
+        version: negotiate_version(hello.version, options.version),
+    };
     let mut writer = BufWriter::new(&stream);
+    bincode::serialize_into(&mut writer, &welcome)?;
+    writer.flush()?;
+    drop(writer);
+
+    let (reader, writer) = wrap_stream(&stream, welcome.compress)?;
+    let writer = PendingWriter {
+        inner: writer,
+        pending: 0,
+    };
+    serve_commands(
+        engine,
+        pool,
+        reader,
+        writer,
+        shutdown_flag,
+        welcome.version,
+        &options,
+    )
+}
 
+/// Commands of a connection are applied one at a time in the order they were sent,
+/// so a get always observes a set sent before it on the same connection (read-your-writes)
+/// Ordering between different connections is not guaranteed
+/// Responses are flushed once `response_batch_bytes` of them are pending or before waiting for the next command
+/// A command beyond the rate limit is answered with `RateLimited` without being applied
+fn serve_commands<E: KvsEngine, F: ThreadPool, R: BufferedRead, W: Write>(
+    engine: SharedEngine<E>,
+    pool: Arc<F>,
+    mut reader: R,
+    mut writer: PendingWriter<W>,
+    shutdown_flag: Arc<AtomicBool>,
+    version: u32,
+    options: &ConnectionOptions,
+) -> Result<()> {
+    let mut kv_store: Option<E> = None;
+    let mut limiter = options
+        .max_ops_per_sec
+        .filter(|&max_ops_per_sec| max_ops_per_sec > 0)
+        .map(RateLimiter::new);
     while !shutdown_flag.load(Ordering::Relaxed) {
-        match bincode::deserialize_from(&mut reader) {
+        let cmd: bincode::Result<Command> = bincode::deserialize_from(&mut reader);
+        #[cfg(feature = "tracing")]
+        let _span = match &cmd {
+            Ok(cmd) => {
+                tracing::info_span!("handle_command", cmd = cmd.name(), key = cmd.key()).entered()
+            }
+            Err(_) => tracing::Span::none().entered(),
+        };
+        if kv_store.is_none() {
+            kv_store = engine.lock().unwrap().clone();
+        }
+        let kv_store = match &kv_store {
+            Some(kv_store) => kv_store,
+            None => {
+                bincode::serialize_into(&mut writer, &Response::Err(STARTING_UP.to_string()))?;
+                writer.flush()?;
+                continue;
+            }
+        };
+        match cmd {
+            Ok(cmd) if min_version(&cmd) > version => {
+                bincode::serialize_into(
+                    &mut writer,
+                    &Response::Err(KvsError::UnsupportedCommand.to_string()),
+                )?;
+            }
+            Ok(cmd) if writes_sequence_key(&cmd) => {
+                bincode::serialize_into(
+                    &mut writer,
+                    &Response::Err(KvsError::ReservedKey.to_string()),
+                )?;
+            }
+            Ok(_) if matches!(limiter.as_mut().map(RateLimiter::admit), Some(false)) => {
+                bincode::serialize_into(
+                    &mut writer,
+                    &Response::Err(KvsError::RateLimited.to_string()),
+                )?;
+                writer.flush()?;
+                // Only the connection's own thread waits, the engine is a clone and locks nothing
+                thread::sleep(limiter.as_ref().unwrap().next_token());
+            }
             Ok(cmd) => match cmd {
                 Command::Set { key, value } => match kv_store.set(key, value) {
                     Ok(()) => bincode::serialize_into(&mut writer, &Response::Ok(None)).unwrap(),
@@ -78,18 +767,24 @@ fn handle_stream<E: KvsEngine>(
                             .unwrap()
                     }
                 },
-                Command::Get { key } => match kv_store.get(key) {
-                    Ok(value) => match value {
-                        Some(value) => {
-                            bincode::serialize_into(&mut writer, &Response::Ok(Some(value)))
-                                .unwrap()
-                        }
-                        None => bincode::serialize_into(
-                            &mut writer,
-                            &Response::Ok(Some("Key not found".to_string())),
-                        )
-                        .unwrap(),
-                    },
+                Command::Get { key, .. } => match kv_store.get(key) {
+                    Ok(Some(value)) => {
+                        bincode::serialize_into(&mut writer, &Response::Ok(Some(value))).unwrap()
+                    }
+                    Ok(None) => bincode::serialize_into(
+                        &mut writer,
+                        &options.missing_key_response.response(),
+                    )
+                    .unwrap(),
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::GetDel { key } => match kv_store.get_and_remove(key) {
+                    Ok(value) => {
+                        bincode::serialize_into(&mut writer, &Response::Ok(value)).unwrap()
+                    }
                     Err(err) => {
                         bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
                             .unwrap()
@@ -99,7 +794,7 @@ fn handle_stream<E: KvsEngine>(
                     Ok(_) => bincode::serialize_into(&mut writer, &Response::Ok(None)).unwrap(),
                     Err(KvsError::KeyNotFound) => bincode::serialize_into(
                         &mut writer,
-                        &Response::Err("Key not found".to_string()),
+                        &Response::Err(KEY_NOT_FOUND.to_string()),
                     )
                     .unwrap(),
                     Err(err) => {
@@ -107,13 +802,231 @@ fn handle_stream<E: KvsEngine>(
                             .unwrap()
                     }
                 },
+                Command::Exists { key } => match kv_store.contains_key(key) {
+                    Ok(found) => {
+                        bincode::serialize_into(&mut writer, &Response::Bool(found)).unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::Replace { key, value } => match kv_store.replace(key, value) {
+                    Ok(replaced) => {
+                        bincode::serialize_into(&mut writer, &Response::Bool(replaced)).unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::SetNx { key, value } => match kv_store.set_if_absent(key, value) {
+                    Ok(set) => bincode::serialize_into(&mut writer, &Response::Bool(set)).unwrap(),
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::NextId { name } => match kv_store.next_id(&name) {
+                    Ok(id) => {
+                        bincode::serialize_into(&mut writer, &Response::Ok(Some(id.to_string())))
+                            .unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::Expire { key, ttl_secs } => match kv_store.expire(key, ttl_secs) {
+                    Ok(existed) => {
+                        bincode::serialize_into(&mut writer, &Response::Bool(existed)).unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::ExpireAt { key, unix_secs } => match kv_store.expire_at(key, unix_secs) {
+                    Ok(existed) => {
+                        bincode::serialize_into(&mut writer, &Response::Bool(existed)).unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::MGet { keys } => match kv_store.get_many(&keys) {
+                    Ok(values) => {
+                        bincode::serialize_into(&mut writer, &Response::Values(values)).unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::ScanPrefix { prefix, limit } => match kv_store.scan_prefix(&prefix, limit)
+                {
+                    Ok(pairs) => {
+                        bincode::serialize_into(&mut writer, &Response::Pairs(pairs)).unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::DbSize => match kv_store.key_count() {
+                    Ok(count) => {
+                        bincode::serialize_into(&mut writer, &Response::Ok(Some(count.to_string())))
+                            .unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::FlushAll => match kv_store.clear() {
+                    Ok(removed) => bincode::serialize_into(
+                        &mut writer,
+                        &Response::Ok(Some(removed.to_string())),
+                    )
+                    .unwrap(),
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::RmPrefix { prefix } => match kv_store.remove_prefix(prefix) {
+                    Ok(removed) => bincode::serialize_into(
+                        &mut writer,
+                        &Response::Ok(Some(removed.to_string())),
+                    )
+                    .unwrap(),
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::Keys {
+                    pattern,
+                    count: true,
+                } => match kv_store.count_matching(&pattern) {
+                    Ok(count) => {
+                        bincode::serialize_into(&mut writer, &Response::Ok(Some(count.to_string())))
+                            .unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::Keys {
+                    pattern,
+                    count: false,
+                } => match kv_store.keys_matching(&pattern) {
+                    Ok(keys) => {
+                        bincode::serialize_into(&mut writer, &Response::Keys(keys)).unwrap()
+                    }
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::Info => bincode::serialize_into(
+                    &mut writer,
+                    &Response::Info(ServerInfo {
+                        version: env!("CARGO_PKG_VERSION").to_owned(),
+                        engine: kv_store.name().to_owned(),
+                        uptime_secs: options.started.elapsed().as_secs(),
+                        protocol_version: options.version,
+                    }),
+                )
+                .unwrap(),
+                Command::Touch { keys } => match kv_store.touch_keys(&keys) {
+                    Ok(existing) => bincode::serialize_into(
+                        &mut writer,
+                        &Response::Ok(Some(existing.to_string())),
+                    )
+                    .unwrap(),
+                    Err(err) => {
+                        bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                            .unwrap()
+                    }
+                },
+                Command::Config { key, value } => {
+                    match configure(&*pool, &key, value, options.max_threads) {
+                        Ok(current) => {
+                            bincode::serialize_into(&mut writer, &Response::Ok(Some(current)))
+                                .unwrap()
+                        }
+                        Err(err) => {
+                            bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))
+                                .unwrap()
+                        }
+                    }
+                }
+                Command::Reserved6(tag)
+                | Command::Reserved8(tag)
+                | Command::Reserved9(tag)
+                | Command::Reserved10(tag)
+                | Command::Reserved11(tag)
+                | Command::Reserved14(tag)
+                | Command::Reserved20(tag) => match tag {},
             },
+            Err(err) if closed_by_client(&err) => break,
             Err(err) => {
                 bincode::serialize_into(&mut writer, &Response::Err(format!("{}", err)))?;
             }
         }
-        writer.flush()?;
+        if writer.pending >= options.response_batch_bytes || !reader.has_buffered()? {
+            writer.flush()?;
+        }
     }
 
     Ok(())
 }
+
+/// Whether reading a command failed because the client closed the connection, not a failure of it
+fn closed_by_client(err: &bincode::Error) -> bool {
+    matches!(&**err, bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+/// Whether the command writes a reserved key of the id sequences, which only `next-id` moves
+fn writes_sequence_key(cmd: &Command) -> bool {
+    match cmd {
+        Command::Set { key, .. }
+        | Command::Rm { key }
+        | Command::Expire { key, .. }
+        | Command::ExpireAt { key, .. }
+        | Command::GetDel { key }
+        | Command::Replace { key, .. }
+        | Command::SetNx { key, .. } => is_sequence_key(key),
+        _ => false,
+    }
+}
+
+/// Changes a server setting when `value` is given, returns the setting value
+/// The thread pool is resized up to `max_threads` workers, not at all without it
+fn configure<F: ThreadPool>(
+    pool: &F,
+    key: &str,
+    value: Option<String>,
+    max_threads: Option<u32>,
+) -> Result<String> {
+    match key {
+        NUM_THREADS_SETTING => {
+            if let Some(value) = value {
+                let max_threads = max_threads.ok_or(KvsError::ReadOnlySetting)?;
+                match value.parse::<u32>() {
+                    Ok(num_threads) if num_threads > 0 && num_threads <= max_threads => {
+                        pool.resize(num_threads)?
+                    }
+                    _ => return Err(KvsError::InvalidSetting),
+                }
+            }
+            pool.num_threads()
+                .map(|num_threads| num_threads.to_string())
+                .ok_or(KvsError::UnsupportedCommand)
+        }
+        _ => Err(KvsError::InvalidSetting),
+    }
+}
@@ -1,4 +1,5 @@
 use crate::common::Result;
+use crate::error::KvsError;
 
 use clap::ArgEnum;
 use serde::{Deserialize, Serialize};
@@ -10,6 +11,9 @@ pub use naive_tp::NaiveThreadPool;
 pub use rayon_tp::RayonThreadPool;
 pub use sharedq_tp::SharedQueueThreadPool;
 
+/// Job of a pool, boxed so that `try_spawn` can hand it back
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
 pub trait ThreadPool {
     fn new(num_threads: u32) -> Result<Self>
     where
@@ -17,9 +21,27 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Queues `job` unless that would block, in which case `job` is handed back
+    /// Pools whose `spawn` never blocks always take it
+    fn try_spawn(&self, job: Job) -> std::result::Result<(), Job> {
+        self.spawn(job);
+        Ok(())
+    }
+
+    /// Number of workers, `None` for pools without a fixed set of them
+    fn num_threads(&self) -> Option<u32> {
+        None
+    }
+
+    /// Grows or shrinks the pool to `num_threads` workers
+    fn resize(&self, _num_threads: u32) -> Result<()> {
+        Err(KvsError::UnsupportedCommand)
+    }
 }
 
 #[derive(ArgEnum, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ThreadPoolType {
     #[clap(alias = "rayon")]
     Rayon,
@@ -24,4 +24,8 @@ impl ThreadPool for RayonThreadPool {
     {
         self.rayon.spawn(job);
     }
+
+    fn num_threads(&self) -> Option<u32> {
+        Some(self.rayon.current_num_threads() as u32)
+    }
 }
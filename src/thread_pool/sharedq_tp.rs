@@ -1,29 +1,62 @@
 use crate::common::Result;
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::{Job, ThreadPool};
 use crossbeam_channel;
-use crossbeam_channel::bounded;
+use crossbeam_channel::{bounded, select, unbounded, TrySendError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::thread::JoinHandle;
+use std::time::Instant;
 pub struct SharedQueueThreadPool {
-    sender: crossbeam_channel::Sender<Message>,
-    num_threads: u32,
+    sender: Option<crossbeam_channel::Sender<Message>>,
+    workers: Workers,
+    /// Kept to start the workers added by `resize`
+    receiver: crossbeam_channel::Receiver<Message>,
+    /// Each message stops one worker, unbounded so stopping never waits for room in the job queue
+    stop_sender: crossbeam_channel::Sender<()>,
+    stop_receiver: crossbeam_channel::Receiver<()>,
+    deadline_exceeded: Arc<AtomicU64>,
+    /// Workers meant to be running
+    num_threads: Mutex<u32>,
 }
 
-type Task = Box<dyn FnOnce() + Send + 'static>;
+/// Handles of the running workers, replacements of panicked workers add theirs
+type Workers = Arc<Mutex<Vec<JoinHandle<()>>>>;
 
 enum Message {
-    Task(Task),
-    Shutdown,
+    /// Job with the latest instant it may still be started at
+    Task(Job, Option<Instant>),
 }
 
 #[derive(Clone)]
 struct TaskHandler {
     receiver: crossbeam_channel::Receiver<Message>,
+    stop_receiver: crossbeam_channel::Receiver<()>,
+    workers: Workers,
+    deadline_exceeded: Arc<AtomicU64>,
 }
 
 impl TaskHandler {
+    /// Runs tasks until it takes a stop message or until the queue is drained and the pool is gone
+    /// A stop message waiting is taken before the next task
     fn run(&mut self) {
-        while let Message::Task(task) = self.receiver.recv().unwrap() {
-            task();
+        loop {
+            if self.stop_receiver.try_recv().is_ok() {
+                return;
+            }
+            let message = select! {
+                recv(self.stop_receiver) -> _ => return,
+                recv(self.receiver) -> message => message,
+            };
+            match message {
+                Ok(Message::Task(task, deadline)) => match deadline {
+                    Some(deadline) if Instant::now() > deadline => {
+                        self.deadline_exceeded.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ => task(),
+                },
+                Err(_) => return,
+            }
         }
     }
 }
@@ -32,43 +65,142 @@ impl Drop for TaskHandler {
     fn drop(&mut self) {
         if thread::panicking() {
             let mut th = self.clone();
-            thread::spawn(move || {
+            let handle = thread::spawn(move || {
                 th.run();
             });
+            self.workers.lock().unwrap().push(handle);
         }
     }
 }
+
+impl SharedQueueThreadPool {
+    /// Queues a job that is dropped instead of run if no worker picks it up before `deadline`
+    pub fn spawn_deadline<F>(&self, deadline: Instant, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.send(Box::new(job), Some(deadline));
+    }
+
+    /// Number of jobs dropped because their deadline passed while queued
+    pub fn deadline_exceeded(&self) -> u64 {
+        self.deadline_exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Also drops the handles of the workers that exited since, stopped by a shrink
+    fn start_worker(&self) {
+        let mut th = TaskHandler {
+            receiver: self.receiver.clone(),
+            stop_receiver: self.stop_receiver.clone(),
+            workers: Arc::clone(&self.workers),
+            deadline_exceeded: Arc::clone(&self.deadline_exceeded),
+        };
+        let handle = thread::spawn(move || th.run());
+        let mut workers = self.workers.lock().unwrap();
+        workers.retain(|worker| !worker.is_finished());
+        workers.push(handle);
+    }
+
+    fn send(&self, task: Job, deadline: Option<Instant>) {
+        if let Some(sender) = &self.sender {
+            sender.send(Message::Task(task, deadline)).unwrap();
+        }
+    }
+
+    /// Stops accepting jobs, waits for every queued job to run, then joins the workers
+    pub fn shutdown_graceful(mut self) {
+        // Workers see the channel disconnected only once the queue is empty
+        self.sender.take();
+        self.join_workers();
+    }
+
+    /// Waits for every worker to exit, including the replacements of panicked ones
+    fn join_workers(&self) {
+        let current = thread::current().id();
+        loop {
+            let handle = self.workers.lock().unwrap().pop();
+            match handle {
+                // A worker dropping its own pool can't wait for itself
+                Some(handle) if handle.thread().id() == current => continue,
+                // A panicked worker has already queued its replacement
+                Some(handle) => handle.join().unwrap_or(()),
+                None => break,
+            }
+        }
+    }
+}
+
 impl ThreadPool for SharedQueueThreadPool {
     fn new(num_threads: u32) -> Result<Self>
     where
         Self: Sized,
     {
         let (sender, receiver) = bounded::<Message>(4 * num_threads as usize);
-
+        let (stop_sender, stop_receiver) = unbounded();
+        let pool = SharedQueueThreadPool {
+            num_threads: Mutex::new(num_threads),
+            sender: Some(sender),
+            receiver,
+            stop_sender,
+            stop_receiver,
+            workers: Arc::new(Mutex::new(Vec::with_capacity(num_threads as usize))),
+            deadline_exceeded: Arc::new(AtomicU64::new(0)),
+        };
         for _ in 0..num_threads {
-            let mut th = TaskHandler {
-                receiver: receiver.clone(),
-            };
-            thread::spawn(move || th.run());
+            pool.start_worker();
         }
-        Ok(SharedQueueThreadPool {
-            num_threads,
-            sender,
-        })
+        Ok(pool)
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.sender.send(Message::Task(Box::new(job))).unwrap();
+        self.send(Box::new(job), None);
+    }
+
+    /// Fails while the queue holds `4 * num_threads` jobs
+    fn try_spawn(&self, job: Job) -> std::result::Result<(), Job> {
+        match &self.sender {
+            Some(sender) => match sender.try_send(Message::Task(job, None)) {
+                Err(TrySendError::Full(Message::Task(job, _))) => Err(job),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn num_threads(&self) -> Option<u32> {
+        Some(*self.num_threads.lock().unwrap())
+    }
+
+    /// Added workers start right away, surplus ones exit once done with the job they run
+    /// Never waits, even when called from a job of the pool while the queue is full
+    /// The queue keeps the capacity it was created with
+    fn resize(&self, num_threads: u32) -> Result<()> {
+        if self.sender.is_none() {
+            return Ok(());
+        }
+        let mut current = self.num_threads.lock().unwrap();
+        for _ in *current..num_threads {
+            self.start_worker();
+        }
+        for _ in num_threads..*current {
+            self.stop_sender.send(()).unwrap();
+        }
+        *current = num_threads;
+        Ok(())
     }
 }
 
+/// Waits for the workers to finish their in-flight jobs, queued jobs are dropped
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
-        for _ in 0..self.num_threads {
-            self.sender.send(Message::Shutdown).unwrap()
+        if self.sender.is_some() {
+            for _ in 0..*self.num_threads.lock().unwrap() {
+                self.stop_sender.send(()).unwrap()
+            }
+            self.join_workers();
         }
     }
 }
@@ -0,0 +1,51 @@
+//! Sleeps of the idle accept loop, see `KvsServer::with_max_accept_backoff`
+
+use kvs::client::KvsClient;
+use kvs::engine::OptLogStructKvs;
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+#[test]
+fn idle_server_sleeps_yet_accepts_within_max_backoff() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    let server = Arc::new(
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .with_max_accept_backoff(MAX_BACKOFF),
+    );
+    let idle_sleeps = server.idle_sleeps();
+    let address: SocketAddr = "127.0.0.1:4813".parse().unwrap();
+    let run = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run(&address).unwrap())
+    };
+
+    thread::sleep(Duration::from_secs(1));
+    // Doubling from a microsecond reaches the max after 16 sleeps, about 20 more fill the second
+    let sleeps = idle_sleeps.load(Ordering::Relaxed);
+    assert!(sleeps > 16 && sleeps < 100, "{} sleeps", sleeps);
+
+    // Long idle, the loop sleeps its longest, a doubling sleep would be past half a second by now
+    let started = Instant::now();
+    let client = KvsClient::new(&address).unwrap();
+    client.get("key".to_owned()).unwrap();
+    let waited = started.elapsed();
+    assert!(
+        waited < MAX_BACKOFF + Duration::from_millis(100),
+        "{:?}",
+        waited
+    );
+    client.shutdown().unwrap();
+
+    server.shutdown();
+    run.join().unwrap();
+}
@@ -0,0 +1,66 @@
+//! Values of `OptLogStructKvs` stored in blob files, see `EngineConfig::blob_threshold`
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const THRESHOLD: usize = 100;
+
+fn config() -> EngineConfig {
+    EngineConfig {
+        blob_threshold: Some(THRESHOLD),
+        ..EngineConfig::default()
+    }
+}
+
+fn blob_bytes(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "blob"))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+#[test]
+fn large_values_survive_compaction_and_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let large = "x".repeat(10 * THRESHOLD);
+    {
+        let store = OptLogStructKvs::open_with_config(temp_dir.path(), config()).unwrap();
+        store.set("large".to_owned(), large.clone()).unwrap();
+        store.set("small".to_owned(), "y".to_owned()).unwrap();
+        assert_eq!(blob_bytes(temp_dir.path()), large.len() as u64);
+
+        store.set("small".to_owned(), "z".to_owned()).unwrap();
+        store.compact().unwrap();
+        // Compaction moves the reference only
+        assert_eq!(blob_bytes(temp_dir.path()), large.len() as u64);
+        assert_eq!(store.get("large".to_owned()).unwrap(), Some(large.clone()));
+    }
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config()).unwrap();
+    assert_eq!(store.get("large".to_owned()).unwrap(), Some(large));
+    assert_eq!(store.get("small".to_owned()).unwrap(), Some("z".to_owned()));
+}
+
+#[test]
+fn gc_reclaims_overwritten_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config()).unwrap();
+    let old = "a".repeat(2 * THRESHOLD);
+    let new = "b".repeat(3 * THRESHOLD);
+    store.set("key".to_owned(), old.clone()).unwrap();
+    store.set("key".to_owned(), new.clone()).unwrap();
+    store.set("gone".to_owned(), old.clone()).unwrap();
+    store.remove("gone".to_owned()).unwrap();
+
+    assert_eq!(store.gc_blobs().unwrap(), 2 * old.len() as u64);
+    assert_eq!(blob_bytes(temp_dir.path()), new.len() as u64);
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some(new.clone()));
+    drop(store);
+
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config()).unwrap();
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some(new));
+    assert_eq!(store.get("gone".to_owned()).unwrap(), None);
+}
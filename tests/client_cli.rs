@@ -0,0 +1,77 @@
+//! Answers of `kvs-client` subcommands, their output and exit code
+
+use kvs::engine::{KvsEngine, OptLogStructKvs};
+use kvs::server::{KvsServer, KEY_NOT_FOUND};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::process::{self, Output};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Serves a storage holding `key` set to `value` on `address`
+fn serve(address: &str) -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    let address: SocketAddr = address.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+    temp_dir
+}
+
+fn client(address: &str, args: &[&str]) -> Output {
+    process::Command::new(env!("CARGO_BIN_EXE_kvs-client"))
+        .args(args)
+        .args(["--addr", address])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn exists_answers_with_the_exit_code() {
+    let address = "127.0.0.1:4811";
+    let _temp_dir = serve(address);
+
+    let present = client(address, &["exists", "key"]);
+    assert_eq!(present.status.code(), Some(0));
+    assert_eq!(String::from_utf8(present.stdout).unwrap(), "true\n");
+    let absent = client(address, &["exists", "missing"]);
+    assert_eq!(absent.status.code(), Some(1));
+    assert_eq!(String::from_utf8(absent.stdout).unwrap(), "false\n");
+}
+
+/// Printed JSON of a `kvs-client` run with `--output json`, and its exit code
+fn client_json(address: &str, args: &[&str]) -> (Value, Option<i32>) {
+    let output = client(address, &[args, &["--output", "json"]].concat());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    (serde_json::from_str(&stdout).unwrap(), output.status.code())
+}
+
+#[test]
+fn json_output_shapes() {
+    let address = "127.0.0.1:4812";
+    let _temp_dir = serve(address);
+
+    assert_eq!(
+        client_json(address, &["get", "key"]),
+        (json!({ "status": "ok", "value": "value" }), Some(0))
+    );
+    assert_eq!(
+        client_json(address, &["get", "missing"]),
+        (json!({ "status": "not_found" }), Some(0))
+    );
+    let (error, code) = client_json(address, &["rm", "missing"]);
+    assert_eq!(
+        error,
+        json!({ "status": "error", "message": KEY_NOT_FOUND })
+    );
+    assert_eq!(code, Some(1));
+}
@@ -0,0 +1,91 @@
+//! `KvsClientPool` reconnecting through a restart of the server
+
+use kvs::client::KvsClientPool;
+use std::net::SocketAddr;
+use std::process::{self, Child};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDRESS: &str = "127.0.0.1:4809";
+
+/// Kills the server when the test ends, passed or not
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_server(dir: &TempDir) -> Server {
+    let server = process::Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--addr", ADDRESS])
+        .current_dir(dir.path())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    Server(server)
+}
+
+#[test]
+fn pool_recovers_from_a_server_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir);
+    let address: SocketAddr = ADDRESS.parse().unwrap();
+    let pool =
+        Arc::new(KvsClientPool::new(address, false).with_retries(50, Duration::from_millis(50)));
+    pool.set("key".to_owned(), "value".to_owned()).unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let workers: Vec<_> = (0..4)
+        .map(|worker| {
+            let pool = Arc::clone(&pool);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut ops = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    let key = format!("worker{}", worker);
+                    pool.set(key.clone(), ops.to_string()).unwrap();
+                    assert_eq!(pool.get(key).unwrap(), Some(ops.to_string()));
+                    assert_eq!(
+                        pool.get("key".to_owned()).unwrap(),
+                        Some("value".to_owned())
+                    );
+                    ops += 1;
+                }
+                ops
+            })
+        })
+        .collect();
+
+    thread::sleep(Duration::from_millis(300));
+    drop(server);
+    thread::sleep(Duration::from_millis(200));
+    let _server = spawn_server(&temp_dir);
+    thread::sleep(Duration::from_millis(300));
+    stop.store(true, Ordering::Relaxed);
+    for worker in workers {
+        assert!(worker.join().unwrap() > 0);
+    }
+
+    // Only the connections opened after the restart are kept
+    assert!(pool.idle_connections() <= 4);
+    assert_eq!(
+        pool.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn retries_are_bounded() {
+    // Nothing listens there
+    let address: SocketAddr = "127.0.0.1:4810".parse().unwrap();
+    let pool = KvsClientPool::new(address, false).with_retries(2, Duration::from_millis(10));
+    assert!(pool.get("key".to_owned()).is_err());
+    assert_eq!(pool.idle_connections(), 0);
+}
@@ -0,0 +1,59 @@
+//! Typed `KvsClient::set`, `get` and `remove` turning the responses into return values
+
+use kvs::client::KvsClient;
+use kvs::engine::LogStructKVStore;
+use kvs::error::KvsError;
+use kvs::server::{KvsServer, MissingKeyResponse};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn serve(address: &str, missing_key_response: MissingKeyResponse) -> (KvsClient, TempDir) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = LogStructKVStore::open(temp_dir.path()).unwrap();
+    let address: SocketAddr = address.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .with_missing_key_response(missing_key_response)
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+    (KvsClient::new(&address).unwrap(), temp_dir)
+}
+
+#[test]
+fn set_get_remove_return_values() {
+    let (client, _temp_dir) = serve("127.0.0.1:4787", MissingKeyResponse::Null);
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.set("key1".to_owned(), "".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some(String::new()));
+
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    client.shutdown().unwrap();
+}
+
+#[test]
+fn get_of_missing_key_answered_with_error_is_none() {
+    let (client, _temp_dir) = serve("127.0.0.1:4788", MissingKeyResponse::Error);
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.shutdown().unwrap();
+}
@@ -0,0 +1,378 @@
+//! Compaction of `OptLogStructKvs` triggered by concurrent writers
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tempfile::TempDir;
+
+const WRITERS: u64 = 16;
+
+fn key(i: u64) -> String {
+    format!("key{:02}", i)
+}
+
+fn value(i: u64, round: u64) -> String {
+    format!("{:02}{:04}", i, round).repeat(200)
+}
+
+/// Size of a record overwritten by `value`, all the keys and values have the same length
+fn record_size() -> u64 {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set(key(0), value(0, 0)).unwrap();
+    store.set(key(0), value(0, 1)).unwrap();
+    store.stats().unwrap().uncompacted_size
+}
+
+#[test]
+fn concurrent_writers_compact_once() {
+    let record_size = record_size();
+    // Writes racing the compaction can't cross the threshold again on their own
+    let threshold = 2 * WRITERS * record_size;
+    let temp_dir = TempDir::new().unwrap();
+    let config = EngineConfig {
+        compact_threshold: threshold,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    for i in 0..=WRITERS {
+        store.set(key(i), value(i, 0)).unwrap();
+    }
+    // Keeps the compaction busy long enough for the writers to pile up on it
+    store
+        .extend((0..20_000).map(|i| (format!("bulk{}", i), i.to_string())))
+        .unwrap();
+    // Overwrites a spare key until any writer but the first crosses the threshold
+    let mut round = 1;
+    while store.stats().unwrap().uncompacted_size < threshold - 2 * record_size {
+        store.set(key(WRITERS), value(WRITERS, round)).unwrap();
+        round += 1;
+    }
+    assert_eq!(store.stats().unwrap().compactions, 0);
+
+    let barrier = Arc::new(Barrier::new(WRITERS as usize));
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                store.set(key(i), value(i, 1)).unwrap();
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    assert_eq!(store.stats().unwrap().compactions, 1);
+    for i in 0..WRITERS {
+        assert_eq!(store.get(key(i)).unwrap(), Some(value(i, 1)));
+    }
+}
+
+/// Store of about 2 MB with a garbage ratio of 0.4 and a byte threshold it ignores
+fn open_with_ratio(path: &std::path::Path) -> OptLogStructKvs {
+    let config = EngineConfig {
+        compact_threshold: 1,
+        compaction_garbage_ratio: Some(0.4),
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(path, config).unwrap();
+    store
+        .extend((0..2_000).map(|i| (key(i), value(i, 0))))
+        .unwrap();
+    store
+}
+
+#[test]
+fn garbage_ratio_ignores_small_garbage_of_a_large_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open_with_ratio(temp_dir.path());
+    // A tenth of the keys overwritten, a few hundred KB of garbage
+    for i in 0..200 {
+        store.set(key(i), value(i, 1)).unwrap();
+    }
+    let stats = store.stats().unwrap();
+    assert!(stats.uncompacted_size > 100 * 1024);
+    assert_eq!(stats.compactions, 0);
+}
+
+#[test]
+fn garbage_ratio_crossed_compacts() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open_with_ratio(temp_dir.path());
+    let mut i = 0;
+    loop {
+        let stats = store.stats().unwrap();
+        if stats.compactions > 0 {
+            break;
+        }
+        let ratio =
+            stats.uncompacted_size as f64 / (stats.uncompacted_size + stats.logical_bytes) as f64;
+        assert!(ratio < 0.4, "ratio {} crossed without compacting", ratio);
+        store
+            .set(key(i % 2_000), value(i % 2_000, 1 + i / 2_000))
+            .unwrap();
+        i += 1;
+    }
+    // Compacting needs about two thirds of the keys overwritten
+    assert!(i > 1_000 && i < 1_500, "compacted after {} overwrites", i);
+    assert_eq!(store.stats().unwrap().uncompacted_size, 0);
+    assert_eq!(store.get(key(0)).unwrap(), Some(value(0, 1)));
+}
+
+#[test]
+fn compaction_moves_key_to_newer_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set(key(0), value(0, 0)).unwrap();
+    let before = store.key_age(key(0)).unwrap().unwrap();
+
+    store.compact().unwrap();
+    let after = store.key_age(key(0)).unwrap().unwrap();
+    assert!(after > before, "key stayed in log {}", before);
+    assert_eq!(store.key_age(key(1)).unwrap(), None);
+}
+
+/// Names of the COMPACTED logs of the storage folder
+fn compacted_logs(path: &std::path::Path) -> Vec<String> {
+    std::fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with('#'))
+        .collect()
+}
+
+#[test]
+fn cancelled_compaction_leaves_recoverable_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = EngineConfig {
+        compact_threshold: 1,
+        compaction_step_bytes: Some(1),
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config.clone()).unwrap();
+    for i in 0..100 {
+        store.set(key(i), value(i, 0)).unwrap();
+    }
+    // The first overwrite starts a compaction that moves a single record
+    store.set(key(0), value(0, 1)).unwrap();
+    assert_eq!(compacted_logs(temp_dir.path()).len(), 1);
+
+    store.cancel_compaction();
+    store.set(key(1), value(1, 1)).unwrap();
+    assert!(compacted_logs(temp_dir.path()).is_empty());
+    assert_eq!(store.stats().unwrap().compactions, 0);
+    for i in 0..100 {
+        let round = if i < 2 { 1 } else { 0 };
+        assert_eq!(store.get(key(i)).unwrap(), Some(value(i, round)));
+    }
+    drop(store);
+
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    for i in 0..100 {
+        let round = if i < 2 { 1 } else { 0 };
+        assert_eq!(store.get(key(i)).unwrap(), Some(value(i, round)));
+    }
+}
+
+/// Bytes of the log files of the folder
+fn logs_size(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+#[test]
+fn incremental_compaction_keeps_count_of_garbage_written_meanwhile() {
+    let record_size = record_size();
+    let temp_dir = TempDir::new().unwrap();
+    let config = EngineConfig {
+        compact_threshold: 10 * record_size,
+        // One key per step, so the overwrites below land while the compaction is in progress
+        compaction_step_bytes: Some(1),
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    for i in 0..20 {
+        store.set(key(i), value(i, 0)).unwrap();
+    }
+    let mut round = 1;
+    while store.stats().unwrap().compactions == 0 {
+        store
+            .set(key(round % 20), value(round % 20, round))
+            .unwrap();
+        round += 1;
+    }
+    // The records overwritten in the logs written since the compaction started are still there
+    let uncompacted_size = store.stats().unwrap().uncompacted_size;
+    assert!(uncompacted_size > 0);
+
+    let before = logs_size(temp_dir.path());
+    store.compact().unwrap();
+    // Copies of the records implying their version write it down, 9 bytes more for each of the 20 keys at most
+    let reclaimed = before - logs_size(temp_dir.path());
+    assert!(reclaimed <= uncompacted_size);
+    assert!(uncompacted_size - reclaimed <= 20 * 9);
+    assert_eq!(store.stats().unwrap().uncompacted_size, 0);
+}
+
+/// Records aren't compressed, physical bytes are the logical ones plus the redundant records
+#[test]
+fn physical_bytes_shrink_to_logical_with_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = EngineConfig {
+        compact_threshold: u64::MAX,
+        max_log_files: u64::MAX,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    for round in 0..2 {
+        for i in 0..WRITERS {
+            store.set(key(i), value(i, round)).unwrap();
+        }
+    }
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.logical_bytes, WRITERS * record_size());
+    assert_eq!(
+        stats.physical_bytes,
+        stats.logical_bytes + stats.uncompacted_size
+    );
+
+    store.compact().unwrap();
+    let stats = store.stats().unwrap();
+    // Grown by the versions the overwrites had implied, written out by compaction
+    assert!(stats.logical_bytes > WRITERS * record_size());
+    assert_eq!(stats.physical_bytes, stats.logical_bytes);
+}
+
+/// Small writes rolling over many logs never cross the byte threshold, the log count forces compaction
+/// The moved records are read through their new pointers right away, without a reopen
+#[test]
+fn log_file_cap_forces_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = EngineConfig {
+        max_file_size: 512,
+        compact_threshold: u64::MAX,
+        max_log_files: 8,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    let mut max_log_files = 0;
+    for i in 0..500 {
+        store
+            .set(format!("key{}", i % 50), format!("value{}", i))
+            .unwrap();
+        max_log_files = max_log_files.max(store.stats().unwrap().log_files);
+    }
+    assert!(max_log_files <= 10, "{}", max_log_files);
+    assert!(!compacted_logs(temp_dir.path()).is_empty());
+    for i in 450..500 {
+        assert_eq!(
+            store.get(format!("key{}", i % 50)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+}
+
+/// Bytes of the COMPACTED logs of the storage folder
+fn compacted_size(path: &Path) -> u64 {
+    compacted_logs(path)
+        .iter()
+        .map(|name| fs::metadata(path.join(name)).unwrap().len())
+        .sum()
+}
+
+#[test]
+fn incremental_compaction_reclaims_as_much_as_a_full_one() {
+    let record_size = record_size();
+    let step = 2 * record_size;
+    let fill = |path: &Path, compaction_step_bytes| {
+        let config = EngineConfig {
+            compact_threshold: WRITERS * record_size,
+            max_log_files: u64::MAX,
+            compaction_step_bytes,
+            ..EngineConfig::default()
+        };
+        let store = OptLogStructKvs::open_with_config(path, config).unwrap();
+        for round in 0..2 {
+            for i in 0..WRITERS {
+                store.set(key(i), value(i, round)).unwrap();
+            }
+        }
+        store
+    };
+    let full_dir = TempDir::new().unwrap();
+    let full = fill(full_dir.path(), None);
+    assert_eq!(full.stats().unwrap().compactions, 1);
+
+    let incremental_dir = TempDir::new().unwrap();
+    let incremental = fill(incremental_dir.path(), Some(step));
+    let mut size = compacted_size(incremental_dir.path());
+    // The last overwrite crossed the threshold and ran the first step
+    assert!(size > 0 && size <= step + record_size, "{}", size);
+    let mut steps = 1;
+    while incremental.stats().unwrap().compactions == 0 {
+        // Each write runs a step, the spare key lands in the new write log and isn't moved
+        incremental
+            .set("spare".to_owned(), steps.to_string())
+            .unwrap();
+        let grown = compacted_size(incremental_dir.path()) - size;
+        assert!(grown <= step + record_size, "step moved {} bytes", grown);
+        size += grown;
+        steps += 1;
+    }
+    assert!(steps >= WRITERS / 2, "compacted in {} steps", steps);
+
+    assert_eq!(size, compacted_size(full_dir.path()));
+    for i in 0..WRITERS {
+        assert_eq!(incremental.get(key(i)).unwrap(), Some(value(i, 1)));
+    }
+}
+
+#[test]
+fn merge_compacted_coalesces_logs_of_interrupted_compactions() {
+    let temp_dir = TempDir::new().unwrap();
+    let stepping = EngineConfig {
+        compact_threshold: 1,
+        compaction_step_bytes: Some(1),
+        ..EngineConfig::default()
+    };
+    let mut store = OptLogStructKvs::open_with_config(temp_dir.path(), stepping.clone()).unwrap();
+    for i in 0..20 {
+        store.set(key(i), value(i, 0)).unwrap();
+    }
+    // Each overwrite starts a compaction that moves a single record, closing the store
+    // leaves it unfinished with its COMPACTED log
+    for i in 0..3 {
+        store.set(key(i), value(i, 1)).unwrap();
+        drop(store);
+        store = OptLogStructKvs::open_with_config(temp_dir.path(), stepping.clone()).unwrap();
+    }
+    drop(store);
+    assert_eq!(compacted_logs(temp_dir.path()).len(), 3);
+
+    let config = EngineConfig {
+        compact_threshold: u64::MAX,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config.clone()).unwrap();
+    store.merge_compacted().unwrap();
+    assert_eq!(compacted_logs(temp_dir.path()).len(), 1);
+    let round = |i| if i < 3 { 1 } else { 0 };
+    for i in 0..20 {
+        assert_eq!(store.get(key(i)).unwrap(), Some(value(i, round(i))));
+    }
+    drop(store);
+
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    for i in 0..20 {
+        assert_eq!(store.get(key(i)).unwrap(), Some(value(i, round(i))));
+    }
+}
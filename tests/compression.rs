@@ -0,0 +1,114 @@
+//! Connections compressed with zstd, see `KvsServer::with_compression`
+
+use kvs::client::KvsClient;
+use kvs::common::{Command, Response};
+use kvs::engine::OptLogStructKvs;
+use kvs::protocol::{wrap_stream, Hello, Welcome, PROTOCOL_VERSION};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(temp_dir: &TempDir, address: &str) -> SocketAddr {
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    let address: SocketAddr = address.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .with_compression(true)
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+    address
+}
+
+/// Value of `len` bytes zstd can hardly compress
+fn value(len: usize, seed: u64) -> String {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (b'!' + (state >> 33) as u8 % 90) as char
+        })
+        .collect()
+}
+
+#[test]
+fn small_and_large_values_with_and_without_compression() {
+    let temp_dir = TempDir::new().unwrap();
+    let address = start_server(&temp_dir, "127.0.0.1:4797");
+    for &compress in &[false, true] {
+        let client = KvsClient::connect(&address, compress).unwrap();
+        for &len in &[0, 1, 100, 200_000, 3_000_000] {
+            let key = format!("{}-{}", compress, len);
+            let value = value(len, len as u64);
+            client.set(key.clone(), value.clone()).unwrap();
+            assert_eq!(client.get(key).unwrap(), Some(value));
+        }
+        client.shutdown().unwrap();
+    }
+
+    // Both kinds of clients read what the other wrote
+    let client = KvsClient::connect(&address, true).unwrap();
+    assert_eq!(
+        client.get("false-200000".to_owned()).unwrap(),
+        Some(value(200_000, 200_000))
+    );
+}
+
+#[test]
+fn pipelined_commands_in_one_compressed_block() {
+    let temp_dir = TempDir::new().unwrap();
+    let address = start_server(&temp_dir, "127.0.0.1:4798");
+    let stream = TcpStream::connect(address).unwrap();
+    // A server waiting for input without flushing its responses fails the test instead of hanging it
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    bincode::serialize_into(
+        &stream,
+        &Hello {
+            compress: true,
+            version: PROTOCOL_VERSION,
+        },
+    )
+    .unwrap();
+    let welcome: Welcome = bincode::deserialize_from(&stream).unwrap();
+    assert!(welcome.compress);
+
+    let (mut reader, mut writer) = wrap_stream(&stream, true).unwrap();
+    let large = value(300_000, 7);
+    let commands = vec![
+        Command::Set {
+            key: "small".to_owned(),
+            value: "v".to_owned(),
+        },
+        Command::Set {
+            key: "large".to_owned(),
+            value: large.clone(),
+        },
+        Command::Get {
+            key: "large".to_owned(),
+        },
+        Command::Get {
+            key: "small".to_owned(),
+        },
+    ];
+    for command in &commands {
+        bincode::serialize_into(&mut writer, command).unwrap();
+    }
+    writer.flush().unwrap();
+
+    for expected in &[None, None, Some(large), Some("v".to_owned())] {
+        match bincode::deserialize_from(&mut reader).unwrap() {
+            Response::Ok(value) => assert_eq!(&value, expected),
+            _ => panic!("unexpected response"),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! A connection failing is logged and dropped, the server keeps serving the others
+
+use kvs::client::KvsClient;
+use kvs::engine::{KvsEngine, OptLogStructKvs};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use slog::{o, Drain, Logger, Never, OwnedKVList, Record};
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Keeps the messages logged
+struct Capture(Arc<Mutex<Vec<String>>>);
+
+impl Drain for Capture {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+#[test]
+fn broken_handshake_is_logged_and_dropped() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    let logged = Arc::new(Mutex::new(Vec::new()));
+    let logger = Logger::root(Capture(Arc::clone(&logged)), o!());
+    let address: SocketAddr = "127.0.0.1:4793".parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(1).unwrap())
+            .unwrap()
+            .with_logger(logger)
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    // Half a `Hello`, then the connection is closed
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(&[1, 0]).unwrap();
+    drop(stream);
+    for _ in 0..50 {
+        if !logged.lock().unwrap().is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(
+        *logged.lock().unwrap(),
+        vec!["Connection dropped".to_owned()]
+    );
+
+    // The single worker is free again, a client closing its connection isn't a failure
+    let client = KvsClient::new(&address).unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    drop(client);
+    let client = KvsClient::new(&address).unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert_eq!(logged.lock().unwrap().len(), 1);
+    client.shutdown().unwrap();
+}
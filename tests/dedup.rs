@@ -0,0 +1,75 @@
+//! Values of `OptLogStructKvs` shared by keys, see `EngineConfig::dedup`
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(path: &Path) -> OptLogStructKvs {
+    let config = EngineConfig {
+        dedup: true,
+        ..EngineConfig::default()
+    };
+    OptLogStructKvs::open_with_config(path, config).unwrap()
+}
+
+/// Bytes of the log files of the folder
+fn logs_size(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+#[test]
+fn shared_value_stays_while_a_key_references_it() {
+    let temp_dir = TempDir::new().unwrap();
+    let value = "x".repeat(10_000);
+    let store = open(temp_dir.path());
+    for key in &["a", "b", "c"] {
+        store.set(key.to_string(), value.clone()).unwrap();
+    }
+    assert!(logs_size(temp_dir.path()) < 2 * value.len() as u64);
+
+    store.remove("a".to_owned()).unwrap();
+    store.set("b".to_owned(), "other".to_owned()).unwrap();
+    store.compact().unwrap();
+    assert_eq!(store.get("c".to_owned()).unwrap(), Some(value.clone()));
+    drop(store);
+
+    // The references are counted again on open
+    let store = open(temp_dir.path());
+    assert_eq!(store.get("c".to_owned()).unwrap(), Some(value.clone()));
+    store.set("d".to_owned(), value.clone()).unwrap();
+    store.remove("c".to_owned()).unwrap();
+    store.compact().unwrap();
+    assert_eq!(store.get("d".to_owned()).unwrap(), Some(value.clone()));
+    assert!(logs_size(temp_dir.path()) > value.len() as u64);
+
+    // The last reference gone, compaction drops the value
+    store.remove("d".to_owned()).unwrap();
+    store.compact().unwrap();
+    assert!(logs_size(temp_dir.path()) < value.len() as u64);
+    drop(store);
+    let store = open(temp_dir.path());
+    assert_eq!(store.get("d".to_owned()).unwrap(), None);
+    assert_eq!(store.get("b".to_owned()).unwrap(), Some("other".to_owned()));
+}
+
+#[test]
+fn shared_value_written_again_once_dropped() {
+    let temp_dir = TempDir::new().unwrap();
+    let value = "y".repeat(1_000);
+    let store = open(temp_dir.path());
+    store.set("a".to_owned(), value.clone()).unwrap();
+    store.remove("a".to_owned()).unwrap();
+    // No key references the value, a new one is written instead of a reference to the old one
+    store.set("b".to_owned(), value.clone()).unwrap();
+    store.compact().unwrap();
+    drop(store);
+    let store = open(temp_dir.path());
+    assert_eq!(store.get("b".to_owned()).unwrap(), Some(value));
+    assert!(logs_size(temp_dir.path()) < 2_000);
+}
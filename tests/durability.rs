@@ -0,0 +1,171 @@
+//! Writes a crash of the process loses with each `Durability` level of `OptLogStructKvs`
+//! The folder is copied while the storage is open, the copy holds what reached the OS
+
+use kvs::engine::{Durability, EngineConfig, KvsEngine, OptLogStructKvs};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(path: &Path, durability: Durability) -> OptLogStructKvs {
+    let config = EngineConfig {
+        durability,
+        max_file_size: 4 * 1024,
+        ..EngineConfig::default()
+    };
+    OptLogStructKvs::open_with_config(path, config).unwrap()
+}
+
+/// Opens a copy of the files of `src` as they are now, like a restart after a crash
+fn crashed_copy(src: &Path, durability: Durability) -> (TempDir, OptLogStructKvs) {
+    let copy_dir = TempDir::new().unwrap();
+    for entry in fs::read_dir(src).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_file() {
+            fs::copy(entry.path(), copy_dir.path().join(entry.file_name())).unwrap();
+        }
+    }
+    let store = open(copy_dir.path(), durability);
+    (copy_dir, store)
+}
+
+fn set(store: &OptLogStructKvs, key: &str) {
+    store.set(key.to_owned(), format!("{}-value", key)).unwrap();
+}
+
+fn has(store: &OptLogStructKvs, key: &str) -> bool {
+    store.get(key.to_owned()).unwrap().is_some()
+}
+
+#[test]
+fn none_loses_writes_held_in_the_buffer() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), Durability::None);
+    set(&store, "first");
+    set(&store, "second");
+    let (_copy_dir, copy) = crashed_copy(temp_dir.path(), Durability::None);
+    assert!(!has(&copy, "first"));
+    assert!(!has(&copy, "second"));
+    drop(copy);
+
+    // A read of the write log hands the buffer to the OS
+    assert!(has(&store, "first"));
+    let (_copy_dir, copy) = crashed_copy(temp_dir.path(), Durability::None);
+    assert!(has(&copy, "first"));
+    assert!(has(&copy, "second"));
+}
+
+#[test]
+fn none_keeps_the_logs_it_rolled_over() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), Durability::None);
+    // Well past the max file size, the write log rolls over several times
+    for i in 0..200 {
+        set(&store, &format!("key{}", i));
+    }
+    set(&store, "last");
+    let (_copy_dir, copy) = crashed_copy(temp_dir.path(), Durability::None);
+    assert!(has(&copy, "key0"));
+    assert!(has(&copy, "key100"));
+    assert!(!has(&copy, "last"));
+}
+
+#[test]
+fn os_and_fsync_lose_nothing_on_a_crash() {
+    // Telling `Fsync` apart from `Os` takes a power loss, both hand each write to the OS
+    for &durability in &[Durability::Os, Durability::Fsync] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open(temp_dir.path(), durability);
+        for i in 0..200 {
+            set(&store, &format!("key{}", i));
+        }
+        store.remove("key7".to_owned()).unwrap();
+        let (_copy_dir, copy) = crashed_copy(temp_dir.path(), durability);
+        assert!(!has(&copy, "key7"));
+        for i in (0..200).filter(|&i| i != 7) {
+            assert!(has(&copy, &format!("key{}", i)));
+        }
+    }
+}
+
+#[test]
+fn none_reads_its_own_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), Durability::None);
+    for i in 0..500 {
+        set(&store, &format!("key{}", i));
+        assert_eq!(
+            store.get(format!("key{}", i)).unwrap(),
+            Some(format!("key{}-value", i))
+        );
+        store
+            .update(format!("key{}", i), |value| value.map(|v| v + "!"))
+            .unwrap();
+    }
+    store.compact().unwrap();
+    for i in 0..500 {
+        assert_eq!(
+            store.get(format!("key{}", i)).unwrap(),
+            Some(format!("key{}-value!", i))
+        );
+    }
+    drop(store);
+    let store = open(temp_dir.path(), Durability::None);
+    assert_eq!(
+        store.get("key499".to_owned()).unwrap(),
+        Some("key499-value!".to_owned())
+    );
+}
+
+#[test]
+fn compaction_and_sync_keep_every_record_with_os() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), Durability::Os);
+    for i in 0..200 {
+        set(&store, &format!("key{}", i));
+    }
+    // Syncs the write logs rolled over so far along with the current one
+    store.sync().unwrap();
+    for i in 200..400 {
+        set(&store, &format!("key{}", i));
+    }
+    // The COMPACTED log is synced before the logs it replaces are removed
+    store.compact().unwrap();
+    store.remove("key7".to_owned()).unwrap();
+    for i in 400..600 {
+        set(&store, &format!("key{}", i));
+    }
+    store.sync().unwrap();
+    let (_copy_dir, copy) = crashed_copy(temp_dir.path(), Durability::Os);
+    assert!(!has(&copy, "key7"));
+    for i in (0..600).filter(|&i| i != 7) {
+        assert!(has(&copy, &format!("key{}", i)));
+    }
+    drop(copy);
+    store.merge_compacted().unwrap();
+    let (_copy_dir, copy) = crashed_copy(temp_dir.path(), Durability::Os);
+    assert!(has(&copy, "key0"));
+    assert!(has(&copy, "key599"));
+}
+
+#[test]
+fn checkpoint_spread_over_shards_opens_whole() {
+    let temp_dir = TempDir::new().unwrap();
+    let checkpoint_dir = TempDir::new().unwrap();
+    let config = EngineConfig {
+        durability: Durability::Os,
+        max_file_size: 4 * 1024,
+        log_shards: 4,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config.clone()).unwrap();
+    for i in 0..300 {
+        set(&store, &format!("key{}", i));
+    }
+    // The copy rolls over across the shard folders, each of its files and folders is synced
+    let dest = checkpoint_dir.path().join("copy");
+    store.checkpoint(&dest).unwrap();
+    let copy = OptLogStructKvs::open_with_config(&dest, config).unwrap();
+    for i in 0..300 {
+        assert!(has(&copy, &format!("key{}", i)));
+    }
+}
@@ -0,0 +1,833 @@
+//! Runs the same scenarios against every engine through the `KvsEngine` trait,
+//! so the engines can't drift apart in behavior
+
+use kvs::common::Result;
+use kvs::engine::{
+    Durability, EngineConfig, KvsEngine, LogStructKVStore, OptLogStructKvs, SledStore,
+};
+use kvs::error::KvsError;
+use std::collections::HashMap;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Engine specific parts of the scenarios
+trait TestEngine: KvsEngine + Sized {
+    fn open(path: &Path) -> Result<Self>;
+
+    fn open_with_durability(path: &Path, durability: Durability) -> Result<Self>;
+
+    /// Makes the engine compact its logs now
+    fn force_compaction(&self) -> Result<()>;
+}
+
+impl TestEngine for OptLogStructKvs {
+    fn open(path: &Path) -> Result<Self> {
+        OptLogStructKvs::open(path)
+    }
+
+    fn open_with_durability(path: &Path, durability: Durability) -> Result<Self> {
+        let config = EngineConfig {
+            durability,
+            ..EngineConfig::default()
+        };
+        OptLogStructKvs::open_with_config(path, config)
+    }
+
+    fn force_compaction(&self) -> Result<()> {
+        self.compact()
+    }
+}
+
+impl TestEngine for LogStructKVStore {
+    fn open(path: &Path) -> Result<Self> {
+        LogStructKVStore::open(path)
+    }
+
+    fn open_with_durability(path: &Path, durability: Durability) -> Result<Self> {
+        LogStructKVStore::open_with_durability(path, durability)
+    }
+
+    /// Compaction only runs past 2MB of redundant records, overwrites a filler key until then
+    fn force_compaction(&self) -> Result<()> {
+        let filler = "f".repeat(10_000);
+        for _ in 0..250 {
+            self.set("__filler".to_owned(), filler.clone())?;
+        }
+        self.remove("__filler".to_owned())
+    }
+}
+
+impl TestEngine for SledStore {
+    fn open(path: &Path) -> Result<Self> {
+        SledStore::open(path)
+    }
+
+    fn open_with_durability(path: &Path, durability: Durability) -> Result<Self> {
+        SledStore::open_with_durability(path, durability)
+    }
+
+    /// Sled compacts on its own
+    fn force_compaction(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Opens the storage again, sled releases the folder from a background thread after a drop
+fn reopen<E: TestEngine>(store: E, path: &Path) -> E {
+    drop(store);
+    open_released(path)
+}
+
+/// Opens the storage at `path` once the handle that had it open let it go
+fn open_released<E: TestEngine>(path: &Path) -> E {
+    for _ in 0..50 {
+        if let Ok(store) = E::open(path) {
+            return store;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    E::open(path).unwrap()
+}
+
+fn get<E: KvsEngine>(store: &E, key: &str) -> Option<String> {
+    store.get(key.to_owned()).unwrap()
+}
+
+fn set<E: KvsEngine>(store: &E, key: &str, value: &str) {
+    store.set(key.to_owned(), value.to_owned()).unwrap();
+}
+
+fn get_missing<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    assert_eq!(get(&store, "key"), None);
+    assert!(!store.contains_key("key".to_owned()).unwrap());
+}
+
+fn overwrite_visibility<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    set(&store, "key", "value1");
+    assert_eq!(get(&store, "key"), Some("value1".to_owned()));
+    set(&store, "key", "value2");
+    assert_eq!(get(&store, "key"), Some("value2".to_owned()));
+    // Clones share the storage
+    let clone = store.clone();
+    set(&clone, "key", "value3");
+    assert_eq!(get(&store, "key"), Some("value3".to_owned()));
+}
+
+fn remove_then_get<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    set(&store, "key", "value");
+    store.remove("key".to_owned()).unwrap();
+    assert_eq!(get(&store, "key"), None);
+    assert!(!store.contains_key("key".to_owned()).unwrap());
+    assert!(matches!(
+        store.remove("key".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    set(&store, "key", "again");
+    assert_eq!(get(&store, "key"), Some("again".to_owned()));
+}
+
+fn reopen_persistence<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    for i in 0..100 {
+        set(&store, &format!("key{}", i), &format!("value{}", i));
+    }
+    for i in 0..50 {
+        set(&store, &format!("key{}", i), &format!("new{}", i));
+    }
+    for i in 90..100 {
+        store.remove(format!("key{}", i)).unwrap();
+    }
+
+    let store = reopen(store, temp_dir.path());
+    for i in 0..50 {
+        assert_eq!(get(&store, &format!("key{}", i)), Some(format!("new{}", i)));
+    }
+    for i in 50..90 {
+        assert_eq!(
+            get(&store, &format!("key{}", i)),
+            Some(format!("value{}", i))
+        );
+    }
+    for i in 90..100 {
+        assert_eq!(get(&store, &format!("key{}", i)), None);
+    }
+}
+
+fn after_compaction<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    for round in 0..3 {
+        for i in 0..200 {
+            set(
+                &store,
+                &format!("key{}", i),
+                &format!("value{}-{}", i, round),
+            );
+        }
+    }
+    for i in 100..200 {
+        store.remove(format!("key{}", i)).unwrap();
+    }
+    store.force_compaction().unwrap();
+
+    let check = |store: &E| {
+        for i in 0..100 {
+            assert_eq!(
+                get(store, &format!("key{}", i)),
+                Some(format!("value{}-2", i))
+            );
+        }
+        for i in 100..200 {
+            assert_eq!(get(store, &format!("key{}", i)), None);
+        }
+        assert_eq!(get(store, "__filler"), None);
+    };
+    check(&store);
+    // Writes after compaction land next to the compacted records
+    set(&store, "key0", "after");
+    store.remove("key1".to_owned()).unwrap();
+
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(get(&store, "key0"), Some("after".to_owned()));
+    assert_eq!(get(&store, "key1"), None);
+    set(&store, "key0", "value0-2");
+    set(&store, "key1", "value1-2");
+    check(&store);
+}
+
+fn export_live_pairs<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    set(&store, "a", "1");
+    set(&store, "b", "2");
+    set(&store, "a", "3");
+    set(&store, "c", "4");
+    store.remove("b".to_owned()).unwrap();
+    let mut pairs = Vec::new();
+    store
+        .export(|key, value| {
+            pairs.push((key, value));
+            Ok(())
+        })
+        .unwrap();
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "3".to_owned()),
+            ("c".to_owned(), "4".to_owned())
+        ]
+    );
+}
+
+fn sequences_survive_reopen<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    assert_eq!(store.next_id("seq").unwrap(), 1);
+    assert_eq!(store.next_id_batch("seq", 10).unwrap(), 2..12);
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(store.next_id("seq").unwrap(), 12);
+}
+
+fn sequence_exhausted<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    store
+        .set("__seq:seq".to_owned(), (u64::MAX - 3).to_string())
+        .unwrap();
+    assert_eq!(
+        store.next_id_batch("seq", 2).unwrap(),
+        u64::MAX - 2..u64::MAX
+    );
+    // The end of the range would be past `u64::MAX`, the sequence is left as it was
+    assert!(matches!(
+        store.next_id_batch("seq", 1),
+        Err(KvsError::SequenceExhausted)
+    ));
+    assert!(matches!(
+        store.next_id_batch("seq", u64::MAX),
+        Err(KvsError::SequenceExhausted)
+    ));
+    assert_eq!(get(&store, "__seq:seq"), Some((u64::MAX - 1).to_string()));
+}
+
+fn empty_value<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    set(&store, "empty", "");
+    assert_eq!(get(&store, "empty"), Some(String::new()));
+    assert!(store.contains_key("empty".to_owned()).unwrap());
+
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(get(&store, "empty"), Some(String::new()));
+    store.force_compaction().unwrap();
+    assert_eq!(get(&store, "empty"), Some(String::new()));
+    store.remove("empty".to_owned()).unwrap();
+    assert_eq!(get(&store, "empty"), None);
+}
+
+fn empty_value_overwrite<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    set(&store, "key", "");
+    set(&store, "key", "value");
+    assert_eq!(get(&store, "key"), Some("value".to_owned()));
+    set(&store, "key", "");
+    assert_eq!(get(&store, "key"), Some(String::new()));
+
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(get(&store, "key"), Some(String::new()));
+}
+
+/// The empty key is a key like any other, also when its value is empty
+fn empty_key<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    assert_eq!(get(&store, ""), None);
+    assert!(!store.contains_key(String::new()).unwrap());
+    set(&store, "", "value");
+    set(&store, "key", "");
+    assert_eq!(get(&store, ""), Some("value".to_owned()));
+    assert_eq!(store.key_count().unwrap(), 2);
+    assert_eq!(
+        store.scan_prefix("", Some(1)).unwrap(),
+        vec![(String::new(), "value".to_owned())]
+    );
+
+    set(&store, "", "");
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(get(&store, ""), Some(String::new()));
+    assert!(store.contains_key(String::new()).unwrap());
+    store.force_compaction().unwrap();
+    assert_eq!(get(&store, ""), Some(String::new()));
+    assert_eq!(get(&store, "key"), Some(String::new()));
+    store.remove(String::new()).unwrap();
+    assert_eq!(get(&store, ""), None);
+    assert!(!store.contains_key(String::new()).unwrap());
+    assert_eq!(get(&store, "key"), Some(String::new()));
+}
+
+/// Records are length prefixed, newlines and other control bytes are plain content
+fn newline_bytes<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    let value = "line 1\nline 2\r\n\n\0end\n";
+    set(&store, "key\nwith\nnewlines", value);
+    set(&store, "next", "after");
+    assert_eq!(get(&store, "key\nwith\nnewlines"), Some(value.to_owned()));
+
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(get(&store, "key\nwith\nnewlines"), Some(value.to_owned()));
+    assert_eq!(get(&store, "next"), Some("after".to_owned()));
+    store.force_compaction().unwrap();
+    assert_eq!(get(&store, "key\nwith\nnewlines"), Some(value.to_owned()));
+    assert_eq!(get(&store, "next"), Some("after".to_owned()));
+}
+
+/// A checkpoint taken while pairs are written together holds every pair of a batch or none
+fn checkpoint_copy<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let checkpoint_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    set(&store, "kept", "value");
+    set(&store, "removed", "value");
+    store.remove("removed".to_owned()).unwrap();
+    set(&store, "expiring", "value");
+    assert!(store.expire("expiring".to_owned(), 3600).unwrap());
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for round in 0..200 {
+                let round = round.to_string();
+                store
+                    .extend(vec![
+                        ("left".to_owned(), round.clone()),
+                        ("right".to_owned(), round),
+                    ])
+                    .unwrap();
+            }
+        })
+    };
+    let dest = checkpoint_dir.path().join("copy");
+    store.checkpoint(&dest).unwrap();
+    writer.join().unwrap();
+    assert!(store.checkpoint(&dest).is_err());
+
+    let copy: E = open_released(&dest);
+    assert_eq!(get(&copy, "kept"), Some("value".to_owned()));
+    assert_eq!(get(&copy, "removed"), None);
+    assert_eq!(get(&copy, "expiring"), Some("value".to_owned()));
+    assert_eq!(get(&copy, "left"), get(&copy, "right"));
+    // The copy is a storage of its own
+    set(&copy, "kept", "changed");
+    assert_eq!(get(&store, "kept"), Some("value".to_owned()));
+    assert_eq!(get(&store, "left"), Some("199".to_owned()));
+}
+
+/// The deadline of a key is reported in milliseconds, kept across a reopen and cleared by a set
+fn expiry_deadline<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    set(&store, "key", "value");
+    assert_eq!(store.expires_at("key".to_owned()).unwrap(), None);
+    assert_eq!(store.expires_at("missing".to_owned()).unwrap(), None);
+    let unix_secs = 4_000_000_000;
+    assert!(store.expire_at("key".to_owned(), unix_secs).unwrap());
+    assert_eq!(
+        store.expires_at("key".to_owned()).unwrap(),
+        Some(unix_secs * 1000)
+    );
+
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(
+        store.expires_at("key".to_owned()).unwrap(),
+        Some(unix_secs * 1000)
+    );
+    set(&store, "key", "again");
+    assert_eq!(store.expires_at("key".to_owned()).unwrap(), None);
+}
+
+/// Consumers popping the same keys get every value exactly once between them
+fn get_and_remove_consumers<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_and_remove("missing".to_owned()).unwrap(), None);
+    store
+        .extend((0..200).map(|i| (format!("item{}", i), format!("work{}", i))))
+        .unwrap();
+
+    let consumers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let mut popped = Vec::new();
+                for i in 0..200 {
+                    if let Some(value) = store.get_and_remove(format!("item{}", i)).unwrap() {
+                        popped.push((i, value));
+                    }
+                }
+                popped
+            })
+        })
+        .collect();
+    let mut popped: Vec<_> = consumers
+        .into_iter()
+        .flat_map(|consumer| consumer.join().unwrap())
+        .collect();
+    popped.sort();
+    let expected: Vec<_> = (0..200).map(|i| (i, format!("work{}", i))).collect();
+    assert_eq!(popped, expected);
+
+    let store = reopen(store, temp_dir.path());
+    for i in 0..200 {
+        assert_eq!(get(&store, &format!("item{}", i)), None);
+    }
+}
+
+/// Writes of every level are found again after the handle is dropped without a sync
+/// Whether `Fsync` writes reach the disk right away can't be told from a running process
+fn durability_modes<E: TestEngine>() {
+    for &durability in &[Durability::None, Durability::Os, Durability::Fsync] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = E::open_with_durability(temp_dir.path(), durability).unwrap();
+        for i in 0..50 {
+            set(&store, &format!("key{}", i), &format!("value{}", i));
+        }
+        store.remove("key0".to_owned()).unwrap();
+        drop(store);
+
+        let store: E = open_released(temp_dir.path());
+        assert_eq!(get(&store, "key0"), None);
+        for i in 1..50 {
+            assert_eq!(
+                get(&store, &format!("key{}", i)),
+                Some(format!("value{}", i))
+            );
+        }
+    }
+}
+
+/// Prefix scans agree on the matching pairs, their order and the limit
+fn scan_prefix_sorted<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    for key in &["b:3", "a:1", "b:1", "b", "b:2", "c:1", "b:4"] {
+        set(&store, key, &key.to_uppercase());
+    }
+    store.remove("b:2".to_owned()).unwrap();
+    // A deadline in the past removes the key
+    store.expire_at("b:4".to_owned(), 1).unwrap();
+
+    let pairs = |keys: &[&str]| -> Vec<(String, String)> {
+        keys.iter()
+            .map(|key| (key.to_string(), key.to_uppercase()))
+            .collect()
+    };
+    assert_eq!(
+        store.scan_prefix("b", None).unwrap(),
+        pairs(&["b", "b:1", "b:3"])
+    );
+    assert_eq!(store.scan_prefix("b:", Some(1)).unwrap(), pairs(&["b:1"]));
+    assert_eq!(store.scan_prefix("d", None).unwrap(), pairs(&[]));
+    assert_eq!(store.scan_prefix("", None).unwrap().len(), 5);
+}
+
+/// Matching leaves out the expired and sequence keys, the literal prefix doesn't change the result
+fn keys_matching_counted<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    for key in &["user:2", "order:1", "user:10", "user:1", "user:3", "users"] {
+        set(&store, key, "value");
+    }
+    store.expire_at("user:3".to_owned(), 1).unwrap();
+    assert_eq!(store.next_id("user:1").unwrap(), 1);
+
+    let keys =
+        |expected: &[&str]| -> Vec<String> { expected.iter().map(|key| key.to_string()).collect() };
+    assert_eq!(
+        store.keys_matching("user:*").unwrap(),
+        keys(&["user:1", "user:10", "user:2"])
+    );
+    assert_eq!(store.count_matching("user:*").unwrap(), 3);
+    assert_eq!(store.count_matching("user:?").unwrap(), 2);
+    assert_eq!(
+        store.keys_matching("*:1").unwrap(),
+        keys(&["order:1", "user:1"])
+    );
+    assert_eq!(store.count_matching("*").unwrap(), 5);
+    assert_eq!(store.count_matching("nobody*").unwrap(), 0);
+
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(store.count_matching("user*").unwrap(), 4);
+}
+
+/// Counting and clearing leave out the removed, expired and sequence keys alike
+fn key_count_and_clear<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    assert_eq!(store.key_count().unwrap(), 0);
+    for i in 0..10 {
+        set(&store, &format!("key{}", i), "value");
+    }
+    store.remove("key0".to_owned()).unwrap();
+    store.expire_at("key1".to_owned(), 1).unwrap();
+    assert_eq!(store.next_id("orders").unwrap(), 1);
+    assert_eq!(store.key_count().unwrap(), 8);
+
+    assert_eq!(store.clear().unwrap(), 8);
+    assert_eq!(store.key_count().unwrap(), 0);
+    assert_eq!(get(&store, "key5"), None);
+    assert_eq!(store.next_id("orders").unwrap(), 2);
+    set(&store, "key5", "again");
+    assert_eq!(store.key_count().unwrap(), 1);
+}
+
+/// Enough keys for several chunks of the optimized log engine, neighbours of the prefix stay
+fn remove_prefix_matching_only<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    for i in 0..600 {
+        set(&store, &format!("tenant:1:{}", i), "value");
+    }
+    for key in &["tenant:1", "tenant:10:a", "tenant:2:a", "a"] {
+        set(&store, key, "kept");
+    }
+    assert_eq!(store.next_id("tenant:1:").unwrap(), 1);
+    store.expire_at("tenant:1:0".to_owned(), 1).unwrap();
+
+    assert_eq!(store.remove_prefix("tenant:1:".to_owned()).unwrap(), 599);
+    assert_eq!(store.remove_prefix("tenant:1:".to_owned()).unwrap(), 0);
+    assert_eq!(get(&store, "tenant:1:5"), None);
+    assert_eq!(store.key_count().unwrap(), 4);
+
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(store.scan_prefix("tenant:1:", None).unwrap(), vec![]);
+    for key in &["tenant:1", "tenant:10:a", "tenant:2:a", "a"] {
+        assert_eq!(get(&store, key), Some("kept".to_owned()));
+    }
+    assert_eq!(store.remove_prefix(String::new()).unwrap(), 4);
+    assert_eq!(store.next_id("tenant:1:").unwrap(), 2);
+
+    // Glob characters of the prefix are literal
+    for key in &["a*[b]?:1", "a*[b]?:2", "ab:1", "a\\:1"] {
+        set(&store, key, "value");
+    }
+    assert_eq!(store.remove_prefix("a*[b]?".to_owned()).unwrap(), 2);
+    assert_eq!(store.key_count().unwrap(), 2);
+}
+
+fn replace_existing_only<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    assert!(!store
+        .replace("key".to_owned(), "value1".to_owned())
+        .unwrap());
+    assert_eq!(get(&store, "key"), None);
+
+    set(&store, "key", "value1");
+    assert!(store
+        .replace("key".to_owned(), "value2".to_owned())
+        .unwrap());
+    assert_eq!(get(&store, "key"), Some("value2".to_owned()));
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(get(&store, "key"), Some("value2".to_owned()));
+}
+
+/// Threads racing to set a missing key, exactly one of them wins
+fn set_if_absent_race<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    let racers: Vec<_> = (0..16)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                store
+                    .set_if_absent("lock".to_owned(), format!("owner{}", i))
+                    .unwrap()
+            })
+        })
+        .collect();
+    let winners: Vec<usize> = racers
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, racer)| if racer.join().unwrap() { Some(i) } else { None })
+        .collect();
+    assert_eq!(winners.len(), 1);
+    assert_eq!(get(&store, "lock"), Some(format!("owner{}", winners[0])));
+    assert!(!store
+        .set_if_absent("lock".to_owned(), "late".to_owned())
+        .unwrap());
+}
+
+/// Threads incrementing shared counters through `update`, no increment is lost
+/// A counter is removed when it reaches its limit and started over by the next increment
+fn update_concurrent_increments<E: TestEngine>() {
+    const THREADS: u64 = 8;
+    const INCREMENTS: u64 = 250;
+    const WRAP: u64 = 9;
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    let workers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..INCREMENTS {
+                    let key = format!("counter{}", i % 2);
+                    store
+                        .update(key, |old| {
+                            let count = old.map_or(0, |old| old.parse::<u64>().unwrap()) + 1;
+                            Some(count.to_string())
+                        })
+                        .unwrap();
+                    store
+                        .update("wrapping".to_owned(), |old| {
+                            match old.map_or(0, |old| old.parse::<u64>().unwrap()) + 1 {
+                                WRAP => None,
+                                count => Some(count.to_string()),
+                            }
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    let total = THREADS * INCREMENTS;
+    assert_eq!(get(&store, "counter0"), Some((total / 2).to_string()));
+    assert_eq!(get(&store, "counter1"), Some((total / 2).to_string()));
+    assert_eq!(get(&store, "wrapping"), Some((total % WRAP).to_string()));
+
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(get(&store, "counter0"), Some((total / 2).to_string()));
+    assert_eq!(get(&store, "wrapping"), Some((total % WRAP).to_string()));
+}
+
+/// Pairs of a `HashMap` written in one batch are all found, before and after a reopen
+fn extend_from_map<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    set(&store, "key0", "old");
+    let pairs: HashMap<String, String> = (0..500)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    store.extend(pairs.clone()).unwrap();
+    store.extend(HashMap::new()).unwrap();
+    assert_eq!(store.key_count().unwrap(), 500);
+    for (key, value) in &pairs {
+        assert_eq!(get(&store, key).as_ref(), Some(value));
+    }
+
+    let store = reopen(store, temp_dir.path());
+    assert_eq!(store.key_count().unwrap(), 500);
+    assert_eq!(get(&store, "key0"), Some("value0".to_owned()));
+    assert_eq!(get(&store, "key499"), Some("value499".to_owned()));
+}
+
+/// Warming up a reopened storage reads it through without changing a pair
+fn warmup_then_get<E: TestEngine>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open(temp_dir.path()).unwrap();
+    for i in 0..1000 {
+        set(&store, &format!("key{}", i), &format!("value{}", i));
+    }
+    store.remove("key0".to_owned()).unwrap();
+
+    let store = reopen(store, temp_dir.path());
+    store.warmup().unwrap();
+    assert_eq!(get(&store, "key0"), None);
+    for i in 1..1000 {
+        assert_eq!(
+            get(&store, &format!("key{}", i)),
+            Some(format!("value{}", i))
+        );
+    }
+}
+
+macro_rules! engine_tests {
+    ($module:ident, $engine:ty) => {
+        mod $module {
+            use super::*;
+
+            #[test]
+            fn get_missing() {
+                super::get_missing::<$engine>();
+            }
+
+            #[test]
+            fn overwrite_visibility() {
+                super::overwrite_visibility::<$engine>();
+            }
+
+            #[test]
+            fn remove_then_get() {
+                super::remove_then_get::<$engine>();
+            }
+
+            #[test]
+            fn reopen_persistence() {
+                super::reopen_persistence::<$engine>();
+            }
+
+            #[test]
+            fn after_compaction() {
+                super::after_compaction::<$engine>();
+            }
+
+            #[test]
+            fn export_live_pairs() {
+                super::export_live_pairs::<$engine>();
+            }
+
+            #[test]
+            fn sequences_survive_reopen() {
+                super::sequences_survive_reopen::<$engine>();
+            }
+
+            #[test]
+            fn sequence_exhausted() {
+                super::sequence_exhausted::<$engine>();
+            }
+
+            #[test]
+            fn empty_value() {
+                super::empty_value::<$engine>();
+            }
+
+            #[test]
+            fn empty_value_overwrite() {
+                super::empty_value_overwrite::<$engine>();
+            }
+
+            #[test]
+            fn empty_key() {
+                super::empty_key::<$engine>();
+            }
+
+            #[test]
+            fn newline_bytes() {
+                super::newline_bytes::<$engine>();
+            }
+
+            #[test]
+            fn checkpoint_copy() {
+                super::checkpoint_copy::<$engine>();
+            }
+
+            #[test]
+            fn expiry_deadline() {
+                super::expiry_deadline::<$engine>();
+            }
+
+            #[test]
+            fn get_and_remove_consumers() {
+                super::get_and_remove_consumers::<$engine>();
+            }
+
+            #[test]
+            fn scan_prefix_sorted() {
+                super::scan_prefix_sorted::<$engine>();
+            }
+
+            #[test]
+            fn keys_matching_counted() {
+                super::keys_matching_counted::<$engine>();
+            }
+
+            #[test]
+            fn key_count_and_clear() {
+                super::key_count_and_clear::<$engine>();
+            }
+
+            #[test]
+            fn remove_prefix_matching_only() {
+                super::remove_prefix_matching_only::<$engine>();
+            }
+
+            #[test]
+            fn replace_existing_only() {
+                super::replace_existing_only::<$engine>();
+            }
+
+            #[test]
+            fn set_if_absent_race() {
+                super::set_if_absent_race::<$engine>();
+            }
+
+            #[test]
+            fn durability_modes() {
+                super::durability_modes::<$engine>();
+            }
+
+            #[test]
+            fn update_concurrent_increments() {
+                super::update_concurrent_increments::<$engine>();
+            }
+
+            #[test]
+            fn extend_from_map() {
+                super::extend_from_map::<$engine>();
+            }
+
+            #[test]
+            fn warmup_then_get() {
+                super::warmup_then_get::<$engine>();
+            }
+        }
+    };
+}
+
+engine_tests!(opt_log_struct, OptLogStructKvs);
+engine_tests!(log_struct, LogStructKVStore);
+engine_tests!(sled, SledStore);
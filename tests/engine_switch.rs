@@ -0,0 +1,68 @@
+//! `kvs-server` refusing to open a storage with another engine than the one that created it
+
+use std::path::Path;
+use std::process::{self, Child, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const ADDRESS: &str = "127.0.0.1:4783";
+
+fn spawn_server(dir: &Path, engine: &str) -> Child {
+    process::Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--engine", engine, "--addr", ADDRESS])
+        .current_dir(dir)
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+fn client(args: &[&str]) -> String {
+    let output = process::Command::new(env!("CARGO_BIN_EXE_kvs-client"))
+        .args(args)
+        .args(["--addr", ADDRESS])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Waits for a server that should exit on its own, killing it after 5 seconds
+fn wait_exit(server: &mut Child) -> ExitStatus {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Some(status) = server.try_wait().unwrap() {
+            return status;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    server.kill().unwrap();
+    panic!("server with the other engine kept running");
+}
+
+fn stop(mut server: Child) {
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn other_engine_is_refused_and_data_kept() {
+    let temp_dir = TempDir::new().unwrap();
+
+    let server = spawn_server(temp_dir.path(), "kvs");
+    thread::sleep(Duration::from_millis(500));
+    client(&["set", "key1", "value1"]);
+    stop(server);
+
+    let mut server = spawn_server(temp_dir.path(), "sled");
+    let status = wait_exit(&mut server);
+    assert!(!status.success());
+    let output = server.wait_with_output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("created with kvs and can't be opened with sled"));
+
+    let server = spawn_server(temp_dir.path(), "kvs");
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client(&["get", "key1"]), "value1\n");
+    stop(server);
+}
@@ -0,0 +1,334 @@
+//! Expired keys are reclaimed by compaction and on open, and don't count against `max_keys`
+
+use kvs::engine::{
+    EngineConfig, KvsEngine, LogStructKVStore, MockClock, OnEvict, OptLogStructKvs, SledStore,
+};
+use kvs::error::KvsError;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn open(path: &Path, clock: &MockClock) -> OptLogStructKvs {
+    let config = EngineConfig {
+        clock: Arc::new(clock.clone()),
+        ..EngineConfig::default()
+    };
+    OptLogStructKvs::open_with_config(path, config).unwrap()
+}
+
+/// Bytes of the files directly in the folder
+fn folder_size(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[test]
+fn compaction_drops_expired_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let clock = MockClock::default();
+    let store = open(temp_dir.path(), &clock);
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        store.set(key.clone(), "x".repeat(100)).unwrap();
+        if i % 2 == 0 {
+            store.expire(key, 10).unwrap();
+        }
+    }
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(store.stats().unwrap().keys, 100);
+    store.compact().unwrap();
+    assert_eq!(store.stats().unwrap().keys, 50);
+    assert_eq!(store.get("key0".to_owned()).unwrap(), None);
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("x".repeat(100)));
+    drop(store);
+
+    let store = open(temp_dir.path(), &clock);
+    assert_eq!(store.stats().unwrap().keys, 50);
+    assert_eq!(store.get("key0".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn open_drops_expired_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let clock = MockClock::default();
+    let store = open(temp_dir.path(), &clock);
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    store.set("gone".to_owned(), "value".to_owned()).unwrap();
+    store.expire("gone".to_owned(), 10).unwrap();
+    drop(store);
+
+    clock.advance(Duration::from_secs(20));
+    let store = open(temp_dir.path(), &clock);
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.keys, 1);
+    assert!(stats.uncompacted_size > 0);
+    assert_eq!(store.get("gone".to_owned()).unwrap(), None);
+    // The key can be set again, and its records are not replayed over the new one
+    store.set("gone".to_owned(), "again".to_owned()).unwrap();
+    store.compact().unwrap();
+    drop(store);
+    let store = open(temp_dir.path(), &clock);
+    assert_eq!(
+        store.get("gone".to_owned()).unwrap(),
+        Some("again".to_owned())
+    );
+}
+
+#[test]
+fn expired_keys_dont_count_against_max_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let clock = MockClock::default();
+    let config = EngineConfig {
+        clock: Arc::new(clock.clone()),
+        max_keys: Some(2),
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    store.expire("b".to_owned(), 10).unwrap();
+    match store.set("c".to_owned(), "3".to_owned()) {
+        Err(KvsError::QuotaExceeded) => {}
+        other => panic!("expected QuotaExceeded, got {:?}", other.map(|_| ())),
+    }
+
+    clock.advance(Duration::from_secs(20));
+    store.set("c".to_owned(), "3".to_owned()).unwrap();
+    // The expired key was reclaimed to make room
+    assert_eq!(store.stats().unwrap().keys, 2);
+    assert!(matches!(
+        store.set("d".to_owned(), "4".to_owned()),
+        Err(KvsError::QuotaExceeded)
+    ));
+    drop(store);
+
+    let config = EngineConfig {
+        clock: Arc::new(clock.clone()),
+        max_keys: Some(2),
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    assert_eq!(store.stats().unwrap().keys, 2);
+    assert_eq!(store.get("b".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn lapsed_ttl_fires_on_evict_once() {
+    let temp_dir = TempDir::new().unwrap();
+    let clock = MockClock::default();
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    let open = |path: &Path| {
+        let evicted = evicted.clone();
+        let config = EngineConfig {
+            clock: Arc::new(clock.clone()),
+            on_evict: Some(OnEvict::new(move |key| {
+                evicted.lock().unwrap().push(key.to_owned())
+            })),
+            ..EngineConfig::default()
+        };
+        OptLogStructKvs::open_with_config(path, config).unwrap()
+    };
+    let store = open(temp_dir.path());
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    store.set("removed".to_owned(), "value".to_owned()).unwrap();
+    store.expire("removed".to_owned(), 10).unwrap();
+    store.remove("removed".to_owned()).unwrap();
+    store
+        .set("compacted".to_owned(), "value".to_owned())
+        .unwrap();
+    store.expire("compacted".to_owned(), 10).unwrap();
+    clock.advance(Duration::from_secs(20));
+    store.compact().unwrap();
+    store.compact().unwrap();
+    assert_eq!(*evicted.lock().unwrap(), ["compacted"]);
+
+    // Expires while the storage is closed, open reclaims it and compaction then has nothing to drop
+    store
+        .set("reopened".to_owned(), "value".to_owned())
+        .unwrap();
+    store.expire("reopened".to_owned(), 10).unwrap();
+    drop(store);
+    clock.advance(Duration::from_secs(20));
+    let store = open(temp_dir.path());
+    store.compact().unwrap();
+    drop(store);
+    let store = open(temp_dir.path());
+    assert_eq!(*evicted.lock().unwrap(), ["compacted", "reopened"]);
+    assert_eq!(
+        store.get("kept".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn lskv_compaction_and_open_drop_expired_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let clock = MockClock::default();
+    let open = |path: &Path| LogStructKVStore::open_with_clock(path, Arc::new(clock.clone()));
+    let store = open(temp_dir.path()).unwrap();
+    for i in 0..100 {
+        store.set(format!("key{}", i), "x".repeat(1000)).unwrap();
+        store.expire(format!("key{}", i), 1).unwrap();
+    }
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    clock.advance(Duration::from_secs(2));
+    // Overwrites until the compaction threshold is reached, the folder then shrinks
+    let filler = "y".repeat(300_000);
+    let mut size = folder_size(temp_dir.path());
+    loop {
+        store.set("filler".to_owned(), filler.clone()).unwrap();
+        let new_size = folder_size(temp_dir.path());
+        if new_size < size {
+            break;
+        }
+        size = new_size;
+    }
+    // The expired values aren't copied to the compacted log
+    assert!(folder_size(temp_dir.path()) < 2 * filler.len() as u64 + 50_000);
+    assert_eq!(store.get("key0".to_owned()).unwrap(), None);
+    drop(store);
+
+    let store = open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("kept".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    store.set("short".to_owned(), "value".to_owned()).unwrap();
+    store.expire("short".to_owned(), 1).unwrap();
+    drop(store);
+    clock.advance(Duration::from_secs(2));
+    let store = open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("short".to_owned()).unwrap(), None);
+    assert_eq!(
+        store.get("kept".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn sled_purges_expired_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let clock = MockClock::default();
+    // Sled releases the folder from a background thread after a drop
+    let open = |path: &Path| {
+        for _ in 0..50 {
+            if let Ok(store) = SledStore::open_with_clock(path, Arc::new(clock.clone())) {
+                return Ok(store);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        SledStore::open_with_clock(path, Arc::new(clock.clone()))
+    };
+    let store = open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    store.set("gone".to_owned(), "value".to_owned()).unwrap();
+    store
+        .touch("gone".to_owned(), Duration::from_millis(50))
+        .unwrap();
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(store.purge_expired().unwrap(), 1);
+    assert_eq!(store.purge_expired().unwrap(), 0);
+    // Without its deadline the value would be read again if it were still there
+    assert_eq!(store.get_raw("gone".to_owned()).unwrap(), None);
+    assert_eq!(
+        store.get_raw("kept".to_owned()).unwrap(),
+        Some(b"value".to_vec())
+    );
+
+    // Open purges the values that expired while the storage was closed
+    store
+        .touch("kept".to_owned(), Duration::from_millis(50))
+        .unwrap();
+    drop(store);
+    clock.advance(Duration::from_millis(100));
+    let store = open(temp_dir.path()).unwrap();
+    assert_eq!(store.purge_expired().unwrap(), 0);
+    assert_eq!(store.get_raw("kept".to_owned()).unwrap(), None);
+}
+
+/// The three engines read the time from the clock they are given, no sleep needed
+#[test]
+fn every_engine_expires_on_the_mock_clock() {
+    fn check<E: KvsEngine>(store: E, clock: &MockClock) {
+        store.set("key".to_owned(), "value".to_owned()).unwrap();
+        assert!(store.expire("key".to_owned(), 60).unwrap());
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(
+            store.get("key".to_owned()).unwrap(),
+            Some("value".to_owned()),
+            "{}",
+            store.name()
+        );
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(
+            store.get("key".to_owned()).unwrap(),
+            None,
+            "{}",
+            store.name()
+        );
+    }
+
+    let temp_dirs = [
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+    ];
+    let clock = MockClock::default();
+    check(open(temp_dirs[0].path(), &clock), &clock);
+    check(
+        LogStructKVStore::open_with_clock(temp_dirs[1].path(), Arc::new(clock.clone())).unwrap(),
+        &clock,
+    );
+    check(
+        SledStore::open_with_clock(temp_dirs[2].path(), Arc::new(clock.clone())).unwrap(),
+        &clock,
+    );
+}
+
+/// `touch` pushes the deadline back, through a small record in `LogStructKVStore`
+/// kept on open and folded into the set record by compaction
+#[test]
+fn lskv_touch_extends_the_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+    let clock = MockClock::default();
+    let open = |path: &Path| LogStructKVStore::open_with_clock(path, Arc::new(clock.clone()));
+    let store = open(temp_dir.path()).unwrap();
+    let value = "x".repeat(10_000);
+    store.set("key".to_owned(), value.clone()).unwrap();
+    assert!(store.expire("key".to_owned(), 10).unwrap());
+
+    let before = folder_size(temp_dir.path());
+    assert!(store
+        .touch("key".to_owned(), Duration::from_secs(60))
+        .unwrap());
+    assert!(folder_size(temp_dir.path()) - before < 100);
+    assert!(!store
+        .touch("missing".to_owned(), Duration::from_secs(60))
+        .unwrap());
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some(value.clone()));
+    drop(store);
+
+    let store = open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some(value.clone()));
+    // Enough overwritten bytes to compact the logs
+    for _ in 0..25 {
+        store.set("filler".to_owned(), "y".repeat(100_000)).unwrap();
+    }
+    assert!(folder_size(temp_dir.path()) < 1_000_000);
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some(value.clone()));
+    drop(store);
+
+    let store = open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some(value));
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(store.get("key".to_owned()).unwrap(), None);
+}
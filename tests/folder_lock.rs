@@ -0,0 +1,96 @@
+//! The advisory lock of a storage folder, a second open fails fast with `AlreadyOpen`
+
+use kvs::common::Result;
+use kvs::engine::{KvsEngine, LogStructKVStore, OptLogStructKvs};
+use kvs::error::KvsError;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tempfile::TempDir;
+
+/// Opens racing from threads, only one of them gets the folder
+fn racing_opens<E, O>(open: O)
+where
+    E: KvsEngine,
+    O: Fn(&Path) -> Result<E> + Send + Sync + 'static,
+{
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().to_path_buf();
+    let open = Arc::new(open);
+    let barrier = Arc::new(Barrier::new(4));
+    let openers: Vec<_> = (0..4)
+        .map(|_| {
+            let open = Arc::clone(&open);
+            let path = path.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                open(&path)
+            })
+        })
+        .collect();
+    let results: Vec<Result<E>> = openers
+        .into_iter()
+        .map(|opener| opener.join().unwrap())
+        .collect();
+    let store = {
+        let mut opened = results.into_iter().filter_map(|result| match result {
+            Ok(store) => Some(store),
+            Err(KvsError::AlreadyOpen) => None,
+            Err(err) => panic!("{:?}", err),
+        });
+        let store = opened.next().unwrap();
+        assert!(opened.next().is_none());
+        store
+    };
+
+    // A clone shares the lock
+    let clone = store.clone();
+    clone.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(matches!(open(&path), Err(KvsError::AlreadyOpen)));
+    drop(store);
+    assert!(matches!(open(&path), Err(KvsError::AlreadyOpen)));
+    drop(clone);
+
+    let store = open(&path).unwrap();
+    assert_eq!(
+        store.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn opt_log_struct_second_open_fails() {
+    racing_opens(OptLogStructKvs::open);
+}
+
+#[test]
+fn log_struct_second_open_fails() {
+    racing_opens(LogStructKVStore::open);
+}
+
+#[test]
+fn other_process_can_not_open_nor_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(matches!(
+        OptLogStructKvs::repair(temp_dir.path()),
+        Err(KvsError::AlreadyOpen)
+    ));
+
+    let fsck = || {
+        process::Command::new(env!("CARGO_BIN_EXE_kvs-admin"))
+            .args(["fsck", temp_dir.path().to_str().unwrap()])
+            .output()
+            .unwrap()
+    };
+    let output = fsck();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("AlreadyOpen"));
+    drop(store);
+    assert!(fsck().status.success());
+}
@@ -0,0 +1,89 @@
+//! `OptLogStructKvs::fsck` and `kvs-admin fsck` on logs changed behind the back of the storage
+
+use kvs::engine::{KvsEngine, OptLogStructKvs};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use tempfile::TempDir;
+
+/// The log file of the folder holding `needle`, and where
+fn find_in_logs(path: &Path, needle: &[u8]) -> (PathBuf, usize) {
+    for entry in fs::read_dir(path).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            let bytes = fs::read(&path).unwrap();
+            if let Some(pos) = bytes.windows(needle.len()).position(|x| x == needle) {
+                return (path, pos);
+            }
+        }
+    }
+    panic!("{:?} not found in the logs", needle);
+}
+
+#[test]
+fn record_of_another_key_is_reported() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    store.sync().unwrap();
+    assert!(store.fsck().unwrap().is_ok());
+
+    // Same length, the index still points at a well formed record, of another key
+    let (log, pos) = find_in_logs(temp_dir.path(), b"key1");
+    let mut bytes = fs::read(&log).unwrap();
+    bytes[pos..pos + 4].copy_from_slice(b"kez1");
+    fs::write(&log, bytes).unwrap();
+
+    let report = store.fsck().unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(report.checked, 2);
+    assert_eq!(report.mismatched, ["key1"]);
+    // The log now sets a key the index doesn't know
+    assert_eq!(report.unindexed, ["kez1"]);
+    assert!(report.orphaned.is_empty());
+    assert!(report.corrupted.is_empty());
+}
+
+#[test]
+fn admin_fsck_reports_an_unreadable_tail() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let fsck = || {
+        process::Command::new(env!("CARGO_BIN_EXE_kvs-admin"))
+            .args(["fsck", temp_dir.path().to_str().unwrap()])
+            .output()
+            .unwrap()
+    };
+    let output = fsck();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Checked keys: 1\n"
+    );
+
+    let (log, _) = find_in_logs(temp_dir.path(), b"key");
+    let size = fs::metadata(&log).unwrap().len();
+    // A record cut short by a crash
+    OpenOptions::new()
+        .append(true)
+        .open(&log)
+        .unwrap()
+        .write_all(&[0, 0, 0, 0, 9])
+        .unwrap();
+    let output = fsck();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains(&format!(
+            "Unreadable tail: {} from {}\n",
+            log.display(),
+            size
+        )),
+        "{}",
+        stdout
+    );
+}
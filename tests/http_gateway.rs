@@ -0,0 +1,88 @@
+//! kvs-http proxying to a kvs-server, whatever answer the server gives to a missing key
+
+use kvs::engine::{KvsEngine, LogStructKVStore};
+use kvs::server::{KvsServer, MissingKeyResponse};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Kills the gateway when the test ends, passed or not
+struct Gateway(Child);
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Serves a storage holding `empty` set to an empty value, with a gateway in front of it
+fn serve(
+    server: &str,
+    gateway: &str,
+    missing_key_response: MissingKeyResponse,
+) -> (Gateway, TempDir) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = LogStructKVStore::open(temp_dir.path()).unwrap();
+    store.set("empty".to_owned(), "".to_owned()).unwrap();
+    let address: SocketAddr = server.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .with_missing_key_response(missing_key_response)
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+    let child = Command::new(env!("CARGO_BIN_EXE_kvs-http"))
+        .args(["--server", server, "--addr", gateway])
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    (Gateway(child), temp_dir)
+}
+
+/// Status code of a `GET` of the key
+fn status(gateway: &str, key: &str) -> u32 {
+    let mut stream = TcpStream::connect(gateway).unwrap();
+    write!(
+        stream,
+        "GET /kv/{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        key, gateway
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.split(' ').nth(1).unwrap().parse().unwrap()
+}
+
+#[test]
+fn missing_key_is_not_found_for_every_server_answer() {
+    let servers = [
+        (
+            "127.0.0.1:4801",
+            "127.0.0.1:4804",
+            MissingKeyResponse::Error,
+        ),
+        (
+            "127.0.0.1:4802",
+            "127.0.0.1:4805",
+            MissingKeyResponse::Empty,
+        ),
+        ("127.0.0.1:4803", "127.0.0.1:4806", MissingKeyResponse::Null),
+    ];
+    for &(server, gateway, missing_key_response) in &servers {
+        let (_gateway, _temp_dir) = serve(server, gateway, missing_key_response);
+        assert_eq!(
+            status(gateway, "missing"),
+            404,
+            "{:?}",
+            missing_key_response
+        );
+        assert_eq!(status(gateway, "empty"), 200, "{:?}", missing_key_response);
+    }
+}
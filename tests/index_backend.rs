@@ -0,0 +1,69 @@
+//! `OptLogStructKvs` on the two index backends, see `OptLogStructKvs::open_with_index`
+
+use kvs::engine::{
+    BTreeBackend, EngineConfig, IndexBackend, KvsEngine, OptLogStructKvs, SkipMapBackend,
+};
+use std::ops::Bound;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open<B: IndexBackend>(path: &Path) -> OptLogStructKvs<B> {
+    OptLogStructKvs::<B>::open_with_index(path, EngineConfig::default()).unwrap()
+}
+
+fn check_backend<B: IndexBackend>() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open::<B>(temp_dir.path());
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), i.to_string()).unwrap();
+    }
+    store.remove("key050".to_owned()).unwrap();
+    store.set("key001".to_owned(), "again".to_owned()).unwrap();
+    assert_eq!(
+        store.get("key001".to_owned()).unwrap(),
+        Some("again".to_owned())
+    );
+    assert_eq!(store.get("key050".to_owned()).unwrap(), None);
+    let pairs = store
+        .range(
+            Bound::Excluded("key048".to_owned()),
+            Bound::Included("key052".to_owned()),
+        )
+        .unwrap();
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, ["key049", "key051", "key052"]);
+    assert!(store
+        .range(
+            Bound::Excluded("key052".to_owned()),
+            Bound::Excluded("key052".to_owned()),
+        )
+        .unwrap()
+        .is_empty());
+    assert_eq!(store.scan_prefix("key09", None).unwrap().len(), 10);
+
+    store.compact().unwrap();
+    assert_eq!(store.verify().unwrap().checked, 99);
+    assert_eq!(
+        store.get("key099".to_owned()).unwrap(),
+        Some("99".to_owned())
+    );
+    drop(store);
+
+    // The logs don't depend on the backend
+    let store = open::<SkipMapBackend>(temp_dir.path());
+    assert_eq!(store.stats().unwrap().keys, 99);
+    assert_eq!(
+        store.get("key001".to_owned()).unwrap(),
+        Some("again".to_owned())
+    );
+}
+
+#[test]
+fn skip_map_backend() {
+    check_backend::<SkipMapBackend>();
+}
+
+#[test]
+fn btree_backend() {
+    check_backend::<BTreeBackend>();
+}
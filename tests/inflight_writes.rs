@@ -0,0 +1,80 @@
+//! Write load shedding of `OptLogStructKvs` with `EngineConfig::max_inflight_writes`
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs};
+use kvs::error::KvsError;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const MAX_INFLIGHT: usize = 4;
+
+fn wait_for_inflight(store: &OptLogStructKvs, inflight: u64) {
+    while store.stats().unwrap().inflight_writes < inflight {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn surplus_writes_are_busy() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = EngineConfig {
+        max_inflight_writes: Some(MAX_INFLIGHT),
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    store.set("key".to_owned(), "old".to_owned()).unwrap();
+
+    // The update closure runs under the write lock, the other writers queue behind it
+    let (release, held) = mpsc::channel::<()>();
+    let holder = {
+        let store = store.clone();
+        thread::spawn(move || {
+            store
+                .update("key".to_owned(), |_| {
+                    held.recv().unwrap();
+                    Some("new".to_owned())
+                })
+                .unwrap();
+        })
+    };
+    wait_for_inflight(&store, 1);
+    let queued: Vec<_> = (1..MAX_INFLIGHT)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || store.set(format!("key{}", i), i.to_string()))
+        })
+        .collect();
+    wait_for_inflight(&store, MAX_INFLIGHT as u64);
+
+    for i in 0..10 {
+        let surplus = store.set(format!("surplus{}", i), i.to_string());
+        assert!(matches!(surplus, Err(KvsError::Busy)));
+    }
+    assert!(matches!(
+        store.remove("key".to_owned()),
+        Err(KvsError::Busy)
+    ));
+    assert_eq!(store.stats().unwrap().inflight_writes, MAX_INFLIGHT as u64);
+
+    release.send(()).unwrap();
+    holder.join().unwrap();
+    for writer in queued {
+        writer.join().unwrap().unwrap();
+    }
+    assert_eq!(store.stats().unwrap().inflight_writes, 0);
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some("new".to_owned()));
+    for i in 1..MAX_INFLIGHT {
+        assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(i.to_string()));
+    }
+    for i in 0..10 {
+        assert_eq!(store.get(format!("surplus{}", i)).unwrap(), None);
+    }
+
+    // The slots are free again once the queue drained
+    store.set("after".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        store.get("after".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
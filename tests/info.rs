@@ -0,0 +1,48 @@
+//! `Command::Info` served over the wire and printed by `kvs-client info`
+
+use kvs::client::KvsClient;
+use kvs::common::{Command, EngineType, Response};
+use kvs::engine::LogStructKVStore;
+use kvs::protocol::PROTOCOL_VERSION;
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDRESS: &str = "127.0.0.1:4782";
+
+#[test]
+fn info_reports_version_and_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = LogStructKVStore::open(temp_dir.path()).unwrap();
+    let address: SocketAddr = ADDRESS.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let client = KvsClient::new(&address).unwrap();
+    let info = match client.send(&Command::Info).unwrap() {
+        Response::Info(info) => info,
+        _ => panic!("info didn't answer with info"),
+    };
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.engine, EngineType::Kvs.to_string());
+    assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_kvs-client"))
+        .args(["info", "--addr", ADDRESS])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(&format!("version\t{}\n", env!("CARGO_PKG_VERSION"))));
+    assert!(stdout.contains("engine\tkvs\n"));
+    client.shutdown().unwrap();
+}
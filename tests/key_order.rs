@@ -0,0 +1,79 @@
+//! Ordered scans of `OptLogStructKvs` following `EngineConfig::key_order`
+
+use kvs::engine::{EngineConfig, KeyOrder, KvsEngine, OptLogStructKvs};
+use std::cmp::Ordering;
+use std::ops::Bound;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Opens a store holding the keys set out of order, each set to itself
+fn open(path: &Path, key_order: KeyOrder) -> OptLogStructKvs {
+    let config = EngineConfig {
+        key_order,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(path, config).unwrap();
+    for key in &["10", "key", "9", "100", "1"] {
+        store.set(key.to_string(), key.to_string()).unwrap();
+    }
+    store
+}
+
+/// Keys of all the pairs in scan order
+fn range_keys(store: &OptLogStructKvs) -> Vec<String> {
+    store
+        .range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap()
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect()
+}
+
+#[test]
+fn numeric_order_puts_9_before_10() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), KeyOrder::Numeric);
+    assert_eq!(range_keys(&store), ["1", "9", "10", "100", "key"]);
+    assert_eq!(
+        store
+            .range(
+                Bound::Included("9".to_owned()),
+                Bound::Excluded("100".to_owned())
+            )
+            .unwrap(),
+        [
+            ("9".to_owned(), "9".to_owned()),
+            ("10".to_owned(), "10".to_owned())
+        ]
+    );
+    let prefixed: Vec<String> = store
+        .scan_prefix("1", None)
+        .unwrap()
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(prefixed, ["1", "10", "100"]);
+    drop(store);
+
+    // The order comes from the config, not from the logs
+    let config = EngineConfig {
+        key_order: KeyOrder::Numeric,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    assert_eq!(range_keys(&store), ["1", "9", "10", "100", "key"]);
+}
+
+#[test]
+fn lexicographic_and_custom_orders() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), KeyOrder::Lexicographic);
+    assert_eq!(range_keys(&store), ["1", "10", "100", "9", "key"]);
+
+    fn reversed(a: &str, b: &str) -> Ordering {
+        b.cmp(a)
+    }
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), KeyOrder::Custom(reversed));
+    assert_eq!(range_keys(&store), ["key", "9", "100", "10", "1"]);
+}
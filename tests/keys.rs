@@ -0,0 +1,54 @@
+//! `Command::Keys` served over the wire, through a namespace and by `kvs-client keys`
+
+use kvs::client::KvsClient;
+use kvs::common::{Command, Response};
+use kvs::engine::{KvsEngine, OptLogStructKvs};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDRESS: &str = "127.0.0.1:4789";
+
+#[test]
+fn keys_listed_and_counted() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    for key in &["user:1", "user:2", "order:1", "t*:user:1", "t:user:2"] {
+        store.set(key.to_string(), "value".to_owned()).unwrap();
+    }
+    let address: SocketAddr = ADDRESS.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let client = KvsClient::new(&address).unwrap();
+    let keys = |pattern: &str| Command::Keys {
+        pattern: pattern.to_owned(),
+        count: false,
+    };
+    assert!(matches!(
+        client.send(&keys("user:*")).unwrap(),
+        Response::Keys(keys) if keys == vec!["user:1", "user:2"]
+    ));
+    // The `*` of the namespace only matches itself
+    assert!(matches!(
+        client.namespaced("t*").send(&keys("user:*")).unwrap(),
+        Response::Keys(keys) if keys == vec!["user:1"]
+    ));
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_kvs-client"))
+        .args(["keys", "*user:*", "--count", "--addr", ADDRESS])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "4\n");
+    client.shutdown().unwrap();
+}
@@ -0,0 +1,41 @@
+//! Latency histograms of `OptLogStructKvs`, run with `--features metrics`
+#![cfg(feature = "metrics")]
+
+use kvs::engine::{KvsEngine, OptLogStructKvs, Percentiles};
+use tempfile::TempDir;
+
+fn assert_ordered(percentiles: &Percentiles) {
+    assert!(percentiles.p50 > 0, "{:?}", percentiles);
+    assert!(percentiles.p50 <= percentiles.p90, "{:?}", percentiles);
+    assert!(percentiles.p90 <= percentiles.p99, "{:?}", percentiles);
+}
+
+#[test]
+fn every_operation_is_recorded() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    let report = store.latency_percentiles();
+    assert_eq!(report.get.count, 0);
+    assert_eq!(report.get.p99, 0);
+
+    for i in 0..20 {
+        store.set(format!("key{}", i), i.to_string()).unwrap();
+    }
+    // Hits and misses alike
+    for i in 0..30 {
+        store.get(format!("key{}", i)).unwrap();
+    }
+    for i in 0..5 {
+        store.remove(format!("key{}", i)).unwrap();
+    }
+    // A failed removal takes time as well
+    assert!(store.remove("missing".to_owned()).is_err());
+
+    let report = store.latency_percentiles();
+    assert_eq!(report.set.count, 20);
+    assert_eq!(report.get.count, 30);
+    assert_eq!(report.remove.count, 6);
+    assert_ordered(&report.set);
+    assert_ordered(&report.get);
+    assert_ordered(&report.remove);
+}
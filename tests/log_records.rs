@@ -0,0 +1,57 @@
+//! Log records share the bincode tags of `Command`s and stay off the wire
+
+use kvs::common::Command;
+use kvs::engine::LogRecord;
+
+fn tag<T: serde::Serialize>(value: &T) -> u32 {
+    let bytes = bincode::serialize(value).unwrap();
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[test]
+fn records_and_commands_keep_their_tags() {
+    let record = LogRecord::SetVersioned {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        version: 3,
+        expires_at: Some(10),
+    };
+    assert_eq!(tag(&record), 8);
+    let bytes = bincode::serialize(&record).unwrap();
+    assert_eq!(bincode::deserialize::<LogRecord>(&bytes).unwrap(), record);
+
+    let set = LogRecord::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+    };
+    let set_cmd = Command::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+    };
+    assert_eq!(
+        bincode::serialize(&set).unwrap(),
+        bincode::serialize(&set_cmd).unwrap()
+    );
+
+    let config = Command::Config {
+        key: "num_threads".to_owned(),
+        value: None,
+    };
+    assert_eq!(tag(&config), 7);
+    let keys = Command::Keys {
+        pattern: "*".to_owned(),
+        count: false,
+    };
+    assert_eq!(tag(&keys), 25);
+}
+
+#[test]
+fn record_is_not_a_command() {
+    let record = LogRecord::SetExpiring {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        expires_at: 10,
+    };
+    let bytes = bincode::serialize(&record).unwrap();
+    assert!(bincode::deserialize::<Command>(&bytes).is_err());
+}
@@ -0,0 +1,82 @@
+//! Logs of `OptLogStructKvs` spread over shard subfolders, see `EngineConfig::log_shards`
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs};
+use kvs::error::KvsError;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(path: &Path, log_shards: u64) -> OptLogStructKvs {
+    let config = EngineConfig {
+        max_file_size: 4096,
+        compact_threshold: u64::MAX,
+        max_log_files: u64::MAX,
+        log_shards,
+        ..EngineConfig::default()
+    };
+    OptLogStructKvs::open_with_config(path, config).unwrap()
+}
+
+/// Number of log files directly in `path`
+fn log_count(path: &Path) -> usize {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .count()
+}
+
+#[test]
+fn logs_spread_over_shards_and_found_on_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path();
+    let store = open(path, 4);
+    for i in 0..2000 {
+        store
+            .set(format!("key{}", i), format!("value{:0100}", i))
+            .unwrap();
+    }
+    drop(store);
+
+    assert_eq!(log_count(path), 0);
+    let counts: Vec<usize> = (0..4)
+        .map(|shard| log_count(&path.join(shard.to_string())))
+        .collect();
+    assert!(counts.iter().all(|&count| count > 5), "{:?}", counts);
+    let total: usize = counts.iter().sum();
+
+    let store = open(path, 4);
+    for i in (0..2000).step_by(97) {
+        assert_eq!(
+            store.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{:0100}", i))
+        );
+    }
+    drop(store);
+
+    // Back in the storage folder once the logs aren't sharded anymore
+    let store = open(path, 1);
+    assert_eq!(log_count(path), total);
+    assert_eq!(
+        store.get("key1999".to_owned()).unwrap(),
+        Some(format!("value{:0100}", 1999))
+    );
+}
+
+#[test]
+fn stray_log_name_fails_open() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), 2);
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    fs::write(temp_dir.path().join("notes.log"), b"").unwrap();
+    let config = EngineConfig {
+        log_shards: 2,
+        ..EngineConfig::default()
+    };
+    assert!(matches!(
+        OptLogStructKvs::open_with_config(temp_dir.path(), config),
+        Err(KvsError::BadLogFile)
+    ));
+}
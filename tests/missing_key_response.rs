@@ -0,0 +1,83 @@
+//! Answers to a `get` of a missing key for each `MissingKeyResponse`
+
+use kvs::client::KvsClient;
+use kvs::common::{Command, Response};
+use kvs::engine::{KvsEngine, LogStructKVStore};
+use kvs::server::{KvsServer, MissingKeyResponse, KEY_NOT_FOUND};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Serves a storage holding `empty` set to an empty value, returns a client of it
+fn serve(address: &str, missing_key_response: MissingKeyResponse) -> (KvsClient, TempDir) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = LogStructKVStore::open(temp_dir.path()).unwrap();
+    store.set("empty".to_owned(), "".to_owned()).unwrap();
+    let address: SocketAddr = address.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .with_missing_key_response(missing_key_response)
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+    (KvsClient::new(&address).unwrap(), temp_dir)
+}
+
+fn get(client: &KvsClient, key: &str) -> Response {
+    client
+        .send(&Command::Get {
+            key: key.to_owned(),
+        })
+        .unwrap()
+}
+
+#[test]
+fn null_tells_miss_from_empty_value() {
+    let (client, _temp_dir) = serve("127.0.0.1:4784", MissingKeyResponse::Null);
+    assert!(matches!(get(&client, "missing"), Response::Ok(None)));
+    assert!(matches!(get(&client, "empty"), Response::Ok(Some(value)) if value.is_empty()));
+    client.shutdown().unwrap();
+}
+
+#[test]
+fn empty_answers_miss_with_empty_value() {
+    let (client, _temp_dir) = serve("127.0.0.1:4785", MissingKeyResponse::Empty);
+    assert!(matches!(get(&client, "missing"), Response::Ok(Some(value)) if value.is_empty()));
+    assert!(matches!(get(&client, "empty"), Response::Ok(Some(value)) if value.is_empty()));
+    client.shutdown().unwrap();
+}
+
+#[test]
+fn error_answers_miss_with_key_not_found() {
+    let (client, _temp_dir) = serve("127.0.0.1:4786", MissingKeyResponse::Error);
+    assert!(matches!(get(&client, "missing"), Response::Err(err) if err == KEY_NOT_FOUND));
+    assert!(matches!(get(&client, "empty"), Response::Ok(Some(value)) if value.is_empty()));
+    client.shutdown().unwrap();
+}
+
+#[test]
+fn client_fails_on_missing_key_only_when_asked() {
+    let address = "127.0.0.1:4808";
+    let (client, _temp_dir) = serve(address, MissingKeyResponse::Null);
+    let get = |args: &[&str]| {
+        process::Command::new(env!("CARGO_BIN_EXE_kvs-client"))
+            .args(["get", "--addr", address])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    assert!(get(&["missing"]).status.success());
+    assert_eq!(
+        get(&["missing", "--fail-on-missing"]).status.code(),
+        Some(1)
+    );
+    let output = get(&["--fail-on-missing", "empty"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\n");
+    client.shutdown().unwrap();
+}
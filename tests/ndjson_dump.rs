@@ -0,0 +1,67 @@
+//! `kvs-admin export` and `import` round trip through an NDJSON dump
+
+use kvs::engine::{KvsEngine, LogStructKVStore, SledStore};
+use std::io::Write;
+use std::process::{self, Stdio};
+use tempfile::TempDir;
+
+/// Keys and values needing JSON escapes, one pair per line whatever they hold
+const PAIRS: [(&str, &str); 5] = [
+    ("plain", "value"),
+    ("line\nbreak", "carriage\r\nreturn"),
+    ("\"quoted\"", "back\\slash\ttab"),
+    ("control\u{1}", "\u{7f}"),
+    ("unicode é", "emoji 🦀 and \u{2028} separator"),
+];
+
+fn admin(args: &[&str], stdin: &[u8]) -> process::Output {
+    let mut child = process::Command::new(env!("CARGO_BIN_EXE_kvs-admin"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    output
+}
+
+#[test]
+fn escaped_pairs_round_trip_with_counts_on_stderr() {
+    let source = TempDir::new().unwrap();
+    let store = LogStructKVStore::open(source.path()).unwrap();
+    for (key, value) in PAIRS.iter() {
+        store.set(key.to_string(), value.to_string()).unwrap();
+    }
+    drop(store);
+
+    let source = format!("{}:kvs", source.path().to_str().unwrap());
+    let export = admin(&["export", &source], b"");
+    let dump = String::from_utf8(export.stdout).unwrap();
+    assert_eq!(dump.lines().count(), PAIRS.len());
+    assert_eq!(
+        String::from_utf8(export.stderr).unwrap(),
+        format!("Exported keys: {}\n", PAIRS.len())
+    );
+
+    let target = TempDir::new().unwrap();
+    let import = admin(
+        &[
+            "import",
+            &format!("{}:sled", target.path().to_str().unwrap()),
+        ],
+        dump.as_bytes(),
+    );
+    assert!(import.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(import.stderr).unwrap(),
+        format!("Imported keys: {}\n", PAIRS.len())
+    );
+
+    let store = SledStore::open(target.path()).unwrap();
+    for (key, value) in PAIRS.iter() {
+        assert_eq!(store.get(key.to_string()).unwrap(), Some(value.to_string()));
+    }
+}
@@ -0,0 +1,51 @@
+//! Background syncing of `OptLogStructKvs` with `EngineConfig::sync_interval`
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const SYNC_INTERVAL: Duration = Duration::from_millis(20);
+
+fn open(path: &std::path::Path) -> OptLogStructKvs {
+    let config = EngineConfig {
+        sync_interval: Some(SYNC_INTERVAL),
+        // Small logs so writes roll over to new ones between syncs
+        max_file_size: 4 * 1024,
+        ..EngineConfig::default()
+    };
+    OptLogStructKvs::open_with_config(path, config).unwrap()
+}
+
+#[test]
+fn writes_survive_interval_and_drop() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path());
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    thread::sleep(SYNC_INTERVAL * 5);
+
+    // Written right before the drop, the last sync of the thread takes them
+    let clone = store.clone();
+    for i in 100..200 {
+        clone
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    store.remove("key0".to_owned()).unwrap();
+    drop(clone);
+    drop(store);
+
+    // The thread is joined before the folder is released
+    let store = open(temp_dir.path());
+    assert_eq!(store.get("key0".to_owned()).unwrap(), None);
+    for i in 1..200 {
+        assert_eq!(
+            store.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Commands pipelined on one connection are applied in send order
+
+use kvs::client::KvsClient;
+use kvs::common::{Command, Response};
+use kvs::engine::OptLogStructKvs;
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn get_observes_the_set_pipelined_before_it() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    let address: SocketAddr = "127.0.0.1:4815".parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .unwrap()
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let client = KvsClient::new(&address).unwrap();
+    for i in 0..200 {
+        let value = format!("value{}", i);
+        let responses = client
+            .pipeline(&[
+                Command::Set {
+                    key: "key".to_owned(),
+                    value: value.clone(),
+                },
+                Command::Get {
+                    key: "key".to_owned(),
+                },
+            ])
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        assert!(matches!(&responses[0], Response::Ok(None)));
+        assert!(matches!(&responses[1], Response::Ok(Some(got)) if *got == value));
+    }
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value199".to_owned())
+    );
+    client.shutdown().unwrap();
+}
@@ -0,0 +1,45 @@
+//! Protocol version negotiated in the connection handshake
+
+use kvs::client::KvsClient;
+use kvs::common::Command;
+use kvs::engine::OptLogStructKvs;
+use kvs::error::KvsError;
+use kvs::protocol::PROTOCOL_VERSION;
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn v2_client_negotiates_down_to_v1_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    let address: SocketAddr = "127.0.0.1:4796".parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .with_protocol_version(1)
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    for &(compress, version) in &[(false, 2), (true, 2), (false, PROTOCOL_VERSION)] {
+        let client = KvsClient::connect_with_version(&address, compress, version).unwrap();
+        assert_eq!(client.protocol_version(), 1);
+        client.set("key".to_owned(), "value".to_owned()).unwrap();
+        assert_eq!(
+            client.get("key".to_owned()).unwrap(),
+            Some("value".to_owned())
+        );
+        // Commands of v2 aren't sent to a v1 server
+        assert!(matches!(
+            client.send(&Command::Exists {
+                key: "key".to_owned()
+            }),
+            Err(KvsError::UnsupportedCommand)
+        ));
+    }
+}
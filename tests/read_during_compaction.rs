@@ -0,0 +1,153 @@
+//! Gets of `OptLogStructKvs` racing a compaction under each `ReadDuringCompaction` policy
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs, ReadDuringCompaction};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const KEYS: u64 = 20_000;
+
+fn key(i: u64) -> String {
+    format!("key{}", i)
+}
+
+fn value(i: u64, round: u64) -> String {
+    format!("{}-{}", i, round)
+}
+
+fn open(temp_dir: &TempDir, policy: ReadDuringCompaction) -> OptLogStructKvs {
+    let config = EngineConfig {
+        read_during_compaction: policy,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    store
+        .extend((0..KEYS).map(|i| (key(i), value(i, 0))))
+        .unwrap();
+    store
+        .extend((0..KEYS).map(|i| (key(i), value(i, 1))))
+        .unwrap();
+    store
+}
+
+/// Gets every key over and over while the store is compacted
+fn reads_stay_correct(policy: ReadDuringCompaction) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(&temp_dir, policy);
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|r| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut i = r;
+                while !done.load(Ordering::Relaxed) {
+                    let i_key = i % KEYS;
+                    assert_eq!(store.get(key(i_key)).unwrap(), Some(value(i_key, 1)));
+                    i += 4;
+                }
+            })
+        })
+        .collect();
+    for _ in 0..3 {
+        store.compact().unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(store.stats().unwrap().compactions, 3);
+}
+
+#[test]
+fn serve_consistent_reads_during_compaction() {
+    reads_stay_correct(ReadDuringCompaction::ServeConsistent);
+}
+
+#[test]
+fn block_reads_during_compaction() {
+    reads_stay_correct(ReadDuringCompaction::Block);
+}
+
+/// Many readers of a small store, gets and scans alike, while a writer makes garbage
+/// and compaction is forced over and over, every read moved under it is retried
+#[test]
+fn reads_race_repeated_compaction() {
+    const SMALL_KEYS: u64 = 1_000;
+    const COMPACTIONS: u64 = 20;
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store
+        .extend((0..SMALL_KEYS).map(|i| (key(i), value(i, 1))))
+        .unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let mut threads: Vec<_> = (0..16)
+        .map(|r| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut i = r;
+                while !done.load(Ordering::Relaxed) {
+                    let i_key = i % SMALL_KEYS;
+                    assert_eq!(store.get(key(i_key)).unwrap(), Some(value(i_key, 1)));
+                    if i % 64 == r {
+                        // key99 and key990 to key999
+                        let pairs = store.scan_prefix("key99", None).unwrap();
+                        assert_eq!(pairs.len(), 11);
+                        for (key, value) in pairs {
+                            assert_eq!(value, format!("{}-1", &key[3..]));
+                        }
+                    }
+                    i += 16;
+                }
+            })
+        })
+        .collect();
+    threads.push({
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            let mut round = 0;
+            while !done.load(Ordering::Relaxed) {
+                store
+                    .set(format!("churn{}", round % 10), round.to_string())
+                    .unwrap();
+                round += 1;
+                // A writer holding on to the write lock would keep compaction from switching logs
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    });
+    for _ in 0..COMPACTIONS {
+        store.compact().unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(store.stats().unwrap().compactions >= COMPACTIONS);
+}
+
+#[test]
+fn block_waits_for_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(&temp_dir, ReadDuringCompaction::Block);
+    let log_files = store.stats().unwrap().log_files;
+    let compaction = {
+        let store = store.clone();
+        thread::spawn(move || store.compact().unwrap())
+    };
+    // The compaction holds on to its step from creating its logs until it finishes
+    loop {
+        let stats = store.stats().unwrap();
+        if stats.log_files > log_files || stats.compactions > 0 {
+            break;
+        }
+        thread::yield_now();
+    }
+    assert_eq!(store.get(key(0)).unwrap(), Some(value(0, 1)));
+    assert_eq!(store.stats().unwrap().compactions, 1);
+    compaction.join().unwrap();
+}
@@ -0,0 +1,54 @@
+//! Where `OptLogStructKvs::get_traced` finds the values, run with `--features read-trace`
+#![cfg(feature = "read-trace")]
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs, ReadSource};
+use tempfile::TempDir;
+
+#[test]
+fn second_get_is_served_from_read_ahead() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = EngineConfig {
+        read_ahead: true,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+    assert_eq!(
+        store.get_traced("key1").unwrap(),
+        (Some("value1".to_owned()), ReadSource::Disk)
+    );
+    assert_eq!(
+        store.get_traced("key1").unwrap(),
+        (Some("value1".to_owned()), ReadSource::ReadAhead)
+    );
+    // Read ahead with the first record
+    assert_eq!(
+        store.get_traced("key2").unwrap(),
+        (Some("value2".to_owned()), ReadSource::ReadAhead)
+    );
+}
+
+#[test]
+fn read_ahead_is_off_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+    for key in &["key1", "key2", "key1"] {
+        assert_eq!(store.get_traced(key).unwrap().1, ReadSource::Disk);
+    }
+}
+
+#[test]
+fn missing_key_is_answered_by_index() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_traced("key1").unwrap(), (None, ReadSource::Index));
+
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    assert_eq!(store.get_traced("key1").unwrap(), (None, ReadSource::Index));
+}
@@ -0,0 +1,92 @@
+//! Logs and blob files of `OptLogStructKvs` replaced while gets are reading them
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tempfile::TempDir;
+
+const KEYS: u64 = 500;
+const THRESHOLD: usize = 100;
+
+fn key(i: u64) -> String {
+    format!("key{}", i)
+}
+
+/// Every other key is large enough for a blob file
+fn value(i: u64, round: u64) -> String {
+    let len = if i.is_multiple_of(2) {
+        2 * THRESHOLD
+    } else {
+        10
+    };
+    format!("{}-{}-{}", i, round, "x".repeat(len))
+}
+
+fn blob_bytes(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "blob"))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+#[test]
+fn gets_racing_compaction_and_gc_never_miss_a_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = EngineConfig {
+        blob_threshold: Some(THRESHOLD),
+        max_file_size: 16 * 1024,
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    store
+        .extend((0..KEYS).map(|i| (key(i), value(i, 0))))
+        .unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|r| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut i = r;
+                while !done.load(Ordering::Relaxed) {
+                    let i_key = i % KEYS;
+                    let value = store.get(key(i_key)).unwrap().unwrap();
+                    assert!(value.starts_with(&format!("{}-", i_key)));
+                    i += 4;
+                }
+            })
+        })
+        .collect();
+    for round in 1..=20 {
+        store
+            .extend((0..KEYS).map(|i| (key(i), value(i, round))))
+            .unwrap();
+        store.compact().unwrap();
+        store.gc_blobs().unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    // The last read done, nothing keeps the replaced files anymore
+    store.compact().unwrap();
+    store.gc_blobs().unwrap();
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.retired_files, 0);
+    assert_eq!(stats.failed_removals, 0);
+    let live_blobs = (0..KEYS)
+        .map(|i| value(i, 20))
+        .filter(|value| value.len() > THRESHOLD)
+        .map(|value| value.len() as u64)
+        .sum::<u64>();
+    assert_eq!(blob_bytes(temp_dir.path()), live_blobs);
+    for i in 0..KEYS {
+        assert_eq!(store.get(key(i)).unwrap(), Some(value(i, 20)));
+    }
+}
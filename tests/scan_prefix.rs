@@ -0,0 +1,72 @@
+//! `Command::ScanPrefix` served over the wire and printed by `kvs-client scan`
+
+use kvs::client::KvsClient;
+use kvs::common::{Command, Response};
+use kvs::engine::{KvsEngine, LogStructKVStore};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDRESS: &str = "127.0.0.1:4781";
+
+#[test]
+fn client_scan_returns_matching_pairs_sorted() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = LogStructKVStore::open(temp_dir.path()).unwrap();
+    // Set out of order, with neighbours on both sides of the prefix
+    for (key, value) in &[
+        ("user:123:name", "ann"),
+        ("user:12", "short"),
+        ("user:123:age", "42"),
+        ("user:124:name", "bob"),
+        ("user:123:email", "ann@example.com"),
+        ("user:123", "exact"),
+    ] {
+        store.set(key.to_string(), value.to_string()).unwrap();
+    }
+    store.remove("user:123".to_owned()).unwrap();
+
+    let address: SocketAddr = ADDRESS.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let expected = vec![
+        ("user:123:age".to_owned(), "42".to_owned()),
+        ("user:123:email".to_owned(), "ann@example.com".to_owned()),
+        ("user:123:name".to_owned(), "ann".to_owned()),
+    ];
+    let client = KvsClient::new(&address).unwrap();
+    let scan = |prefix: &str, limit| match client
+        .send(&Command::ScanPrefix {
+            prefix: prefix.to_owned(),
+            limit,
+        })
+        .unwrap()
+    {
+        Response::Pairs(pairs) => pairs,
+        _ => panic!("scan didn't answer with pairs"),
+    };
+    assert_eq!(scan("user:123:", None), expected);
+    assert_eq!(scan("user:123:", Some(2)), expected[..2].to_vec());
+    assert_eq!(scan("order:", None), vec![]);
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_kvs-client"))
+        .args(["scan", "--prefix", "user:123:", "--addr", ADDRESS])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "user:123:age\t42\nuser:123:email\tann@example.com\nuser:123:name\tann\n"
+    );
+    client.shutdown().unwrap();
+}
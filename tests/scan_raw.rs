@@ -0,0 +1,43 @@
+//! Records of a log read by `OptLogStructKvs::scan_raw`, tombstones included
+
+use kvs::engine::{KvsEngine, LogRecord, OptLogStructKvs};
+use std::process;
+use tempfile::TempDir;
+
+#[test]
+fn remove_writes_rm_record_after_the_sets() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    let log = store.key_age("key2".to_owned()).unwrap().unwrap();
+
+    let records = store.scan_raw(log).unwrap();
+    assert_eq!(records.len(), 3);
+    let (offset, rm) = &records[2];
+    assert!(matches!(rm, LogRecord::Rm { key } if key == "key1"));
+    let sets_size: u64 = records[..2]
+        .iter()
+        .map(|(_, cmd)| bincode::serialized_size(cmd).unwrap())
+        .sum();
+    assert_eq!(*offset, sets_size);
+    assert_eq!(
+        records[1].0,
+        bincode::serialized_size(&records[0].1).unwrap()
+    );
+    assert!(store.scan_raw(log + 1).is_err());
+    drop(store);
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_kvs-admin"))
+        .args([
+            "scan-raw",
+            temp_dir.path().to_str().unwrap(),
+            &log.to_string(),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(&format!("{}\tRm {{ key: \"key1\" }}\n", offset)));
+}
@@ -0,0 +1,58 @@
+//! Reserved keys of the id sequences can't be written over the wire, only `next-id` moves them
+
+use kvs::client::KvsClient;
+use kvs::common::{Command, Response};
+use kvs::engine::{KvsEngine, OptLogStructKvs};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDRESS: &str = "127.0.0.1:4790";
+
+#[test]
+fn sequence_keys_are_not_written_by_clients() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    assert_eq!(store.next_id("ids").unwrap(), 1);
+    let address: SocketAddr = ADDRESS.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let client = KvsClient::new(&address).unwrap();
+    let key = "__seq:ids".to_owned();
+    let writes = [
+        Command::Set {
+            key: key.clone(),
+            value: "0".to_owned(),
+        },
+        Command::Rm { key: key.clone() },
+        Command::GetDel { key: key.clone() },
+        Command::Expire {
+            key: key.clone(),
+            ttl_secs: 1,
+        },
+        Command::Replace {
+            key: key.clone(),
+            value: "0".to_owned(),
+        },
+    ];
+    for cmd in writes.iter() {
+        match client.send(cmd).unwrap() {
+            Response::Err(err) => assert!(err.contains("reserved"), "{}", err),
+            _ => panic!("{} of a sequence key accepted", cmd.name()),
+        }
+    }
+    assert!(matches!(
+        client.send(&Command::NextId { name: "ids".to_owned() }).unwrap(),
+        Response::Ok(Some(id)) if id == "2"
+    ));
+    client.shutdown().unwrap();
+}
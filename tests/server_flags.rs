@@ -0,0 +1,61 @@
+//! `kvs-server` flags overriding the settings of its config file
+
+use kvs::protocol::{Hello, Welcome, PROTOCOL_VERSION};
+use std::fs;
+use std::net::TcpStream;
+use std::process::{self, Child};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDRESS: &str = "127.0.0.1:4807";
+
+fn spawn_server(dir: &TempDir, args: &[&str]) -> Child {
+    let server = process::Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--addr", ADDRESS])
+        .args(args)
+        .current_dir(dir.path())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    server
+}
+
+/// Whether the server accepts to compress a connection asking for it
+fn compresses() -> bool {
+    let stream = TcpStream::connect(ADDRESS).unwrap();
+    let hello = Hello {
+        compress: true,
+        version: PROTOCOL_VERSION,
+    };
+    bincode::serialize_into(&stream, &hello).unwrap();
+    let welcome: Welcome = bincode::deserialize_from(&stream).unwrap();
+    welcome.compress
+}
+
+fn stop(mut server: Child) {
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn compress_flag_overrides_the_config_file_both_ways() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("server.toml");
+    fs::write(&config, "compress = true\n").unwrap();
+    let config = config.to_str().unwrap();
+
+    let cases: [(&[&str], bool); 5] = [
+        (&[], false),
+        (&["--compress"], true),
+        (&["--config", config], true),
+        (&["--config", config, "--compress=false"], false),
+        (&["--compress=true"], true),
+    ];
+    for &(args, expected) in &cases {
+        let server = spawn_server(&temp_dir, args);
+        assert_eq!(compresses(), expected, "{:?}", args);
+        stop(server);
+    }
+}
@@ -0,0 +1,64 @@
+//! Servers accepting connections while their engine is opened in the background
+
+use kvs::client::KvsClient;
+use kvs::engine::{KvsEngine, OptLogStructKvs};
+use kvs::error::KvsError;
+use kvs::server::{KvsServer, STARTING_UP};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn commands_wait_for_a_slow_open() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = PathBuf::from(temp_dir.path());
+    OptLogStructKvs::open(&path)
+        .unwrap()
+        .set("key".to_owned(), "value".to_owned())
+        .unwrap();
+    let (opened, open) = mpsc::channel::<()>();
+    let address: SocketAddr = "127.0.0.1:4794".parse().unwrap();
+    thread::spawn(move || {
+        let slow_open = move || {
+            open.recv().unwrap();
+            OptLogStructKvs::open(&path)
+        };
+        KvsServer::opening(slow_open, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let client = KvsClient::new(&address).unwrap();
+    match client.get("key".to_owned()) {
+        Err(KvsError::Server(message)) => assert_eq!(message, STARTING_UP),
+        other => panic!("expected {}, got {:?}", STARTING_UP, other.map(|_| ())),
+    }
+    opened.send(()).unwrap();
+    let mut value = client.get("key".to_owned());
+    for _ in 0..50 {
+        if value.is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+        value = client.get("key".to_owned());
+    }
+    assert_eq!(value.unwrap(), Some("value".to_owned()));
+    client.shutdown().unwrap();
+}
+
+#[test]
+fn failed_open_is_returned_by_run() {
+    let address: SocketAddr = "127.0.0.1:4795".parse().unwrap();
+    let failing_open = || -> kvs::common::Result<OptLogStructKvs> { Err(KvsError::BadLogFile) };
+    let server = KvsServer::opening(failing_open, SharedQueueThreadPool::new(1).unwrap()).unwrap();
+    match server.run(&address) {
+        Err(KvsError::BadLogFile) => {}
+        other => panic!("expected the open error, got {:?}", other),
+    }
+}
@@ -0,0 +1,53 @@
+//! Jobs queued in a `SharedQueueThreadPool`, when it shuts down or past their deadline
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn graceful_shutdown_runs_every_queued_job() {
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let done = Arc::new(AtomicU64::new(0));
+    // More than the queue holds, the last ones are queued as the first ones finish
+    for i in 0..20 {
+        let done = Arc::clone(&done);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            // The replacement of the panicked worker takes over the rest of the queue
+            if i == 5 {
+                panic!("job {} failed", i);
+            }
+            done.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    assert!(done.load(Ordering::Relaxed) < 19);
+
+    pool.shutdown_graceful();
+    assert_eq!(done.load(Ordering::Relaxed), 19);
+}
+
+#[test]
+fn expired_jobs_behind_a_slow_one_are_skipped() {
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let ran = Arc::new(Mutex::new(Vec::new()));
+    pool.spawn(|| thread::sleep(Duration::from_millis(200)));
+    let now = Instant::now();
+    for i in 0..6 {
+        let ran = Arc::clone(&ran);
+        // Even jobs are stale by the time the slow one is done
+        let deadline = if i % 2 == 0 {
+            now + Duration::from_millis(50)
+        } else {
+            now + Duration::from_secs(60)
+        };
+        pool.spawn_deadline(deadline, move || ran.lock().unwrap().push(i));
+    }
+    let (sender, receiver) = mpsc::channel();
+    pool.spawn(move || sender.send(()).unwrap());
+    receiver.recv().unwrap();
+
+    assert_eq!(*ran.lock().unwrap(), [1, 3, 5]);
+    assert_eq!(pool.deadline_exceeded(), 3);
+}
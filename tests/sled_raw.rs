@@ -0,0 +1,42 @@
+//! Values of a `SledStore` tree written by another tool than kvs
+
+use kvs::engine::{KvsEngine, SledStore};
+use kvs::error::KvsError;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn get_raw_reads_non_utf8_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let bytes = vec![0xff, 0xfe, 0x00, 0x41];
+    {
+        let db = sled::open(temp_dir.path()).unwrap();
+        db.insert("binary", bytes.clone()).unwrap();
+        db.flush().unwrap();
+    }
+    // Sled's background threads release the folder lock a little after the handle is dropped
+    let mut attempts = 0;
+    let store = loop {
+        match SledStore::open(temp_dir.path()) {
+            Ok(store) => break store,
+            Err(_) if attempts < 100 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(err) => panic!("{}", err),
+        }
+    };
+    assert_eq!(store.get_raw("binary".to_owned()).unwrap(), Some(bytes));
+    assert!(matches!(
+        store.get("binary".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+
+    store.set("text".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        store.get_raw("text".to_owned()).unwrap(),
+        Some(b"value".to_vec())
+    );
+    assert_eq!(store.get_raw("missing".to_owned()).unwrap(), None);
+}
@@ -0,0 +1,65 @@
+//! TCP options of the connections accepted by the server, see `KvsServer::with_nodelay`
+
+use kvs::client::KvsClient;
+use kvs::engine::OptLogStructKvs;
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use socket2::SockRef;
+use std::fs;
+use std::net::SocketAddr;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// `(nodelay, keepalive)` of the socket the server listening on `address` accepted,
+/// found among the open files
+fn accepted_options(address: SocketAddr) -> (bool, bool) {
+    for entry in fs::read_dir("/proc/self/fd").unwrap() {
+        let fd: RawFd = match entry.unwrap().file_name().to_str().unwrap().parse() {
+            Ok(fd) => fd,
+            Err(_) => continue,
+        };
+        // The server thread keeps the connection open until the client shuts it down
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        let socket = SockRef::from(&fd);
+        // The listening socket has the address as well, but no peer
+        let accepted = socket.peer_addr().is_ok()
+            && socket.local_addr().ok().and_then(|x| x.as_socket()) == Some(address);
+        if accepted {
+            return (socket.nodelay().unwrap(), socket.keepalive().unwrap());
+        }
+    }
+    panic!("no socket accepted on {}", address);
+}
+
+#[test]
+fn accepted_streams_get_the_configured_options() {
+    let temp_dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let servers = [
+        ("127.0.0.1:4799", true, false),
+        ("127.0.0.1:4800", false, true),
+    ];
+    for (&(address, nodelay, keepalive), temp_dir) in servers.iter().zip(&temp_dirs) {
+        let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+        let address: SocketAddr = address.parse().unwrap();
+        thread::spawn(move || {
+            KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+                .unwrap()
+                .with_nodelay(nodelay)
+                .with_keepalive(keepalive)
+                .run(&address)
+                .unwrap();
+        });
+    }
+    thread::sleep(Duration::from_millis(300));
+
+    for &(address, nodelay, keepalive) in &servers {
+        let address: SocketAddr = address.parse().unwrap();
+        let client = KvsClient::new(&address).unwrap();
+        // Answered once the handshake set the options
+        client.get("key".to_owned()).unwrap();
+        assert_eq!(accepted_options(address), (nodelay, keepalive));
+        client.shutdown().unwrap();
+    }
+}
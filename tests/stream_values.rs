@@ -0,0 +1,86 @@
+//! Values of several MB streamed in and out of `OptLogStructKvs` through open, compaction and touch
+
+use kvs::engine::{KvsEngine, OptLogStructKvs};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Size of the streamed values, many times the read-ahead buffer and the copy chunks
+const VALUE_SIZE: usize = 8 * 1024 * 1024;
+
+fn file_hash(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    fs::read(path).unwrap().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Writes a UTF-8 file of about `VALUE_SIZE` bytes whose multi-byte characters straddle the chunks
+fn write_value(path: &Path, seed: char) -> u64 {
+    let pattern = format!("{}é€𝄞 stream ", seed);
+    let value = pattern.repeat(VALUE_SIZE / pattern.len());
+    fs::write(path, value).unwrap();
+    file_hash(path)
+}
+
+fn assert_streamed(store: &OptLogStructKvs, key: &str, dest: &Path, hash: u64) {
+    assert!(store.get_to_path(key.to_owned(), dest).unwrap());
+    assert_eq!(file_hash(dest), hash);
+}
+
+#[test]
+fn streamed_values_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let files = TempDir::new().unwrap();
+    let (first, second, dest) = (
+        files.path().join("first"),
+        files.path().join("second"),
+        files.path().join("dest"),
+    );
+    let first_hash = write_value(&first, 'a');
+    let second_hash = write_value(&second, 'b');
+
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("before".to_owned(), "small".to_owned()).unwrap();
+    store.set_from_path("big".to_owned(), &first).unwrap();
+    assert_streamed(&store, "big", &dest, first_hash);
+    // A second write of the key leaves the first record to compaction
+    store.set_from_path("big".to_owned(), &second).unwrap();
+    store.set_from_path("other".to_owned(), &first).unwrap();
+    store.set("after".to_owned(), "small".to_owned()).unwrap();
+    assert_streamed(&store, "big", &dest, second_hash);
+
+    store.compact().unwrap();
+    assert_streamed(&store, "big", &dest, second_hash);
+    assert_streamed(&store, "other", &dest, first_hash);
+
+    // A touched key is written again with its new expiry at compaction
+    assert!(store
+        .touch("other".to_owned(), Duration::from_secs(3600))
+        .unwrap());
+    store.compact().unwrap();
+    assert_streamed(&store, "other", &dest, first_hash);
+    drop(store);
+
+    // Open skips over the values while it replays the logs
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    assert_streamed(&store, "big", &dest, second_hash);
+    assert_streamed(&store, "other", &dest, first_hash);
+    assert_eq!(
+        store.get("before".to_owned()).unwrap(),
+        Some("small".to_owned())
+    );
+    assert_eq!(
+        store.get("after".to_owned()).unwrap(),
+        Some("small".to_owned())
+    );
+    assert_eq!(
+        store
+            .get("big".to_owned())
+            .unwrap()
+            .map(|value| value.len()),
+        Some(fs::metadata(&second).unwrap().len() as usize)
+    );
+}
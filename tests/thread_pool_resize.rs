@@ -0,0 +1,118 @@
+//! `SharedQueueThreadPool` resized while it runs jobs, and the `num_threads` setting of the server
+
+use kvs::client::KvsClient;
+use kvs::common::{Command, Response};
+use kvs::engine::OptLogStructKvs;
+use kvs::server::{KvsServer, NUM_THREADS_SETTING};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn grows_and_shrinks_under_load() {
+    let pool = Arc::new(SharedQueueThreadPool::new(2).unwrap());
+    let done = Arc::new(AtomicU64::new(0));
+    let producer = {
+        let pool = Arc::clone(&pool);
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            for _ in 0..2000 {
+                let done = Arc::clone(&done);
+                pool.spawn(move || {
+                    thread::sleep(Duration::from_micros(50));
+                    done.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        })
+    };
+    for &num_threads in [8, 1, 4, 1, 6, 2].iter().cycle().take(30) {
+        pool.resize(num_threads).unwrap();
+        assert_eq!(pool.num_threads(), Some(num_threads));
+        thread::sleep(Duration::from_millis(2));
+    }
+    producer.join().unwrap();
+    match Arc::try_unwrap(pool) {
+        Ok(pool) => pool.shutdown_graceful(),
+        Err(_) => panic!("pool still shared"),
+    }
+    assert_eq!(done.load(Ordering::Relaxed), 2000);
+}
+
+#[test]
+fn shrinking_doesnt_wait_for_room_in_the_queue() {
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let (release, blocked) = crossbeam_channel::unbounded::<()>();
+    for _ in 0..2 {
+        let blocked = blocked.clone();
+        pool.spawn(move || blocked.recv().unwrap());
+    }
+    // The workers are busy, the queue fills up
+    let mut queued = 0;
+    while pool.try_spawn(Box::new(|| ())).is_ok() {
+        queued += 1;
+    }
+    assert!(queued > 0);
+
+    let pool = Arc::new(pool);
+    let (resized, resized_receiver) = mpsc::channel();
+    {
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || {
+            pool.resize(1).unwrap();
+            resized.send(()).unwrap();
+        });
+    }
+    resized_receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("resize waited for the queue");
+    assert_eq!(pool.num_threads(), Some(1));
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+}
+
+fn config(client: &KvsClient, value: Option<&str>) -> Response {
+    client
+        .send(&Command::Config {
+            key: NUM_THREADS_SETTING.to_owned(),
+            value: value.map(str::to_owned),
+        })
+        .unwrap()
+}
+
+#[test]
+fn num_threads_resized_up_to_max_threads() {
+    let address: SocketAddr = "127.0.0.1:4791".parse().unwrap();
+    let limited: SocketAddr = "127.0.0.1:4792".parse().unwrap();
+    let temp_dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    for (address, max_threads, temp_dir) in [
+        (address, None, &temp_dirs[0]),
+        (limited, Some(4), &temp_dirs[1]),
+    ] {
+        let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+        thread::spawn(move || {
+            KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+                .unwrap()
+                .with_max_threads(max_threads)
+                .run(&address)
+                .unwrap();
+        });
+    }
+    thread::sleep(Duration::from_millis(300));
+
+    // Resizing is refused by default, the setting can still be read
+    let client = KvsClient::new(&address).unwrap();
+    assert!(matches!(config(&client, Some("3")), Response::Err(_)));
+    assert!(matches!(config(&client, None), Response::Ok(Some(n)) if n == "2"));
+    client.shutdown().unwrap();
+
+    let client = KvsClient::new(&limited).unwrap();
+    assert!(matches!(config(&client, Some("4")), Response::Ok(Some(n)) if n == "4"));
+    assert!(matches!(config(&client, Some("5")), Response::Err(_)));
+    assert!(matches!(config(&client, Some("1")), Response::Ok(Some(n)) if n == "1"));
+    client.shutdown().unwrap();
+}
@@ -0,0 +1,65 @@
+//! `Command::Touch` served over the wire and by `kvs-client touch`
+
+use kvs::client::KvsClient;
+use kvs::common::{Command, Response};
+use kvs::engine::{EngineConfig, KvsEngine, MockClock, OptLogStructKvs};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDRESS: &str = "127.0.0.1:4816";
+
+#[test]
+fn touch_counts_the_existing_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let clock = MockClock::default();
+    let config = EngineConfig {
+        clock: Arc::new(clock.clone()),
+        ..EngineConfig::default()
+    };
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config).unwrap();
+    store.set("a".to_owned(), "value".to_owned()).unwrap();
+    store.set("b".to_owned(), "value".to_owned()).unwrap();
+    store.set("expired".to_owned(), "value".to_owned()).unwrap();
+    store.expire("expired".to_owned(), 10).unwrap();
+    clock.advance(Duration::from_secs(20));
+    let address: SocketAddr = ADDRESS.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let client = KvsClient::new(&address).unwrap();
+    let touch = Command::Touch {
+        keys: vec![
+            "a".to_owned(),
+            "b".to_owned(),
+            "expired".to_owned(),
+            "missing".to_owned(),
+        ],
+    };
+    assert!(matches!(
+        client.send(&touch).unwrap(),
+        Response::Ok(Some(count)) if count == "2"
+    ));
+    assert_eq!(
+        client.get("a".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_kvs-client"))
+        .args(["touch", "a", "missing", "--addr", ADDRESS])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    client.shutdown().unwrap();
+}
@@ -0,0 +1,132 @@
+//! Spans the server enters per connection and per command, run with `--features tracing`
+#![cfg(feature = "tracing")]
+
+use kvs::client::KvsClient;
+use kvs::engine::OptLogStructKvs;
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+#[derive(Debug)]
+struct SpanRecord {
+    name: &'static str,
+    fields: HashMap<String, String>,
+    parent: Option<u64>,
+}
+
+impl Visit for SpanRecord {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields
+            .insert(field.name().to_owned(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.fields
+            .insert(field.name().to_owned(), format!("{:?}", value));
+    }
+}
+
+thread_local! {
+    /// Spans entered by the thread, innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Keeps every span created, indexed by id minus one, with its parent
+#[derive(Clone, Default)]
+struct Recorder {
+    spans: Arc<Mutex<Vec<SpanRecord>>>,
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attributes.is_contextual() => {
+                ENTERED.with(|entered| entered.borrow().last().copied())
+            }
+            None => None,
+        };
+        let mut span = SpanRecord {
+            name: attributes.metadata().name(),
+            fields: HashMap::new(),
+            parent,
+        };
+        attributes.record(&mut span);
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(span);
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        values.record(&mut self.spans.lock().unwrap()[span.into_u64() as usize - 1]);
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, _: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, _: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().pop());
+    }
+}
+
+#[test]
+fn one_handle_command_span_per_request() {
+    let recorder = Recorder::default();
+    tracing::subscriber::set_global_default(recorder.clone()).unwrap();
+
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    let address: SocketAddr = "127.0.0.1:4814".parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+            .run(&address)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let client = KvsClient::new(&address).unwrap();
+    client.set("key0".to_owned(), "value".to_owned()).unwrap();
+    for i in 0..3 {
+        client.get(format!("key{}", i)).unwrap();
+    }
+    client.shutdown().unwrap();
+    // The connection span is done once the server reads the end of the stream
+    thread::sleep(Duration::from_millis(100));
+
+    let spans = recorder.spans.lock().unwrap();
+    let span = |id: u64| &spans[id as usize - 1];
+    let commands: Vec<&SpanRecord> = spans
+        .iter()
+        .filter(|span| span.name == "handle_command")
+        .collect();
+    let gets: Vec<&str> = commands
+        .iter()
+        .filter(|command| command.fields["cmd"] == "get")
+        .map(|command| command.fields["key"].as_str())
+        .collect();
+    assert_eq!(gets, ["key0", "key1", "key2"]);
+    assert_eq!(commands.len(), 4, "{:?}", commands);
+    for command in commands {
+        assert_eq!(span(command.parent.unwrap()).name, "connection");
+    }
+}
@@ -0,0 +1,147 @@
+//! `upgrade_format` rewriting a `LogStructKVStore` of on-disk format 1 in the current one
+
+use kvs::common::EngineType;
+use kvs::engine::{upgrade_format, KvsEngine, LogStructKVStore};
+use kvs::error::KvsError;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Writer of format 1 pinned here, bincode records one after the other with the tags
+/// they have always had, in a storage without a `FORMAT` file
+struct FormatOneWriter {
+    bytes: Vec<u8>,
+}
+
+impl FormatOneWriter {
+    fn record<T: Serialize>(&mut self, tag: u32, fields: T) -> &mut Self {
+        self.bytes.extend(bincode::serialize(&tag).unwrap());
+        self.bytes.extend(bincode::serialize(&fields).unwrap());
+        self
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> &mut Self {
+        self.record(0, (key, value))
+    }
+
+    fn rm(&mut self, key: &str) -> &mut Self {
+        self.record(2, key)
+    }
+
+    fn set_expiring(&mut self, key: &str, value: &str, expires_at: u64) -> &mut Self {
+        self.record(6, (key, value, expires_at))
+    }
+}
+
+/// Storage of format 1 with a removed key, an expiring key and an expired one
+fn write_format_one(dir: &Path) {
+    let mut writer = FormatOneWriter { bytes: Vec::new() };
+    writer
+        .set("key1", "value1")
+        .set("key2", "value2")
+        .rm("key1")
+        .set("key2", "value2b")
+        .set_expiring("key3", "value3", u64::MAX)
+        .set_expiring("key4", "value4", 1);
+    fs::write(dir.join("?0.log"), &writer.bytes).unwrap();
+    fs::write(dir.join(".engine"), "kvs").unwrap();
+}
+
+fn assert_upgraded(dir: &Path) {
+    let store = LogStructKVStore::open(dir).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2b".to_owned())
+    );
+    assert_eq!(
+        store.get("key3".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.get("key4".to_owned()).unwrap(), None);
+    assert_eq!(fs::read_to_string(dir.join(".engine")).unwrap(), "kvs");
+}
+
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap().to_os_string();
+    name.push(suffix);
+    dir.with_file_name(name)
+}
+
+#[test]
+fn upgrade_rewrites_format_one() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("store");
+    fs::create_dir(&dir).unwrap();
+    write_format_one(&dir);
+
+    match LogStructKVStore::open(&dir) {
+        Err(KvsError::IncompatibleFormat { found, current }) => {
+            assert_eq!(found, 1);
+            assert!(current > 1);
+        }
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("format 1 storage opened"),
+    }
+
+    upgrade_format(&dir, EngineType::Kvs).unwrap();
+    assert_upgraded(&dir);
+    assert!(!sibling(&dir, ".old").exists());
+    assert!(!sibling(&dir, ".upgrade").exists());
+    assert!(!dir.join("UPGRADE").exists());
+    // The new records are framed, the pinned writer's bytes are gone
+    let logs: Vec<u8> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .flat_map(|path| fs::read(path).unwrap())
+        .collect();
+    assert!(!logs.is_empty());
+    assert_ne!(&logs[..4], &0u32.to_le_bytes());
+}
+
+#[test]
+fn upgrade_resumes_after_crash_between_renames() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("store");
+    fs::create_dir(&dir).unwrap();
+    write_format_one(&dir);
+    // Upgraded copy, then put back where a crash after the first rename leaves it
+    let copy = temp_dir.path().join("copy");
+    fs::create_dir(&copy).unwrap();
+    write_format_one(&copy);
+    upgrade_format(&copy, EngineType::Kvs).unwrap();
+    fs::write(copy.join("UPGRADE"), "complete").unwrap();
+    fs::rename(&copy, sibling(&dir, ".upgrade")).unwrap();
+    fs::rename(&dir, sibling(&dir, ".old")).unwrap();
+
+    upgrade_format(&dir, EngineType::Kvs).unwrap();
+    assert_upgraded(&dir);
+    assert!(!sibling(&dir, ".old").exists());
+    assert!(!sibling(&dir, ".upgrade").exists());
+}
+
+#[test]
+fn upgrade_keeps_foreign_staging_folder() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("store");
+    fs::create_dir(&dir).unwrap();
+    write_format_one(&dir);
+    let staging = sibling(&dir, ".upgrade");
+    fs::create_dir(&staging).unwrap();
+    fs::write(staging.join("precious"), "data").unwrap();
+
+    assert!(upgrade_format(&dir, EngineType::Kvs).is_err());
+    assert_eq!(
+        fs::read_to_string(staging.join("precious")).unwrap(),
+        "data"
+    );
+
+    // An interrupted copy of an upgrade is discarded
+    fs::remove_dir_all(&staging).unwrap();
+    fs::create_dir(&staging).unwrap();
+    fs::write(staging.join("UPGRADE"), "").unwrap();
+    upgrade_format(&dir, EngineType::Kvs).unwrap();
+    assert_upgraded(&dir);
+}
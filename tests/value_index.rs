@@ -0,0 +1,60 @@
+//! Keys found by their value with `OptLogStructKvs::keys_by_value`, see `EngineConfig::value_index`
+
+use kvs::engine::{EngineConfig, KvsEngine, OptLogStructKvs};
+use kvs::error::KvsError;
+use tempfile::TempDir;
+
+fn config() -> EngineConfig {
+    EngineConfig {
+        value_index: true,
+        ..EngineConfig::default()
+    }
+}
+
+fn keys_by_value(store: &OptLogStructKvs, value: &str) -> Vec<String> {
+    store.keys_by_value(value.to_owned()).unwrap()
+}
+
+#[test]
+fn keys_follow_overwrites_and_removes() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = OptLogStructKvs::open_with_config(temp_dir.path(), config()).unwrap();
+        for key in &["c", "a", "b"] {
+            store.set(key.to_string(), "x".to_owned()).unwrap();
+        }
+        store.set("d".to_owned(), "y".to_owned()).unwrap();
+        assert_eq!(keys_by_value(&store, "x"), vec!["a", "b", "c"]);
+        assert_eq!(keys_by_value(&store, "y"), vec!["d"]);
+
+        store.set("b".to_owned(), "y".to_owned()).unwrap();
+        store.remove("c".to_owned()).unwrap();
+        store.expire_at("d".to_owned(), 1).unwrap();
+        assert_eq!(keys_by_value(&store, "x"), vec!["a"]);
+        assert_eq!(keys_by_value(&store, "y"), vec!["b"]);
+        assert_eq!(keys_by_value(&store, "z"), Vec::<String>::new());
+        // Sequence keys hold the last issued id as their value
+        assert_eq!(store.next_id("orders").unwrap(), 1);
+        assert_eq!(keys_by_value(&store, "1"), Vec::<String>::new());
+        store.compact().unwrap();
+        assert_eq!(keys_by_value(&store, "y"), vec!["b"]);
+    }
+
+    let store = OptLogStructKvs::open_with_config(temp_dir.path(), config()).unwrap();
+    assert_eq!(keys_by_value(&store, "x"), vec!["a"]);
+    assert_eq!(keys_by_value(&store, "y"), vec!["b"]);
+    store.set("a".to_owned(), "y".to_owned()).unwrap();
+    assert_eq!(keys_by_value(&store, "x"), Vec::<String>::new());
+    assert_eq!(keys_by_value(&store, "y"), vec!["a", "b"]);
+}
+
+#[test]
+fn keys_by_value_needs_the_index() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("a".to_owned(), "x".to_owned()).unwrap();
+    assert!(matches!(
+        store.keys_by_value("x".to_owned()),
+        Err(KvsError::InvalidSetting)
+    ));
+}
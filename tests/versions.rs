@@ -0,0 +1,109 @@
+//! Versions of the keys of `OptLogStructKvs`, the tokens of `set_if_version`
+
+use kvs::engine::{KvsEngine, OptLogStructKvs};
+use kvs::error::KvsError;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tempfile::TempDir;
+
+fn version(store: &OptLogStructKvs, key: &str) -> Option<u64> {
+    store
+        .get_with_version(key.to_owned())
+        .unwrap()
+        .map(|(_, version)| version)
+}
+
+#[test]
+fn one_of_two_concurrent_writes_of_a_version_wins() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    let mut expected = None;
+    for round in 0..100 {
+        let barrier = Arc::new(Barrier::new(2));
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let store = store.clone();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    store.set_if_version(
+                        "key".to_owned(),
+                        format!("{}-{}", round, writer),
+                        expected,
+                    )
+                })
+            })
+            .collect();
+        let results: Vec<_> = writers.into_iter().map(|x| x.join().unwrap()).collect();
+        let won: Vec<u64> = results
+            .iter()
+            .filter_map(|x| x.as_ref().ok())
+            .copied()
+            .collect();
+        assert_eq!(won.len(), 1);
+        assert!(results
+            .iter()
+            .any(|x| matches!(x, Err(KvsError::VersionConflict))));
+        assert_eq!(version(&store, "key"), Some(won[0]));
+        expected = Some(won[0]);
+    }
+}
+
+#[test]
+fn removed_key_never_gets_a_version_again() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "a".to_owned()).unwrap();
+    store.set("key".to_owned(), "b".to_owned()).unwrap();
+    let token = version(&store, "key").unwrap();
+    store.remove("key".to_owned()).unwrap();
+    store.set("key".to_owned(), "c".to_owned()).unwrap();
+    assert!(version(&store, "key").unwrap() > token);
+    assert!(matches!(
+        store.set_if_version("key".to_owned(), "d".to_owned(), Some(token)),
+        Err(KvsError::VersionConflict)
+    ));
+
+    // The records of the old key are gone once compacted, the mark is kept
+    let token = version(&store, "key").unwrap();
+    store.remove("key".to_owned()).unwrap();
+    store.compact().unwrap();
+    drop(store);
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "e".to_owned()).unwrap();
+    assert!(version(&store, "key").unwrap() > token);
+}
+
+#[test]
+fn versions_survive_reopen_compaction_and_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    store.set("gone".to_owned(), "a".to_owned()).unwrap();
+    store.remove("gone".to_owned()).unwrap();
+    for i in 0..3 {
+        store.set("key".to_owned(), i.to_string()).unwrap();
+    }
+    store.set("new".to_owned(), "a".to_owned()).unwrap();
+    let key_version = version(&store, "key").unwrap();
+    let new_version = version(&store, "new").unwrap();
+    assert!(new_version > key_version);
+    drop(store);
+
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    assert_eq!(version(&store, "key"), Some(key_version));
+    assert_eq!(version(&store, "new"), Some(new_version));
+    store.compact().unwrap();
+    drop(store);
+
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    assert_eq!(version(&store, "key"), Some(key_version));
+    store.set("key".to_owned(), "4".to_owned()).unwrap();
+    drop(store);
+
+    OptLogStructKvs::repair(temp_dir.path()).unwrap();
+    let store = OptLogStructKvs::open(temp_dir.path()).unwrap();
+    assert_eq!(version(&store, "key"), Some(key_version + 1));
+    assert_eq!(version(&store, "new"), Some(new_version));
+    store.set("other".to_owned(), "a".to_owned()).unwrap();
+    assert!(version(&store, "other").unwrap() > new_version);
+}
@@ -0,0 +1,197 @@
+//! `WriteThrough` of an in-memory cache in front of a sled backing engine
+
+use kvs::common::Result;
+use kvs::engine::{KvsEngine, SledStore, WriteThrough};
+use kvs::error::KvsError;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Cache keeping the pairs in a map, the expiries are left to the backing engine
+#[derive(Clone, Default)]
+struct MemoryCache(Arc<Mutex<HashMap<String, String>>>);
+
+impl KvsEngine for MemoryCache {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.lock().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.lock().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.0.lock().unwrap().remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        let mut pairs = self.0.lock().unwrap();
+        let new = f(pairs.remove(&key));
+        if let Some(value) = &new {
+            pairs.insert(key, value.clone());
+        }
+        Ok(new)
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let mut pairs = self.0.lock().unwrap();
+        if pairs.contains_key(&key) {
+            return Ok(false);
+        }
+        pairs.insert(key, value);
+        Ok(true)
+    }
+
+    fn expire(&self, _: String, _: u64) -> Result<bool> {
+        Err(KvsError::UnsupportedCommand)
+    }
+
+    fn expire_at(&self, _: String, _: u64) -> Result<bool> {
+        Err(KvsError::UnsupportedCommand)
+    }
+
+    fn expires_at(&self, _: String) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn expire_matching(&self, _: &str, _: u64) -> Result<usize> {
+        Err(KvsError::UnsupportedCommand)
+    }
+
+    /// Only `*` at the end of the pattern is supported, enough for the tests
+    fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let prefix = pattern.trim_end_matches('*');
+        let mut keys: Vec<String> = self
+            .0
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.0.lock().unwrap().contains_key(&key))
+    }
+
+    fn next_id_batch(&self, _: &str, _: u64) -> Result<Range<u64>> {
+        Err(KvsError::UnsupportedCommand)
+    }
+
+    fn export<F>(&self, mut visit: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        let pairs = self.0.lock().unwrap().clone();
+        for (key, value) in pairs {
+            visit(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn checkpoint(&self, _: &Path) -> Result<()> {
+        Err(KvsError::UnsupportedCommand)
+    }
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+}
+
+fn get<E: KvsEngine>(store: &E, key: &str) -> Option<String> {
+    store.get(key.to_owned()).unwrap()
+}
+
+#[test]
+fn miss_fills_cache_and_writes_reach_backing() {
+    let backing_dir = TempDir::new().unwrap();
+    let cache = MemoryCache::default();
+    let backing = SledStore::open(backing_dir.path()).unwrap();
+    let store = WriteThrough::new(cache.clone(), backing.clone());
+
+    // Written before the cache was put in front
+    backing.set("old".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(get(&cache, "old"), None);
+    assert_eq!(get(&store, "old"), Some("value".to_owned()));
+    assert_eq!(get(&cache, "old"), Some("value".to_owned()));
+
+    store.set("new".to_owned(), "durable".to_owned()).unwrap();
+    assert_eq!(get(&backing, "new"), Some("durable".to_owned()));
+    assert_eq!(get(&cache, "new"), Some("durable".to_owned()));
+
+    store.remove("new".to_owned()).unwrap();
+    assert_eq!(get(&backing, "new"), None);
+    assert_eq!(get(&cache, "new"), None);
+
+    store
+        .update("old".to_owned(), |_| Some("updated".to_owned()))
+        .unwrap();
+    assert_eq!(get(&cache, "old"), None);
+    assert_eq!(get(&store, "old"), Some("updated".to_owned()));
+
+    store.set("a:1".to_owned(), "1".to_owned()).unwrap();
+    store.set("a:2".to_owned(), "2".to_owned()).unwrap();
+    assert_eq!(store.remove_prefix("a:".to_owned()).unwrap(), 2);
+    assert_eq!(get(&cache, "a:1"), None);
+    assert_eq!(get(&store, "a:2"), None);
+}
+
+#[test]
+fn hits_are_served_from_the_cache_alone() {
+    let backing_dir = TempDir::new().unwrap();
+    let cache = MemoryCache::default();
+    let backing = SledStore::open(backing_dir.path()).unwrap();
+    let store = WriteThrough::new(cache, backing.clone());
+
+    store.set("key".to_owned(), "cached".to_owned()).unwrap();
+    // Changed behind the back of the `WriteThrough`, a hit doesn't look at the backing engine
+    backing.set("key".to_owned(), "behind".to_owned()).unwrap();
+    assert_eq!(get(&store, "key"), Some("cached".to_owned()));
+    store.remove("key".to_owned()).unwrap();
+    assert_eq!(get(&store, "key"), None);
+}
+
+#[test]
+fn expiring_keys_are_dropped_from_the_cache() {
+    let backing_dir = TempDir::new().unwrap();
+    let cache = MemoryCache::default();
+    let backing = SledStore::open(backing_dir.path()).unwrap();
+    let store = WriteThrough::new(cache.clone(), backing.clone());
+
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    store.set("other".to_owned(), "value".to_owned()).unwrap();
+    assert!(store.expire("key".to_owned(), 1).unwrap());
+    assert_eq!(get(&cache, "key"), None);
+    // Read from the backing engine as long as it has an expiry
+    assert_eq!(get(&store, "key"), Some("value".to_owned()));
+    assert_eq!(get(&cache, "key"), None);
+    assert_eq!(store.expire_matching("oth*", 1).unwrap(), 1);
+    assert_eq!(get(&cache, "other"), None);
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(get(&store, "key"), None);
+    assert_eq!(get(&store, "other"), None);
+
+    // A set clears the expiry, the value is cached again
+    store.set("key".to_owned(), "again".to_owned()).unwrap();
+    assert_eq!(get(&store, "key"), Some("again".to_owned()));
+    assert_eq!(get(&cache, "key"), Some("again".to_owned()));
+}